@@ -1,48 +1,114 @@
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Read;
-use std::os::linux::fs::MetadataExt;
-use std::path::Path;
-use std::time::Duration;
-use std::time::SystemTime;
-
-use crate::shared::{check_response, retry, Config, EType, Error, Secrets};
-use crypto::blake2b::Blake2b;
-use crypto::digest::Digest;
-use crypto::symmetriccipher::SynchronousStreamCipher;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::chunker::Chunker;
+use crate::crypt::{decrypt_chunk, encrypt_chunk, hash_chunk, hash_path};
+use crate::format;
+use crate::platform::{self, path_bytes, path_from_bytes};
+use crate::progress::{Progress, ScanProgress};
+use crate::shared::{
+    check_response, chunk_object, http_client, list_chunks_on, retry, Config, EType, Error,
+    LogFormat, ProgressFormat, Remote, Secrets, ROOT_TAGS_HEADER,
+};
+use crate::upload::Uploader;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use lzma;
-use pbr::ProgressBar;
 use rand::Rng;
-use rusqlite::{params, Connection, Statement, NO_PARAMS};
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OptionalExtension, Statement, NO_PARAMS};
+use serde::Serialize;
+
+/// The largest chunk_size allowed, the server does not accept more than 1GB per chunk
+const MAX_CHUNK_SIZE: u64 = 512 * 1024 * 1024;
 
-const CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+/// The largest number of chunks the server checks in one request
+const EXISTS_BATCH_SIZE: usize = 10_000;
+
+/// How long to wait for the webhook to accept a report
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
 struct DirEnt {
-    path: String,
+    path: PathBuf,
     etype: EType,
-    content: String,
+    /// The chunks of files and hard links
+    content: Vec<u8>,
+    /// The target of links and hard links
+    target: PathBuf,
     size: u64,
     mode: u32,
     uid: u32,
     gid: u32,
     mtime: i64,
+    mtime_nsec: u32,
     ctime: i64,
+    ctime_nsec: u32,
+    /// The birth time, if it is recorded and known
+    btime: Option<(i64, u32)>,
+    /// The device number of device nodes
+    rdev: u64,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// A server the backup is stored on, the configured server or one of its mirrors
+struct Destination<'a> {
+    remote: Remote<'a>,
+    uploader: Uploader,
+    /// Chunks found not to be on the server during this run
+    absent_chunks: HashSet<String>,
+    /// Chunks queued for upload during this run
+    queued_chunks: HashSet<String>,
+    /// Set when a request to the server fails, nothing more is sent to it during this run
+    failed: bool,
 }
 
 struct State<'a> {
-    secrets: Secrets,
-    config: Config,
+    secrets: &'a Secrets,
+    config: &'a Config,
     client: reqwest::Client,
     scan: bool,
     transfer_bytes: u64,
-    progress: Option<ProgressBar<std::io::Stdout>>,
+    progress: Option<Progress>,
+    scan_progress: Option<ScanProgress>,
+    chunker: Option<Chunker>,
+    exclude: Gitignore,
+    destinations: Vec<Destination<'a>>,
+    /// How many destinations must not fail for the backup to succeed
+    quorum: usize,
     has_remote_stmt: Statement<'a>,
     update_remote_stmt: Statement<'a>,
     get_chunks_stmt: Statement<'a>,
+    get_inode_chunks_stmt: Statement<'a>,
     update_chunks_stmt: Statement<'a>,
+    get_resume_stmt: Statement<'a>,
+    update_resume_stmt: Statement<'a>,
+    delete_resume_stmt: Statement<'a>,
+    get_packed_stmt: Statement<'a>,
+    update_packed_stmt: Statement<'a>,
+    /// The encrypted chunks added to the pack not yet uploaded, see push_file_chunk
+    pack: Vec<u8>,
+    /// The name the pack is uploaded as
+    pack_name: String,
     rng: rand::rngs::OsRng,
     entries: Vec<DirEnt>,
+    /// The path and chunks of files with more than one link, by device and inode
+    hardlinks: HashMap<(u64, u64), (PathBuf, Vec<u8>)>,
+    /// The number of folders being backed up below the backup dir
+    depth: usize,
+    /// The device and inode of the folders being backed up
+    ancestors: HashSet<(u64, u64)>,
+    /// The device of the backup dir being backed up, for one_file_system
+    root_dev: u64,
     modified_files_count: u64,
+    files_count: u64,
+    folders_count: u64,
+    total_bytes: u64,
+    existing_chunks: u64,
+    /// The paths that could not be backed up, and why
+    skipped: Vec<(PathBuf, String)>,
     transfered_bytes: usize,
     skipped_bytes: usize,
     conflict_bytes: usize,
@@ -55,16 +121,59 @@ enum HasChunkResult {
     No,
 }
 
-fn has_chunk(chunk: &str, state: &mut State, size: Option<usize>) -> Result<HasChunkResult, Error> {
+/// Stop using a destination that failed, unless that leaves fewer than the quorum
+fn fail_destination(d: usize, e: Error, state: &mut State) -> Result<(), Error> {
+    if state.destinations[d].failed {
+        return Ok(());
+    }
+    let live = state.destinations.iter().filter(|d| !d.failed).count();
+    if live <= state.quorum {
+        return Err(e);
+    }
+    error!(
+        "Server {} failed, continuing without it: {:?}",
+        state.destinations[d].remote.server, e
+    );
+    state.destinations[d].failed = true;
+    Ok(())
+}
+
+/// The outcome of a request to a destination, None if the request failed and the backup
+/// continues without the destination
+fn destination_result<T>(
+    d: usize,
+    res: Result<T, Error>,
+    state: &mut State,
+) -> Result<Option<T>, Error> {
+    match res {
+        Ok(v) => Ok(Some(v)),
+        Err(e @ Error::Reqwest(_)) | Err(e @ Error::HttpStatus(_)) => {
+            fail_destination(d, e, state)?;
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn has_chunk(
+    chunk: &str,
+    d: usize,
+    state: &mut State,
+    size: Option<usize>,
+) -> Result<HasChunkResult, Error> {
+    let remote = state.destinations[d].remote;
     let cnt: i64 = state
         .has_remote_stmt
-        .query(params![chunk])?
+        .query(params![remote.server, chunk])?
         .next()?
         .ok_or(Error::MissingRow())?
         .get(0)?;
-    if cnt == 1 {
+    if cnt == 1 || state.destinations[d].queued_chunks.contains(chunk) {
         return Ok(HasChunkResult::YesCached);
     }
+    if state.destinations[d].absent_chunks.contains(chunk) {
+        return Ok(HasChunkResult::No);
+    }
 
     // For small chunks it is quicker to just reupload
     if let Some(size) = size {
@@ -75,75 +184,146 @@ fn has_chunk(chunk: &str, state: &mut State, size: Option<usize>) -> Result<HasC
 
     let url = format!(
         "{}/chunks/{}/{}",
-        &state.config.server,
+        remote.server,
         hex::encode(&state.secrets.bucket),
         &chunk
     );
-    let res = retry(&mut || {
+    let res = retry(&state.config.retry, &mut || {
         state
             .client
             .head(&url[..])
-            .basic_auth(&state.config.user, Some(&state.config.password))
+            .basic_auth(remote.user, Some(remote.password))
             .send()
     })?;
     match res.status() {
         reqwest::StatusCode::OK => Ok(HasChunkResult::Yes),
-        reqwest::StatusCode::NOT_FOUND => Ok(HasChunkResult::No),
+        reqwest::StatusCode::NOT_FOUND => {
+            state.destinations[d]
+                .absent_chunks
+                .insert(chunk.to_string());
+            Ok(HasChunkResult::No)
+        }
         code => Err(Error::HttpStatus(code)),
     }
 }
 
-fn push_chunk(content: &[u8], state: &mut State) -> Result<String, Error> {
-    let now = std::time::Instant::now();
-    let mut hasher = Blake2b::new(256 / 8);
-    hasher.input(&state.secrets.seed);
-    hasher.input(content);
-    let hash = hasher.result_str().to_string();
-    let t0 = now.elapsed().as_millis();
-    let hc = has_chunk(&hash, state, Some(content.len()))?;
-    let t1 = now.elapsed().as_millis();
-    let mut t2 = t1;
-    if hc == HasChunkResult::No {
-        let url = format!(
-            "{}/chunks/{}/{}",
-            &state.config.server,
-            hex::encode(&state.secrets.bucket),
-            &hash
-        );
-
-        let mut crypted = Vec::new();
-        crypted.resize(content.len() + 12, 0);
-        state.rng.fill(&mut crypted[..12]);
+/// Check if all the given chunks are on every destination that has not failed
+///
+/// Packed chunks are there if their pack is
+fn has_chunks(chunks: &[&str], state: &mut State) -> Result<bool, Error> {
+    let mut objects: Vec<&str> = chunks.iter().map(|chunk| chunk_object(chunk)).collect();
+    objects.sort_unstable();
+    objects.dedup();
+    for d in 0..state.destinations.len() {
+        if state.destinations[d].failed {
+            continue;
+        }
+        let res = has_chunks_on(&objects, d, state);
+        if destination_result(d, res, state)? == Some(false) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
 
-        crypto::chacha20::ChaCha20::new(&state.secrets.key, &crypted[..12])
-            .process(content, &mut crypted[12..]);
-        t2 = now.elapsed().as_millis();
+/// Check if all the given chunks are on a destination
+///
+/// Chunks not known to be there from the remote_chunks table are checked in batches,
+/// falling back to checking them one by one on servers that do not support that
+fn has_chunks_on(chunks: &[&str], d: usize, state: &mut State) -> Result<bool, Error> {
+    let remote = state.destinations[d].remote;
+    let mut unknown = Vec::new();
+    for chunk in chunks {
+        let cnt: i64 = state
+            .has_remote_stmt
+            .query(params![remote.server, chunk])?
+            .next()?
+            .ok_or(Error::MissingRow())?
+            .get(0)?;
+        if cnt != 1 {
+            unknown.push(*chunk);
+        }
+    }
 
-        let res = retry(&mut || {
+    let url = format!(
+        "{}/chunks/{}/exists",
+        remote.server,
+        hex::encode(&state.secrets.bucket)
+    );
+    for batch in unknown.chunks(EXISTS_BATCH_SIZE) {
+        let body = batch.join("\0");
+        let mut res = retry(&state.config.retry, &mut || {
             state
                 .client
-                .put(&url[..])
-                .basic_auth(&state.config.user, Some(&state.config.password))
-                .body(reqwest::Body::from(crypted.clone()))
+                .post(&url[..])
+                .basic_auth(remote.user, Some(remote.password))
+                .body(body.clone())
                 .send()
         })?;
         match res.status() {
-            reqwest::StatusCode::OK => {
-                state.transfered_bytes += crypted.len();
-            }
-            reqwest::StatusCode::CONFLICT => {
-                state.conflict_bytes += crypted.len();
-                debug!("Conflict in upload");
+            reqwest::StatusCode::OK => (),
+            reqwest::StatusCode::NOT_FOUND => {
+                for chunk in batch {
+                    if has_chunk(chunk, d, state, None)? == HasChunkResult::No {
+                        return Ok(false);
+                    }
+                }
+                continue;
             }
             code => return Err(Error::HttpStatus(code)),
         }
+        let found = res.text()?;
+        if found.len() != batch.len() {
+            return Err(Error::Msg("Bad exists response"));
+        }
+        if found.bytes().any(|v| v != b'1') {
+            return Ok(false);
+        }
+        for chunk in batch {
+            state
+                .update_remote_stmt
+                .execute(params![remote.server, chunk])?;
+        }
+    }
+    Ok(true)
+}
+
+/// Upload a chunk to every destination that has not failed and does not have it already
+fn push_chunk(content: &[u8], state: &mut State) -> Result<String, Error> {
+    let now = std::time::Instant::now();
+    let hash = hash_chunk(content, state.secrets);
+    let t0 = now.elapsed().as_millis();
+    let mut missing = Vec::new();
+    for d in 0..state.destinations.len() {
+        if state.destinations[d].failed {
+            continue;
+        }
+        let res = has_chunk(&hash, d, state, Some(content.len()));
+        match destination_result(d, res, state)? {
+            Some(HasChunkResult::No) => missing.push(d),
+            Some(HasChunkResult::Yes) => {
+                let server = state.destinations[d].remote.server;
+                state.update_remote_stmt.execute(params![server, hash])?;
+            }
+            Some(HasChunkResult::YesCached) | None => (),
+        }
+    }
+    let t1 = now.elapsed().as_millis();
+    let mut t2 = t1;
+    if !missing.is_empty() {
+        let crypted = encrypt_content(content, state)?;
+        t2 = now.elapsed().as_millis();
+
+        let (last, rest) = missing.split_last().unwrap();
+        for d in rest {
+            queue_upload(&mut state.destinations[*d], &hash, crypted.clone())?;
+        }
+        queue_upload(&mut state.destinations[*last], &hash, crypted)?;
     } else {
         state.skipped_bytes += content.len();
     }
     let t3 = now.elapsed().as_millis();
-    if hc != HasChunkResult::YesCached {
-        state.update_remote_stmt.execute(params![hash])?;
-    }
+    handle_upload_results(state, false)?;
     if let Some(p) = &mut state.progress {
         p.add(content.len() as u64);
     }
@@ -161,15 +341,209 @@ fn push_chunk(content: &[u8], state: &mut State) -> Result<String, Error> {
     Ok(hash)
 }
 
-fn backup_file(path: &Path, size: u64, mtime: u64, state: &mut State) -> Result<String, Error> {
-    let path_str = path
-        .to_str()
-        .ok_or_else(|| Error::BadPath(path.to_path_buf()))?;
+/// Encrypt a chunk, compressing it first if compression is enabled
+fn encrypt_content(content: &[u8], state: &mut State) -> Result<Vec<u8>, Error> {
+    let compression_level = if state.config.compression {
+        Some(state.config.compression_level)
+    } else {
+        None
+    };
+    encrypt_chunk(
+        content,
+        state.secrets,
+        compression_level,
+        state.config.deterministic_nonce,
+        &mut state.rng,
+    )
+}
+
+/// Upload a chunk of a file, adding it to the pack instead if it is smaller than pack_chunk_size
+///
+/// A packed chunk is referred to as hash:pack:offset:length, where the offset and length are
+/// those of the encrypted chunk in the pack. Packs are named at random, as the chunks of files
+/// refer to a pack before its content is known
+fn push_file_chunk(content: &[u8], state: &mut State) -> Result<String, Error> {
+    if state.config.pack_size == 0 || content.len() as u64 >= state.config.pack_chunk_size {
+        return push_chunk(content, state);
+    }
+    let hash = hash_chunk(content, state.secrets);
+    let packed: Option<String> = state
+        .get_packed_stmt
+        .query_row(params![hash], |row| row.get(0))
+        .optional()?;
+    if let Some(chunk) = packed {
+        // The pack may not have been uploaded if an earlier backup failed
+        let pack = chunk_object(&chunk);
+        let queued = state
+            .destinations
+            .iter()
+            .all(|d| d.failed || d.queued_chunks.contains(pack));
+        if pack == state.pack_name || queued || has_chunks(&[pack], state)? {
+            state.skipped_bytes += content.len();
+            if let Some(p) = &mut state.progress {
+                p.add(content.len() as u64);
+            }
+            return Ok(chunk);
+        }
+    }
+
+    let crypted = encrypt_content(content, state)?;
+    if !state.pack.is_empty() && (state.pack.len() + crypted.len()) as u64 > state.config.pack_size
+    {
+        flush_pack(state)?;
+    }
+    if state.pack.is_empty() {
+        let mut name = [0; 32];
+        state.rng.fill(&mut name[..]);
+        state.pack_name = hex::encode(name);
+    }
+    let chunk = format!(
+        "{}:{}:{}:{}",
+        hash,
+        state.pack_name,
+        state.pack.len(),
+        crypted.len()
+    );
+    state.pack.extend_from_slice(&crypted);
+    state.update_packed_stmt.execute(params![hash, chunk])?;
+    if let Some(p) = &mut state.progress {
+        p.add(content.len() as u64);
+    }
+    Ok(chunk)
+}
+
+/// Upload the pack being filled to every destination that has not failed
+fn flush_pack(state: &mut State) -> Result<(), Error> {
+    if state.pack.is_empty() {
+        return Ok(());
+    }
+    let pack = std::mem::replace(&mut state.pack, Vec::new());
+    let name = state.pack_name.clone();
+    debug!("Put pack; pack: {}, size: {}", name, pack.len());
+    let live: Vec<usize> = (0..state.destinations.len())
+        .filter(|d| !state.destinations[*d].failed)
+        .collect();
+    if let Some((last, rest)) = live.split_last() {
+        for d in rest {
+            queue_upload(&mut state.destinations[*d], &name, pack.clone())?;
+        }
+        queue_upload(&mut state.destinations[*last], &name, pack)?;
+    }
+    handle_upload_results(state, false)
+}
+
+/// Queue the upload of a chunk to a destination
+fn queue_upload(dest: &mut Destination, hash: &str, crypted: Vec<u8>) -> Result<(), Error> {
+    // The chunk is recorded as present remotely once the upload has finished,
+    // until then it is known to be there from queued_chunks
+    dest.absent_chunks.remove(hash);
+    dest.queued_chunks.insert(hash.to_string());
+    dest.uploader.push(hash.to_string(), crypted)
+}
+
+/// Account for finished uploads
+///
+/// If wait is true, wait for all pending uploads to finish
+fn handle_upload_results(state: &mut State, wait: bool) -> Result<(), Error> {
+    for d in 0..state.destinations.len() {
+        while let Some(res) = state.destinations[d].uploader.result(wait)? {
+            let status = res
+                .status
+                .map_err(Error::from)
+                .and_then(|status| match status {
+                    reqwest::StatusCode::OK | reqwest::StatusCode::CONFLICT => Ok(status),
+                    code => Err(Error::HttpStatus(code)),
+                });
+            match destination_result(d, status, state)? {
+                Some(reqwest::StatusCode::OK) => {
+                    state.transfered_bytes += res.len;
+                }
+                Some(_) => {
+                    state.conflict_bytes += res.len;
+                    debug!("Conflict in upload");
+                }
+                None => continue,
+            }
+            let server = state.destinations[d].remote.server;
+            state
+                .update_remote_stmt
+                .execute(params![server, res.hash])?;
+        }
+    }
+    Ok(())
+}
+
+/// Wait for the uploads in flight when the backup stops early, recording those that
+/// made it in the cache so that the next backup does not upload them again
+fn drain_uploads(state: &mut State) {
+    for dest in state.destinations.iter_mut() {
+        while let Ok(Some(res)) = dest.uploader.result(true) {
+            match res.status {
+                Ok(reqwest::StatusCode::OK) | Ok(reqwest::StatusCode::CONFLICT) => {
+                    if let Err(e) = state
+                        .update_remote_stmt
+                        .execute(params![dest.remote.server, res.hash])
+                    {
+                        warn!("Unable to record upload of {}: {:?}", res.hash, e);
+                    }
+                }
+                _ => (),
+            }
+        }
+        dest.uploader.finish();
+    }
+}
+
+/// The key of a path in the files table of the cache
+///
+/// Paths are stored as text if they are valid utf-8 and as blobs otherwise,
+/// or as their keyed hash with hash_cache_paths
+fn path_key(path: &Path, state: &State) -> Value {
+    if state.config.hash_cache_paths {
+        return Value::Blob(hash_path(&path_bytes(path), state.secrets));
+    }
+    match path.to_str() {
+        Some(path) => Value::Text(path.to_string()),
+        None => Value::Blob(path_bytes(path).into_owned()),
+    }
+}
+
+/// Check if a file has been modified since before was read
+fn modified_since(path: &Path, before: &fs::Metadata) -> Result<bool, Error> {
+    let after = fs::metadata(path)?;
+    let (a, b) = (platform::meta(&after), platform::meta(before));
+    Ok(after.len() != before.len()
+        || after.modified()? != before.modified()?
+        || a.ctime != b.ctime
+        || a.ctime_nsec != b.ctime_nsec)
+}
+
+fn backup_file(path: &Path, md: &fs::Metadata, state: &mut State) -> Result<String, Error> {
+    let size = md.len();
+    let meta = platform::meta(md);
+    let mtime = md
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    let mtime_nsec = mtime.subsec_nanos();
+    let mtime = mtime.as_secs();
+    let path_key = path_key(path, state);
     if let Some(p) = &mut state.progress {
-        let start = i64::max(0, path_str.len() as i64 - 40) as usize;
+        let path_str = path.to_string_lossy();
+        let start = path_str
+            .char_indices()
+            .rev()
+            .nth(39)
+            .map_or(0, |(idx, _)| idx);
         p.message(&format!("{} ", &path_str[start..]));
     }
 
+    if state.scan {
+        state.files_count += 1;
+        state.total_bytes += size;
+        update_scan_progress(state);
+    }
+
     // IF the file is empty we just do nothing
     if size == 0 {
         return Ok("empty".to_string());
@@ -177,25 +551,57 @@ fn backup_file(path: &Path, size: u64, mtime: u64, state: &mut State) -> Result<
 
     // Check if we have allready checked the file once
     if !state.config.recheck {
-        let chunks: Option<String> = {
-            let mut rows =
-                state
-                    .get_chunks_stmt
-                    .query(params![path_str, size as i64, mtime as i64])?;
-            match rows.next()? {
-                Some(row) => row.get(0)?,
-                None => None,
+        let by_inode = params![
+            meta.dev as i64,
+            meta.ino as i64,
+            size as i64,
+            mtime as i64,
+            mtime_nsec
+        ];
+        // With inode_change_detection the file is found by its inode, so that
+        // renamed files are found while replaced files are not
+        let chunks: Option<String> = if state.config.inode_change_detection {
+            state
+                .get_inode_chunks_stmt
+                .query_row(by_inode, |row| row.get(0))
+                .optional()?
+        } else {
+            let chunks = state
+                .get_chunks_stmt
+                .query_row(
+                    params![path_key, size as i64, mtime as i64, mtime_nsec],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            // A path that is not in the cache may be a renamed file, which is found by
+            // its inode. The path is then cached, so it is found directly next time
+            match chunks {
+                Some(chunks) => Some(chunks),
+                None => {
+                    let chunks: Option<String> = state
+                        .get_inode_chunks_stmt
+                        .query_row(by_inode, |row| row.get(0))
+                        .optional()?;
+                    if let (Some(chunks), false) = (&chunks, state.scan) {
+                        state.update_chunks_stmt.execute(params![
+                            path_key,
+                            size as i64,
+                            mtime as i64,
+                            mtime_nsec,
+                            meta.dev as i64,
+                            meta.ino as i64,
+                            chunks
+                        ])?;
+                    }
+                    chunks
+                }
             }
         };
         if let Some(chunks) = chunks {
-            let mut good = true;
-            for chunk in chunks.split(',') {
-                if has_chunk(chunk, state, None)? == HasChunkResult::No {
-                    good = false;
-                    break;
+            if has_chunks(&chunks.split(',').collect::<Vec<_>>(), state)? {
+                if state.scan {
+                    state.existing_chunks += chunks.split(',').count() as u64;
                 }
-            }
-            if good {
                 return Ok(chunks);
             }
         }
@@ -204,15 +610,47 @@ fn backup_file(path: &Path, size: u64, mtime: u64, state: &mut State) -> Result<
     if state.scan {
         state.modified_files_count += 1;
         state.transfer_bytes += size;
-        return Ok("_".repeat((65 * (size + CHUNK_SIZE - 1) / CHUNK_SIZE - 1) as usize));
+        let chunk_size = state.config.chunk_size;
+        return Ok("_".repeat((65 * (size + chunk_size - 1) / chunk_size - 1) as usize));
     }
 
     // Open the file and read each chunk
     let mut file = fs::File::open(&path)?;
 
-    let mut buffer: Vec<u8> = Vec::new();
-    buffer.resize(u64::min(size, CHUNK_SIZE) as usize, 0);
     let mut chunks = "".to_string();
+    if state.chunker.is_some() {
+        backup_file_content_defined(&mut file, size, &mut chunks, state)?;
+    } else {
+        backup_file_fixed(&mut file, size, &mut chunks, state)?;
+    }
+
+    // If the file was modified while we read it the chunks may not match the cached
+    // size and mtime, so do not trust them next time
+    if modified_since(path, md)? {
+        warn!("File {:?} was modified while it was backed up", path);
+        return Ok(chunks);
+    }
+    state.update_chunks_stmt.execute(params![
+        path_key,
+        size as i64,
+        mtime as i64,
+        mtime_nsec,
+        meta.dev as i64,
+        meta.ino as i64,
+        &chunks
+    ])?;
+    Ok(chunks)
+}
+
+/// Push the content of file in chunks of chunk_size
+fn backup_file_fixed(
+    file: &mut dyn Read,
+    size: u64,
+    chunks: &mut String,
+    state: &mut State,
+) -> Result<(), Error> {
+    let mut buffer: Vec<u8> = Vec::new();
+    buffer.resize(u64::min(size, state.config.chunk_size) as usize, 0);
     loop {
         let mut used = 0;
         while used < buffer.len() {
@@ -225,140 +663,691 @@ fn backup_file(path: &Path, size: u64, mtime: u64, state: &mut State) -> Result<
         if used == 0 {
             break;
         }
+        if platform::interrupted() {
+            return Err(Error::Interrupted());
+        }
 
         if !chunks.is_empty() {
             chunks.push_str(&",");
         }
-        chunks.push_str(&push_chunk(&buffer[..used], state)?);
+        chunks.push_str(&push_file_chunk(&buffer[..used], state)?);
 
         if used != buffer.len() {
             break;
         }
     }
+    Ok(())
+}
 
-    //TODO check if the mtime has changed while we where pushing
-    state.update_chunks_stmt.execute(params![
-        &path.to_str().unwrap(),
-        size as i64,
-        mtime as i64,
-        &chunks
-    ])?;
-    Ok(chunks)
+/// Push the content of file in chunks with boundaries chosen by state.chunker
+fn backup_file_content_defined(
+    file: &mut dyn Read,
+    size: u64,
+    chunks: &mut String,
+    state: &mut State,
+) -> Result<(), Error> {
+    let max = state.chunker.as_ref().unwrap().max_size();
+    // Leave room for one byte more than the file size, so that we detect the end of
+    // the file without growing the buffer
+    let mut buffer: Vec<u8> = Vec::new();
+    buffer.resize(u64::min(size.saturating_add(1), max as u64) as usize, 0);
+    let mut used = 0;
+    let mut eof = false;
+    loop {
+        while !eof && used < buffer.len() {
+            let w = file.read(&mut buffer[used..])?;
+            if w == 0 {
+                eof = true;
+            }
+            used += w;
+        }
+        if !eof && buffer.len() < max {
+            // The file has grown since we looked at its size
+            buffer.resize(max, 0);
+            continue;
+        }
+        if used == 0 {
+            break;
+        }
+        if platform::interrupted() {
+            return Err(Error::Interrupted());
+        }
+
+        let cut = state.chunker.as_ref().unwrap().cut(&buffer[..used]);
+        if !chunks.is_empty() {
+            chunks.push_str(&",");
+        }
+        chunks.push_str(&push_file_chunk(&buffer[..cut], state)?);
+        buffer.copy_within(cut..used, 0);
+        used -= cut;
+
+        if eof && used == 0 {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A reader counting the bytes read through it
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Back up what is read from stdin until its end as a file named name
+///
+/// The length of the stream is not known upfront, so it is always read and pushed chunk by
+/// chunk. The file is owned by the current user, with mode 644 and the current time as mtime
+fn backup_stdin(name: &str, state: &mut State) -> Result<DirEnt, Error> {
+    if name.is_empty() {
+        return Err(Error::Msg("The name of stdin must not be empty"));
+    }
+    let stdin = std::io::stdin();
+    let mut input = CountingReader {
+        inner: stdin.lock(),
+        count: 0,
+    };
+    let mut chunks = String::new();
+    if state.chunker.is_some() {
+        backup_file_content_defined(&mut input, u64::MAX, &mut chunks, state)?;
+    } else {
+        backup_file_fixed(&mut input, u64::MAX, &mut chunks, state)?;
+    }
+    let size = input.count;
+    if chunks.is_empty() {
+        chunks.push_str("empty");
+    }
+    let (mtime, mtime_nsec) = platform::split_time(SystemTime::now());
+    let (uid, gid) = platform::current_owner();
+    Ok(DirEnt {
+        path: Path::new("/").join(name),
+        etype: EType::File,
+        content: chunks.into_bytes(),
+        target: PathBuf::new(),
+        size,
+        mode: 0o644,
+        uid,
+        gid,
+        mtime,
+        mtime_nsec,
+        ctime: mtime,
+        ctime_nsec: mtime_nsec,
+        btime: None,
+        rdev: 0,
+        xattrs: Vec::new(),
+    })
+}
+
+/// The birth time of a file, if btime is set and the file system reports it
+fn birth_time(md: &fs::Metadata, state: &State) -> Option<(i64, u32)> {
+    if state.config.btime {
+        platform::birth_time(md)
+    } else {
+        None
+    }
+}
+
+/// Read the extended attributes of path if enabled
+///
+/// If the file system does not support extended attributes there are none
+fn read_xattrs(path: &Path, state: &State) -> Vec<(Vec<u8>, Vec<u8>)> {
+    if !state.config.xattrs {
+        return Vec::new();
+    }
+    platform::xattrs(path)
+}
+
+/// Check if files of size are excluded by max_file_size or min_file_size
+fn excluded_size(size: u64, config: &Config) -> bool {
+    (config.max_file_size != 0 && size > config.max_file_size) || size < config.min_file_size
+}
+
+/// Build the matcher of the paths excluded by the config
+///
+/// The patterns use the gitignore syntax, anchored patterns are relative to the file system root
+fn exclude_matcher(config: &Config) -> Result<Gitignore, Error> {
+    let mut builder = GitignoreBuilder::new("/");
+    for pattern in config.exclude.iter() {
+        builder.add_line(None, pattern)?;
+    }
+    Ok(builder.build()?)
+}
+
+/// Skip an entry that could not be backed up, remembering why
+///
+/// Unless continue_on_error is set the error fails the backup instead
+fn skip(kind: &str, path: &Path, e: Error, state: &mut State) -> Result<(), Error> {
+    error!("Unable to backup {} {:?}: {:?}\n", kind, path, e);
+    if !state.config.continue_on_error {
+        return Err(e);
+    }
+    state.skipped.push((path.to_path_buf(), format!("{:?}", e)));
+    Ok(())
+}
+
+/// Redraw the counter of what the scan has found, if it is shown
+fn update_scan_progress(state: &mut State) {
+    if let Some(p) = &mut state.scan_progress {
+        p.update(state.files_count, state.folders_count, state.total_bytes);
+    }
+}
+
+/// Log the paths that were skipped
+fn report_skipped(state: &State) {
+    if state.skipped.is_empty() {
+        return;
+    }
+    warn!(
+        "Skipped {} entries that could not be read:",
+        state.skipped.len()
+    );
+    for (path, e) in state.skipped.iter() {
+        warn!("  {:?}: {}", path, e);
+    }
 }
 
 fn backup_folder(dir: &Path, state: &mut State) -> Result<(), Error> {
     let raw_entries = match fs::read_dir(dir) {
         Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
-        Err(e) => {
-            error!("Unable to backup folder {:?}: {:?}\n", dir, e);
-            return Ok(());
-        }
+        Err(e) => return skip("folder", dir, e.into(), state),
         Ok(v) => v,
     };
     for entry in raw_entries {
-        let path = entry?.path();
+        if platform::interrupted() {
+            return Err(Error::Interrupted());
+        }
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => return skip("folder", dir, e.into(), state),
+        };
         let md = match fs::symlink_metadata(&path) {
             Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
             Err(e) => {
-                error!("Unable to backup entry {:?}: {:?}\n", path, e);
+                skip("entry", &path, e.into(), state)?;
                 continue;
             }
             Ok(v) => v,
         };
-        let path_str = path
-            .to_str()
-            .ok_or_else(|| Error::BadPath(path.to_path_buf()))?;
-        if path_str.contains('\0') {
-            return Err(Error::BadPath(path.to_path_buf()));
-        }
+        // Followed links to folders are guarded against cycles by backup_subtree,
+        // links that lead nowhere are stored as links
+        let md = if state.config.follow_symlinks && md.file_type().is_symlink() {
+            fs::metadata(&path).unwrap_or(md)
+        } else {
+            md
+        };
         let ft = md.file_type();
-        let mode = md.st_mode() & 0xFFF;
+        if state.exclude.matched(&path, ft.is_dir()).is_ignore() {
+            debug!("Excluding {:?}", path);
+            continue;
+        }
+        let meta = platform::meta(&md);
+        let btime = birth_time(&md, state);
+        let mode = meta.mode & 0xFFF;
         if ft.is_dir() {
             state.entries.push(DirEnt {
-                path: path_str.to_string(),
+                path: path.clone(),
                 etype: EType::Dir,
-                content: "0".to_string(),
+                content: b"0".to_vec(),
+                target: PathBuf::new(),
                 size: 0,
                 mode,
-                uid: md.st_uid(),
-                gid: md.st_gid(),
-                mtime: md.st_mtime(),
-                ctime: md.st_ctime(),
+                uid: meta.uid,
+                gid: meta.gid,
+                mtime: meta.mtime,
+                mtime_nsec: meta.mtime_nsec,
+                ctime: meta.ctime,
+                ctime_nsec: meta.ctime_nsec,
+                btime,
+                rdev: 0,
+                xattrs: read_xattrs(&path, state),
             });
-            backup_folder(&path, state)?;
+            // The mount point itself is kept, so that there is somewhere to mount on restore
+            if state.config.one_file_system && meta.dev != state.root_dev {
+                info!("Skipping mount point {:?}", path);
+                continue;
+            }
+            backup_subtree(&path, &md, state)?;
+        } else if ft.is_file()
+            && meta.nlink > 1
+            && state.hardlinks.contains_key(&(meta.dev, meta.ino))
+        {
+            // We have already seen another link to this file, only record the link
+            let (target, content) = &state.hardlinks[&(meta.dev, meta.ino)];
+            let ent = DirEnt {
+                path: path.clone(),
+                etype: EType::Hardlink,
+                content: content.clone(),
+                target: target.clone(),
+                size: md.len(),
+                mode,
+                uid: meta.uid,
+                gid: meta.gid,
+                mtime: meta.mtime,
+                mtime_nsec: meta.mtime_nsec,
+                ctime: meta.ctime,
+                ctime_nsec: meta.ctime_nsec,
+                btime,
+                rdev: 0,
+                xattrs: Vec::new(),
+            };
+            state.entries.push(ent);
         } else if ft.is_file() {
-            let mtime = md
-                .modified()?
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
+            if excluded_size(md.len(), state.config) {
+                if state.scan {
+                    info!("Excluding {:?} of {} bytes", path, md.len());
+                }
+                continue;
+            }
             let ent = DirEnt {
-                path: path_str.to_string(),
+                path: path.clone(),
                 etype: EType::File,
-                content: match backup_file(&path, md.len(), mtime, state) {
+                content: match backup_file(&path, &md, state) {
                     Err(Error::Io(ref e)) if e.kind() == std::io::ErrorKind::NotFound => continue,
-                    Err(e) => {
-                        error!("Unable to backup file {}: {:?}\n", path_str, e);
+                    // Failing to open or read the file only loses this file, while failing
+                    // to talk to the server or the cache would lose every file after it
+                    Err(e @ Error::Io(_)) => {
+                        skip("file", &path, e, state)?;
                         continue;
                     }
-                    Ok(v) => v,
+                    Err(e) => return Err(e),
+                    Ok(v) => v.into_bytes(),
                 },
+                target: PathBuf::new(),
                 size: md.len(),
                 mode,
-                uid: md.st_uid(),
-                gid: md.st_gid(),
-                mtime: md.st_mtime(),
-                ctime: md.st_ctime(),
+                uid: meta.uid,
+                gid: meta.gid,
+                mtime: meta.mtime,
+                mtime_nsec: meta.mtime_nsec,
+                ctime: meta.ctime,
+                ctime_nsec: meta.ctime_nsec,
+                btime,
+                rdev: 0,
+                xattrs: read_xattrs(&path, state),
             };
+            if meta.nlink > 1 {
+                state
+                    .hardlinks
+                    .insert((meta.dev, meta.ino), (path.clone(), ent.content.clone()));
+            }
             state.entries.push(ent);
         } else if ft.is_symlink() {
             let link = match fs::read_link(&path) {
                 Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
                 Err(e) => {
-                    error!("Unable to backup link {:?}: {:?}\n", path, e);
+                    skip("link", &path, e.into(), state)?;
                     continue;
                 }
                 Ok(v) => v,
             };
             state.entries.push(DirEnt {
-                path: path_str.to_string(),
+                path: path.clone(),
                 etype: EType::Link,
-                content: link
-                    .to_str()
-                    .ok_or_else(|| Error::BadPath(link.to_path_buf()))?
-                    .to_string(),
+                content: Vec::new(),
+                target: link,
                 size: 0,
                 mode,
-                uid: md.st_uid(),
-                gid: md.st_gid(),
-                mtime: md.st_mtime(),
-                ctime: md.st_ctime(),
+                uid: meta.uid,
+                gid: meta.gid,
+                mtime: meta.mtime,
+                mtime_nsec: meta.mtime_nsec,
+                ctime: meta.ctime,
+                ctime_nsec: meta.ctime_nsec,
+                btime,
+                rdev: 0,
+                xattrs: read_xattrs(&path, state),
             });
+        } else {
+            let etype = match platform::special_type(&ft) {
+                Some(etype) => etype,
+                None => continue,
+            };
+            state.entries.push(DirEnt {
+                path: path.clone(),
+                etype,
+                content: Vec::new(),
+                target: PathBuf::new(),
+                size: 0,
+                mode,
+                uid: meta.uid,
+                gid: meta.gid,
+                mtime: meta.mtime,
+                mtime_nsec: meta.mtime_nsec,
+                ctime: meta.ctime,
+                ctime_nsec: meta.ctime_nsec,
+                btime,
+                rdev: meta.rdev,
+                xattrs: read_xattrs(&path, state),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Append the entries of a folder backed up by an interrupted backup
+///
+/// Returns false if the folder or any entry below it has changed since, or some of its
+/// chunks never made it to the server, in which case it must be backed up again
+fn resume_folder(dir: &Path, md: &fs::Metadata, state: &mut State) -> Result<bool, Error> {
+    let meta = platform::meta(md);
+    let key = resume_key(dir, state);
+    let row: Option<(Vec<u8>, Vec<u8>)> = {
+        let mut rows = state
+            .get_resume_stmt
+            .query(params![key, meta.mtime, meta.ctime])?;
+        match rows.next()? {
+            Some(row) => Some((row.get(0)?, row.get(1)?)),
+            None => None,
+        }
+    };
+    let (entries, links) =
+        match row.and_then(|(e, l)| Some((open_resume(e, state)?, open_resume(l, state)?))) {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+
+    let mut ents = Vec::new();
+    let mut data = &entries[..];
+    while !data.is_empty() {
+        ents.push(decode_entry(format::read_field(&mut data)?)?);
+    }
+    // The stamps of the folder do not change when a file inside it is rewritten
+    if !ents.iter().all(entry_unchanged) {
+        return Ok(false);
+    }
+    let mut chunks = Vec::new();
+    for ent in ents.iter() {
+        if (ent.etype == EType::File || ent.etype == EType::Hardlink) && ent.size != 0 {
+            chunks.extend(
+                std::str::from_utf8(&ent.content)
+                    .map_err(|_| Error::Msg("Chunks are not utf-8"))?
+                    .split(','),
+            );
         }
     }
+    if !has_chunks(&chunks, state)? {
+        return Ok(false);
+    }
+
+    // Later links to files in the folder must still be recorded as hard links
+    let mut data = &links[..];
+    while !data.is_empty() {
+        let dev = format::to_u64(format::read_field(&mut data)?)?;
+        let ino = format::to_u64(format::read_field(&mut data)?)?;
+        let path = path_from_bytes(format::read_field(&mut data)?);
+        let content = format::read_field(&mut data)?.to_vec();
+        state.hardlinks.insert((dev, ino), (path, content));
+    }
+
+    debug!("Resuming {:?}", dir);
+    state.entries.extend(ents);
+    Ok(true)
+}
+
+/// Check that the file system still has an entry as it was recorded
+fn entry_unchanged(ent: &DirEnt) -> bool {
+    let md = match fs::symlink_metadata(&ent.path) {
+        Ok(md) => md,
+        Err(_) => return false,
+    };
+    let meta = platform::meta(&md);
+    let sized = ent.etype == EType::File || ent.etype == EType::Hardlink;
+    (!sized || ent.size == md.len())
+        && ent.mtime == meta.mtime
+        && ent.mtime_nsec == meta.mtime_nsec
+        && ent.ctime == meta.ctime
+        && ent.ctime_nsec == meta.ctime_nsec
+}
+
+/// Remember the entries of a folder that has been backed up, starting at start,
+/// so that the folder can be skipped if the backup is interrupted
+fn record_folder(
+    dir: &Path,
+    md: &fs::Metadata,
+    start: usize,
+    state: &mut State,
+) -> Result<(), Error> {
+    let mut entries = Vec::new();
+    let mut record = Vec::new();
+    for ent in state.entries[start..].iter() {
+        record.clear();
+        encode_entry(ent, &mut record);
+        format::push_field(&mut entries, &record);
+    }
+    let mut links = Vec::new();
+    for ((dev, ino), (path, content)) in state.hardlinks.iter() {
+        if path.starts_with(dir) {
+            format::push_field(&mut links, &dev.to_le_bytes());
+            format::push_field(&mut links, &ino.to_le_bytes());
+            format::push_field(&mut links, &path_bytes(path));
+            format::push_field(&mut links, content);
+        }
+    }
+
+    // The entries of the sub folders are part of this record, so theirs can go
+    let folders: Vec<Vec<u8>> = state.entries[start..]
+        .iter()
+        .filter(|ent| ent.etype == EType::Dir)
+        .map(|ent| resume_key(&ent.path, state))
+        .collect();
+    for key in folders {
+        state.delete_resume_stmt.execute(params![key])?;
+    }
+    let entries = seal_resume(entries, state)?;
+    let links = seal_resume(links, state)?;
+    let meta = platform::meta(md);
+    state.update_resume_stmt.execute(params![
+        resume_key(dir, state),
+        meta.mtime,
+        meta.ctime,
+        entries,
+        links
+    ])?;
+    Ok(())
+}
+
+/// The key of a folder in the resume table, hashed like the paths of files if hash_cache_paths
+fn resume_key(dir: &Path, state: &State) -> Vec<u8> {
+    if state.config.hash_cache_paths {
+        hash_path(&path_bytes(dir), state.secrets)
+    } else {
+        path_bytes(dir).into_owned()
+    }
+}
+
+/// Encrypt the entries of a folder for the resume table if hash_cache_paths,
+/// as they hold the paths of the files in it
+fn seal_resume(data: Vec<u8>, state: &mut State) -> Result<Vec<u8>, Error> {
+    if !state.config.hash_cache_paths {
+        return Ok(data);
+    }
+    encrypt_chunk(&data, state.secrets, None, false, &mut state.rng)
+}
+
+/// Decrypt what seal_resume stored, None if it cannot be
+fn open_resume(data: Vec<u8>, state: &State) -> Option<Vec<u8>> {
+    if !state.config.hash_cache_paths {
+        return Some(data);
+    }
+    decrypt_chunk(&data, state.secrets, "").ok()
+}
+
+/// Backup a folder, unless an interrupted backup already did
+///
+/// Folders nested more than max_depth folders below a backup dir, and folders inside
+/// themselves are skipped with a warning
+fn backup_subtree(dir: &Path, md: &fs::Metadata, state: &mut State) -> Result<(), Error> {
+    if state.depth > state.config.max_depth {
+        warn!(
+            "Skipping {:?} nested more than {} folders deep",
+            dir, state.config.max_depth
+        );
+        return Ok(());
+    }
+    // Platforms without inodes report them as 0
+    let meta = platform::meta(md);
+    let key = (meta.dev, meta.ino);
+    if key.1 != 0 && !state.ancestors.insert(key) {
+        warn!("Skipping {:?} which is inside itself", dir);
+        return Ok(());
+    }
+    state.depth += 1;
+    let res = backup_subtree_inner(dir, md, state);
+    state.depth -= 1;
+    state.ancestors.remove(&key);
+    res
+}
 
+fn backup_subtree_inner(dir: &Path, md: &fs::Metadata, state: &mut State) -> Result<(), Error> {
+    if resume_folder(dir, md, state)? {
+        return Ok(());
+    }
+    if state.scan {
+        state.folders_count += 1;
+        update_scan_progress(state);
+    }
+    let start = state.entries.len();
+    let skipped = state.skipped.len();
+    backup_folder(dir, state)?;
+    // Folders with skipped entries are backed up again when resuming, to retry them
+    if !state.scan && state.skipped.len() == skipped {
+        record_folder(dir, md, start, state)?;
+    }
     Ok(())
 }
 
+/// Decode a record written by encode_entry
+fn decode_entry(mut record: &[u8]) -> Result<DirEnt, Error> {
+    let mut ent = DirEnt {
+        path: PathBuf::new(),
+        etype: EType::File,
+        content: Vec::new(),
+        target: PathBuf::new(),
+        size: 0,
+        mode: 0,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+        mtime_nsec: 0,
+        ctime: 0,
+        ctime_nsec: 0,
+        btime: None,
+        rdev: 0,
+        xattrs: Vec::new(),
+    };
+    while !record.is_empty() {
+        let (tag, data) = format::read_tagged(&mut record)?;
+        match tag {
+            format::TAG_PATH => ent.path = path_from_bytes(data),
+            format::TAG_TYPE => {
+                ent.etype = EType::from_code(*data.first().ok_or(Error::Msg("Missing type"))?)?
+            }
+            format::TAG_SIZE => ent.size = format::to_u64(data)?,
+            format::TAG_CHUNKS => ent.content = data.to_vec(),
+            format::TAG_TARGET => ent.target = path_from_bytes(data),
+            format::TAG_MODE => ent.mode = format::to_u32(data)?,
+            format::TAG_UID => ent.uid = format::to_u32(data)?,
+            format::TAG_GID => ent.gid = format::to_u32(data)?,
+            format::TAG_MTIME => ent.mtime = format::to_i64(data)?,
+            format::TAG_CTIME => ent.ctime = format::to_i64(data)?,
+            format::TAG_MTIME_NSEC => ent.mtime_nsec = format::to_u32(data)?,
+            format::TAG_CTIME_NSEC => ent.ctime_nsec = format::to_u32(data)?,
+            format::TAG_BTIME => ent.btime.get_or_insert((0, 0)).0 = format::to_i64(data)?,
+            format::TAG_BTIME_NSEC => ent.btime.get_or_insert((0, 0)).1 = format::to_u32(data)?,
+            format::TAG_RDEV => ent.rdev = format::to_u64(data)?,
+            format::TAG_XATTR => {
+                let mut data = data;
+                let name = format::read_field(&mut data)?;
+                let value = format::read_field(&mut data)?;
+                ent.xattrs.push((name.to_vec(), value.to_vec()));
+            }
+            _ => return Err(Error::Msg("Unknown field")),
+        }
+    }
+    Ok(ent)
+}
+
+/// Encode an entry as a record of tagged fields
+fn encode_entry(ent: &DirEnt, out: &mut Vec<u8>) {
+    format::push_tagged(out, format::TAG_PATH, &path_bytes(&ent.path));
+    format::push_tagged(out, format::TAG_TYPE, &[ent.etype.code()]);
+    format::push_tagged(out, format::TAG_SIZE, &ent.size.to_le_bytes());
+    match ent.etype {
+        EType::File | EType::Hardlink => format::push_tagged(out, format::TAG_CHUNKS, &ent.content),
+        EType::Link
+        | EType::Dir
+        | EType::Root
+        | EType::Fifo
+        | EType::Socket
+        | EType::CharDevice
+        | EType::BlockDevice => (),
+    }
+    if ent.etype == EType::CharDevice || ent.etype == EType::BlockDevice {
+        format::push_tagged(out, format::TAG_RDEV, &ent.rdev.to_le_bytes());
+    }
+    if ent.etype == EType::Link || ent.etype == EType::Hardlink {
+        format::push_tagged(out, format::TAG_TARGET, &path_bytes(&ent.target));
+    }
+    format::push_tagged(out, format::TAG_MODE, &ent.mode.to_le_bytes());
+    format::push_tagged(out, format::TAG_UID, &ent.uid.to_le_bytes());
+    format::push_tagged(out, format::TAG_GID, &ent.gid.to_le_bytes());
+    format::push_tagged(out, format::TAG_MTIME, &ent.mtime.to_le_bytes());
+    format::push_tagged(out, format::TAG_CTIME, &ent.ctime.to_le_bytes());
+    format::push_tagged(out, format::TAG_MTIME_NSEC, &ent.mtime_nsec.to_le_bytes());
+    format::push_tagged(out, format::TAG_CTIME_NSEC, &ent.ctime_nsec.to_le_bytes());
+    if let Some((btime, btime_nsec)) = ent.btime {
+        format::push_tagged(out, format::TAG_BTIME, &btime.to_le_bytes());
+        format::push_tagged(out, format::TAG_BTIME_NSEC, &btime_nsec.to_le_bytes());
+    }
+    for (name, value) in ent.xattrs.iter() {
+        let mut data = Vec::with_capacity(name.len() + value.len() + 8);
+        format::push_field(&mut data, name);
+        format::push_field(&mut data, value);
+        format::push_tagged(out, format::TAG_XATTR, &data);
+    }
+}
+
+/// Reload the chunks known to be on each destination if chunks were deleted from it
 fn update_remote(conn: &Connection, state: &mut State) -> Result<(), Error> {
+    for d in 0..state.destinations.len() {
+        let res = update_remote_of(conn, d, state);
+        destination_result(d, res, state)?;
+    }
+    Ok(())
+}
+
+fn update_remote_of(conn: &Connection, d: usize, state: &mut State) -> Result<(), Error> {
+    let remote = state.destinations[d].remote;
     let url = format!(
         "{}/status/{}",
-        &state.config.server,
+        remote.server,
         hex::encode(&state.secrets.bucket)
     );
 
-    let last_delete: i64 = check_response(&mut || {
+    let last_delete: i64 = check_response(&state.config.retry, &mut || {
         state
             .client
             .get(&url[..])
-            .basic_auth(&state.config.user, Some(&state.config.password))
+            .basic_auth(remote.user, Some(remote.password))
             .send()
     })?
     .text()?
     .parse()?;
 
-    let oldest_remote: Option<i64> =
-        conn.query_row("SELECT min(time) FROM remote", NO_PARAMS, |row| row.get(0))?;
+    let oldest_remote: Option<i64> = conn.query_row(
+        "SELECT min(time) FROM remote_chunks WHERE server = ?",
+        params![remote.server],
+        |row| row.get(0),
+    )?;
 
     let should_update_remote = match oldest_remote {
         Some(t) => t < last_delete,
@@ -368,33 +1357,247 @@ fn update_remote(conn: &Connection, state: &mut State) -> Result<(), Error> {
     if !should_update_remote {
         return Ok(());
     }
-    conn.execute("DELETE FROM remote", NO_PARAMS)?;
-    let url = format!(
-        "{}/chunks/{}",
-        &state.config.server,
-        hex::encode(&state.secrets.bucket)
-    );
-    let content = check_response(&mut || {
-        state
-            .client
-            .get(&url[..])
-            .basic_auth(&state.config.user, Some(&state.config.password))
-            .send()
-    })?
-    .text()?;
+    conn.execute(
+        "DELETE FROM remote_chunks WHERE server = ?",
+        params![remote.server],
+    )?;
     let mut cnt = 0;
-    for row in content.split('\n') {
-        let mut row = row.split(' ');
-        let chunk = row.next().ok_or(Error::Msg("Missing churk"))?;
-        state.update_remote_stmt.execute(params![chunk])?;
-        cnt += 1;
+    let update_remote_stmt = &mut state.update_remote_stmt;
+    list_chunks_on(
+        &state.client,
+        remote,
+        &state.config.retry,
+        state.secrets,
+        false,
+        |row| {
+            let mut row = row.split(' ');
+            let chunk = row.next().ok_or(Error::Msg("Missing churk"))?;
+            update_remote_stmt.execute(params![remote.server, chunk])?;
+            cnt += 1;
+            Ok(())
+        },
+    )?;
+    info!(
+        "Prune detected. {} objects reloaded from remote state of {}",
+        cnt, remote.server
+    );
+    Ok(())
+}
+
+/// What a backup would do, as found by the scan
+#[derive(Serialize)]
+struct DryRunSummary {
+    files: u64,
+    total_bytes: u64,
+    modified_files: u64,
+    /// An upper bound, the content of modified files may partly be on the server already
+    new_bytes: u64,
+    existing_chunks: u64,
+}
+
+fn print_dry_run(state: &State, json: bool) {
+    let summary = DryRunSummary {
+        files: state.files_count,
+        total_bytes: state.total_bytes,
+        modified_files: state.modified_files_count,
+        new_bytes: state.transfer_bytes,
+        existing_chunks: state.existing_chunks,
+    };
+    if json {
+        println!("{}", serde_json::to_string(&summary).unwrap());
+    } else {
+        println!("Files:               {}", summary.files);
+        println!("Total bytes:         {}", summary.total_bytes);
+        println!("Modified files:      {}", summary.modified_files);
+        println!("New bytes (at most): {}", summary.new_bytes);
+        println!("Existing chunks:     {}", summary.existing_chunks);
+    }
+}
+
+/// Add a nullable integer column to a table of the cache, unless it is already there
+fn add_column(conn: &Connection, table: &str, column: &str) -> Result<(), Error> {
+    let cnt: i64 = conn.query_row(
+        &format!(
+            "SELECT count(*) FROM pragma_table_info('{}') WHERE name = ?",
+            table
+        ),
+        params![column],
+        |row| row.get(0),
+    )?;
+    if cnt == 0 {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} INTEGER", table, column),
+            NO_PARAMS,
+        )?;
     }
-    info!("Prune detected. {} objects reloaded from remote state", cnt);
     Ok(())
 }
 
-pub fn run(config: Config, secrets: Secrets) -> Result<(), Error> {
+/// What a finished backup did
+struct Outcome {
+    /// The root stored, None for a dry run
+    root: Option<String>,
+    files: u64,
+    transfered_bytes: u64,
+    /// The number of entries that could not be backed up
+    skipped: usize,
+    /// The number of servers the backup could not be stored on
+    failed_servers: usize,
+}
+
+impl Outcome {
+    /// Whether everything was backed up to every server
+    fn complete(&self) -> bool {
+        self.skipped == 0 && self.failed_servers == 0
+    }
+}
+
+/// The summary posted to the webhook when a backup finishes
+#[derive(Serialize)]
+struct WebhookReport<'a> {
+    bucket: String,
+    host: &'a str,
+    success: bool,
+    /// The message of the error that failed the backup
+    error: Option<String>,
+    /// The root stored by a successful backup
+    root: Option<String>,
+    files: u64,
+    transferred_bytes: u64,
+    /// The number of entries that could not be read and are missing from the root
+    skipped: usize,
+    /// The number of servers that failed, when a quorum of the others stored the backup
+    failed_servers: usize,
+    /// Seconds the backup took
+    duration: f64,
+}
+
+/// Post report to the webhook, a failure to do so is logged but does not fail the backup
+fn notify_webhook(url: &str, report: &WebhookReport) {
+    let res = reqwest::Client::builder()
+        .timeout(WEBHOOK_TIMEOUT)
+        .build()
+        .and_then(|client| client.post(url).json(report).send())
+        .and_then(|res| res.error_for_status());
+    if let Err(e) = res {
+        warn!("Unable to notify webhook {}: {:?}", url, e);
+    }
+}
+
+/// Backup the configured folders, or stdin as a file named by stdin if it is given
+///
+/// If dry_run is true only scan the folders and report what would be uploaded.
+/// Unless it is a dry run the outcome is posted to the configured webhook.
+/// Returns false if some entries were skipped because they could not be read,
+/// or some servers failed while a quorum of the others stored the backup
+pub fn run(
+    config: &Config,
+    secrets: &Secrets,
+    dry_run: bool,
+    json: bool,
+    stdin: Option<&str>,
+) -> Result<bool, Error> {
+    let webhook = match &config.webhook {
+        Some(url) if !dry_run => url.clone(),
+        _ => return run_backup(config, secrets, dry_run, json, stdin).map(|o| o.complete()),
+    };
+    let bucket = hex::encode(&secrets.bucket);
+    let t1 = SystemTime::now();
+    let res = run_backup(config, secrets, dry_run, json, stdin);
+    let mut report = WebhookReport {
+        bucket,
+        host: &config.hostname,
+        success: res.is_ok(),
+        error: None,
+        root: None,
+        files: 0,
+        transferred_bytes: 0,
+        skipped: 0,
+        failed_servers: 0,
+        duration: t1.elapsed().map_or(0.0, |d| d.as_secs_f64()),
+    };
+    match &res {
+        Ok(outcome) => {
+            report.root = outcome.root.clone();
+            report.files = outcome.files;
+            report.transferred_bytes = outcome.transfered_bytes;
+            report.skipped = outcome.skipped;
+            report.failed_servers = outcome.failed_servers;
+        }
+        Err(e) => report.error = Some(format!("{:?}", e)),
+    }
+    notify_webhook(&webhook, &report);
+    res.map(|o| o.complete())
+}
+
+/// Run a backup every interval until SIGTERM or SIGINT
+///
+/// A failed backup is logged and retried at the next cycle. Cycles that would start while the
+/// backup before them is still running are skipped
+pub fn run_daemon(config: &Config, secrets: &Secrets, interval: Duration) -> Result<(), Error> {
+    platform::catch_terminate()?;
+    let mut next = Instant::now();
+    loop {
+        info!("Starting scheduled backup");
+        match run(config, secrets, false, false, None) {
+            Ok(true) => info!("Scheduled backup complete"),
+            Ok(false) => warn!("Scheduled backup complete, but some files were skipped"),
+            Err(Error::Interrupted()) => {
+                info!("Scheduled backup interrupted, stopping");
+                return Ok(());
+            }
+            Err(e) => error!("Scheduled backup failed: {:?}", e),
+        }
+
+        next += interval;
+        let mut missed = 0;
+        while next <= Instant::now() {
+            next += interval;
+            missed += 1;
+        }
+        if missed != 0 {
+            warn!(
+                "The backup took longer than the interval, skipped {} cycles",
+                missed
+            );
+        }
+        info!(
+            "Next backup in {:?}",
+            next.saturating_duration_since(Instant::now())
+        );
+        // Sleep in short steps, so that a signal is handled promptly
+        while let Some(left) = next.checked_duration_since(Instant::now()) {
+            if platform::interrupted() {
+                info!("Stopping");
+                return Ok(());
+            }
+            std::thread::sleep(std::cmp::min(left, Duration::from_millis(200)));
+        }
+    }
+}
+
+/// Backup the configured folders or stdin, returning what was done
+fn run_backup(
+    config: &Config,
+    secrets: &Secrets,
+    dry_run: bool,
+    json: bool,
+    stdin: Option<&str>,
+) -> Result<Outcome, Error> {
     let t1 = SystemTime::now();
+    // On SIGINT the backup stops between chunks, so the cache is left as it would be
+    // after an error, and the next backup resumes from the folders that were completed
+    platform::catch_interrupt()?;
+
+    // Backups using the same cache would record each others files as their own
+    let lock_path = format!("{}.lock", config.cache_db);
+    let _lock = match platform::lock_file(Path::new(&lock_path))? {
+        Some(lock) => lock,
+        None => {
+            error!("Another backup holds the lock {}", lock_path);
+            return Err(Error::Msg("Another backup is using the cache"));
+        }
+    };
 
     let conn = Connection::open(&config.cache_db)?;
 
@@ -412,31 +1615,270 @@ pub fn run(config: Config, secrets: Secrets) -> Result<(), Error> {
         NO_PARAMS,
     )?;
 
+    // The nanoseconds of the mtime, caches from before it was recorded have it NULL
+    // and are matched on the seconds alone
+    add_column(&conn, "files", "mtime_nsec")?;
+    // The device and inode of the file, for inode_change_detection
+    add_column(&conn, "files", "dev")?;
+    add_column(&conn, "files", "ino")?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS files_inode ON files (dev, ino)",
+        NO_PARAMS,
+    )?;
+
+    // The chunks known to be on each server, by the time they were last seen there
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS remote_chunks (
+            server TEXT NOT NULL,
+            chunk TEXT NOT NULL,
+            time INTEGER NOT NULL,
+            UNIQUE (server, chunk)
+        )",
+        NO_PARAMS,
+    )?;
+    // Caches from before mirrors list the chunks of the configured server
+    let old_remote: i64 = conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type='table' AND name='remote'",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    if old_remote != 0 {
+        conn.execute(
+            "INSERT OR IGNORE INTO remote_chunks (server, chunk, time)
+             SELECT ?, chunk, time FROM remote",
+            params![config.server],
+        )?;
+        conn.execute("DROP TABLE remote", NO_PARAMS)?;
+    }
+
+    // The folders completed by a backup that has not yet stored its root
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS resume (
+            path BLOB NOT NULL UNIQUE,
+            mtime INTEGER NOT NULL,
+            ctime INTEGER NOT NULL,
+            entries BLOB NOT NULL,
+            links BLOB NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+
+    // The pack small chunks were last stored in, as their reference in the chunks of files
     conn.execute(
-        "CREATE TABLE IF NOT EXISTS remote (
+        "CREATE TABLE IF NOT EXISTS packed_chunks (
             chunk TEXT NOT NULL UNIQUE,
-            time INTEGER NOT NULL
+            ref TEXT NOT NULL
+        )",
+        NO_PARAMS,
+    )?;
+
+    // The hash function used to name the chunks listed in the other tables
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS settings (
+            name TEXT NOT NULL UNIQUE,
+            value TEXT NOT NULL
         )",
         NO_PARAMS,
     )?;
 
+    let hash = config.hash_algorithm.to_string();
+    let cached_hash: Option<String> = conn
+        .query_row(
+            "SELECT value FROM settings WHERE name='hash'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .optional()?;
+    if cached_hash.as_ref().map_or(false, |h| h != &hash) {
+        info!("Hash algorithm changed, clearing the cache");
+        conn.execute("DELETE FROM files", NO_PARAMS)?;
+        conn.execute("DELETE FROM remote_chunks", NO_PARAMS)?;
+        conn.execute("DELETE FROM resume", NO_PARAMS)?;
+        conn.execute("DELETE FROM packed_chunks", NO_PARAMS)?;
+    }
+    conn.execute(
+        "REPLACE INTO settings (name, value) VALUES ('hash', ?)",
+        params![hash],
+    )?;
+
+    // The chunks known to be on a server are those of the bucket of the last backup
+    let bucket = hex::encode(&secrets.bucket);
+    let cached_bucket: Option<String> = conn
+        .query_row(
+            "SELECT value FROM settings WHERE name='bucket'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .optional()?;
+    if cached_bucket.as_ref().map_or(false, |b| b != &bucket) {
+        info!("Bucket changed, clearing the chunks of the cache");
+        conn.execute("DELETE FROM remote_chunks", NO_PARAMS)?;
+        conn.execute("DELETE FROM resume", NO_PARAMS)?;
+        conn.execute("DELETE FROM packed_chunks", NO_PARAMS)?;
+    }
+    conn.execute(
+        "REPLACE INTO settings (name, value) VALUES ('bucket', ?)",
+        params![bucket],
+    )?;
+
+    // Caches from before paths could be hashed store them as they are
+    let paths = if config.hash_cache_paths {
+        "hashed"
+    } else {
+        "plain"
+    };
+    let cached_paths: Option<String> = conn
+        .query_row(
+            "SELECT value FROM settings WHERE name='paths'",
+            NO_PARAMS,
+            |row| row.get(0),
+        )
+        .optional()?;
+    if cached_paths.as_ref().map_or("plain", String::as_str) != paths {
+        // The files are found by the other kind of key, and plain paths must not be left behind,
+        // not even in the free pages of the database
+        info!(
+            "Cache paths changed to {}, clearing the files and folders of the cache",
+            paths
+        );
+        conn.execute("DELETE FROM files", NO_PARAMS)?;
+        conn.execute("DELETE FROM resume", NO_PARAMS)?;
+        conn.execute("VACUUM", NO_PARAMS)?;
+    }
+    conn.execute(
+        "REPLACE INTO settings (name, value) VALUES ('paths', ?)",
+        params![paths],
+    )?;
+
+    if config.recheck {
+        conn.execute("DELETE FROM resume", NO_PARAMS)?;
+    } else {
+        let cnt: i64 =
+            conn.query_row("SELECT count(*) FROM resume", NO_PARAMS, |row| row.get(0))?;
+        if cnt != 0 {
+            info!("Resuming interrupted backup");
+        }
+    }
+
+    if config.chunk_size == 0 || config.chunk_size > MAX_CHUNK_SIZE {
+        return Err(Error::Msg("chunk_size must be between 1 and 512MB"));
+    }
+    if config.pack_size > MAX_CHUNK_SIZE {
+        return Err(Error::Msg("pack_size must be at most 512MB"));
+    }
+
+    let chunker = if config.content_defined_chunking {
+        if config.min_chunk_size == 0
+            || config.min_chunk_size > config.avg_chunk_size
+            || config.avg_chunk_size > config.max_chunk_size
+            || config.max_chunk_size > MAX_CHUNK_SIZE
+        {
+            return Err(Error::Msg(
+                "Chunk sizes must satisfy 0 < min_chunk_size <= avg_chunk_size <= max_chunk_size <= 512MB",
+            ));
+        }
+        Some(Chunker::new(
+            config.min_chunk_size as usize,
+            config.avg_chunk_size as usize,
+            config.max_chunk_size as usize,
+        ))
+    } else {
+        None
+    };
+
+    let exclude = exclude_matcher(config)?;
+
+    // The counter shares stderr with the log, so it is only shown to someone watching it
+    let show_scan_progress = config.verbosity >= log::LevelFilter::Info
+        && config.progress == ProgressFormat::Bar
+        && config.log_format == LogFormat::Plain
+        && platform::stderr_is_terminal();
+
+    let remotes = config.remotes();
+    let quorum = match config.quorum {
+        0 => remotes.len(),
+        quorum => quorum,
+    };
+    if quorum > remotes.len() {
+        return Err(Error::Msg("quorum is larger than the number of servers"));
+    }
+
+    let client = http_client(config)?;
+    let destinations = remotes
+        .into_iter()
+        .map(|remote| Destination {
+            remote,
+            uploader: Uploader::new(
+                config.upload_threads,
+                &client,
+                format!("{}/chunks/{}", remote.server, hex::encode(&secrets.bucket)),
+                remote.user,
+                remote.password,
+                config.max_upload_bytes_per_sec,
+                config.retry,
+            ),
+            absent_chunks: HashSet::new(),
+            queued_chunks: HashSet::new(),
+            failed: false,
+        })
+        .collect();
+
     let mut state = State {
         secrets,
         config,
-        client: reqwest::Client::new(),
+        client,
         scan: true,
         transfer_bytes: 0,
         progress: None,
-        has_remote_stmt: conn.prepare("SELECT count(*) FROM remote WHERE chunk = ?")?,
+        scan_progress: if show_scan_progress {
+            Some(ScanProgress::new())
+        } else {
+            None
+        },
+        chunker,
+        exclude,
+        destinations,
+        quorum,
+        has_remote_stmt: conn
+            .prepare("SELECT count(*) FROM remote_chunks WHERE server = ? AND chunk = ?")?,
         update_remote_stmt: conn
-            .prepare("REPLACE INTO remote VALUES (?, strftime('%s', 'now'))")?,
-        get_chunks_stmt: conn
-            .prepare("SELECT chunks FROM files WHERE path = ? AND size = ? AND mtime = ?")?,
-        update_chunks_stmt: conn
-            .prepare("REPLACE INTO files (path, size, mtime, chunks) VALUES (?, ?, ?, ?)")?,
+            .prepare("REPLACE INTO remote_chunks VALUES (?, ?, strftime('%s', 'now'))")?,
+        get_chunks_stmt: conn.prepare(
+            "SELECT chunks FROM files WHERE path = ? AND size = ? AND mtime = ?
+             AND (mtime_nsec IS NULL OR mtime_nsec = ?)",
+        )?,
+        get_inode_chunks_stmt: conn.prepare(
+            "SELECT chunks FROM files WHERE dev = ? AND ino = ? AND size = ? AND mtime = ?
+             AND mtime_nsec = ? LIMIT 1",
+        )?,
+        update_chunks_stmt: conn.prepare(
+            "REPLACE INTO files (path, size, mtime, mtime_nsec, dev, ino, chunks)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )?,
+        get_resume_stmt: conn.prepare(
+            "SELECT entries, links FROM resume WHERE path = ? AND mtime = ? AND ctime = ?",
+        )?,
+        update_resume_stmt: conn.prepare(
+            "REPLACE INTO resume (path, mtime, ctime, entries, links) VALUES (?, ?, ?, ?, ?)",
+        )?,
+        delete_resume_stmt: conn.prepare("DELETE FROM resume WHERE path = ?")?,
+        get_packed_stmt: conn.prepare("SELECT ref FROM packed_chunks WHERE chunk = ?")?,
+        update_packed_stmt: conn
+            .prepare("REPLACE INTO packed_chunks (chunk, ref) VALUES (?, ?)")?,
+        pack: Vec::new(),
+        pack_name: String::new(),
         rng: rand::rngs::OsRng,
         entries: Vec::new(),
+        hardlinks: HashMap::new(),
+        depth: 0,
+        ancestors: HashSet::new(),
+        root_dev: 0,
         modified_files_count: 0,
+        files_count: 0,
+        folders_count: 0,
+        total_bytes: 0,
+        existing_chunks: 0,
+        skipped: Vec::new(),
         transfered_bytes: 0,
         conflict_bytes: 0,
         skipped_bytes: 0,
@@ -444,6 +1886,30 @@ pub fn run(config: Config, secrets: Secrets) -> Result<(), Error> {
 
     update_remote(&conn, &mut state)?;
 
+    if let Some(name) = stdin {
+        info!("Backing up stdin as {}", name);
+        state.scan = false;
+        match backup_stdin(name, &mut state) {
+            Ok(ent) => {
+                state.files_count = 1;
+                state.entries.push(ent);
+            }
+            Err(e) => {
+                drain_uploads(&mut state);
+                return Err(e);
+            }
+        }
+        handle_upload_results(&mut state, true)?;
+        info!(
+            "Backup complete after {:?}, {} bytes transfered, {} bytes skipped\n",
+            t1.elapsed(),
+            state.transfered_bytes,
+            state.skipped_bytes
+        );
+        // The folders of an interrupted backup are still resumed by the next one
+        return store_root(&mut state);
+    }
+
     let dirs = state.config.backup_dirs.clone();
     for dir in dirs.iter() {
         let path = Path::new(dir);
@@ -452,19 +1918,35 @@ pub fn run(config: Config, secrets: Secrets) -> Result<(), Error> {
             continue;
         }
         info!("Scanning {}", &dir);
-        backup_folder(path, &mut state)?;
+        let md = fs::metadata(&path)?;
+        state.root_dev = platform::meta(&md).dev;
+        backup_subtree(path, &md, &mut state)?;
+    }
+
+    if let Some(p) = state.scan_progress.take() {
+        p.finish();
     }
 
-    if state.config.verbosity >= log::LevelFilter::Info {
-        state.progress = Some({
-            let mut p = ProgressBar::new(state.transfer_bytes);
-            p.set_max_refresh_rate(Some(Duration::from_millis(500)));
-            p.set_units(pbr::Units::Bytes);
-            p.set_width(Some(140));
-            p
+    if dry_run {
+        for dest in state.destinations.iter_mut() {
+            dest.uploader.finish();
+        }
+        report_skipped(&state);
+        print_dry_run(&state, json);
+        return Ok(Outcome {
+            root: None,
+            files: state.files_count,
+            transfered_bytes: 0,
+            skipped: state.skipped.len(),
+            failed_servers: 0,
         });
     }
 
+    state.progress = Progress::new(state.config, state.transfer_bytes);
+    if let Some(Progress::Bar(p)) = &mut state.progress {
+        p.set_width(Some(140));
+    }
+
     let t2 = SystemTime::now();
     info!(
         "Scan complete after {:?}, {} modified files, {} bytes to transfer\n",
@@ -474,6 +1956,8 @@ pub fn run(config: Config, secrets: Secrets) -> Result<(), Error> {
     );
 
     state.entries.clear();
+    state.hardlinks.clear();
+    state.skipped.clear();
     state.scan = false;
     for dir in dirs.iter() {
         let path = Path::new(dir);
@@ -484,18 +1968,34 @@ pub fn run(config: Config, secrets: Secrets) -> Result<(), Error> {
         info!("Backing up {}", &dir);
 
         let md = fs::metadata(&path)?;
+        let meta = platform::meta(&md);
+        state.root_dev = meta.dev;
         state.entries.push(DirEnt {
-            path: dir.to_string(),
+            path: PathBuf::from(dir),
             etype: EType::Dir,
-            content: "0".to_string(),
+            content: b"0".to_vec(),
+            target: PathBuf::new(),
             size: 0,
-            mode: md.st_mode() & 0xFFF,
-            uid: md.st_uid(),
-            gid: md.st_gid(),
-            mtime: md.st_mtime(),
-            ctime: md.st_ctime(),
+            mode: meta.mode & 0xFFF,
+            uid: meta.uid,
+            gid: meta.gid,
+            mtime: meta.mtime,
+            mtime_nsec: meta.mtime_nsec,
+            ctime: meta.ctime,
+            ctime_nsec: meta.ctime_nsec,
+            btime: birth_time(&md, &state),
+            rdev: 0,
+            xattrs: read_xattrs(path, &state),
         });
-        backup_folder(path, &mut state)?;
+        if let Err(e) = backup_subtree(path, &md, &mut state) {
+            drain_uploads(&mut state);
+            return Err(e);
+        }
+    }
+
+    handle_upload_results(&mut state, true)?;
+    if let Some(p) = &mut state.progress {
+        p.finish();
     }
 
     let t3 = SystemTime::now();
@@ -506,45 +2006,69 @@ pub fn run(config: Config, secrets: Secrets) -> Result<(), Error> {
         state.conflict_bytes,
         state.skipped_bytes
     );
+    report_skipped(&state);
+    let outcome = store_root(&mut state)?;
 
+    // The backup is complete, so there is nothing to resume
+    conn.execute("DELETE FROM resume", NO_PARAMS)?;
+    Ok(outcome)
+}
+
+/// Store the entries backed up as a root on every destination that has not failed
+fn store_root(state: &mut State) -> Result<Outcome, Error> {
+    flush_pack(state)?;
     info!("Storing root");
 
-    let mut ans = "".to_string();
+    let mut ans = Vec::new();
+    let mut record = Vec::new();
+    format::push_header(&mut ans);
     for ent in state.entries.iter() {
-        if !ans.is_empty() {
-            ans.push('\0');
-            ans.push('\0');
-        }
-        ans.push_str(&format!(
-            "{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}\0{}",
-            ent.path,
-            ent.etype,
-            ent.size,
-            ent.content,
-            ent.mode,
-            ent.uid,
-            ent.gid,
-            ent.mtime,
-            ent.ctime,
-        ));
-    }
-
-    let root = push_chunk(&lzma::compress(ans.as_bytes(), 7)?, &mut state)?;
+        record.clear();
+        encode_entry(ent, &mut record);
+        format::push_field(&mut ans, &record);
+    }
 
-    let url = format!(
-        "{}/roots/{}/{}",
-        &state.config.server,
-        hex::encode(&state.secrets.bucket),
-        &state.config.hostname
-    );
+    let root = push_chunk(&lzma::compress(&ans, 7)?, state)?;
+    handle_upload_results(&mut state, true)?;
+    for dest in state.destinations.iter_mut() {
+        dest.uploader.finish();
+    }
 
-    check_response(&mut || {
-        state
-            .client
-            .put(&url[..])
-            .basic_auth(&state.config.user, Some(&state.config.password))
-            .body(root.clone())
-            .send()
-    })?;
-    Ok(())
+    for d in 0..state.destinations.len() {
+        if state.destinations[d].failed {
+            continue;
+        }
+        let remote = state.destinations[d].remote;
+        let url = format!(
+            "{}/roots/{}/{}",
+            remote.server,
+            hex::encode(&state.secrets.bucket),
+            &state.config.hostname
+        );
+        let tags = state.config.tags.join(",");
+        let res = check_response(&state.config.retry, &mut || {
+            let mut req = state
+                .client
+                .put(&url[..])
+                .basic_auth(remote.user, Some(remote.password));
+            if !tags.is_empty() {
+                req = req.header(ROOT_TAGS_HEADER, &tags[..]);
+            }
+            req.body(root.clone()).send()
+        });
+        destination_result(d, res, state)?;
+    }
+    let mut failed_servers = 0;
+    for dest in state.destinations.iter().filter(|d| d.failed) {
+        error!("The backup was not stored on {}", dest.remote.server);
+        failed_servers += 1;
+    }
+
+    Ok(Outcome {
+        root: Some(root),
+        files: state.files_count,
+        transfered_bytes: state.transfered_bytes as u64,
+        skipped: state.skipped.len(),
+        failed_servers,
+    })
 }
@@ -0,0 +1,60 @@
+/// Content defined chunking based on a gear rolling hash
+///
+/// A chunk boundary is placed after a byte where the rolling hash of the
+/// preceding 64 bytes is below threshold. That way the boundaries only depend
+/// on the local content, so inserting or removing bytes in a file only changes
+/// the chunks around the edit.
+pub struct Chunker {
+    table: [u64; 256],
+    min: usize,
+    threshold: u64,
+    max: usize,
+}
+
+impl Chunker {
+    pub fn new(min: usize, avg: usize, max: usize) -> Chunker {
+        // Fill the gear table from splitmix64 with a fixed seed, the table
+        // must never change since it determines the chunk boundaries
+        let mut table = [0; 256];
+        let mut x: u64 = 0x6d62_6163_6b75_7021;
+        for v in table.iter_mut() {
+            x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = x;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *v = z ^ (z >> 31);
+        }
+        // Past min a boundary follows each byte with a chance of 1 / (avg - min), so chunks
+        // are avg bytes on average, somewhat less if max is not well above avg
+        let threshold = u64::MAX / usize::max(avg.saturating_sub(min), 1) as u64;
+        Chunker {
+            table,
+            min,
+            threshold,
+            max: usize::max(max, min + 1),
+        }
+    }
+
+    /// The largest chunk the chunker will produce
+    pub fn max_size(&self) -> usize {
+        self.max
+    }
+
+    /// Return the length of the first chunk of data
+    ///
+    /// data must either be at least max_size bytes long or be the end of the stream
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+        let end = usize::min(data.len(), self.max);
+        let mut hash: u64 = 0;
+        for i in self.min.saturating_sub(64)..end {
+            hash = (hash << 1).wrapping_add(self.table[data[i] as usize]);
+            if i >= self.min && hash < self.threshold {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
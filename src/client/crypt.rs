@@ -0,0 +1,211 @@
+use crate::shared::{Error, HashAlgorithm, Secrets};
+use blake2::digest::{Update, VariableOutput};
+use blake2::VarBlake2b;
+use chacha20::cipher::{NewCipher, StreamCipher};
+use chacha20::{ChaCha20, Key, Nonce};
+use rand::Rng;
+use subtle::ConstantTimeEq;
+
+/// Size of the random nonce used for ChaCha20
+const NONCE_SIZE: usize = 12;
+
+/// Size of the keyed Blake2b tag appended to authenticated chunks
+const TAG_SIZE: usize = 32;
+
+/// Version byte of chunks stored as version || nonce || ciphertext || tag
+const FORMAT_AUTHENTICATED: u8 = 1;
+
+/// Version byte of authenticated chunks where the plain text starts with a compression byte
+const FORMAT_COMPRESSED: u8 = 2;
+
+/// Compression byte of content stored as is
+const COMPRESSION_NONE: u8 = 0;
+
+/// Compression byte of content compressed with zstd
+const COMPRESSION_ZSTD: u8 = 1;
+
+/// Only store the compressed content if it is at most this fraction of the original size
+const MAX_COMPRESSION_RATIO: f64 = 0.9;
+
+/// The largest number of bytes encryption adds to a chunk
+pub const MAX_OVERHEAD: usize = 2 + NONCE_SIZE + TAG_SIZE;
+
+/// Compute the Blake2b of the concatenation of parts into out, keyed by key if it is not empty
+///
+/// The output size is part of the Blake2b parameters, so this is not a truncation of a longer hash
+pub fn blake2b(key: &[u8], parts: &[&[u8]], out: &mut [u8]) {
+    let mut hasher = if key.is_empty() {
+        VarBlake2b::new(out.len()).expect("Bad Blake2b output size")
+    } else {
+        VarBlake2b::new_keyed(key, out.len())
+    };
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize_variable(|res| out.copy_from_slice(res));
+}
+
+/// Derive a 256bit subkey for a given purpose from the master key
+pub fn subkey(key: &[u8], purpose: &str) -> [u8; 32] {
+    let mut ans = [0; 32];
+    blake2b(key, &[purpose.as_bytes()], &mut ans);
+    ans
+}
+
+/// Compute the keyed Blake2b tag of some data
+fn tag(key: &[u8], data: &[u8]) -> [u8; TAG_SIZE] {
+    let mut ans = [0; TAG_SIZE];
+    blake2b(key, &[data], &mut ans);
+    ans
+}
+
+/// Apply the ChaCha20 key stream of key and nonce to data
+fn chacha20(key: &[u8], nonce: &[u8], data: &mut [u8]) {
+    ChaCha20::new(Key::from_slice(key), Nonce::from_slice(nonce)).apply_keystream(data);
+}
+
+/// Compute the name of a chunk from its plain text content
+pub fn hash_chunk(content: &[u8], secrets: &Secrets) -> String {
+    match secrets.hash {
+        HashAlgorithm::Blake2b => {
+            let mut ans = [0; 32];
+            blake2b(&[], &[&secrets.seed[..], content], &mut ans);
+            hex::encode(ans)
+        }
+        HashAlgorithm::Blake3 => blake3::keyed_hash(&secrets.seed, content)
+            .to_hex()
+            .to_string(),
+    }
+}
+
+/// Compute the key of a path in the cache when cache paths are hashed
+///
+/// This is the Blake2b-256 of the path keyed by the path key, so the cache does not reveal
+/// the paths without the encryption key
+pub fn hash_path(path: &[u8], secrets: &Secrets) -> Vec<u8> {
+    let mut ans = vec![0; 32];
+    blake2b(&secrets.path_key, &[path], &mut ans);
+    ans
+}
+
+/// Compute the integrity hash of an encrypted chunk, sent along when uploading it
+///
+/// This is the unkeyed Blake2b-256 of the bytes sent, so that the server can check it
+pub fn upload_hash(encrypted: &[u8]) -> String {
+    let mut ans = [0; 32];
+    blake2b(&[], &[encrypted], &mut ans);
+    hex::encode(ans)
+}
+
+/// Prefix content with its compression byte, compressing it if worthwhile
+fn compress(content: &[u8], level: Option<i32>) -> Result<Vec<u8>, Error> {
+    if let Some(level) = level {
+        let compressed = zstd::encode_all(content, level)?;
+        if (compressed.len() as f64) <= content.len() as f64 * MAX_COMPRESSION_RATIO {
+            let mut payload = Vec::with_capacity(compressed.len() + 1);
+            payload.push(COMPRESSION_ZSTD);
+            payload.extend_from_slice(&compressed);
+            return Ok(payload);
+        }
+    }
+    let mut payload = Vec::with_capacity(content.len() + 1);
+    payload.push(COMPRESSION_NONE);
+    payload.extend_from_slice(content);
+    Ok(payload)
+}
+
+/// Undo compress
+fn decompress(payload: &[u8]) -> Result<Vec<u8>, Error> {
+    match payload.first() {
+        Some(&COMPRESSION_NONE) => Ok(payload[1..].to_vec()),
+        Some(&COMPRESSION_ZSTD) => Ok(zstd::decode_all(&payload[1..])?),
+        Some(_) => Err(Error::Msg("Unknown compression")),
+        None => Err(Error::Msg("Missing compression")),
+    }
+}
+
+/// Compute the nonce of a payload when nonces are deterministic
+///
+/// The nonce is a keyed Blake2b of the exact bytes encrypted under it (as in SIV), so a nonce is
+/// only ever reused to encrypt the same bytes again, which reveals nothing but that equality
+fn synthetic_nonce(payload: &[u8], secrets: &Secrets) -> [u8; NONCE_SIZE] {
+    let mut ans = [0; NONCE_SIZE];
+    blake2b(&secrets.nonce_key, &[payload], &mut ans);
+    ans
+}
+
+/// Encrypt a chunk, producing version || nonce || ciphertext || tag
+///
+/// If compression_level is given the content is compressed with zstd before it is encrypted,
+/// unless it does not compress well.
+///
+/// If deterministic is true the nonce is derived from the content, so that the encrypted chunk
+/// is a pure function of the content, the secrets and compression_level and independent
+/// clients encrypt the same content to the same bytes. Otherwise the nonce is drawn from rng
+pub fn encrypt_chunk<R: Rng>(
+    content: &[u8],
+    secrets: &Secrets,
+    compression_level: Option<i32>,
+    deterministic: bool,
+    rng: &mut R,
+) -> Result<Vec<u8>, Error> {
+    let payload = compress(content, compression_level)?;
+    let mut crypted = Vec::new();
+    crypted.resize(payload.len() + 1 + NONCE_SIZE + TAG_SIZE, 0);
+    crypted[0] = FORMAT_COMPRESSED;
+    if deterministic {
+        crypted[1..1 + NONCE_SIZE].copy_from_slice(&synthetic_nonce(&payload, secrets));
+    } else {
+        rng.fill(&mut crypted[1..1 + NONCE_SIZE]);
+    }
+
+    let end = 1 + NONCE_SIZE + payload.len();
+    {
+        let (head, body) = crypted.split_at_mut(1 + NONCE_SIZE);
+        body[..payload.len()].copy_from_slice(&payload);
+        chacha20(&secrets.enc_key, &head[1..], &mut body[..payload.len()]);
+    }
+    let t = tag(&secrets.mac_key, &crypted[..end]);
+    crypted[end..].copy_from_slice(&t);
+    Ok(crypted)
+}
+
+/// Decrypt a chunk produced by encrypt_chunk, hash is the name of the chunk
+///
+/// Chunks stored before authentication was introduced are on the form nonce || ciphertext,
+/// these are still accepted, their integrity is checked by the caller validating the content hash.
+/// Chunks starting with a version byte must have a valid tag. The random nonce of an old chunk
+/// may also start with a version byte, so it is only taken as an old chunk if its content has
+/// the hash of the chunk
+pub fn decrypt_chunk(encrypted: &[u8], secrets: &Secrets, hash: &str) -> Result<Vec<u8>, Error> {
+    if encrypted.first() != Some(&FORMAT_AUTHENTICATED)
+        && encrypted.first() != Some(&FORMAT_COMPRESSED)
+    {
+        return decrypt_legacy(encrypted, secrets);
+    }
+    if encrypted.len() >= 1 + NONCE_SIZE + TAG_SIZE {
+        let (data, t) = encrypted.split_at(encrypted.len() - TAG_SIZE);
+        if bool::from(tag(&secrets.mac_key, data).ct_eq(t)) {
+            let mut content = data[1 + NONCE_SIZE..].to_vec();
+            chacha20(&secrets.enc_key, &data[1..1 + NONCE_SIZE], &mut content);
+            if data[0] == FORMAT_COMPRESSED {
+                return decompress(&content);
+            }
+            return Ok(content);
+        }
+    }
+    match decrypt_legacy(encrypted, secrets) {
+        Ok(content) if hash_chunk(&content, secrets) == hash => Ok(content),
+        _ => Err(Error::Authentication()),
+    }
+}
+
+/// Decrypt a chunk stored as nonce || ciphertext, before chunks were authenticated
+fn decrypt_legacy(encrypted: &[u8], secrets: &Secrets) -> Result<Vec<u8>, Error> {
+    if encrypted.len() < NONCE_SIZE {
+        return Err(Error::Msg("Missing nonce"));
+    }
+    let mut content = encrypted[NONCE_SIZE..].to_vec();
+    chacha20(&secrets.key, &encrypted[..NONCE_SIZE], &mut content);
+    Ok(content)
+}
@@ -0,0 +1,125 @@
+use crate::shared::Error;
+
+/// Roots in the versioned formats start with this byte followed by the version.
+/// Roots in the legacy format start with the path of the first entry, which is never empty.
+const MARKER: u8 = 0;
+
+/// Version of the root format written by backups
+///
+/// 0: The legacy format, where the fields of an entry are separated by \0 and entries by \0\0.
+/// 1: The fields of the legacy format each prefixed by their length as a little endian u32,
+///    so that paths and link targets may contain any bytes.
+/// 2: Each entry is a length prefixed record of tagged fields, see push_tagged.
+///    Readers skip fields with unknown tags, so that fields can be added.
+/// 3: Version 2 with extended attributes.
+/// 4: Version 3 with hard links.
+/// 5: Version 4 with fifos, sockets and device nodes.
+/// 6: Version 5 with the nanoseconds of mtime and ctime.
+/// 7: Version 6 where chunks may be parts of packs, see ChunkRef.
+/// 8: Version 7 with the birth time of entries where it is known.
+pub const VERSION: u8 = 8;
+
+/// Tags of the fields of an entry in a version 2 root
+pub const TAG_PATH: u8 = 1;
+pub const TAG_TYPE: u8 = 2;
+pub const TAG_SIZE: u8 = 3;
+/// The comma separated chunks of a file, hashes or references to parts of packs
+pub const TAG_CHUNKS: u8 = 4;
+/// The target of a link or hard link
+pub const TAG_TARGET: u8 = 5;
+pub const TAG_MODE: u8 = 6;
+pub const TAG_UID: u8 = 7;
+pub const TAG_GID: u8 = 8;
+pub const TAG_MTIME: u8 = 9;
+pub const TAG_CTIME: u8 = 10;
+/// An extended attribute, the name and value as two fields written by push_field
+pub const TAG_XATTR: u8 = 11;
+/// The device number of device nodes
+pub const TAG_RDEV: u8 = 12;
+/// The nanoseconds of the mtime and ctime, as u32s
+pub const TAG_MTIME_NSEC: u8 = 13;
+pub const TAG_CTIME_NSEC: u8 = 14;
+/// The birth time of an entry, as an i64 of seconds and a u32 of nanoseconds,
+/// left out where the file system does not report it
+pub const TAG_BTIME: u8 = 15;
+pub const TAG_BTIME_NSEC: u8 = 16;
+
+/// Write the header of a root in the current format
+pub fn push_header(out: &mut Vec<u8>) {
+    out.push(MARKER);
+    out.push(VERSION);
+}
+
+/// Append a field prefixed by its length
+pub fn push_field(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out.extend_from_slice(data);
+}
+
+/// Append a field prefixed by its tag and length to a record
+pub fn push_tagged(out: &mut Vec<u8>, tag: u8, data: &[u8]) {
+    out.push(tag);
+    push_field(out, data);
+}
+
+/// Read a field written by push_tagged, advancing data past it
+pub fn read_tagged<'a>(data: &mut &'a [u8]) -> Result<(u8, &'a [u8]), Error> {
+    let tag = *data.first().ok_or(Error::Msg("Truncated tag"))?;
+    *data = &data[1..];
+    Ok((tag, read_field(data)?))
+}
+
+/// Decode a little endian u32 field
+pub fn to_u32(data: &[u8]) -> Result<u32, Error> {
+    let mut v = [0; 4];
+    if data.len() != v.len() {
+        return Err(Error::Msg("Bad u32 field"));
+    }
+    v.copy_from_slice(data);
+    Ok(u32::from_le_bytes(v))
+}
+
+/// Decode a little endian u64 field
+pub fn to_u64(data: &[u8]) -> Result<u64, Error> {
+    let mut v = [0; 8];
+    if data.len() != v.len() {
+        return Err(Error::Msg("Bad u64 field"));
+    }
+    v.copy_from_slice(data);
+    Ok(u64::from_le_bytes(v))
+}
+
+/// Decode a little endian i64 field
+pub fn to_i64(data: &[u8]) -> Result<i64, Error> {
+    Ok(to_u64(data)? as i64)
+}
+
+/// Split a root into its format version and the entries following the header
+pub fn split_version(root: &[u8]) -> (u8, &[u8]) {
+    if root.len() >= 2 && root[0] == MARKER {
+        (root[1], &root[2..])
+    } else {
+        (0, root)
+    }
+}
+
+/// Read a field written by push_field, advancing data past it
+pub fn read_field<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], Error> {
+    if data.len() < 4 {
+        return Err(Error::Msg("Truncated field length"));
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&data[..4]);
+    let len = u32::from_le_bytes(len) as usize;
+    if data.len() < 4 + len {
+        return Err(Error::Msg("Truncated field"));
+    }
+    let field = &data[4..4 + len];
+    *data = &data[4 + len..];
+    Ok(field)
+}
+
+/// Read a field written by push_field that must be utf-8
+pub fn read_text<'a>(data: &mut &'a [u8]) -> Result<&'a str, Error> {
+    std::str::from_utf8(read_field(data)?).map_err(|_| Error::Msg("Field is not utf-8"))
+}
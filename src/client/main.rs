@@ -1,7 +1,14 @@
+extern crate blake2;
+extern crate blake3;
+extern crate chacha20;
 extern crate chrono;
 extern crate clap;
-extern crate crypto;
+#[cfg(feature = "fuser")]
+extern crate fuser;
 extern crate hex;
+extern crate ignore;
+#[cfg(feature = "keyring")]
+extern crate keyring;
 extern crate libc;
 extern crate nix;
 extern crate pbr;
@@ -9,44 +16,100 @@ extern crate rand;
 extern crate reqwest;
 extern crate rusqlite;
 extern crate serde;
+extern crate serde_json;
+extern crate subtle;
+extern crate xattr;
+extern crate zeroize;
 #[macro_use]
 extern crate log;
 use clap::{App, Arg, ArgMatches, SubCommand};
-use crypto::blake2b::Blake2b;
-use crypto::digest::Digest;
 mod backup;
+mod chunker;
+mod crypt;
+mod format;
+#[cfg(feature = "fuser")]
+mod mount;
+mod platform;
+mod progress;
 mod shared;
+mod throttle;
+mod upload;
 mod visit;
 use chrono::NaiveDateTime;
-use shared::{check_response, Config, Error, Secrets};
+use serde::Serialize;
+use shared::{
+    check_response, http_client, Config, Error, HashAlgorithm, LogFormat, ProgressFormat, Secrets,
+};
+use std::sync::atomic::{AtomicBool, Ordering};
+use zeroize::Zeroize;
 
-struct Logger {}
+/// Collect the key values of a log record as strings
+struct Fields(Vec<(String, String)>);
+impl<'kvs> log::kv::Visitor<'kvs> for Fields {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+struct Logger {
+    /// Log a json object per line instead of plain text
+    json: AtomicBool,
+}
 impl log::Log for Logger {
     fn enabled(&self, _metadata: &log::Metadata) -> bool {
         true
     }
 
     fn log(&self, record: &log::Record) {
+        progress::clear_line();
         let level_string = record.level().to_string();
         let target = if record.target().len() > 0 {
             record.target()
         } else {
             record.module_path().unwrap_or_default()
         };
-        eprintln!(
-            "{} {:<5} [{}] {}",
-            chrono::Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
-            level_string,
-            target,
-            record.args()
-        );
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        if self.json.load(Ordering::Relaxed) {
+            let mut line = serde_json::Map::new();
+            line.insert(
+                "timestamp".to_string(),
+                chrono::Local::now().to_rfc3339().into(),
+            );
+            line.insert("level".to_string(), level_string.into());
+            line.insert("target".to_string(), target.into());
+            line.insert("message".to_string(), record.args().to_string().into());
+            for (key, value) in fields.0 {
+                line.insert(key, value.into());
+            }
+            eprintln!("{}", serde_json::Value::Object(line));
+        } else {
+            let mut message = record.args().to_string();
+            for (key, value) in fields.0 {
+                message.push_str(&format!(" {}={}", key, value));
+            }
+            eprintln!(
+                "{} {:<5} [{}] {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
+                level_string,
+                target,
+                message
+            );
+        }
     }
 
     fn flush(&self) {}
 }
-static LOGGER: Logger = Logger {};
+static LOGGER: Logger = Logger {
+    json: AtomicBool::new(false),
+};
 
-fn derive_secrets(password: &str) -> Secrets {
+fn derive_secrets(password: &str, hash: HashAlgorithm) -> Secrets {
     // Derive secrets from password, since we need the same value every time
     // on different machines we cannot use salts or nonces
     // We derive the secrects
@@ -64,7 +127,6 @@ fn derive_secrets(password: &str) -> Secrets {
     const ROUNDS: usize = 16;
     const W: usize = 32;
     const X: usize = std::mem::size_of::<usize>();
-    let mut hasher = Blake2b::new(W);
     let mut data: Vec<u8> = Vec::new();
     data.resize(W * ITEMS, 42);
     for _ in 0..ROUNDS {
@@ -76,12 +138,18 @@ fn derive_secrets(password: &str) -> Secrets {
             let mut o2: [u8; X] = [0; X];
             o2.copy_from_slice(&data[prev * W + X..prev * W + 2 * X]);
             let o2 = usize::from_ne_bytes(o2) & (ITEMS - 1);
-            hasher.reset();
-            hasher.input(&password.as_bytes());
-            hasher.input(&data[prev * W..(prev + 1) * W]);
-            hasher.input(&data[o1 * W..(o1 + 1) * W]);
-            hasher.input(&data[o2 * W..(o2 + 1) * W]);
-            hasher.result(&mut data[cur * W..(cur + 1) * W]);
+            let mut digest = [0; W];
+            crypt::blake2b(
+                &[],
+                &[
+                    password.as_bytes(),
+                    &data[prev * W..(prev + 1) * W],
+                    &data[o1 * W..(o1 + 1) * W],
+                    &data[o2 * W..(o2 + 1) * W],
+                ],
+                &mut digest,
+            );
+            data[cur * W..(cur + 1) * W].copy_from_slice(&digest);
             prev = cur;
         }
     }
@@ -89,9 +157,165 @@ fn derive_secrets(password: &str) -> Secrets {
     secrets.bucket.copy_from_slice(&data[0..W]);
     secrets.seed.copy_from_slice(&data[128..128 + W]);
     secrets.key.copy_from_slice(&data[(ITEMS - 1) * W..]);
+    secrets.enc_key = crypt::subkey(&secrets.key, "encryption");
+    secrets.mac_key = crypt::subkey(&secrets.key, "authentication");
+    secrets.nonce_key = crypt::subkey(&secrets.key, "nonce");
+    secrets.path_key = crypt::subkey(&secrets.seed, "cache path");
+    // Chunks named by different hash functions are kept in different buckets,
+    // so that a bucket never mixes them
+    secrets.hash = hash;
+    if hash != HashAlgorithm::Blake2b {
+        secrets.bucket = crypt::subkey(&secrets.bucket, &hash.to_string());
+    }
+    data.zeroize();
     secrets
 }
 
+/// Validate that a bucket is the lowercase hex encoding of 32 bytes, as the server requires
+fn check_bucket(bucket: &str) -> Result<(), Error> {
+    if bucket.len() != 64
+        || !bucket
+            .bytes()
+            .all(|c| c.is_ascii_digit() || (b'a'..=b'f').contains(&c))
+    {
+        return Err(Error::Msg("The bucket must be 64 lowercase hex digits"));
+    }
+    Ok(())
+}
+
+/// A random encryption key, 32 bytes from the operating system encoded as hex
+fn generate_key() -> String {
+    use rand::Rng;
+    let mut key = [0u8; 32];
+    rand::rngs::OsRng.fill(&mut key);
+    let ans = hex::encode(&key);
+    key.zeroize();
+    ans
+}
+
+/// The fields of a config written by generate-config, everything else is left at its default
+#[derive(Serialize)]
+struct GeneratedConfig<'a> {
+    server: &'a str,
+    user: &'a str,
+    password: &'a str,
+    encryption_key: &'a str,
+    hostname: &'a str,
+    backup_dirs: Vec<&'a str>,
+    cache_db: &'a str,
+}
+
+/// Write a config with a random encryption key to the output given, or stdout
+///
+/// An existing file is never overwritten, since that would lose its encryption key
+fn generate_config(m: &ArgMatches) -> Result<(), Error> {
+    let mut hostname_buf = [0u8; 256];
+    let hostname = match m.value_of("hostname") {
+        Some(hostname) => hostname,
+        None => nix::unistd::gethostname(&mut hostname_buf)?
+            .to_str()
+            .map_err(|_| Error::Msg("Host name is not utf-8"))?,
+    };
+    let mut key = generate_key();
+    let config = GeneratedConfig {
+        server: m.value_of("server").unwrap_or("https://backup.example.com"),
+        user: m.value_of("user").unwrap_or("backup"),
+        password: m.value_of("password").unwrap_or(""),
+        encryption_key: &key,
+        hostname,
+        backup_dirs: m.values_of("dir").map_or(Vec::new(), |v| v.collect()),
+        cache_db: m
+            .value_of("cache_db")
+            .unwrap_or("/var/cache/mbackup/cache.db"),
+    };
+    let mut out =
+        "# Keep a copy of the encryption_key somewhere safe, without it nothing can be restored\n"
+            .to_string();
+    out.push_str(&toml::to_string(&config).map_err(|_| Error::Msg("Unable to write config"))?);
+    key.zeroize();
+    match m.value_of("output") {
+        Some(path) => {
+            use std::io::Write;
+            use std::os::unix::fs::OpenOptionsExt;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?
+                .write_all(out.as_bytes())?;
+        }
+        None => print!("{}", out),
+    }
+    out.zeroize();
+    Ok(())
+}
+
+/// Read the encryption key from a file, warning if other users can access it
+///
+/// Trailing white space, such as the newline ending the file, is not part of the key
+fn read_key_file(path: &str) -> Result<String, Error> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut key = std::fs::read_to_string(path)?;
+    if std::fs::metadata(path)?.permissions().mode() & 0o077 != 0 {
+        warn!(
+            "The key file {} is accessible by other users, it should have mode 600",
+            path
+        );
+    }
+    key.truncate(key.trim_end().len());
+    Ok(key)
+}
+
+#[cfg(feature = "keyring")]
+fn read_keyring(name: &str) -> Result<String, Error> {
+    keyring::Entry::new("mbackup", name)
+        .get_password()
+        .map_err(|_| Error::Msg("Unable to read the encryption key from the keyring"))
+}
+
+#[cfg(not(feature = "keyring"))]
+fn read_keyring(_name: &str) -> Result<String, Error> {
+    Err(Error::Msg("mbackup was built without keyring support"))
+}
+
+#[cfg(feature = "fuser")]
+fn run_mount(
+    config: Config,
+    secrets: Secrets,
+    root: &str,
+    mountpoint: &std::path::Path,
+) -> Result<bool, Error> {
+    mount::run_mount(config, secrets, root, mountpoint)
+}
+
+#[cfg(not(feature = "fuser"))]
+fn run_mount(
+    _config: Config,
+    _secrets: Secrets,
+    _root: &str,
+    _mountpoint: &std::path::Path,
+) -> Result<bool, Error> {
+    Err(Error::Msg("mbackup was built without FUSE support"))
+}
+
+/// Find the encryption key in the first of encryption_key_file, encryption_key_env or
+/// encryption_key_keyring that is configured, if it was not given directly
+fn load_encryption_key(config: &mut Config) -> Result<(), Error> {
+    if !config.encryption_key.is_empty() {
+        return Ok(());
+    }
+    config.encryption_key = if let Some(path) = &config.encryption_key_file {
+        read_key_file(path)?
+    } else if let Some(name) = &config.encryption_key_env {
+        std::env::var(name).map_err(|_| Error::Msg("Encryption key variable is not set"))?
+    } else if let Some(name) = &config.encryption_key_keyring {
+        read_keyring(name)?
+    } else {
+        return Ok(());
+    };
+    Ok(())
+}
+
 fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
     let matches = App::new("mbackup client")
         .version("0.1")
@@ -105,6 +329,28 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                 .possible_values(&["none", "error", "warn", "info", "debug", "trace"])
                 .help("Sets the level of verbosity"),
         )
+        .arg(
+            Arg::with_name("bucket")
+                .long("bucket")
+                .takes_value(true)
+                .help(
+                    "The bucket to use instead of the one of the encryption key, as 64 hex digits",
+                ),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .conflicts_with("progress")
+                .help("Do not show progress, only log"),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .takes_value(true)
+                .possible_values(&["bar", "json", "none"])
+                .help("How to show progress, json writes an object per line to stdout"),
+        )
         .arg(
             Arg::with_name("user")
                 .short("u")
@@ -126,6 +372,12 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                 .takes_value(true)
                 .help("The key to use when encrypting data"),
         )
+        .arg(
+            Arg::with_name("encryption_key_file")
+                .long("key-file")
+                .takes_value(true)
+                .help("A file containing the key to use when encrypting data"),
+        )
         .arg(
             Arg::with_name("server")
                 .short("s")
@@ -157,8 +409,17 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                 .arg(
                     Arg::with_name("hostname")
                         .long("hostname")
+                        .alias("label")
                         .takes_value(true)
-                        .help("Hostname to back up as"),
+                        .help("Hostname to back up as, or a label of what is backed up"),
+                )
+                .arg(
+                    Arg::with_name("tag")
+                        .long("tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("Tag to store with the root, may be given more than once"),
                 )
                 .arg(
                     Arg::with_name("dir")
@@ -166,6 +427,38 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                         .takes_value(true)
                         .multiple(true)
                         .help("Directories to backup"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Only report what would be backed up"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .requires("dry_run")
+                        .help("Report the dry run as json"),
+                )
+                .arg(
+                    Arg::with_name("daemon")
+                        .long("daemon")
+                        .conflicts_with("dry_run")
+                        .help("Keep running, backing up every backup_interval seconds"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .requires("daemon")
+                        .help("Seconds between the starts of backups, instead of backup_interval"),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .conflicts_with_all(&["dry_run", "daemon", "dir"])
+                        .help("Back up stdin as a single file named NAME instead of the folders"),
                 ),
         )
         .subcommand(
@@ -192,6 +485,21 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                 )
                 .about("Validate all backed up content"),
         )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .arg(
+                    Arg::with_name("root")
+                        .index(1)
+                        .required(true)
+                        .help("The root to verify"),
+                )
+                .arg(
+                    Arg::with_name("deep")
+                        .long("deep")
+                        .help("Download all chunks and check their content"),
+                )
+                .about("Verify that everything needed to restore a root is backed up"),
+        )
         .subcommand(
             SubCommand::with_name("roots").about("list roots").arg(
                 Arg::with_name("hostname")
@@ -202,6 +510,63 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
         )
         .subcommand(SubCommand::with_name("du").about("list disk usage"))
         .subcommand(SubCommand::with_name("ping").about("measure ping time"))
+        .subcommand(
+            SubCommand::with_name("generate-config")
+                .about("Write a config with a random encryption key")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .short("o")
+                        .takes_value(true)
+                        .help("The file to write, which must not exist, instead of stdout"),
+                )
+                .arg(
+                    Arg::with_name("server")
+                        .long("server")
+                        .takes_value(true)
+                        .help("The server to back up to"),
+                )
+                .arg(
+                    Arg::with_name("user")
+                        .long("user")
+                        .takes_value(true)
+                        .help("The user to connect as"),
+                )
+                .arg(
+                    Arg::with_name("password")
+                        .long("password")
+                        .takes_value(true)
+                        .help("The password to connect with"),
+                )
+                .arg(
+                    Arg::with_name("hostname")
+                        .long("hostname")
+                        .takes_value(true)
+                        .help("Hostname to back up as, the name of this host if not given"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .takes_value(true)
+                        .multiple(true)
+                        .help("Directories to backup"),
+                )
+                .arg(
+                    Arg::with_name("cache_db")
+                        .long("cache-db")
+                        .takes_value(true)
+                        .help("The path to the hash cache db"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("generate-key")
+                .alias("generate-secrets")
+                .about("Print a random encryption key"),
+        )
+        .subcommand(
+            SubCommand::with_name("bench-hash")
+                .about("measure the throughput of the hash functions"),
+        )
         .subcommand(
             SubCommand::with_name("ls").about("list files in root").arg(
                 Arg::with_name("root")
@@ -210,6 +575,54 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                     .help("the root to restore"),
             ),
         )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("list the entries of a folder in a root, like ls -l")
+                .arg(
+                    Arg::with_name("root")
+                        .index(1)
+                        .required(true)
+                        .help("the root to list"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .index(2)
+                        .default_value("/")
+                        .help("the folder to list"),
+                )
+                .arg(
+                    Arg::with_name("recursive")
+                        .long("recursive")
+                        .short("r")
+                        .help("also list the entries of sub folders"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("output the entries as a json array"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("show the files added (+), removed (-) and modified (~) between two roots")
+                .arg(
+                    Arg::with_name("old")
+                        .index(1)
+                        .required(true)
+                        .help("the root to compare from"),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .index(2)
+                        .required(true)
+                        .help("the root to compare to"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("output the changes as a json array"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("delete-root")
                 .about("delete a root")
@@ -217,7 +630,16 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                     Arg::with_name("root")
                         .index(1)
                         .required(true)
-                        .help("the root to restore"),
+                        .help("the root to delete"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("delete-bucket")
+                .about("delete every root of the bucket")
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .help("Confirm that every root of every host is to be deleted"),
                 ),
         )
         .subcommand(
@@ -232,10 +654,11 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                 .arg(
                     Arg::with_name("pattern")
                         .long("pattern")
+                        .alias("path")
                         .short("p")
                         .required(true)
                         .default_value("")
-                        .help("pattern of files to restore"),
+                        .help("file or folder to restore, with the folders above it"),
                 )
                 .arg(
                     Arg::with_name("dest")
@@ -253,7 +676,8 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                 .arg(
                     Arg::with_name("dry")
                         .long("dry")
-                        .help("Don't actually restore anything"),
+                        .alias("dry-run")
+                        .help("Only print what would be restored"),
                 ),
         )
         .subcommand(
@@ -272,13 +696,41 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
                         .help("path of file to restore"),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("mount")
+                .about("Mount a root as a read-only file system, until it is unmounted")
+                .arg(
+                    Arg::with_name("root")
+                        .index(1)
+                        .required(true)
+                        .help("the root to mount"),
+                )
+                .arg(
+                    Arg::with_name("mountpoint")
+                        .index(2)
+                        .required(true)
+                        .help("the folder to mount it on"),
+                ),
+        )
         .get_matches();
 
+    // These do not need a config, so they are done before reading it
+    if let Some(m) = matches.subcommand_matches("generate-config") {
+        generate_config(m)?;
+        std::process::exit(0)
+    }
+    if matches.subcommand_matches("generate-key").is_some() {
+        println!("{}", generate_key());
+        std::process::exit(0)
+    }
+    if matches.subcommand_matches("bench-hash").is_some() {
+        bench_hash(derive_secrets(&generate_key(), HashAlgorithm::Blake2b));
+        std::process::exit(0)
+    }
+
     let mut config: Config = match matches.value_of("config") {
         Some(path) => toml::from_str(&std::fs::read_to_string(path)?)?,
-        None => Config {
-            ..Default::default()
-        },
+        None => Config::default(),
     };
 
     match matches.value_of("verbosity") {
@@ -292,6 +744,40 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
         None => (),
     }
 
+    if let Some(v) = matches.value_of("bucket") {
+        config.bucket = Some(v.to_string());
+    }
+    if let Some(bucket) = &config.bucket {
+        check_bucket(bucket)?;
+    }
+
+    if matches.is_present("quiet") {
+        config.progress = ProgressFormat::None;
+    }
+    match matches.value_of("progress") {
+        Some("bar") => config.progress = ProgressFormat::Bar,
+        Some("json") => config.progress = ProgressFormat::Json,
+        Some("none") => config.progress = ProgressFormat::None,
+        Some(_) => return Err(Error::Msg("Unknown progress format")),
+        None => (),
+    }
+
+    match std::env::var("MBACKUP_LOG_FORMAT")
+        .as_ref()
+        .map(String::as_str)
+    {
+        Ok("plain") => config.log_format = LogFormat::Plain,
+        Ok("json") => config.log_format = LogFormat::Json,
+        Ok(_) => return Err(Error::Msg("Unknown log format")),
+        Err(_) => (),
+    }
+
+    // Log from here on, so that problems with the rest of the config can be warned about
+    log::set_max_level(config.verbosity);
+    LOGGER
+        .json
+        .store(config.log_format == LogFormat::Json, Ordering::Relaxed);
+
     if let Some(v) = matches.value_of("user") {
         config.user = v.to_string();
     }
@@ -300,6 +786,7 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
     }
 
     if let Some(v) = matches.value_of("password") {
+        config.password.zeroize();
         config.password = v.to_string();
     }
     if config.password.is_empty() {
@@ -307,8 +794,13 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
     }
 
     if let Some(v) = matches.value_of("encryption_key") {
+        config.encryption_key.zeroize();
         config.encryption_key = v.to_string();
+    } else if let Some(v) = matches.value_of("encryption_key_file") {
+        config.encryption_key.zeroize();
+        config.encryption_key = read_key_file(v)?;
     }
+    load_encryption_key(&mut config)?;
     if config.encryption_key.is_empty() {
         return Err(Error::Msg("No encryption key specified"));
     }
@@ -339,10 +831,25 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
             return Err(Error::Msg("No host name specified"));
         }
 
+        if let Some(v) = m.values_of("tag") {
+            config.tags = v.map(std::string::ToString::to_string).collect();
+        }
+        for tag in config.tags.iter() {
+            if tag.is_empty()
+                || !tag
+                    .bytes()
+                    .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'.')
+            {
+                return Err(Error::Msg(
+                    "Tags must be made of letters, digits, '-', '_' and '.'",
+                ));
+            }
+        }
+
         if let Some(v) = m.values_of("dir") {
             config.backup_dirs = v.map(std::string::ToString::to_string).collect();
         }
-        if config.backup_dirs.is_empty() {
+        if config.backup_dirs.is_empty() && !m.is_present("stdin") {
             return Err(Error::Msg("No backup dirs specified"));
         }
     } else if let Some(m) = matches.subcommand_matches("prune") {
@@ -351,12 +858,17 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
         }
     } else if matches.subcommand_matches("roots").is_some()
         || matches.subcommand_matches("validate").is_some()
+        || matches.subcommand_matches("verify").is_some()
         || matches.subcommand_matches("restore").is_some()
         || matches.subcommand_matches("cat").is_some()
+        || matches.subcommand_matches("mount").is_some()
         || matches.subcommand_matches("delete-root").is_some()
+        || matches.subcommand_matches("delete-bucket").is_some()
         || matches.subcommand_matches("du").is_some()
         || matches.subcommand_matches("ping").is_some()
         || matches.subcommand_matches("ls").is_some()
+        || matches.subcommand_matches("list").is_some()
+        || matches.subcommand_matches("diff").is_some()
     {
     } else {
         return Err(Error::Msg("No sub command specified"));
@@ -365,15 +877,15 @@ fn parse_config() -> Result<(Config, ArgMatches<'static>), Error> {
 }
 
 fn list_roots(host_name: Option<&str>, config: Config, secrets: Secrets) -> Result<(), Error> {
-    let client = reqwest::Client::new();
+    let client = http_client(&config)?;
     let url = format!("{}/roots/{}", &config.server, hex::encode(&secrets.bucket));
-    let mut res = check_response(&mut || {
+    let mut res = check_response(&config.retry, &mut || {
         client
             .get(&url[..])
             .basic_auth(&config.user, Some(&config.password))
             .send()
     })?;
-    println!("{:5} {:12} TIME", "ID", "HOST");
+    println!("{:5} {:12} {:19} TAGS", "ID", "HOST", "TIME");
 
     for row in res.text().expect("utf-8").split("\0\0") {
         if row.is_empty() {
@@ -383,51 +895,87 @@ fn list_roots(host_name: Option<&str>, config: Config, secrets: Secrets) -> Resu
         let id: u64 = ans.get(0).ok_or(Error::MissingRow())?.parse()?;
         let host: &str = ans.get(1).ok_or(Error::MissingRow())?;
         let time: i64 = ans.get(2).ok_or(Error::MissingRow())?.parse()?;
+        // Servers that predate tags do not send them
+        let tags: &str = ans.get(4).unwrap_or(&"");
         if let Some(name) = host_name {
             if name != host {
                 continue;
             }
         }
         println!(
-            "{:<5} {:12} {}",
+            "{:<5} {:12} {:19} {}",
             id,
             host,
-            NaiveDateTime::from_timestamp(time, 0)
+            NaiveDateTime::from_timestamp(time, 0),
+            tags
         );
     }
     Ok(())
 }
 
+/// Delete the root with the given id from the server
+fn delete_root_id(
+    client: &reqwest::Client,
+    config: &Config,
+    secrets: &Secrets,
+    id: u64,
+) -> Result<(), Error> {
+    let url = format!(
+        "{}/roots/{}/{}",
+        &config.server,
+        hex::encode(&secrets.bucket),
+        id
+    );
+    check_response(&config.retry, &mut || {
+        client
+            .delete(&url[..])
+            .basic_auth(&config.user, Some(&config.password))
+            .send()
+    })?;
+    Ok(())
+}
+
 fn delete_root(root: &str, config: Config, secrets: Secrets) -> Result<(), Error> {
-    let client = reqwest::Client::new();
+    let client = http_client(&config)?;
     match visit::roots(&config, &secrets, &client, Some(root))?
         .iter()
         .next()
     {
-        Some(Err(e)) => error!("Bad root: {:?}", e),
+        Some(Err(e)) => {
+            error!("Bad root: {:?}", e);
+            Err(Error::Msg("Bad root"))
+        }
         Some(Ok(root)) => {
-            let url = format!(
-                "{}/roots/{}/{}",
-                &config.server,
-                hex::encode(&secrets.bucket),
-                root.id
-            );
-            check_response(&mut || {
-                client
-                    .delete(&url[..])
-                    .basic_auth(&config.user, Some(&config.password))
-                    .send()
-            })?;
+            delete_root_id(&client, &config, &secrets, root.id)?;
+            println!("Deleted 1 root");
+            Ok(())
         }
         None => {
             error!("Could not find root {}", root);
+            Err(Error::Msg("Root not found"))
         }
     }
+}
+
+/// Delete every root of the bucket, of every host, printing how many were deleted
+///
+/// The chunks of the roots are left for prune or the garbage collection of the server
+fn delete_bucket(config: Config, secrets: Secrets) -> Result<(), Error> {
+    let client = http_client(&config)?;
+    let roots = visit::roots(&config, &secrets, &client, None)?;
+    let mut deleted = 0;
+    for root in roots.iter() {
+        let root = root?;
+        info!("Deleting root {} of {}", root.id, root.host);
+        delete_root_id(&client, &config, &secrets, root.id)?;
+        deleted += 1;
+    }
+    println!("Deleted {} roots", deleted);
     Ok(())
 }
 
 fn ping(config: Config, secrets: Secrets) -> Result<(), Error> {
-    let client = reqwest::Client::new();
+    let client = http_client(&config)?;
     loop {
         let start = std::time::Instant::now();
         visit::roots(&config, &secrets, &client, None)?;
@@ -437,20 +985,76 @@ fn ping(config: Config, secrets: Secrets) -> Result<(), Error> {
     Ok(())
 }
 
+fn bench_hash(mut secrets: Secrets) {
+    const SIZE: usize = 256 * 1024 * 1024;
+    let data = vec![42; SIZE];
+    for hash in [HashAlgorithm::Blake2b, HashAlgorithm::Blake3].iter() {
+        secrets.hash = *hash;
+        let start = std::time::Instant::now();
+        crypt::hash_chunk(&data, &secrets);
+        let duration = start.elapsed();
+        println!(
+            "{}: {:.0} MB/s",
+            hash,
+            SIZE as f64 / 1024.0 / 1024.0 / duration.as_secs_f64()
+        );
+    }
+}
+
 fn main() -> Result<(), Error> {
     log::set_logger(&LOGGER).unwrap();
     let (config, matches) = parse_config()?;
-    log::set_max_level(config.verbosity);
     debug!("Config {:?}", config);
 
     debug!("Derive secret!!\n");
-    let secrets = derive_secrets(&config.encryption_key);
+    let mut secrets = derive_secrets(&config.encryption_key, config.hash_algorithm);
+    if let Some(bucket) = &config.bucket {
+        let bucket = hex::decode(bucket).map_err(|_| Error::Msg("Bad bucket"))?;
+        secrets.bucket.copy_from_slice(&bucket);
+    }
     let ok = {
-        if matches.subcommand_matches("backup").is_some() {
-            backup::run(config, secrets)?;
+        if let Some(m) = matches
+            .subcommand_matches("backup")
+            .filter(|m| m.is_present("daemon"))
+        {
+            let interval = match m.value_of("interval") {
+                Some(v) => v.parse()?,
+                None => config.backup_interval,
+            };
+            if interval == 0 {
+                return Err(Error::Msg("The backup interval must be positive"));
+            }
+            backup::run_daemon(&config, &secrets, std::time::Duration::from_secs(interval))?;
+            true
+        } else if let Some(m) = matches.subcommand_matches("backup") {
+            let complete = match backup::run(
+                &config,
+                &secrets,
+                m.is_present("dry_run"),
+                m.is_present("json"),
+                m.value_of("stdin"),
+            ) {
+                Err(Error::Interrupted()) => {
+                    error!("Backup interrupted");
+                    // The exit status of a process killed by SIGINT
+                    std::process::exit(130);
+                }
+                res => res?,
+            };
+            // Tell skipped files apart from failures, which exit with 1
+            if !complete {
+                std::process::exit(2);
+            }
             true
         } else if let Some(m) = matches.subcommand_matches("validate") {
             visit::run_validate(config, secrets, m.is_present("full"))?
+        } else if let Some(m) = matches.subcommand_matches("verify") {
+            visit::run_verify(
+                config,
+                secrets,
+                m.value_of("root").unwrap(),
+                m.is_present("deep"),
+            )?
         } else if let Some(m) = matches.subcommand_matches("prune") {
             visit::run_prune(
                 config,
@@ -481,9 +1085,43 @@ fn main() -> Result<(), Error> {
                     .to_string(),
                 std::path::PathBuf::from(m.value_of("path").ok_or(Error::Msg("Missing path"))?),
             )?
+        } else if let Some(m) = matches.subcommand_matches("mount") {
+            run_mount(
+                config,
+                secrets,
+                m.value_of("root").ok_or(Error::Msg("Missing root"))?,
+                std::path::Path::new(
+                    m.value_of("mountpoint")
+                        .ok_or(Error::Msg("Missing mountpoint"))?,
+                ),
+            )?
+        } else if let Some(m) = matches.subcommand_matches("list") {
+            visit::run_list(
+                config,
+                secrets,
+                m.value_of("root").ok_or(Error::Msg("Missing root"))?,
+                std::path::Path::new(m.value_of("path").ok_or(Error::Msg("Missing path"))?),
+                m.is_present("recursive"),
+                m.is_present("json"),
+            )?
+        } else if let Some(m) = matches.subcommand_matches("diff") {
+            visit::run_diff(
+                config,
+                secrets,
+                m.value_of("old").ok_or(Error::Msg("Missing root"))?,
+                m.value_of("new").ok_or(Error::Msg("Missing root"))?,
+                m.is_present("json"),
+            )?
         } else if let Some(m) = matches.subcommand_matches("delete-root") {
             delete_root(m.value_of("root").unwrap(), config, secrets)?;
             true
+        } else if let Some(m) = matches.subcommand_matches("delete-bucket") {
+            if !m.is_present("yes") {
+                error!("This deletes every root of the bucket, run it with --yes to do so");
+                return Err(Error::Msg("Not confirmed"));
+            }
+            delete_bucket(config, secrets)?;
+            true
         } else if let Some(m) = matches.subcommand_matches("roots") {
             list_roots(m.value_of("hostname"), config, secrets)?;
             true
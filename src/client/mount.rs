@@ -0,0 +1,375 @@
+use crate::shared::{http_client, Config, EType, Error, Secrets};
+use crate::visit::{find_entries, get_chunk, Ent};
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    ReplyOpen, Request,
+};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long the kernel may cache attributes and lookups, roots never change
+const TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The most bytes of decrypted chunks kept in memory
+const CACHE_BYTES: usize = 256 * 1024 * 1024;
+
+/// The inode of the top folder of the mount
+const ROOT_INO: u64 = 1;
+
+/// A file or folder in the mounted root
+struct Node {
+    /// The entry of the node, None for the folders above the backup dirs
+    ent: Option<Ent>,
+    parent: u64,
+    /// The children of folders by name, in the order they are in the root
+    children: Vec<(OsString, u64)>,
+    /// The offsets in the content of a file where its chunks end,
+    /// for the chunks read so far, as the size of chunks is not stored
+    chunk_ends: Vec<u64>,
+}
+
+struct MountFs {
+    config: Config,
+    secrets: Secrets,
+    client: reqwest::Client,
+    /// The nodes by their inode less one
+    nodes: Vec<Node>,
+    /// The inodes of the children of folders by the inode of the folder and the name
+    lookup: HashMap<(u64, OsString), u64>,
+    /// Decrypted chunks, the least recently used first
+    cache: VecDeque<(String, Rc<Vec<u8>>)>,
+    cache_bytes: usize,
+}
+
+fn system_time(secs: i64, nsec: u32) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nsec)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.wrapping_neg() as u64)
+            + Duration::from_nanos(nsec.into())
+    }
+}
+
+fn file_type(etype: &EType) -> FileType {
+    match etype {
+        EType::File | EType::Hardlink => FileType::RegularFile,
+        EType::Dir | EType::Root => FileType::Directory,
+        EType::Link => FileType::Symlink,
+        EType::Fifo => FileType::NamedPipe,
+        EType::Socket => FileType::Socket,
+        EType::CharDevice => FileType::CharDevice,
+        EType::BlockDevice => FileType::BlockDevice,
+    }
+}
+
+impl MountFs {
+    fn new(
+        config: Config,
+        secrets: Secrets,
+        client: reqwest::Client,
+        entries: Vec<Ent>,
+    ) -> MountFs {
+        let mut fs = MountFs {
+            config,
+            secrets,
+            client,
+            nodes: vec![Node {
+                ent: None,
+                parent: ROOT_INO,
+                children: Vec::new(),
+                chunk_ends: Vec::new(),
+            }],
+            lookup: HashMap::new(),
+            cache: VecDeque::new(),
+            cache_bytes: 0,
+        };
+        for ent in entries {
+            if ent.etype == EType::Root {
+                continue;
+            }
+            let names: Vec<OsString> = ent
+                .path
+                .components()
+                .filter_map(|c| match c {
+                    Component::Normal(name) => Some(name.to_os_string()),
+                    _ => None,
+                })
+                .collect();
+            let (name, folders) = match names.split_last() {
+                Some(v) => v,
+                None => continue,
+            };
+            let mut parent = ROOT_INO;
+            for folder in folders {
+                parent = fs.child(parent, folder, None);
+            }
+            fs.child(parent, name, Some(ent));
+        }
+        fs
+    }
+
+    /// The inode of the child of a folder, added if it is not there,
+    /// and given ent if it is not None
+    fn child(&mut self, parent: u64, name: &OsStr, ent: Option<Ent>) -> u64 {
+        let key = (parent, name.to_os_string());
+        let ino = match self.lookup.get(&key) {
+            Some(ino) => *ino,
+            None => {
+                self.nodes.push(Node {
+                    ent: None,
+                    parent,
+                    children: Vec::new(),
+                    chunk_ends: Vec::new(),
+                });
+                let ino = self.nodes.len() as u64;
+                self.nodes[(parent - 1) as usize]
+                    .children
+                    .push((key.1.clone(), ino));
+                self.lookup.insert(key, ino);
+                ino
+            }
+        };
+        if ent.is_some() {
+            self.nodes[(ino - 1) as usize].ent = ent;
+        }
+        ino
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        self.nodes.get(ino.checked_sub(1)? as usize)
+    }
+
+    fn attr(&self, ino: u64, node: &Node) -> FileAttr {
+        let (kind, perm, size, uid, gid, mtime, rdev) = match &node.ent {
+            None => (FileType::Directory, 0o555, 0, 0, 0, UNIX_EPOCH, 0),
+            Some(ent) => {
+                let size = match ent.etype {
+                    EType::Link => ent.target.as_os_str().len() as u64,
+                    EType::File | EType::Hardlink => ent.size,
+                    _ => 0,
+                };
+                (
+                    file_type(&ent.etype),
+                    (ent.st_mode & 0o7777) as u16,
+                    size,
+                    ent.uid,
+                    ent.gid,
+                    system_time(ent.mtime, ent.mtime_nsec),
+                    ent.rdev as u32,
+                )
+            }
+        };
+        let crtime = match node.ent.as_ref().and_then(|ent| ent.btime) {
+            Some((secs, nsec)) => system_time(secs, nsec),
+            None => mtime,
+        };
+        FileAttr {
+            ino,
+            size,
+            blocks: (size + 511) / 512,
+            atime: mtime,
+            mtime,
+            ctime: mtime,
+            crtime,
+            kind,
+            perm,
+            nlink: if kind == FileType::Directory { 2 } else { 1 },
+            uid,
+            gid,
+            rdev,
+            blksize: 4096,
+            padding: 0,
+            flags: 0,
+        }
+    }
+
+    /// Fetch the decrypted content of a chunk, keeping the recently used chunks in memory
+    fn chunk(&mut self, chunk: &str) -> Result<Rc<Vec<u8>>, Error> {
+        if let Some(idx) = self.cache.iter().position(|(name, _)| name == chunk) {
+            let entry = self.cache.remove(idx).unwrap();
+            let content = entry.1.clone();
+            self.cache.push_back(entry);
+            return Ok(content);
+        }
+        let content = Rc::new(get_chunk(
+            &mut self.client,
+            &self.config,
+            &self.secrets,
+            chunk,
+        )?);
+        self.cache_bytes += content.len();
+        self.cache.push_back((chunk.to_string(), content.clone()));
+        while self.cache_bytes > CACHE_BYTES && self.cache.len() > 1 {
+            let (_, old) = self.cache.pop_front().unwrap();
+            self.cache_bytes -= old.len();
+        }
+        Ok(content)
+    }
+
+    /// Read up to size bytes of the content of a file from offset
+    fn read_content(&mut self, ino: u64, offset: u64, size: u64) -> Result<Vec<u8>, Error> {
+        let idx = (ino - 1) as usize;
+        let (chunks, file_size) = match &self.nodes[idx].ent {
+            Some(ent) if file_type(&ent.etype) == FileType::RegularFile => {
+                (ent.chunks.clone(), ent.size)
+            }
+            _ => return Err(Error::Msg("Not a file")),
+        };
+        let end = u64::min(offset.saturating_add(size), file_size);
+        let mut out = Vec::new();
+        let mut pos = offset;
+        let mut start = 0;
+        for (i, chunk) in chunks.iter().enumerate() {
+            if pos >= end {
+                break;
+            }
+            // The chunks before the one holding offset are fetched once to find where it starts
+            let chunk_end = match self.nodes[idx].chunk_ends.get(i) {
+                Some(chunk_end) => *chunk_end,
+                None => {
+                    let chunk_end = start + self.chunk(chunk)?.len() as u64;
+                    self.nodes[idx].chunk_ends.push(chunk_end);
+                    chunk_end
+                }
+            };
+            if chunk_end > pos {
+                let content = self.chunk(chunk)?;
+                let to = u64::min(end, chunk_end);
+                out.extend_from_slice(&content[(pos - start) as usize..(to - start) as usize]);
+                pos = to;
+            }
+            start = chunk_end;
+        }
+        Ok(out)
+    }
+}
+
+impl Filesystem for MountFs {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        match self.lookup.get(&(parent, name.to_os_string())) {
+            Some(ino) => {
+                let attr = self.attr(*ino, &self.nodes[(*ino - 1) as usize]);
+                reply.entry(&TTL, &attr, 0);
+            }
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        match self.node(ino) {
+            Some(node) => reply.attr(&TTL, &self.attr(ino, node)),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self.node(ino).and_then(|node| node.ent.as_ref()) {
+            Some(ent) if ent.etype == EType::Link => reply.data(ent.target.as_os_str().as_bytes()),
+            Some(_) => reply.error(libc::EINVAL),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        if self.node(ino).is_none() {
+            reply.error(libc::ENOENT);
+        } else if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            reply.error(libc::EROFS);
+        } else {
+            reply.opened(0, 0);
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if self.node(ino).is_none() || offset < 0 {
+            reply.error(libc::EINVAL);
+            return;
+        }
+        match self.read_content(ino, offset as u64, size.into()) {
+            Ok(data) => reply.data(&data),
+            Err(e) => {
+                error!("Unable to read inode {}: {:?}", ino, e);
+                reply.error(libc::EIO);
+            }
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let node = match self.node(ino) {
+            Some(node) => node,
+            None => {
+                reply.error(libc::ENOENT);
+                return;
+            }
+        };
+        if self.attr(ino, node).kind != FileType::Directory {
+            reply.error(libc::ENOTDIR);
+            return;
+        }
+        let mut entries = vec![
+            (ino, FileType::Directory, OsStr::new(".")),
+            (node.parent, FileType::Directory, OsStr::new("..")),
+        ];
+        for (name, child) in node.children.iter() {
+            let kind = self.attr(*child, &self.nodes[(*child - 1) as usize]).kind;
+            entries.push((*child, kind, name.as_os_str()));
+        }
+        for (i, (child, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            // The offset of an entry is that of the entry after it
+            if reply.add(child, (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+/// Mount a root as a read-only file system at mountpoint, until it is unmounted
+pub fn run_mount(
+    config: Config,
+    secrets: Secrets,
+    root: &str,
+    mountpoint: &Path,
+) -> Result<bool, Error> {
+    let mut entries: Vec<Ent> = Vec::new();
+    let (root_found, ok) = find_entries(
+        &config,
+        &secrets,
+        Some(root),
+        |_| Ok(true),
+        |ent| entries.push(ent),
+    )?;
+    if !root_found {
+        return Err(Error::Msg("Root not found"));
+    }
+    let client = http_client(&config)?;
+    let fs = MountFs::new(config, secrets, client, entries);
+    info!("Mounting root {} at {:?}", root, mountpoint);
+    fuser::mount2(
+        fs,
+        mountpoint,
+        &[MountOption::RO, MountOption::FSName("mbackup".to_string())],
+    )?;
+    Ok(ok)
+}
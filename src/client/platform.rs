@@ -0,0 +1,282 @@
+//! The parts of reading files that differ between platforms
+use crate::shared::{EType, Error};
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
+/// Set by the first SIGINT received after catch_interrupt, or a SIGTERM after catch_terminate
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// The metadata of a file recorded by backups
+///
+/// On unix these are the fields of stat, on other platforms they are made up from
+/// what is available, see meta
+pub struct Meta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    pub ctime: i64,
+    pub ctime_nsec: u32,
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub rdev: u64,
+}
+
+#[cfg(unix)]
+pub fn meta(md: &fs::Metadata) -> Meta {
+    use std::os::unix::fs::MetadataExt;
+    Meta {
+        mode: md.mode(),
+        uid: md.uid(),
+        gid: md.gid(),
+        mtime: md.mtime(),
+        mtime_nsec: md.mtime_nsec() as u32,
+        ctime: md.ctime(),
+        ctime_nsec: md.ctime_nsec() as u32,
+        dev: md.dev(),
+        ino: md.ino(),
+        nlink: md.nlink(),
+        rdev: md.rdev(),
+    }
+}
+
+/// Without stat the owner is root, the mode is made up from the read only flag,
+/// and the ctime is the mtime. Every file has a single link
+#[cfg(not(unix))]
+pub fn meta(md: &fs::Metadata) -> Meta {
+    let (mtime, mtime_nsec) = md.modified().map_or((0, 0), split_time);
+    Meta {
+        mode: mode_of(md.is_dir(), md.permissions().readonly()),
+        uid: 0,
+        gid: 0,
+        mtime,
+        mtime_nsec,
+        ctime: mtime,
+        ctime_nsec: mtime_nsec,
+        dev: 0,
+        ino: 0,
+        nlink: 1,
+        rdev: 0,
+    }
+}
+
+/// The permission bits of a file on platforms that only have a read only flag
+#[cfg_attr(unix, allow(dead_code))]
+pub fn mode_of(is_dir: bool, readonly: bool) -> u32 {
+    let mode = if readonly { 0o444 } else { 0o644 };
+    if is_dir {
+        mode | 0o111
+    } else {
+        mode
+    }
+}
+
+/// The birth time of a file in seconds and nanoseconds, None where the kernel or the
+/// file system does not report it. On Linux this is the stx_btime of statx
+pub fn birth_time(md: &fs::Metadata) -> Option<(i64, u32)> {
+    md.created().ok().map(split_time)
+}
+
+/// Split a time into seconds and nanoseconds since the epoch, as stat does
+pub fn split_time(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(e) => {
+            let d = e.duration();
+            if d.subsec_nanos() == 0 {
+                (-(d.as_secs() as i64), 0)
+            } else {
+                (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        }
+    }
+}
+
+/// The user and group of the process, the owner of the files it creates
+#[cfg(unix)]
+pub fn current_owner() -> (u32, u32) {
+    unsafe { (libc::getuid(), libc::getgid()) }
+}
+
+#[cfg(not(unix))]
+pub fn current_owner() -> (u32, u32) {
+    (0, 0)
+}
+
+/// The type of files that are not regular files, directories or links
+#[cfg(unix)]
+pub fn special_type(ft: &fs::FileType) -> Option<EType> {
+    use std::os::unix::fs::FileTypeExt;
+    if ft.is_fifo() {
+        Some(EType::Fifo)
+    } else if ft.is_socket() {
+        Some(EType::Socket)
+    } else if ft.is_char_device() {
+        Some(EType::CharDevice)
+    } else if ft.is_block_device() {
+        Some(EType::BlockDevice)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub fn special_type(_ft: &fs::FileType) -> Option<EType> {
+    None
+}
+
+/// The bytes a path is stored as
+///
+/// On unix these are the bytes of the path, elsewhere the utf-8 encoding of it
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Cow<[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> Cow<[u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(v) => Cow::Borrowed(v.as_bytes()),
+        Cow::Owned(v) => Cow::Owned(v.into_bytes()),
+    }
+}
+
+/// Undo path_bytes
+#[cfg(unix)]
+pub fn path_from_bytes(data: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(data))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(data: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(data).into_owned())
+}
+
+/// An exclusive lock on a file, released when this is dropped, also when unwinding from a panic
+pub struct FileLock {
+    _file: fs::File,
+}
+
+/// Take an exclusive lock on the file at path, creating it if needed
+///
+/// Returns None if the lock is held by another process
+#[cfg(unix)]
+pub fn lock_file(path: &Path) -> std::io::Result<Option<FileLock>> {
+    use std::os::unix::io::AsRawFd;
+    let file = fs::OpenOptions::new().create(true).write(true).open(path)?;
+    // The lock belongs to the open file, so it goes away when the file is closed
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+            return Ok(None);
+        }
+        return Err(err);
+    }
+    Ok(Some(FileLock { _file: file }))
+}
+
+/// Without flock the file is only created, nothing is locked
+#[cfg(not(unix))]
+pub fn lock_file(path: &Path) -> std::io::Result<Option<FileLock>> {
+    let file = fs::OpenOptions::new().create(true).write(true).open(path)?;
+    Ok(Some(FileLock { _file: file }))
+}
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+/// Catch the first SIGINT so that the process can stop cleanly when it sees interrupted,
+/// a second SIGINT kills the process as usual
+#[cfg(unix)]
+pub fn catch_interrupt() -> Result<(), Error> {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+    let action = SigAction::new(
+        SigHandler::Handler(on_interrupt),
+        SaFlags::SA_RESETHAND,
+        SigSet::empty(),
+    );
+    // The handler only stores to an atomic, which is safe to do in a signal handler
+    unsafe { sigaction(Signal::SIGINT, &action) }?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn catch_interrupt() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Catch SIGTERM like catch_interrupt catches SIGINT, for processes that stay running
+#[cfg(unix)]
+pub fn catch_terminate() -> Result<(), Error> {
+    use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+    let action = SigAction::new(
+        SigHandler::Handler(on_interrupt),
+        SaFlags::SA_RESETHAND,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGTERM, &action) }?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn catch_terminate() -> Result<(), Error> {
+    Ok(())
+}
+
+/// Whether a SIGINT, or a SIGTERM after catch_terminate, has been caught
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Whether stderr is a terminal, rather than a file or a pipe
+#[cfg(unix)]
+pub fn stderr_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stderr_is_terminal() -> bool {
+    false
+}
+
+/// Read the extended attributes of a file
+///
+/// If the file system does not support extended attributes there are none
+#[cfg(unix)]
+pub fn xattrs(path: &Path) -> Vec<(Vec<u8>, Vec<u8>)> {
+    use std::os::unix::ffi::OsStrExt;
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Vec::new(),
+        Err(e) => {
+            warn!("Unable to list extended attributes of {:?}: {:?}", path, e);
+            return Vec::new();
+        }
+    };
+    let mut ans = Vec::new();
+    for name in names {
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => ans.push((name.as_bytes().to_vec(), value)),
+            Ok(None) => (),
+            Err(e) => warn!(
+                "Unable to read extended attribute {:?} of {:?}: {:?}",
+                name, path, e
+            ),
+        }
+    }
+    ans
+}
+
+#[cfg(not(unix))]
+pub fn xattrs(_path: &Path) -> Vec<(Vec<u8>, Vec<u8>)> {
+    Vec::new()
+}
@@ -0,0 +1,176 @@
+//! A counter of what the scan of a backup has found so far, and the progress of transfers
+use crate::shared::{Config, ProgressFormat};
+use pbr::ProgressBar;
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often the counter is redrawn
+const REFRESH: Duration = Duration::from_millis(500);
+
+/// How often json progress events are written
+const JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether the counter is on the last line of stderr
+static DRAWN: AtomicBool = AtomicBool::new(false);
+
+/// Erase the counter, so that a log line can take its place
+///
+/// The counter is drawn again below the log line by the next update
+pub fn clear_line() {
+    if DRAWN.swap(false, Ordering::Relaxed) {
+        let mut err = std::io::stderr();
+        let _ = write!(err, "\r\x1b[K");
+        let _ = err.flush();
+    }
+}
+
+/// A line on stderr counting the files and folders scanned, redrawn at most every REFRESH
+pub struct ScanProgress {
+    last: Instant,
+}
+
+impl ScanProgress {
+    pub fn new() -> ScanProgress {
+        ScanProgress {
+            last: Instant::now(),
+        }
+    }
+
+    /// Redraw the counter, unless it was drawn recently and is still there
+    pub fn update(&mut self, files: u64, folders: u64, bytes: u64) {
+        let now = Instant::now();
+        if DRAWN.load(Ordering::Relaxed) && now.duration_since(self.last) < REFRESH {
+            return;
+        }
+        self.last = now;
+        let mut err = std::io::stderr();
+        let _ = write!(
+            err,
+            "\r\x1b[KScanned {} files in {} folders, {:.1} MB",
+            files,
+            folders,
+            bytes as f64 / 1024.0 / 1024.0
+        );
+        let _ = err.flush();
+        DRAWN.store(true, Ordering::Relaxed);
+    }
+
+    /// Erase the counter when the scan is done
+    pub fn finish(self) {
+        clear_line();
+    }
+}
+
+/// A json progress event, written as a line on stdout
+#[derive(Serialize)]
+struct ProgressEvent<'a> {
+    done: u64,
+    total: u64,
+    file: &'a str,
+    bytes_per_sec: u64,
+    finished: bool,
+}
+
+/// Progress written as json events, at most every JSON_INTERVAL
+pub struct JsonProgress {
+    start: Instant,
+    last: Option<Instant>,
+    done: u64,
+    total: u64,
+    file: String,
+}
+
+impl JsonProgress {
+    fn write(&mut self, finished: bool) {
+        let now = Instant::now();
+        if !finished
+            && self
+                .last
+                .map_or(false, |last| now.duration_since(last) < JSON_INTERVAL)
+        {
+            return;
+        }
+        self.last = Some(now);
+        let secs = now.duration_since(self.start).as_secs_f64();
+        let event = ProgressEvent {
+            done: self.done,
+            total: self.total,
+            file: &self.file,
+            bytes_per_sec: if secs > 0.0 {
+                (self.done as f64 / secs) as u64
+            } else {
+                0
+            },
+            finished,
+        };
+        let stdout = std::io::stdout();
+        let mut out = stdout.lock();
+        let _ = writeln!(out, "{}", serde_json::to_string(&event).unwrap());
+        let _ = out.flush();
+    }
+}
+
+/// The progress of transferring a known number of bytes, shown as configured by progress
+pub enum Progress {
+    Bar(ProgressBar<std::io::Stdout>),
+    Json(JsonProgress),
+}
+
+impl Progress {
+    /// Start showing the progress of transferring total bytes, None if it is not shown
+    ///
+    /// The bar is only shown when logging at info or above
+    pub fn new(config: &Config, total: u64) -> Option<Progress> {
+        match config.progress {
+            ProgressFormat::Bar if config.verbosity >= log::LevelFilter::Info => {
+                let mut pb = ProgressBar::new(total);
+                pb.set_max_refresh_rate(Some(REFRESH));
+                pb.set_units(pbr::Units::Bytes);
+                Some(Progress::Bar(pb))
+            }
+            ProgressFormat::Bar | ProgressFormat::None => None,
+            ProgressFormat::Json => Some(Progress::Json(JsonProgress {
+                start: Instant::now(),
+                last: None,
+                done: 0,
+                total,
+                file: String::new(),
+            })),
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        match self {
+            Progress::Bar(pb) => {
+                pb.add(bytes);
+            }
+            Progress::Json(p) => {
+                p.done += bytes;
+                p.write(false);
+            }
+        }
+    }
+
+    /// Show what is being transferred, messages of the bar end in a space to set them apart
+    pub fn message(&mut self, message: &str) {
+        match self {
+            Progress::Bar(pb) => pb.message(message),
+            Progress::Json(p) => {
+                p.file.clear();
+                p.file.push_str(message.trim_end());
+            }
+        }
+    }
+
+    pub fn finish(&mut self) {
+        match self {
+            Progress::Bar(pb) => pb.finish(),
+            Progress::Json(p) => {
+                p.file.clear();
+                p.write(true);
+            }
+        }
+    }
+}
@@ -1,7 +1,13 @@
 extern crate serde;
-use serde::Deserialize;
-extern crate crypto;
 use lzma;
+use serde::Deserialize;
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Header holding the Blake2b-256 of the body of chunk uploads
+pub const UPLOAD_HASH_HEADER: &str = "X-Content-Blake2b";
+
+/// Header holding the comma separated tags of a root when it is stored
+pub const ROOT_TAGS_HEADER: &str = "X-Root-Tags";
 
 #[derive(Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum EType {
@@ -9,6 +15,12 @@ pub enum EType {
     File,
     Dir,
     Link,
+    /// A hard link to the file at the target path
+    Hardlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
 }
 
 impl std::str::FromStr for EType {
@@ -20,10 +32,47 @@ impl std::str::FromStr for EType {
             "file" => Ok(EType::File),
             "dir" => Ok(EType::Dir),
             "link" => Ok(EType::Link),
+            "hardlink" => Ok(EType::Hardlink),
+            "fifo" => Ok(EType::Fifo),
+            "socket" => Ok(EType::Socket),
+            "chardev" => Ok(EType::CharDevice),
+            "blockdev" => Ok(EType::BlockDevice),
             _ => Err(Error::Msg("Bad type")),
         }
     }
 }
+impl EType {
+    /// The code of the type in binary root formats
+    pub fn code(&self) -> u8 {
+        match self {
+            EType::Root => 0,
+            EType::File => 1,
+            EType::Dir => 2,
+            EType::Link => 3,
+            EType::Hardlink => 4,
+            EType::Fifo => 5,
+            EType::Socket => 6,
+            EType::CharDevice => 7,
+            EType::BlockDevice => 8,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Result<EType, Error> {
+        match code {
+            0 => Ok(EType::Root),
+            1 => Ok(EType::File),
+            2 => Ok(EType::Dir),
+            3 => Ok(EType::Link),
+            4 => Ok(EType::Hardlink),
+            5 => Ok(EType::Fifo),
+            6 => Ok(EType::Socket),
+            7 => Ok(EType::CharDevice),
+            8 => Ok(EType::BlockDevice),
+            _ => Err(Error::Msg("Bad type")),
+        }
+    }
+}
+
 impl std::fmt::Display for EType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -31,6 +80,11 @@ impl std::fmt::Display for EType {
             EType::File => write!(f, "file"),
             EType::Dir => write!(f, "dir"),
             EType::Link => write!(f, "link"),
+            EType::Hardlink => write!(f, "hardlink"),
+            EType::Fifo => write!(f, "fifo"),
+            EType::Socket => write!(f, "socket"),
+            EType::CharDevice => write!(f, "chardev"),
+            EType::BlockDevice => write!(f, "blockdev"),
         }
     }
 }
@@ -46,44 +100,438 @@ pub enum LevelFilterDef {
     Trace,
 }
 
+/// The format of log lines
 #[derive(Deserialize, PartialEq, Debug)]
+pub enum LogFormat {
+    /// Human readable text
+    Plain,
+    /// A json object per line, for log ingestion
+    Json,
+}
+
+/// How the progress of transfers is shown
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum ProgressFormat {
+    /// A progress bar on stdout
+    Bar,
+    /// A json object per line on stdout, for programs wrapping the client
+    Json,
+    /// Nothing, only the log
+    None,
+}
+
+/// The hash function naming chunks
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+pub enum HashAlgorithm {
+    /// Blake2b-256 prefixed by the seed
+    Blake2b,
+    /// Blake3 keyed with the seed, faster on modern cpus
+    Blake3,
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> HashAlgorithm {
+        HashAlgorithm::Blake2b
+    }
+}
+
+impl std::fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Blake2b => write!(f, "blake2b"),
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+/// Which roots of each host prune keeps, roots kept by any rule are kept
+#[derive(Deserialize, PartialEq, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    /// Keep the newest keep_last roots
+    pub keep_last: u32,
+    /// Keep the newest root of each of the newest keep_daily days with a root
+    pub keep_daily: u32,
+    /// Keep the newest root of each of the newest keep_weekly weeks with a root
+    pub keep_weekly: u32,
+    /// Keep the newest root of each of the newest keep_monthly months with a root
+    pub keep_monthly: u32,
+}
+
+impl Retention {
+    /// Check if no retention policy is configured
+    pub fn is_empty(&self) -> bool {
+        *self == Retention::default()
+    }
+}
+
+/// How failed requests are retried
+///
+/// The delay before each retry doubles up to max_delay, and is shortened by a random
+/// amount of up to a half, so that clients failing together do not retry together
+#[derive(Deserialize, PartialEq, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// The number of times a request is retried
+    pub attempts: u32,
+    /// The delay in seconds before the first retry
+    pub delay: f64,
+    /// The longest delay in seconds between retries
+    pub max_delay: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            attempts: 4,
+            delay: 5.0,
+            max_delay: 120.0,
+        }
+    }
+}
+
+/// Another server every backup is also stored on
+///
+/// The user and password of the configured server are used if none are given
+#[derive(Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Mirror {
+    pub server: String,
+    #[serde(default)]
+    pub user: String,
+    #[serde(default)]
+    pub password: String,
+}
+
+/// Like a derived Debug, but without the password
+impl std::fmt::Debug for Mirror {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Mirror")
+            .field("server", &self.server)
+            .field("user", &self.user)
+            .field("password", &"<redacted>")
+            .finish()
+    }
+}
+
+impl Drop for Mirror {
+    fn drop(&mut self) {
+        self.password.zeroize();
+    }
+}
+
+/// A server backups are stored on and how to log in to it
+#[derive(Clone, Copy)]
+pub struct Remote<'a> {
+    pub server: &'a str,
+    pub user: &'a str,
+    pub password: &'a str,
+}
+
+#[derive(Deserialize, PartialEq)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
     #[serde(with = "LevelFilterDef")]
     pub verbosity: log::LevelFilter,
+    pub log_format: LogFormat,
     pub backup_dirs: Vec<String>,
     pub user: String,
     pub password: String,
     pub encryption_key: String,
+    /// Read the encryption key from this file when encryption_key is not given
+    pub encryption_key_file: Option<String>,
+    /// Read the encryption key from this environment variable when encryption_key is not given
+    pub encryption_key_env: Option<String>,
+    /// Read the encryption key from the entry of this name in the system keyring
+    /// when encryption_key is not given
+    pub encryption_key_keyring: Option<String>,
     pub server: String,
     pub recheck: bool,
+    /// Find files in the cache by their device and inode rather than their path
+    pub inode_change_detection: bool,
     pub cache_db: String,
     pub hostname: String,
     pub no_atime: bool,
+    /// The size of chunks when not using content defined chunking, changing it changes the chunks
+    /// of files, so their content is not deduplicated against backups made with another size
+    pub chunk_size: u64,
+    pub content_defined_chunking: bool,
+    pub min_chunk_size: u64,
+    pub avg_chunk_size: u64,
+    pub max_chunk_size: u64,
+    pub compression: bool,
+    pub compression_level: i32,
+    /// The hash function naming chunks, each hash function has its own bucket
+    pub hash_algorithm: HashAlgorithm,
+    /// Derive the nonce of a chunk from its content instead of generating it at random
+    pub deterministic_nonce: bool,
+    pub upload_threads: usize,
+    /// Limit on the upload rate, 0 for unlimited
+    pub max_upload_bytes_per_sec: u64,
+    pub retention: Retention,
+    pub retry: RetryPolicy,
+    /// Seconds to wait for a connection to the server, 0 to wait forever
+    pub connect_timeout: u64,
+    /// Seconds to wait for a request to complete, including sending and receiving the body,
+    /// 0 to wait forever
+    pub request_timeout: u64,
+    /// Backup and restore extended attributes
+    pub xattrs: bool,
+    /// Gitignore style patterns of paths not to backup
+    pub exclude: Vec<String>,
+    /// Files larger than this are not backed up, 0 for no limit
+    pub max_file_size: u64,
+    /// Files smaller than this are not backed up
+    pub min_file_size: u64,
+    /// Folders nested deeper than this below a backup dir are skipped
+    pub max_depth: usize,
+    /// Backup what symbolic links point to as if it was where the link is
+    pub follow_symlinks: bool,
+    /// Do not descend into folders on another file system than their backup dir
+    pub one_file_system: bool,
+    /// Skip files and folders that cannot be read instead of failing the backup
+    pub continue_on_error: bool,
+    /// Url to post a json summary to when a backup succeeds or fails
+    pub webhook: Option<String>,
+    /// Seconds between the starts of backups when running with --daemon
+    pub backup_interval: u64,
+    /// Store keyed hashes of paths in the cache instead of the paths
+    pub hash_cache_paths: bool,
+    /// Other servers every backup is also stored on
+    pub mirrors: Vec<Mirror>,
+    /// How many of the servers must store a backup for it to succeed, 0 for all of them
+    pub quorum: usize,
+    /// Bundle chunks smaller than pack_chunk_size into packs of up to this size,
+    /// 0 to store every chunk on its own
+    pub pack_size: u64,
+    pub pack_chunk_size: u64,
+    /// Record the birth time of files where the file system reports it
+    pub btime: bool,
+    pub progress: ProgressFormat,
+    /// Store roots and chunks in this bucket, as 64 lowercase hex digits,
+    /// instead of the bucket derived from the encryption key
+    pub bucket: Option<String>,
+    /// Tags stored with the roots of backups, like "monthly" or "pre-upgrade"
+    pub tags: Vec<String>,
+}
+
+/// Like a derived Debug, but without the password and encryption key
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Config")
+            .field("verbosity", &self.verbosity)
+            .field("log_format", &self.log_format)
+            .field("backup_dirs", &self.backup_dirs)
+            .field("user", &self.user)
+            .field("password", &"<redacted>")
+            .field("encryption_key", &"<redacted>")
+            .field("encryption_key_file", &self.encryption_key_file)
+            .field("encryption_key_env", &self.encryption_key_env)
+            .field("encryption_key_keyring", &self.encryption_key_keyring)
+            .field("server", &self.server)
+            .field("recheck", &self.recheck)
+            .field("inode_change_detection", &self.inode_change_detection)
+            .field("cache_db", &self.cache_db)
+            .field("hostname", &self.hostname)
+            .field("no_atime", &self.no_atime)
+            .field("chunk_size", &self.chunk_size)
+            .field("content_defined_chunking", &self.content_defined_chunking)
+            .field("min_chunk_size", &self.min_chunk_size)
+            .field("avg_chunk_size", &self.avg_chunk_size)
+            .field("max_chunk_size", &self.max_chunk_size)
+            .field("compression", &self.compression)
+            .field("compression_level", &self.compression_level)
+            .field("hash_algorithm", &self.hash_algorithm)
+            .field("deterministic_nonce", &self.deterministic_nonce)
+            .field("upload_threads", &self.upload_threads)
+            .field("max_upload_bytes_per_sec", &self.max_upload_bytes_per_sec)
+            .field("retention", &self.retention)
+            .field("retry", &self.retry)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("request_timeout", &self.request_timeout)
+            .field("xattrs", &self.xattrs)
+            .field("exclude", &self.exclude)
+            .field("max_file_size", &self.max_file_size)
+            .field("min_file_size", &self.min_file_size)
+            .field("max_depth", &self.max_depth)
+            .field("follow_symlinks", &self.follow_symlinks)
+            .field("one_file_system", &self.one_file_system)
+            .field("continue_on_error", &self.continue_on_error)
+            .field("webhook", &self.webhook)
+            .field("backup_interval", &self.backup_interval)
+            .field("hash_cache_paths", &self.hash_cache_paths)
+            .field("mirrors", &self.mirrors)
+            .field("quorum", &self.quorum)
+            .field("pack_size", &self.pack_size)
+            .field("pack_chunk_size", &self.pack_chunk_size)
+            .field("btime", &self.btime)
+            .field("progress", &self.progress)
+            .field("bucket", &self.bucket)
+            .field("tags", &self.tags)
+            .finish()
+    }
 }
 
 impl Default for Config {
     fn default() -> Config {
         Config {
             verbosity: log::LevelFilter::Info,
+            log_format: LogFormat::Plain,
             backup_dirs: Vec::new(),
             user: "".to_string(),
             password: "".to_string(),
             encryption_key: "".to_string(),
+            encryption_key_file: None,
+            encryption_key_env: None,
+            encryption_key_keyring: None,
             server: "".to_string(),
             recheck: false,
+            inode_change_detection: false,
             cache_db: "cache.db".to_string(),
             hostname: "".to_string(),
             no_atime: true,
+            chunk_size: 64 * 1024 * 1024,
+            content_defined_chunking: false,
+            min_chunk_size: 512 * 1024,
+            avg_chunk_size: 2 * 1024 * 1024,
+            max_chunk_size: 8 * 1024 * 1024,
+            compression: false,
+            compression_level: 3,
+            hash_algorithm: HashAlgorithm::Blake2b,
+            deterministic_nonce: false,
+            upload_threads: 4,
+            max_upload_bytes_per_sec: 0,
+            retention: Retention::default(),
+            retry: RetryPolicy::default(),
+            connect_timeout: 30,
+            request_timeout: 600,
+            xattrs: false,
+            exclude: Vec::new(),
+            max_file_size: 0,
+            min_file_size: 0,
+            max_depth: 1000,
+            follow_symlinks: false,
+            one_file_system: false,
+            continue_on_error: true,
+            webhook: None,
+            backup_interval: 24 * 60 * 60,
+            hash_cache_paths: false,
+            mirrors: Vec::new(),
+            quorum: 0,
+            pack_size: 0,
+            pack_chunk_size: 64 * 1024,
+            btime: false,
+            progress: ProgressFormat::Bar,
+            bucket: None,
+            tags: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// The configured server
+    pub fn remote(&self) -> Remote {
+        Remote {
+            server: &self.server,
+            user: &self.user,
+            password: &self.password,
+        }
+    }
+
+    /// The configured server followed by its mirrors
+    pub fn remotes(&self) -> Vec<Remote> {
+        let mut ans = vec![self.remote()];
+        for mirror in self.mirrors.iter() {
+            ans.push(Remote {
+                server: &mirror.server,
+                user: if mirror.user.is_empty() {
+                    &self.user
+                } else {
+                    &mirror.user
+                },
+                password: if mirror.password.is_empty() {
+                    &self.password
+                } else {
+                    &mirror.password
+                },
+            });
         }
+        ans
+    }
+}
+
+impl Drop for Config {
+    /// Overwrite the password and encryption key so they do not linger in freed memory
+    fn drop(&mut self) {
+        self.password.zeroize();
+        self.encryption_key.zeroize();
     }
 }
 
-#[derive(Default)]
+/// Keys derived from the encryption key, overwritten with zeros when dropped
+#[derive(Default, Zeroize, ZeroizeOnDrop)]
 pub struct Secrets {
     pub bucket: [u8; 32],
     pub seed: [u8; 32],
     pub key: [u8; 32],
+    pub enc_key: [u8; 32],
+    pub mac_key: [u8; 32],
+    pub nonce_key: [u8; 32],
+    /// Keys the hashes of paths in the cache, with hash_cache_paths
+    pub path_key: [u8; 32],
+    #[zeroize(skip)]
+    pub hash: HashAlgorithm,
+}
+
+/// A chunk in the chunks of a file
+///
+/// Chunks are stored on the server on their own, named by their hash, or as part of a pack,
+/// in which case they are written as hash:pack:offset:length
+pub struct ChunkRef<'a> {
+    /// The hash of the content of the chunk
+    pub hash: &'a str,
+    /// The object holding the chunk on the server, its pack or the chunk itself
+    pub object: &'a str,
+    /// The offset and length of the encrypted chunk in its pack
+    pub range: Option<(u64, u64)>,
+}
+
+impl<'a> ChunkRef<'a> {
+    pub fn parse(chunk: &'a str) -> Result<ChunkRef<'a>, Error> {
+        let mut parts = chunk.split(':');
+        let hash = parts.next().unwrap_or(chunk);
+        let object = match parts.next() {
+            Some(object) => object,
+            None => {
+                return Ok(ChunkRef {
+                    hash,
+                    object: hash,
+                    range: None,
+                })
+            }
+        };
+        let offset: u64 = parts
+            .next()
+            .ok_or(Error::Msg("Missing pack offset"))?
+            .parse()?;
+        let length: u64 = parts
+            .next()
+            .ok_or(Error::Msg("Missing pack length"))?
+            .parse()?;
+        if parts.next().is_some() || length == 0 {
+            return Err(Error::Msg("Bad packed chunk"));
+        }
+        Ok(ChunkRef {
+            hash,
+            object,
+            range: Some((offset, length)),
+        })
+    }
+}
+
+/// The object holding a chunk of a file on the server, see ChunkRef
+pub fn chunk_object(chunk: &str) -> &str {
+    chunk.split(':').nth(1).unwrap_or(chunk)
 }
 
 #[derive(Debug)]
@@ -92,16 +540,20 @@ pub enum Error {
     MissingRow(),
     Reqwest(reqwest::Error),
     HttpStatus(reqwest::StatusCode),
-    BadPath(std::path::PathBuf),
     Io(std::io::Error),
     ParseInt(std::num::ParseIntError),
     InvalidHash(),
+    /// An encrypted chunk did not match its authentication tag
+    Authentication(),
+    /// The process was asked to stop by SIGINT
+    Interrupted(),
     Utf8(std::string::FromUtf8Error),
     Time(std::time::SystemTimeError),
     Msg(&'static str),
     Toml(toml::de::Error),
     Nix(nix::Error),
     LZMA(lzma::LzmaError),
+    Ignore(ignore::Error),
 }
 
 impl From<rusqlite::Error> for Error {
@@ -158,11 +610,45 @@ impl From<lzma::LzmaError> for Error {
     }
 }
 
-pub fn retry<F>(f: &mut F) -> Result<reqwest::Response, reqwest::Error>
+impl From<ignore::Error> for Error {
+    fn from(error: ignore::Error) -> Self {
+        Error::Ignore(error)
+    }
+}
+
+/// Construct the client used for requests to the server, with the timeouts of the config
+pub fn http_client(config: &Config) -> Result<reqwest::Client, Error> {
+    let timeout = |secs| match secs {
+        0 => None,
+        secs => Some(std::time::Duration::from_secs(secs)),
+    };
+    Ok(reqwest::Client::builder()
+        .connect_timeout(timeout(config.connect_timeout))
+        .timeout(timeout(config.request_timeout))
+        .build()?)
+}
+
+/// The delay asked for by the Retry-After header of a response, in seconds
+fn retry_after(res: &reqwest::Response) -> Option<u64> {
+    res.headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Send a request until it succeeds or fails in a way that retrying will not fix,
+/// waiting between attempts as described by policy
+pub fn retry<F>(policy: &RetryPolicy, f: &mut F) -> Result<reqwest::Response, reqwest::Error>
 where
     F: FnMut() -> Result<reqwest::Response, reqwest::Error>,
 {
-    for sleep in [5, 20, 60, 120].iter() {
+    let mut delay = policy.delay;
+    for _ in 0..policy.attempts {
+        let mut sleep = f64::min(delay, policy.max_delay) * (1.0 - rand::random::<f64>() / 2.0);
+        delay *= 2.0;
         match f() {
             Ok(res) => {
                 if match res.status() {
@@ -177,6 +663,10 @@ where
                     return Ok(res);
                 } else {
                     warn!("Request failed, retrying {}", res.status());
+                    // The server tells us when it is ready for us again
+                    if let Some(after) = retry_after(&res) {
+                        sleep = after as f64;
+                    }
                 }
             }
             Err(e) => {
@@ -187,18 +677,99 @@ where
                 }
             }
         };
-        std::thread::sleep(std::time::Duration::from_secs(*sleep));
+        std::thread::sleep(std::time::Duration::from_secs_f64(sleep));
     }
     f()
 }
 
-pub fn check_response<F>(f: &mut F) -> Result<reqwest::Response, Error>
+pub fn check_response<F>(policy: &RetryPolicy, f: &mut F) -> Result<reqwest::Response, Error>
 where
     F: FnMut() -> Result<reqwest::Response, reqwest::Error>,
 {
-    let res = retry(f)?;
+    let res = retry(policy, f)?;
     match res.status() {
         reqwest::StatusCode::OK => Ok(res),
         code => Err(Error::HttpStatus(code)),
     }
 }
+
+/// The number of chunks to request per page when listing chunks
+const LIST_PAGE_SIZE: usize = 100_000;
+
+/// Call handle with every row of the chunk list of the bucket
+///
+/// The rows are on the form "hash size", or "hash size content_size" if validate is true.
+/// Returns the server time of the first response
+pub fn list_chunks<F>(
+    client: &reqwest::Client,
+    config: &Config,
+    secrets: &Secrets,
+    validate: bool,
+    handle: F,
+) -> Result<Option<i64>, Error>
+where
+    F: FnMut(&str) -> Result<(), Error>,
+{
+    list_chunks_on(
+        client,
+        config.remote(),
+        &config.retry,
+        secrets,
+        validate,
+        handle,
+    )
+}
+
+/// Call handle with every row of the chunk list of the bucket on remote, as list_chunks
+pub fn list_chunks_on<F>(
+    client: &reqwest::Client,
+    remote: Remote,
+    policy: &RetryPolicy,
+    secrets: &Secrets,
+    validate: bool,
+    mut handle: F,
+) -> Result<Option<i64>, Error>
+where
+    F: FnMut(&str) -> Result<(), Error>,
+{
+    let mut start = None;
+    let mut after = String::new();
+    loop {
+        let url = format!(
+            "{}/chunks/{}?limit={}&after={}{}",
+            remote.server,
+            hex::encode(&secrets.bucket),
+            LIST_PAGE_SIZE,
+            after,
+            if validate { "&validate=validate" } else { "" }
+        );
+        let mut res = check_response(policy, &mut || {
+            client
+                .get(&url[..])
+                .basic_auth(remote.user, Some(remote.password))
+                .send()
+        })?;
+        if start.is_none() {
+            start = res
+                .headers()
+                .get(reqwest::header::DATE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| chrono::DateTime::parse_from_rfc2822(v).ok())
+                .map(|v| v.timestamp());
+        }
+        let next_after = res
+            .headers()
+            .get("X-Next-After")
+            .and_then(|v| v.to_str().ok())
+            .map(std::string::ToString::to_string);
+        for row in res.text()?.split('\n') {
+            if !row.is_empty() {
+                handle(row)?;
+            }
+        }
+        match next_after {
+            Some(next_after) => after = next_after,
+            None => return Ok(start),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The largest number of bytes sent between checks of the throttle
+const PIECE_SIZE: usize = 64 * 1024;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket limiting the rate at which bytes are sent, shared by all senders
+///
+/// The bucket holds at most a second worth of bytes. Senders may take more bytes than
+/// are in the bucket, they then sleep until the debt has been refilled
+pub struct Throttle {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Throttle {
+        Throttle {
+            rate: bytes_per_sec as f64,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until bytes may be sent
+    pub fn take(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            bucket.tokens = f64::min(self.rate, bucket.tokens + elapsed * self.rate);
+            bucket.last = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / self.rate)
+            } else {
+                Duration::from_secs(0)
+            }
+        };
+        if wait > Duration::from_secs(0) {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// A reader of some content that is throttled as it is read
+pub struct ThrottledReader {
+    content: std::io::Cursor<Vec<u8>>,
+    throttle: Arc<Throttle>,
+}
+
+impl ThrottledReader {
+    pub fn new(content: Vec<u8>, throttle: Arc<Throttle>) -> ThrottledReader {
+        ThrottledReader {
+            content: std::io::Cursor::new(content),
+            throttle,
+        }
+    }
+}
+
+impl Read for ThrottledReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = usize::min(buf.len(), PIECE_SIZE);
+        let read = self.content.read(&mut buf[..len])?;
+        self.throttle.take(read);
+        Ok(read)
+    }
+}
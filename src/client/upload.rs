@@ -0,0 +1,166 @@
+use crate::crypt::upload_hash;
+use crate::shared::{retry, Error, RetryPolicy, UPLOAD_HASH_HEADER};
+use crate::throttle::{Throttle, ThrottledReader};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use zeroize::Zeroizing;
+
+/// A chunk waiting to be uploaded
+struct Upload {
+    hash: String,
+    content: Vec<u8>,
+}
+
+/// The outcome of uploading a chunk
+pub struct UploadResult {
+    pub hash: String,
+    pub len: usize,
+    pub status: Result<reqwest::StatusCode, reqwest::Error>,
+}
+
+/// A pool of threads uploading chunks to the server
+///
+/// Chunks are handed to the workers over a bounded channel, so that at most
+/// a few chunks are kept in memory while waiting to be uploaded
+pub struct Uploader {
+    jobs: Option<SyncSender<Upload>>,
+    results: Receiver<UploadResult>,
+    workers: Vec<JoinHandle<()>>,
+    pending: usize,
+}
+
+struct Worker {
+    client: reqwest::Client,
+    url: String,
+    user: String,
+    password: Zeroizing<String>,
+    throttle: Option<Arc<Throttle>>,
+    retry: RetryPolicy,
+}
+
+impl Worker {
+    fn run(self, jobs: Arc<Mutex<Receiver<Upload>>>, results: Sender<UploadResult>) {
+        loop {
+            let job = match jobs.lock().unwrap().recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            };
+            let url = format!("{}/{}", self.url, job.hash);
+            let upload_hash = upload_hash(&job.content);
+            let status = retry(&self.retry, &mut || {
+                let body = match &self.throttle {
+                    Some(throttle) => reqwest::Body::sized(
+                        ThrottledReader::new(job.content.clone(), throttle.clone()),
+                        job.content.len() as u64,
+                    ),
+                    None => reqwest::Body::from(job.content.clone()),
+                };
+                self.client
+                    .put(&url[..])
+                    .basic_auth(&self.user, Some(&*self.password))
+                    .header(UPLOAD_HASH_HEADER, &upload_hash[..])
+                    .body(body)
+                    .send()
+            })
+            .map(|res| res.status());
+            let result = UploadResult {
+                hash: job.hash,
+                len: job.content.len(),
+                status,
+            };
+            if results.send(result).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Uploader {
+    /// Start threads workers uploading to {url}/{hash}
+    ///
+    /// If max_bytes_per_sec is not 0 the workers together upload at most that many bytes per second
+    pub fn new(
+        threads: usize,
+        client: &reqwest::Client,
+        url: String,
+        user: &str,
+        password: &str,
+        max_bytes_per_sec: u64,
+        retry: RetryPolicy,
+    ) -> Uploader {
+        let threads = usize::max(threads, 1);
+        let throttle = if max_bytes_per_sec != 0 {
+            Some(Arc::new(Throttle::new(max_bytes_per_sec)))
+        } else {
+            None
+        };
+        let (jobs, job_receiver) = sync_channel(threads);
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let (result_sender, results) = channel();
+        let workers = (0..threads)
+            .map(|_| {
+                let worker = Worker {
+                    client: client.clone(),
+                    url: url.clone(),
+                    user: user.to_string(),
+                    password: Zeroizing::new(password.to_string()),
+                    throttle: throttle.clone(),
+                    retry,
+                };
+                let job_receiver = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                std::thread::spawn(move || worker.run(job_receiver, result_sender))
+            })
+            .collect();
+        Uploader {
+            jobs: Some(jobs),
+            results,
+            workers,
+            pending: 0,
+        }
+    }
+
+    /// Queue a chunk for upload, blocks while all workers are busy
+    pub fn push(&mut self, hash: String, content: Vec<u8>) -> Result<(), Error> {
+        self.jobs
+            .as_ref()
+            .ok_or(Error::Msg("Uploader finished"))?
+            .send(Upload { hash, content })
+            .map_err(|_| Error::Msg("Upload workers died"))?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Return the result of a finished upload
+    ///
+    /// If wait is true block until an upload finishes, unless nothing is pending
+    pub fn result(&mut self, wait: bool) -> Result<Option<UploadResult>, Error> {
+        if self.pending == 0 {
+            return Ok(None);
+        }
+        let res = if wait {
+            self.results
+                .recv()
+                .map_err(|_| Error::Msg("Upload workers died"))?
+        } else {
+            match self.results.try_recv() {
+                Ok(res) => res,
+                Err(TryRecvError::Empty) => return Ok(None),
+                Err(TryRecvError::Disconnected) => return Err(Error::Msg("Upload workers died")),
+            }
+        };
+        self.pending -= 1;
+        Ok(Some(res))
+    }
+
+    /// Stop the workers, all results must have been collected first
+    pub fn finish(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!("Upload worker panicked");
+            }
+        }
+    }
+}
@@ -1,15 +1,20 @@
-use crate::shared::{check_response, Config, EType, Error, Secrets};
-use chrono::NaiveDateTime;
-use crypto::blake2b::Blake2b;
-use crypto::digest::Digest;
-use crypto::symmetriccipher::SynchronousStreamCipher;
+use crate::crypt::{decrypt_chunk, hash_chunk, MAX_OVERHEAD};
+use crate::format;
+use crate::progress::Progress;
+use crate::shared::{
+    check_response, chunk_object, http_client, list_chunks, retry, ChunkRef, Config, EType, Error,
+    Retention, Secrets,
+};
+use chrono::{Datelike, NaiveDateTime};
 use lzma;
-use pbr::ProgressBar;
+use nix::sys::stat::{Mode, SFlag};
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
 use std::io::Read;
 use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
-use std::time::Duration;
 use std::time::SystemTime;
 
 struct Size {
@@ -55,7 +60,7 @@ impl From<u64> for Size {
     }
 }
 
-fn get_chunk(
+pub fn get_chunk(
     client: &mut reqwest::Client,
     config: &Config,
     secrets: &Secrets,
@@ -64,37 +69,45 @@ fn get_chunk(
     if hash == "empty" {
         return Ok(Vec::new());
     }
+    let chunk = ChunkRef::parse(hash)?;
     let url = format!(
         "{}/chunks/{}/{}",
         &config.server,
         hex::encode(&secrets.bucket),
-        &hash
+        chunk.object
     );
 
-    let mut res = check_response(&mut || {
-        client
+    let mut res = check_response(&config.retry, &mut || {
+        let req = client
             .get(&url[..])
-            .basic_auth(&config.user, Some(&config.password))
-            .send()
+            .basic_auth(&config.user, Some(&config.password));
+        match chunk.range {
+            Some((offset, length)) => req
+                .header(
+                    reqwest::header::RANGE,
+                    format!("bytes={}-{}", offset, offset + length - 1),
+                )
+                .send(),
+            None => req.send(),
+        }
     })?;
 
     let len = res.content_length().unwrap_or(0);
     let mut encrypted = Vec::with_capacity(len as usize);
-    let len = res.read_to_end(&mut encrypted)?;
-    if len < 12 {
-        return Err(Error::Msg("Missing nonce"));
+    res.read_to_end(&mut encrypted)?;
+    // Servers that do not support ranges send the whole pack
+    if let (Some((offset, length)), reqwest::StatusCode::OK) = (chunk.range, res.status()) {
+        let end = offset
+            .checked_add(length)
+            .ok_or(Error::Msg("Bad packed chunk"))?;
+        if end > encrypted.len() as u64 {
+            return Err(Error::Msg("Pack is too short"));
+        }
+        encrypted = encrypted[offset as usize..end as usize].to_vec();
     }
+    let content = decrypt_chunk(&encrypted, secrets, &chunk.hash)?;
 
-    let mut content = Vec::with_capacity(encrypted.len());
-    content.resize(encrypted.len() - 12, 0);
-    crypto::chacha20::ChaCha20::new(&secrets.key, &encrypted[..12])
-        .process(&encrypted[12..], &mut content);
-
-    let mut hasher = Blake2b::new(256 / 8);
-    hasher.input(&secrets.seed);
-    hasher.input(&content);
-
-    if hasher.result_str() != hash {
+    if hash_chunk(&content, secrets) != chunk.hash {
         Err(Error::InvalidHash())
     } else {
         Ok(content)
@@ -106,23 +119,181 @@ fn get_root(
     config: &Config,
     secrets: &Secrets,
     hash: &str,
-) -> Result<String, Error> {
-    Ok(String::from_utf8(lzma::decompress(&get_chunk(
+) -> Result<Vec<u8>, Error> {
+    Ok(lzma::decompress(&get_chunk(
         client, config, secrets, hash,
-    )?)?)?)
+    )?)?)
 }
 
-struct Ent {
-    etype: EType,
-    path: std::path::PathBuf,
-    size: u64,
-    st_mode: u32,
-    uid: u32,
-    gid: u32,
-    mtime: i64,
-    chunks: Vec<String>,
+pub struct Ent {
+    pub etype: EType,
+    pub path: std::path::PathBuf,
+    pub size: u64,
+    pub st_mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub mtime_nsec: u32,
+    /// The birth time, in roots that recorded it
+    pub btime: Option<(i64, u32)>,
+    pub chunks: Vec<String>,
+    /// The target of links
+    pub target: PathBuf,
+    /// The device number of device nodes
+    pub rdev: u64,
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Decode the entries of a root, an entry that cannot be decoded is returned as an error in its place
+fn root_entries(root: &[u8]) -> Vec<Result<Ent, Error>> {
+    match format::split_version(root) {
+        (0, data) => match std::str::from_utf8(data) {
+            Ok(text) => text
+                .split("\0\0")
+                .filter_map(|row| row_entry(row).transpose())
+                .collect(),
+            Err(_) => vec![Err(Error::Msg("Root is not utf-8"))],
+        },
+        (1, mut data) => {
+            let mut ans = Vec::new();
+            while !data.is_empty() {
+                match record_entry(&mut data) {
+                    Ok(ent) => ans.push(Ok(ent)),
+                    Err(e) => {
+                        // We cannot find the start of the next entry
+                        ans.push(Err(e));
+                        break;
+                    }
+                }
+            }
+            ans
+        }
+        (version, mut data) if version >= 2 && version <= format::VERSION => {
+            let mut ans = Vec::new();
+            while !data.is_empty() {
+                match format::read_field(&mut data) {
+                    Ok(record) => ans.push(tagged_entry(record)),
+                    Err(e) => {
+                        ans.push(Err(e));
+                        break;
+                    }
+                }
+            }
+            ans
+        }
+        _ => vec![Err(Error::Msg("Unknown root format"))],
+    }
+}
+
+/// Decode an entry of a version 2 root from its record of tagged fields
+fn tagged_entry(mut record: &[u8]) -> Result<Ent, Error> {
+    let mut ent = Ent {
+        path: PathBuf::new(),
+        etype: EType::Root,
+        size: 0,
+        st_mode: 0,
+        uid: 0,
+        gid: 0,
+        mtime: 0,
+        mtime_nsec: 0,
+        btime: None,
+        chunks: Vec::new(),
+        target: PathBuf::new(),
+        rdev: 0,
+        xattrs: Vec::new(),
+    };
+    let mut has_path = false;
+    let mut has_type = false;
+    while !record.is_empty() {
+        let (tag, data) = format::read_tagged(&mut record)?;
+        match tag {
+            format::TAG_PATH => {
+                ent.path = PathBuf::from(OsStr::from_bytes(data));
+                has_path = true;
+            }
+            format::TAG_TYPE => {
+                ent.etype = EType::from_code(*data.first().ok_or(Error::Msg("Missing type"))?)?;
+                has_type = true;
+            }
+            format::TAG_SIZE => ent.size = format::to_u64(data)?,
+            format::TAG_CHUNKS => {
+                ent.chunks = std::str::from_utf8(data)
+                    .map_err(|_| Error::Msg("Chunks are not utf-8"))?
+                    .split(',')
+                    .map(std::string::ToString::to_string)
+                    .collect()
+            }
+            format::TAG_TARGET => ent.target = PathBuf::from(OsStr::from_bytes(data)),
+            format::TAG_MODE => ent.st_mode = format::to_u32(data)?,
+            format::TAG_UID => ent.uid = format::to_u32(data)?,
+            format::TAG_GID => ent.gid = format::to_u32(data)?,
+            format::TAG_MTIME => ent.mtime = format::to_i64(data)?,
+            format::TAG_MTIME_NSEC => ent.mtime_nsec = format::to_u32(data)?,
+            format::TAG_BTIME => ent.btime.get_or_insert((0, 0)).0 = format::to_i64(data)?,
+            format::TAG_BTIME_NSEC => ent.btime.get_or_insert((0, 0)).1 = format::to_u32(data)?,
+            format::TAG_RDEV => ent.rdev = format::to_u64(data)?,
+            format::TAG_XATTR => {
+                let mut data = data;
+                let name = format::read_field(&mut data)?;
+                let value = format::read_field(&mut data)?;
+                ent.xattrs.push((name.to_vec(), value.to_vec()));
+            }
+            // Fields we do not know are written by newer versions, skip them
+            _ => (),
+        }
+    }
+    if !has_path {
+        return Err(Error::Msg("Missing path"));
+    }
+    if !has_type {
+        return Err(Error::Msg("Missing type"));
+    }
+    Ok(ent)
 }
 
+/// Decode an entry of a version 1 root, advancing data past it
+fn record_entry(data: &mut &[u8]) -> Result<Ent, Error> {
+    let path = PathBuf::from(OsStr::from_bytes(format::read_field(data)?));
+    let etype: EType = format::read_text(data)?.parse()?;
+    let size: u64 = format::read_text(data)?.parse()?;
+    let content = format::read_field(data)?;
+    let st_mode: u32 = format::read_text(data)?.parse()?;
+    let uid: u32 = format::read_text(data)?.parse()?;
+    let gid: u32 = format::read_text(data)?.parse()?;
+    let mtime: i64 = format::read_text(data)?.parse()?;
+    let _ctime: i64 = format::read_text(data)?.parse()?;
+
+    let (chunks, target) = if etype == EType::Link {
+        (Vec::new(), PathBuf::from(OsStr::from_bytes(content)))
+    } else {
+        let content =
+            std::str::from_utf8(content).map_err(|_| Error::Msg("Chunks are not utf-8"))?;
+        (
+            content
+                .split(',')
+                .map(std::string::ToString::to_string)
+                .collect(),
+            PathBuf::new(),
+        )
+    };
+    Ok(Ent {
+        path,
+        etype,
+        size,
+        st_mode,
+        uid,
+        gid,
+        mtime,
+        mtime_nsec: 0,
+        btime: None,
+        chunks,
+        target,
+        rdev: 0,
+        xattrs: Vec::new(),
+    })
+}
+
+/// Decode an entry of a legacy root
 fn row_entry(row: &str) -> Result<Option<Ent>, Error> {
     if row.is_empty() {
         return Ok(None);
@@ -140,6 +311,17 @@ fn row_entry(row: &str) -> Result<Option<Ent>, Error> {
     let _ctime: i64 = ans.next().ok_or(Error::Msg("Missing ctime"))?.parse()?;
     let path = PathBuf::from_str(name).map_err(|_| Error::Msg("Bad path"))?;
 
+    let (chunks, target) = if etype == EType::Link {
+        (Vec::new(), PathBuf::from(reference))
+    } else {
+        (
+            reference
+                .split(',')
+                .map(std::string::ToString::to_string)
+                .collect(),
+            PathBuf::new(),
+        )
+    };
     Ok(Some(Ent {
         path,
         etype,
@@ -148,15 +330,37 @@ fn row_entry(row: &str) -> Result<Option<Ent>, Error> {
         uid,
         gid,
         mtime,
-        chunks: reference
-            .split(',')
-            .map(std::string::ToString::to_string)
-            .collect(),
+        mtime_nsec: 0,
+        btime: None,
+        chunks,
+        target,
+        rdev: 0,
+        xattrs: Vec::new(),
     }))
 }
 
+/// Write the content of a file entry to dpath
+fn restore_content(
+    pb: &mut Option<Progress>,
+    ent: &Ent,
+    dpath: &PathBuf,
+    client: &mut reqwest::Client,
+    config: &Config,
+    secrets: &Secrets,
+) -> Result<(), Error> {
+    let mut file = std::fs::File::create(dpath)?;
+    for chunk in ent.chunks.iter() {
+        let res = get_chunk(client, &config, &secrets, &chunk)?;
+        file.write_all(&res)?;
+        if let Some(pb) = pb {
+            pb.add(res.len() as u64);
+        }
+    }
+    Ok(())
+}
+
 fn recover_entry(
-    pb: &mut Option<ProgressBar<std::io::Stdout>>,
+    pb: &mut Option<Progress>,
     ent: &Ent,
     dry: bool,
     dest: &PathBuf,
@@ -176,6 +380,9 @@ fn recover_entry(
             .strip_prefix("/")
             .map_err(|_| Error::Msg("Path not absolute"))?,
     );
+    if dry {
+        info!("Would restore {} {:?}", ent.etype, dpath);
+    }
     match ent.etype {
         EType::Root => (),
         EType::Dir => {
@@ -190,7 +397,7 @@ fn recover_entry(
         EType::Link => {
             debug!("LINK {:?}", dpath);
             if !dry {
-                std::os::unix::fs::symlink(ent.chunks.first().unwrap(), &dpath)?;
+                std::os::unix::fs::symlink(&ent.target, &dpath)?;
             }
             if let Some(pb) = pb {
                 pb.add(ent.size);
@@ -199,18 +406,63 @@ fn recover_entry(
         EType::File => {
             debug!("FILE {:?}", dpath);
             if !dry {
-                let mut file = std::fs::File::create(&dpath)?;
-                for chunk in ent.chunks.iter() {
-                    let res = get_chunk(client, &config, &secrets, &chunk)?;
-                    file.write_all(&res)?;
-                    if let Some(pb) = pb {
-                        pb.add(res.len() as u64);
-                    }
-                }
+                restore_content(pb, ent, &dpath, client, config, secrets)?;
             } else if let Some(pb) = pb {
                 pb.add(ent.size);
             }
         }
+        EType::Hardlink => {
+            debug!("HARDLINK {:?}", dpath);
+            let dtarget = dest.join(
+                ent.target
+                    .strip_prefix("/")
+                    .map_err(|_| Error::Msg("Target not absolute"))?,
+            );
+            if dry {
+                if let Some(pb) = pb {
+                    pb.add(ent.size);
+                }
+            } else if dtarget.is_file() {
+                std::fs::hard_link(&dtarget, &dpath)?;
+                if let Some(pb) = pb {
+                    pb.add(ent.size);
+                }
+            } else {
+                // The target is not restored, so restore the content instead
+                restore_content(pb, ent, &dpath, client, config, secrets)?;
+            }
+        }
+        EType::Fifo => {
+            debug!("FIFO {:?}", dpath);
+            if !dry {
+                nix::unistd::mkfifo(&dpath, Mode::from_bits_truncate(ent.st_mode))?;
+            }
+        }
+        EType::Socket | EType::CharDevice | EType::BlockDevice => {
+            debug!("NODE {:?}", dpath);
+            let kind = match ent.etype {
+                EType::Socket => SFlag::S_IFSOCK,
+                EType::CharDevice => SFlag::S_IFCHR,
+                _ => SFlag::S_IFBLK,
+            };
+            if !dry {
+                let res = nix::sys::stat::mknod(
+                    &dpath,
+                    kind,
+                    Mode::from_bits_truncate(ent.st_mode),
+                    ent.rdev as libc::dev_t,
+                );
+                match res {
+                    Ok(()) => (),
+                    Err(e) if ent.etype == EType::Socket => {
+                        // A socket is useless without the process listening on it
+                        warn!("Unable to restore socket {:?}: {:?}", dpath, e);
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
     }
     if !dry && ent.etype != EType::Link {
         use std::os::unix::fs::PermissionsExt;
@@ -224,16 +476,43 @@ fn recover_entry(
                 nix::unistd::FchownatFlags::NoFollowSymlink,
             )?;
         }
-        nix::sys::stat::lutimes(
+        if config.xattrs {
+            restore_xattrs(&dpath, &ent.xattrs);
+        }
+        let mtime = nix::sys::time::TimeValLike::nanoseconds(
+            ent.mtime * 1_000_000_000 + i64::from(ent.mtime_nsec),
+        );
+        nix::sys::stat::utimensat(
+            None,
             &dpath,
-            &nix::sys::time::TimeValLike::seconds(ent.mtime),
-            &nix::sys::time::TimeValLike::seconds(ent.mtime),
+            &mtime,
+            &mtime,
+            nix::sys::stat::UtimensatFlags::NoFollowSymlink,
         )?;
     }
 
     Ok(())
 }
 
+/// Set the extended attributes of path, failures are only reported as warnings
+fn restore_xattrs(path: &PathBuf, xattrs: &[(Vec<u8>, Vec<u8>)]) {
+    for (name, value) in xattrs.iter() {
+        match xattr::set(path, OsStr::from_bytes(name), value) {
+            Ok(()) => (),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => {
+                warn!("Extended attributes are not supported for {:?}", path);
+                return;
+            }
+            Err(e) => warn!(
+                "Unable to set extended attribute {:?} of {:?}: {:?}",
+                OsStr::from_bytes(name),
+                path,
+                e
+            ),
+        }
+    }
+}
+
 pub struct Root<'l> {
     pub id: u64,
     pub host: &'l str,
@@ -313,7 +592,7 @@ pub fn roots<'a: 'b, 'b>(
     filter: Option<&'a str>,
 ) -> Result<Roots<'b>, Error> {
     let url = format!("{}/roots/{}", &config.server, hex::encode(&secrets.bucket));
-    let mut res = check_response(&mut || {
+    let mut res = check_response(&config.retry, &mut || {
         client
             .get(&url[..])
             .basic_auth(&config.user, Some(&config.password))
@@ -342,14 +621,7 @@ fn full_validate(
         bytes += ent.size;
     }
 
-    let mut pb = if config.verbosity >= log::LevelFilter::Info {
-        let mut pb = ProgressBar::new(bytes);
-        pb.set_units(pbr::Units::Bytes);
-        pb.set_max_refresh_rate(Some(Duration::from_millis(500)));
-        Some(pb)
-    } else {
-        None
-    };
+    let mut pb = Progress::new(config, bytes);
     let mut bad_files: usize = 0;
     for (hash, (idx, path)) in files.iter() {
         if let Some(pb) = &mut pb {
@@ -384,31 +656,67 @@ fn full_validate(
     }
 }
 
-fn partial_validate(
+/// Check that every chunk referenced by entries is on the server, without downloading them
+fn exists_validate(
     entries: &[Ent],
     client: &mut reqwest::Client,
     config: &Config,
     secrets: &Secrets,
 ) -> Result<bool, Error> {
-    info!("Fetching chunk list",);
-    let url = format!(
-        "{}/chunks/{}?validate=validate",
-        &config.server,
-        hex::encode(&secrets.bucket)
-    );
-    let content = check_response(&mut || {
-        client
-            .get(&url)
-            .basic_auth(&config.user, Some(&config.password))
-            .send()
-    })?
-    .text()?;
+    let mut files: HashMap<&str, (usize, &PathBuf)> = HashMap::new();
+    for ent in entries.iter() {
+        if ent.etype != EType::File {
+            continue;
+        }
+        // Packed chunks are checked once for their pack
+        for (idx, chunk) in ent.chunks.iter().enumerate() {
+            files.entry(chunk_object(chunk)).or_insert((idx, &ent.path));
+        }
+    }
 
-    let mut existing: HashMap<&str, (i64, i64)> = HashMap::new();
-    for row in content.split('\n') {
-        if row.is_empty() {
+    let mut missing: usize = 0;
+    for (hash, (idx, path)) in files.iter() {
+        if hash == &"empty" {
             continue;
         }
+        let url = format!(
+            "{}/chunks/{}/{}",
+            &config.server,
+            hex::encode(&secrets.bucket),
+            &hash
+        );
+        let res = retry(&config.retry, &mut || {
+            client
+                .head(&url[..])
+                .basic_auth(&config.user, Some(&config.password))
+                .send()
+        })?;
+        match res.status() {
+            reqwest::StatusCode::OK => (),
+            reqwest::StatusCode::NOT_FOUND => {
+                missing += 1;
+                error!("Missing file chunk {} at path {:?}:{}", hash, path, idx);
+            }
+            code => return Err(Error::HttpStatus(code)),
+        }
+    }
+    if missing != 0 {
+        error!("{} of {} file chunks are missing", missing, files.len());
+        Ok(false)
+    } else {
+        Ok(true)
+    }
+}
+
+fn partial_validate(
+    entries: &[Ent],
+    client: &mut reqwest::Client,
+    config: &Config,
+    secrets: &Secrets,
+) -> Result<bool, Error> {
+    info!("Fetching chunk list",);
+    let mut existing: HashMap<String, (i64, i64)> = HashMap::new();
+    list_chunks(client, config, secrets, true, |row| {
         let mut row = row.split(' ');
         let chunk = row.next().ok_or(Error::Msg("Missing churk"))?;
         let size: i64 = row.next().ok_or(Error::Msg("Missing size"))?.parse()?;
@@ -416,21 +724,23 @@ fn partial_validate(
             .next()
             .ok_or(Error::Msg("Missing content size"))?
             .parse()?;
-        existing.insert(chunk, (size, content_size));
-    }
+        existing.insert(chunk.to_string(), (size, content_size));
+        Ok(())
+    })?;
     let mut ok = true;
     info!("Checking entries");
     for ent in entries {
         if ent.etype != EType::File {
             continue;
         }
-        let mut ent_size: i64 = 0;
+        let mut min_size: i64 = 0;
         for chunk in &ent.chunks {
             let chunk: &str = &chunk;
             if chunk == "empty" {
                 continue;
             }
-            match existing.get(chunk) {
+            let chunk_ref = ChunkRef::parse(chunk)?;
+            match existing.get(chunk_ref.object) {
                 Some((size, content_size)) => {
                     if size != content_size {
                         error!(
@@ -439,7 +749,12 @@ fn partial_validate(
                         );
                         ok = false;
                     }
-                    ent_size += size - 12;
+                    // A packed chunk is only part of the object it is in
+                    let size = match chunk_ref.range {
+                        Some((_, length)) => length as i64,
+                        None => *size,
+                    };
+                    min_size += size - MAX_OVERHEAD as i64;
                 }
                 None => {
                     error!("Missing chunk {} of entry {:?}", chunk, ent.path);
@@ -447,10 +762,12 @@ fn partial_validate(
                 }
             };
         }
-        if ent.size as i64 != ent_size {
+        // Compressed chunks may be much smaller than their content,
+        // so we can only check that the chunks are not too large
+        if (ent.size as i64) < min_size {
             error!(
-                "Entry {:?}, should have size {} but had size {}",
-                ent.path, ent.size, ent_size
+                "Entry {:?}, should have size {} but had size at least {}",
+                ent.path, ent.size, min_size
             );
         }
     }
@@ -458,7 +775,7 @@ fn partial_validate(
 }
 
 pub fn disk_usage(config: Config, secrets: Secrets) -> Result<(), Error> {
-    let mut client = reqwest::Client::new();
+    let mut client = http_client(&config)?;
     let root_visit = roots(&config, &secrets, &client, None)?;
     let mut root_vec = Vec::new();
     for root in root_visit.iter() {
@@ -483,10 +800,9 @@ pub fn disk_usage(config: Config, secrets: Secrets) -> Result<(), Error> {
         let old_total_size = total_size;
         total_size += v.len() as u64;
 
-        for row in v.split("\0\0") {
-            match row_entry(row) {
-                Ok(None) => {}
-                Ok(Some(ent)) => {
+        for ent in root_entries(&v) {
+            match ent {
+                Ok(ent) => {
                     size += ent.size;
                     let mut remaining = ent.size;
                     for chunk in ent.chunks {
@@ -498,7 +814,7 @@ pub fn disk_usage(config: Config, secrets: Secrets) -> Result<(), Error> {
                     }
                 }
                 Err(e) => {
-                    error!("Bad row '{}`: {:?}", row, e);
+                    error!("Bad entry in root {}: {:?}", root.hash, e);
                 }
             }
         }
@@ -515,7 +831,7 @@ pub fn disk_usage(config: Config, secrets: Secrets) -> Result<(), Error> {
 }
 
 pub fn list_root(root: &str, config: Config, secrets: Secrets) -> Result<(), Error> {
-    let mut client = reqwest::Client::new();
+    let mut client = http_client(&config)?;
     info!("{:4} {:<70} {:>10}", "Type", "Path", "Size",);
     for root in roots(&config, &secrets, &client, Some(root))?.iter() {
         let root = root?;
@@ -526,21 +842,20 @@ pub fn list_root(root: &str, config: Config, secrets: Secrets) -> Result<(), Err
             }
             Ok(v) => v,
         };
-        for row in v.split("\0\0") {
-            match row_entry(row) {
-                Ok(None) => {}
-                Ok(Some(ent)) => {
+        for ent in root_entries(&v) {
+            match ent {
+                Ok(ent) => {
                     let etype = format!("{}", ent.etype);
                     let size = Size::from(ent.size);
                     info!(
                         "{:4} {:<70} {:>10}",
                         etype,
-                        ent.path.to_str().unwrap(),
+                        ent.path.to_string_lossy(),
                         size
                     );
                 }
                 Err(e) => {
-                    error!("Bad row '{}`: {:?}", row, e);
+                    error!("Bad entry in root {}: {:?}", root.hash, e);
                 }
             }
         }
@@ -548,14 +863,265 @@ pub fn list_root(root: &str, config: Config, secrets: Secrets) -> Result<(), Err
     Ok(())
 }
 
-fn find_entries<Handler: FnMut(Ent), Filter: for<'a> FnMut(&Root<'a>) -> Result<bool, Error>>(
+/// Fetch and decode the entries of the root with the given id, None if there is no such root
+fn get_entries(
+    client: &mut reqwest::Client,
+    config: &Config,
+    secrets: &Secrets,
+    root: &str,
+) -> Result<Option<Vec<Ent>>, Error> {
+    let hash = match roots(config, secrets, client, Some(root))?.iter().next() {
+        Some(r) => r?.hash.to_string(),
+        None => return Ok(None),
+    };
+    let v = get_root(client, config, secrets, &hash)?;
+    let mut ents = Vec::new();
+    for ent in root_entries(&v) {
+        ents.push(ent?);
+    }
+    Ok(Some(ents))
+}
+
+/// The ls -l style mode of an entry, like drwxr-xr-x
+fn mode_string(etype: &EType, mode: u32) -> String {
+    let mut ans = String::with_capacity(10);
+    ans.push(match etype {
+        EType::Dir | EType::Root => 'd',
+        EType::Link => 'l',
+        EType::Fifo => 'p',
+        EType::Socket => 's',
+        EType::CharDevice => 'c',
+        EType::BlockDevice => 'b',
+        EType::File | EType::Hardlink => '-',
+    });
+    for (bit, c) in [
+        (0o400, 'r'),
+        (0o200, 'w'),
+        (0o100, 'x'),
+        (0o040, 'r'),
+        (0o020, 'w'),
+        (0o010, 'x'),
+        (0o004, 'r'),
+        (0o002, 'w'),
+        (0o001, 'x'),
+    ]
+    .iter()
+    {
+        ans.push(if mode & bit != 0 { *c } else { '-' });
+    }
+    ans
+}
+
+/// An entry as listed by run_list with json output
+#[derive(Serialize)]
+struct ListEntry {
+    path: String,
+    #[serde(rename = "type")]
+    etype: String,
+    size: u64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    mtime: i64,
+    mtime_nsec: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    btime: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    btime_nsec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+}
+
+/// List the entries of a root below path, like ls -l
+///
+/// Only the root itself is fetched, since it holds all the entries. Unless recursive is
+/// given only the entries directly in path are listed, entries whose folder is not in
+/// the root, such as the backup dirs, count as directly in path
+pub fn run_list(
+    config: Config,
+    secrets: Secrets,
+    root: &str,
+    path: &std::path::Path,
+    recursive: bool,
+    json: bool,
+) -> Result<bool, Error> {
+    let mut client = http_client(&config)?;
+    let mut ents = match get_entries(&mut client, &config, &secrets, root)? {
+        Some(ents) => ents,
+        None => {
+            error!("Could not find root {}", root);
+            return Ok(false);
+        }
+    };
+    ents.retain(|ent| ent.path.starts_with(path) && ent.path != path);
+
+    if !recursive {
+        let dirs: HashSet<PathBuf> = ents
+            .iter()
+            .filter(|ent| ent.etype == EType::Dir)
+            .map(|ent| ent.path.clone())
+            .collect();
+        ents.retain(|ent| match ent.path.parent() {
+            Some(parent) => parent == path || !dirs.contains(parent),
+            None => true,
+        });
+    }
+    ents.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if json {
+        let list: Vec<ListEntry> = ents
+            .iter()
+            .map(|ent| ListEntry {
+                path: ent.path.to_string_lossy().into_owned(),
+                etype: ent.etype.to_string(),
+                size: ent.size,
+                mode: ent.st_mode,
+                uid: ent.uid,
+                gid: ent.gid,
+                mtime: ent.mtime,
+                mtime_nsec: ent.mtime_nsec,
+                btime: ent.btime.map(|(btime, _)| btime),
+                btime_nsec: ent.btime.map(|(_, btime_nsec)| btime_nsec),
+                target: match ent.etype {
+                    EType::Link | EType::Hardlink => {
+                        Some(ent.target.to_string_lossy().into_owned())
+                    }
+                    _ => None,
+                },
+            })
+            .collect();
+        writeln!(out, "{}", serde_json::to_string(&list).unwrap())?;
+        return Ok(true);
+    }
+    for ent in ents.iter() {
+        write!(
+            out,
+            "{} {:>6} {:>6} {:>12} {} ",
+            mode_string(&ent.etype, ent.st_mode),
+            ent.uid,
+            ent.gid,
+            ent.size,
+            NaiveDateTime::from_timestamp(ent.mtime, 0),
+        )?;
+        out.write_all(ent.path.as_os_str().as_bytes())?;
+        if ent.etype == EType::Link || ent.etype == EType::Hardlink {
+            out.write_all(b" -> ")?;
+            out.write_all(ent.target.as_os_str().as_bytes())?;
+        }
+        out.write_all(b"\n")?;
+    }
+    Ok(true)
+}
+
+/// A change between two roots as output by run_diff with json output
+#[derive(Serialize)]
+struct DiffEntry {
+    change: &'static str,
+    path: String,
+}
+
+/// Check if an entry has changed between two roots, by its content and its metadata
+fn entry_changed(a: &Ent, b: &Ent) -> bool {
+    a.etype != b.etype
+        || a.size != b.size
+        || a.chunks != b.chunks
+        || a.target != b.target
+        || a.st_mode != b.st_mode
+        || a.uid != b.uid
+        || a.gid != b.gid
+        || a.mtime != b.mtime
+        || a.mtime_nsec != b.mtime_nsec
+        // Roots that did not record the birth time say nothing about it
+        || (a.btime.is_some() && b.btime.is_some() && a.btime != b.btime)
+        || a.rdev != b.rdev
+        || a.xattrs != b.xattrs
+}
+
+/// Print the entries added (+), removed (-) and modified (~) from the root old to the root new
+///
+/// Only the roots are fetched, files are compared by their chunks and metadata.
+/// A renamed file shows up as removed and added
+pub fn run_diff(
+    config: Config,
+    secrets: Secrets,
+    old: &str,
+    new: &str,
+    json: bool,
+) -> Result<bool, Error> {
+    let mut client = http_client(&config)?;
+    let mut ents = Vec::new();
+    for root in [old, new].iter() {
+        match get_entries(&mut client, &config, &secrets, root)? {
+            Some(v) => ents.push(v),
+            None => {
+                error!("Could not find root {}", root);
+                return Ok(false);
+            }
+        }
+    }
+    let new_ents = ents.pop().unwrap();
+    let old_ents: HashMap<PathBuf, Ent> = ents
+        .pop()
+        .unwrap()
+        .into_iter()
+        .map(|ent| (ent.path.clone(), ent))
+        .collect();
+
+    let mut changes: Vec<(&'static str, PathBuf)> = Vec::new();
+    let mut seen = HashSet::new();
+    for ent in new_ents.iter() {
+        seen.insert(&ent.path);
+        match old_ents.get(&ent.path) {
+            None => changes.push(("+", ent.path.clone())),
+            Some(old) if entry_changed(old, ent) => changes.push(("~", ent.path.clone())),
+            Some(_) => (),
+        }
+    }
+    for path in old_ents.keys() {
+        if !seen.contains(path) {
+            changes.push(("-", path.clone()));
+        }
+    }
+    changes.sort_by(|a, b| a.1.cmp(&b.1));
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    if json {
+        let list: Vec<DiffEntry> = changes
+            .iter()
+            .map(|(change, path)| DiffEntry {
+                change: match *change {
+                    "+" => "added",
+                    "-" => "removed",
+                    _ => "modified",
+                },
+                path: path.to_string_lossy().into_owned(),
+            })
+            .collect();
+        writeln!(out, "{}", serde_json::to_string(&list).unwrap())?;
+        return Ok(true);
+    }
+    for (change, path) in changes.iter() {
+        write!(out, "{} ", change)?;
+        out.write_all(path.as_os_str().as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+    Ok(true)
+}
+
+pub fn find_entries<
+    Handler: FnMut(Ent),
+    Filter: for<'a> FnMut(&Root<'a>) -> Result<bool, Error>,
+>(
     config: &Config,
     secrets: &Secrets,
     only_root: Option<&str>,
     mut filter_root: Filter,
     mut handle_entry: Handler,
 ) -> Result<(bool, bool), Error> {
-    let mut client = reqwest::Client::new();
+    let mut client = http_client(&config)?;
     let mut root_found = false;
     let mut ok = true;
     let x = roots(&config, &secrets, &client, only_root)?;
@@ -588,18 +1154,22 @@ fn find_entries<Handler: FnMut(Ent), Filter: for<'a> FnMut(&Root<'a>) -> Result<
             uid: 0,
             gid: 0,
             mtime: 0,
+            mtime_nsec: 0,
+            btime: None,
             chunks: vec![root.hash.to_string()],
+            target: PathBuf::new(),
+            rdev: 0,
+            xattrs: Vec::new(),
         });
 
-        for row in v.split("\0\0") {
-            match row_entry(row) {
-                Ok(None) => {}
-                Ok(Some(ent)) => {
+        for ent in root_entries(&v) {
+            match ent {
+                Ok(ent) => {
                     handle_entry(ent);
                 }
                 Err(e) => {
                     ok = false;
-                    error!("Bad row '{}`: {:?}", row, e);
+                    error!("Bad entry in root {}: {:?}", root.hash, e);
                 }
             }
         }
@@ -608,7 +1178,7 @@ fn find_entries<Handler: FnMut(Ent), Filter: for<'a> FnMut(&Root<'a>) -> Result<
 }
 
 pub fn run_validate(config: Config, secrets: Secrets, full: bool) -> Result<bool, Error> {
-    let mut client = reqwest::Client::new();
+    let mut client = http_client(&config)?;
 
     let mut entries: Vec<Ent> = Vec::new();
 
@@ -630,6 +1200,37 @@ pub fn run_validate(config: Config, secrets: Secrets, full: bool) -> Result<bool
     Ok(ok)
 }
 
+/// Check that everything needed to restore a root is on the server
+///
+/// If deep is true all chunks are downloaded and their content checked
+pub fn run_verify(config: Config, secrets: Secrets, root: &str, deep: bool) -> Result<bool, Error> {
+    let mut client = http_client(&config)?;
+
+    let mut entries: Vec<Ent> = Vec::new();
+    let (root_found, ok) = find_entries(
+        &config,
+        &secrets,
+        Some(root),
+        |_| Ok(true),
+        |ent| {
+            entries.push(ent);
+        },
+    )?;
+    if !root_found {
+        return Err(Error::Msg("Root not found"));
+    }
+
+    let valid = if deep {
+        full_validate(&entries, &mut client, &config, &secrets)?
+    } else {
+        exists_validate(&entries, &mut client, &config, &secrets)?
+    };
+    if ok && valid {
+        info!("Root {} is restorable", root);
+    }
+    Ok(ok && valid)
+}
+
 pub fn run_restore(
     config: Config,
     secrets: Secrets,
@@ -640,16 +1241,20 @@ pub fn run_restore(
     pattern: PathBuf,
 ) -> Result<bool, Error> {
     let mut entries: Vec<Ent> = Vec::new();
+    let mut path_found = pattern.as_os_str().is_empty();
 
+    // Only the content of the entries below pattern is downloaded, the folders above it are
+    // restored so that it has somewhere to go
     let (root_found, ok) = find_entries(
         &config,
         &secrets,
         Some(root.as_ref()),
         |_| Ok(true),
         |ent| {
-            if ent.path.starts_with(&pattern)
-                || (pattern.starts_with(&ent.path) && ent.etype == EType::Dir)
-            {
+            if ent.path.starts_with(&pattern) {
+                path_found |= ent.etype != EType::Root;
+                entries.push(ent);
+            } else if pattern.starts_with(&ent.path) && ent.etype == EType::Dir {
                 entries.push(ent);
             }
         },
@@ -658,17 +1263,14 @@ pub fn run_restore(
     if !root_found {
         return Err(Error::Msg("Root not found"));
     }
+    if !path_found {
+        error!("No entry {:?} in root {}", pattern, root);
+        return Err(Error::Msg("Path not found"));
+    }
     let bytes = entries.iter().map(|e| e.size).sum();
-    let mut pb = if config.verbosity >= log::LevelFilter::Info {
-        let mut pb = ProgressBar::new(bytes);
-        pb.set_max_refresh_rate(Some(Duration::from_millis(500)));
-        pb.set_units(pbr::Units::Bytes);
-        Some(pb)
-    } else {
-        None
-    };
+    let mut pb = Progress::new(&config, bytes);
 
-    let mut client = reqwest::Client::new();
+    let mut client = http_client(&config)?;
 
     for ent in entries {
         if let Err(e) = recover_entry(
@@ -685,6 +1287,9 @@ pub fn run_restore(
             return Err(e);
         }
     }
+    if let Some(pb) = &mut pb {
+        pb.finish();
+    }
     Ok(ok)
 }
 
@@ -718,8 +1323,14 @@ pub fn run_cat(
         Some(ent) => ent,
     };
     match ent.etype {
-        EType::File => {}
-        EType::Root | EType::Dir | EType::Link => {
+        EType::File | EType::Hardlink => {}
+        EType::Root
+        | EType::Dir
+        | EType::Link
+        | EType::Fifo
+        | EType::Socket
+        | EType::CharDevice
+        | EType::BlockDevice => {
             panic!("Expected file but got {}", ent.etype);
             //return Err(Error::Msg("Expected file but got some other type"));
         }
@@ -727,7 +1338,7 @@ pub fn run_cat(
     let stdout = std::io::stdout();
     let mut handle = stdout.lock();
 
-    let mut client = reqwest::Client::new();
+    let mut client = http_client(&config)?;
 
     for chunk in ent.chunks.iter() {
         let res = get_chunk(&mut client, &config, &secrets, &chunk)?;
@@ -736,6 +1347,54 @@ pub fn run_cat(
     Ok(ok)
 }
 
+/// Find the ids of the roots kept by a retention policy
+///
+/// roots are given as (id, host, time), the policy is applied to the roots of each host separately
+fn retained_roots(policy: &Retention, roots: &[(u64, &str, i64)]) -> HashSet<u64> {
+    let mut by_host: HashMap<&str, Vec<(u64, i64)>> = HashMap::new();
+    for (id, host, time) in roots.iter() {
+        by_host.entry(*host).or_default().push((*id, *time));
+    }
+
+    let mut keep = HashSet::new();
+    for roots in by_host.values_mut() {
+        // Newest first
+        roots.sort_by(|a, b| b.1.cmp(&a.1).then(b.0.cmp(&a.0)));
+        for (id, _) in roots.iter().take(policy.keep_last as usize) {
+            keep.insert(*id);
+        }
+
+        let day = |time: i64| time.div_euclid(60 * 60 * 24);
+        // Days since the epoch, shifted so weeks start on mondays
+        let week = |time: i64| (day(time) + 3).div_euclid(7);
+        let month = |time: i64| {
+            let date = NaiveDateTime::from_timestamp(time, 0);
+            i64::from(date.year()) * 12 + i64::from(date.month0())
+        };
+        let periods: [(u32, &dyn Fn(i64) -> i64); 3] = [
+            (policy.keep_daily, &day),
+            (policy.keep_weekly, &week),
+            (policy.keep_monthly, &month),
+        ];
+        for (count, period) in periods.iter() {
+            let mut last = None;
+            let mut kept = 0;
+            for (id, time) in roots.iter() {
+                if kept == *count {
+                    break;
+                }
+                let p = period(*time);
+                if last != Some(p) {
+                    keep.insert(*id);
+                    last = Some(p);
+                    kept += 1;
+                }
+            }
+        }
+    }
+    keep
+}
+
 pub fn run_prune(
     config: Config,
     secrets: Secrets,
@@ -746,50 +1405,74 @@ pub fn run_prune(
         .duration_since(SystemTime::UNIX_EPOCH)?
         .as_secs() as i64;
 
-    let client = reqwest::Client::new();
+    let client = http_client(&config)?;
 
     let mut used: HashSet<String> = HashSet::new();
 
+    // The gc is refused if a root was stored in the second it starts in or later, so a root
+    // stored by a backup that just finished must be in an earlier second
+    if !dry {
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+
     info!("Fetching chunk list");
-    let url = format!("{}/chunks/{}", &config.server, hex::encode(&secrets.bucket));
-    let content = check_response(&mut || {
-        client
-            .get(&url[..])
-            .basic_auth(&config.user, Some(&config.password))
-            .send()
+    let mut chunks: Vec<(String, u64)> = Vec::new();
+    // Chunks uploaded after this point may belong to a backup in progress,
+    // and must not be collected. Use the server clock to decide.
+    let start = list_chunks(&client, &config, &secrets, false, |row| {
+        let mut row = row.split(' ');
+        let chunk = row.next().ok_or(Error::Msg("Missing churk"))?;
+        let size: u64 = row.next().ok_or(Error::Msg("Missing size"))?.parse()?;
+        chunks.push((chunk.to_string(), size));
+        Ok(())
     })?
-    .text()?;
+    .ok_or(Error::Msg("Missing server date"))?;
+
+    let keep = if config.retention.is_empty() {
+        None
+    } else {
+        let all = roots(&config, &secrets, &client, None)?;
+        let all = all
+            .iter()
+            .map(|root| root.map(|root| (root.id, root.host, root.time)))
+            .collect::<Result<Vec<_>, Error>>()?;
+        Some(retained_roots(&config.retention, &all))
+    };
 
     let (_, ok) = find_entries(
         &config,
         &secrets,
         None,
         |root| {
-            if let Some(age) = age {
-                if root.time + 60 * 60 * 24 * i64::from(age) < now {
-                    info!(
-                        "Removing root {} {}",
-                        root.host,
-                        NaiveDateTime::from_timestamp(root.time, 0)
+            let expired = match age {
+                Some(age) => root.time + 60 * 60 * 24 * i64::from(age) < now,
+                None => false,
+            };
+            let unretained = match &keep {
+                Some(keep) => !keep.contains(&root.id),
+                None => false,
+            };
+            if expired || unretained {
+                info!(
+                    "Removing root {} {}",
+                    root.host,
+                    NaiveDateTime::from_timestamp(root.time, 0)
+                );
+                if !dry {
+                    let url = format!(
+                        "{}/roots/{}/{}",
+                        &config.server,
+                        hex::encode(&secrets.bucket),
+                        root.id
                     );
-                    if !dry {
-                        let url = format!(
-                            "{}/roots/{}/{}",
-                            &config.server,
-                            hex::encode(&secrets.bucket),
-                            root.id
-                        );
-                        check_response(&mut || {
-                            client
-                                .delete(&url[..])
-                                .basic_auth(&config.user, Some(&config.password))
-                                .send()
-                        })?;
-                    }
-                    Ok(false)
-                } else {
-                    Ok(true)
+                    check_response(&config.retry, &mut || {
+                        client
+                            .delete(&url[..])
+                            .basic_auth(&config.user, Some(&config.password))
+                            .send()
+                    })?;
                 }
+                Ok(false)
             } else {
                 Ok(true)
             }
@@ -799,82 +1482,69 @@ pub fn run_prune(
                 return;
             }
             for chunk in ent.chunks.iter() {
-                used.insert(chunk.to_owned());
+                used.insert(chunk_object(chunk).to_owned());
             }
         },
     )?;
 
     let mut total = 0;
     let mut removed_size = 0;
-    let mut remove = Vec::new();
-    for row in content.split('\n') {
-        if row.is_empty() {
-            continue;
-        }
-        let mut row = row.split(' ');
-        let chunk = row.next().ok_or(Error::Msg("Missing churk"))?;
-        let size: u64 = row.next().ok_or(Error::Msg("Missing size"))?.parse()?;
+    let mut remove_count = 0;
+    for (chunk, size) in chunks.iter() {
         total += 1;
         if used.contains(chunk) {
             continue;
         }
         removed_size += size;
-        remove.push((chunk, size));
+        remove_count += 1;
     }
 
-    info!("Removing {} of {} chunks", remove.len(), total);
+    info!(
+        "Removing {} of {} chunks, {}",
+        remove_count,
+        total,
+        Size::from(removed_size)
+    );
     if dry {
         return Ok(ok);
     }
+    // The chunks of a root that could not be read are not marked, and would be removed
+    if !ok {
+        error!("Not removing any chunks, as some roots could not be read");
+        return Err(Error::Msg("Bad roots"));
+    }
 
-    let mut pb = if config.verbosity >= log::LevelFilter::Info {
-        let mut pb = ProgressBar::new(removed_size);
-        pb.set_max_refresh_rate(Some(Duration::from_millis(500)));
-        pb.set_units(pbr::Units::Bytes);
-        Some(pb)
-    } else {
-        None
-    };
-
-    use itertools::Itertools;
-
-    for group in &remove.iter().enumerate().chunks(2048) {
-        let mut data = String::new();
-
-        let mut last_idx = 0;
-        let mut sum_size = 0;
-        for (idx, (chunk, size)) in group {
-            last_idx = idx;
-            sum_size += size;
-            if !data.is_empty() {
-                data.push('\0');
-            }
-            data.push_str(chunk);
+    let mut data = String::new();
+    for chunk in used.iter() {
+        if chunk == "empty" {
+            continue;
         }
-        if let Some(pb) = &mut pb {
-            pb.message(&format!("Chunk {} / {}: ", last_idx, remove.len()));
-        }
-        let url = format!("{}/chunks/{}", &config.server, hex::encode(&secrets.bucket));
-
-        match check_response(&mut || {
-            client
-                .delete(&url[..])
-                .basic_auth(&config.user, Some(&config.password))
-                .body(data.clone())
-                .send()
-        }) {
-            Ok(_) => (),
-            Err(Error::HttpStatus(reqwest::StatusCode::NOT_FOUND)) => (),
-            Err(e) => Err(e)?,
-        };
-
-        if let Some(pb) = &mut pb {
-            pb.add(sum_size);
+        if !data.is_empty() {
+            data.push('\0');
         }
+        data.push_str(chunk);
     }
-
-    if let Some(pb) = &mut pb {
-        pb.finish();
-    }
+    let url = format!(
+        "{}/gc/{}?start={}",
+        &config.server,
+        hex::encode(&secrets.bucket),
+        start
+    );
+    let summary = check_response(&config.retry, &mut || {
+        client
+            .post(&url[..])
+            .basic_auth(&config.user, Some(&config.password))
+            .body(data.clone())
+            .send()
+    })?
+    .text()?;
+    let mut summary = summary.split(' ');
+    let removed_count: u64 = summary.next().ok_or(Error::Msg("Missing count"))?.parse()?;
+    let removed_bytes: u64 = summary.next().ok_or(Error::Msg("Missing size"))?.parse()?;
+    info!(
+        "Removed {} chunks, {} bytes",
+        removed_count,
+        Size::from(removed_bytes)
+    );
     Ok(ok)
 }
@@ -1,8 +1,15 @@
-use clap::{App, Arg};
+use clap::{App, Arg, SubCommand};
 use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::BufRead;
+use subtle::ConstantTimeEq;
 
-/// Chunks smaller that this goes into the sqlite database instead of directly on disk
-pub const SMALL_SIZE: usize = 1024 * 128;
+/// The largest small_size allowed, content this large is buffered in memory and stored as a blob,
+/// well below the 1GB limit on the size of sqlite blobs
+const MAX_SMALL_SIZE: usize = 64 * 1024 * 1024;
+
+/// The largest shard_depth allowed, the hash of a chunk is 64 hex digits
+const MAX_SHARD_DEPTH: usize = 4;
 
 /// The access level required, Put is the minimal, Delete is the maximal
 #[derive(Deserialize, PartialEq, Debug)]
@@ -32,8 +39,100 @@ impl std::cmp::PartialOrd for AccessType {
 #[serde(deny_unknown_fields)]
 pub struct User {
     pub name: String,
-    pub password: String,
+    /// Plain text password, deprecated in favour of password_hash
+    pub password: Option<String>,
+    /// Argon2 hash of the password as produced by the hash-password command
+    pub password_hash: Option<String>,
     pub access_level: AccessType,
+    /// Bearer tokens that authenticate as the user
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+    /// Tls client certificates that authenticate as the user
+    #[serde(default)]
+    pub certs: Vec<ClientCert>,
+}
+
+/// A bearer token as defined in the config file
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Token {
+    pub token: String,
+    /// The access granted by the token, independently of the access level of the user
+    pub access_level: AccessType,
+}
+
+/// A tls client certificate as defined in the config file
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ClientCert {
+    /// The hex encoded Blake2b-256 of the DER encoding of the certificate
+    pub fingerprint: String,
+    /// The access granted by the certificate, independently of the access level of the user
+    pub access_level: AccessType,
+}
+
+impl User {
+    /// Check if password is the password of the user
+    pub fn verify_password(&self, password: &[u8]) -> bool {
+        if let Some(hash) = &self.password_hash {
+            return argon2::verify_encoded(hash, password).unwrap_or(false);
+        }
+        match &self.password {
+            Some(expected) => bool::from(expected.as_bytes().ct_eq(password)),
+            None => false,
+        }
+    }
+}
+
+/// Produce an argon2id hash of a password with a random salt
+pub fn hash_password(password: &str) -> String {
+    let salt: [u8; 16] = rand::random();
+    let config = argon2::Config {
+        variant: argon2::Variant::Argon2id,
+        ..Default::default()
+    };
+    argon2::hash_encoded(password.as_bytes(), &salt, &config).expect("Unable to hash password")
+}
+
+/// Where the content of chunks not stored in the database goes
+#[derive(Deserialize, PartialEq, Debug)]
+#[serde(tag = "backend", deny_unknown_fields)]
+pub enum Storage {
+    /// Store chunks in files below data_dir
+    Local,
+    /// Store chunks as objects in an S3 bucket
+    S3 {
+        bucket: String,
+        region: String,
+        endpoint: Option<String>,
+        #[serde(default)]
+        prefix: String,
+    },
+    /// Keep chunks and the database in memory, everything is lost when the server stops
+    Memory,
+}
+
+/// Where the server listens for connections
+#[derive(PartialEq, Debug)]
+pub enum Listen {
+    /// An ip address and port, port 0 picks a free port
+    Tcp(std::net::SocketAddr),
+    /// The path of a unix domain socket
+    Unix(String),
+}
+
+impl Listen {
+    /// Parse a bind address, ip:port, [ipv6]:port or unix:/path/to/socket
+    pub fn parse(bind: &str) -> Option<Listen> {
+        if bind.starts_with("unix:") {
+            let path = &bind["unix:".len()..];
+            if path.is_empty() {
+                return None;
+            }
+            return Some(Listen::Unix(path.to_string()));
+        }
+        bind.parse().ok().map(Listen::Tcp)
+    }
 }
 
 /// The log level as defined in the config file
@@ -50,15 +149,73 @@ pub enum LevelFilterDef {
     Trace,
 }
 
+/// The format of log lines
+#[derive(Deserialize, PartialEq, Debug)]
+pub enum LogFormat {
+    /// Human readable text
+    Plain,
+    /// A json object per line, for log ingestion
+    Json,
+}
+
 /// The main configuration structure
 #[derive(Deserialize, PartialEq, Debug)]
 #[serde(default, deny_unknown_fields)]
 pub struct Config {
     #[serde(with = "LevelFilterDef")]
     pub verbosity: log::LevelFilter,
+    pub log_format: LogFormat,
+    /// Where to listen, ip:port, [ipv6]:port or unix:/path/to/socket, see Listen
     pub bind: String,
     pub data_dir: String,
     pub users: Vec<User>,
+    /// Path to a PEM encoded certificate chain, if set the server speaks https
+    pub tls_cert: Option<String>,
+    /// Path to the PEM encoded private key of tls_cert
+    pub tls_key: Option<String>,
+    /// Path to PEM encoded certificates of the authorities signing client certificates,
+    /// if set clients may authenticate with a certificate
+    pub tls_client_ca: Option<String>,
+    /// Refuse tls connections from clients without a certificate signed by tls_client_ca
+    pub tls_client_required: bool,
+    pub storage: Storage,
+    /// The maximal number of concurrent database readers
+    pub read_connections: u32,
+    /// Chunks smaller that this goes into the sqlite database instead of directly on disk,
+    /// changing it only affects where new chunks are stored
+    pub small_size: usize,
+    /// The small_size of specific buckets, overriding small_size
+    pub bucket_small_sizes: HashMap<String, usize>,
+    /// Temporary files of uploads not modified for this many seconds are removed
+    pub upload_max_age: u64,
+    /// How often in seconds to look for stale temporary files, 0 to only do it at startup
+    pub upload_sweep_interval: u64,
+    /// The maximal number of bytes stored in a bucket, unlimited if not set
+    pub quota: Option<u64>,
+    /// The quota of specific buckets, overriding quota
+    pub bucket_quotas: HashMap<String, u64>,
+    /// Compress the content of chunks with zstd before storing it, when that makes it smaller
+    pub compress_chunks: bool,
+    /// The number of requests per second allowed for each user, or address if not authenticated,
+    /// 0 for no limit
+    pub rate_limit: f64,
+    /// The number of requests allowed in a burst above rate_limit
+    pub rate_limit_burst: u32,
+    /// Refuse requests that change anything, for replicas serving restores
+    pub read_only: bool,
+    /// The number of folder levels chunks are spread over in local storage, each level named by
+    /// the next two hex digits of the chunk. Run reshard after changing it
+    pub shard_depth: usize,
+    /// The origins of web pages allowed to make requests from a browser, "*" for any, but
+    /// without credentials. Requests must still authenticate
+    pub cors_origins: Vec<String>,
+    /// The methods allowed in requests from the cors_origins
+    pub cors_methods: Vec<String>,
+    /// The headers allowed in requests from the cors_origins
+    pub cors_headers: Vec<String>,
+    /// Allow users with Delete access to VACUUM the database with POST /maintenance,
+    /// which blocks all other writes while it runs
+    pub allow_maintenance: bool,
 }
 
 /// Provide default values for the configuration
@@ -66,14 +223,59 @@ impl Default for Config {
     fn default() -> Config {
         Config {
             verbosity: log::LevelFilter::Info,
+            log_format: LogFormat::Plain,
             bind: "0.0.0.0:3321".to_string(),
             data_dir: ".".to_string(),
             users: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            tls_client_required: false,
+            storage: Storage::Local,
+            read_connections: 8,
+            small_size: 1024 * 128,
+            bucket_small_sizes: HashMap::new(),
+            upload_max_age: 24 * 60 * 60,
+            upload_sweep_interval: 60 * 60,
+            quota: None,
+            bucket_quotas: HashMap::new(),
+            compress_chunks: false,
+            rate_limit: 0.0,
+            rate_limit_burst: 100,
+            read_only: false,
+            shard_depth: 1,
+            cors_origins: Vec::new(),
+            cors_methods: ["GET", "PUT", "POST", "DELETE"]
+                .iter()
+                .map(|v| v.to_string())
+                .collect(),
+            cors_headers: [
+                "Authorization",
+                "Content-Type",
+                "Range",
+                "If-None-Match",
+                "X-Content-Blake2b",
+                "X-Root-Tags",
+            ]
+            .iter()
+            .map(|v| v.to_string())
+            .collect(),
+            allow_maintenance: false,
         }
     }
 }
 
-pub fn parse_config() -> Config {
+/// What the server was asked to do
+pub enum Command {
+    /// Serve requests until stopped
+    Serve,
+    /// Check the chunks in the chunk store, of a single bucket if given
+    Scrub { bucket: Option<String> },
+    /// Move the chunks of local storage to the folders of shard_depth
+    Reshard,
+}
+
+pub fn parse_config() -> (Config, Command) {
     let matches = App::new("mbackup server")
         .version("0.1")
         .about("A server for mbackup")
@@ -91,7 +293,7 @@ pub fn parse_config() -> Config {
                 .short("b")
                 .long("bind")
                 .takes_value(true)
-                .help("The interface/port to bind to"),
+                .help("The ip:port, [ipv6]:port or unix:/path/to/socket to listen on"),
         )
         .arg(
             Arg::with_name("data_dir")
@@ -106,8 +308,46 @@ pub fn parse_config() -> Config {
                 .takes_value(true)
                 .help("Path to config file"),
         )
+        .subcommand(
+            SubCommand::with_name("hash-password")
+                .about("Print the hash of a password for use as password_hash in the config")
+                .arg(
+                    Arg::with_name("password")
+                        .help("The password to hash, read from stdin if not given"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scrub")
+                .about("Check that the chunks not stored in the database are in the chunk store")
+                .arg(
+                    Arg::with_name("bucket")
+                        .long("bucket")
+                        .takes_value(true)
+                        .help("Only check the chunks of this bucket"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reshard")
+                .about("Move the chunks in data_dir to the folders of shard_depth"),
+        )
         .get_matches();
 
+    if let Some(m) = matches.subcommand_matches("hash-password") {
+        let password = match m.value_of("password") {
+            Some(password) => password.to_string(),
+            None => {
+                let mut line = String::new();
+                if let Err(e) = std::io::stdin().lock().read_line(&mut line) {
+                    error!("Unable to read password: {:?}", e);
+                    std::process::exit(1)
+                }
+                line.trim_end_matches(&['\r', '\n'][..]).to_string()
+            }
+        };
+        println!("{}", hash_password(&password));
+        std::process::exit(0)
+    }
+
     let mut config: Config = match matches.value_of("config") {
         Some(path) => {
             let data = match std::fs::read_to_string(path) {
@@ -139,6 +379,16 @@ pub fn parse_config() -> Config {
         None => (),
     }
 
+    match std::env::var("MBACKUP_LOG_FORMAT")
+        .as_ref()
+        .map(String::as_str)
+    {
+        Ok("plain") => config.log_format = LogFormat::Plain,
+        Ok("json") => config.log_format = LogFormat::Json,
+        Ok(v) => panic!("Unknown log format {}", v),
+        Err(_) => (),
+    }
+
     if let Some(bind) = matches.value_of("bind") {
         config.bind = bind.to_string();
     }
@@ -146,5 +396,91 @@ pub fn parse_config() -> Config {
         config.data_dir = dir.to_string();
     }
 
-    config
+    for user in config.users.iter() {
+        match (&user.password, &user.password_hash) {
+            (Some(_), None) => warn!(
+                "User {} has a plain text password, this is deprecated, use password_hash instead",
+                user.name
+            ),
+            (None, Some(_)) => (),
+            _ => {
+                error!(
+                    "Exactly one of password and password_hash must be given for user {}",
+                    user.name
+                );
+                std::process::exit(1)
+            }
+        }
+        if user.tokens.iter().any(|t| t.token.is_empty()) {
+            error!("Empty token given for user {}", user.name);
+            std::process::exit(1)
+        }
+    }
+
+    if config.small_size > MAX_SMALL_SIZE {
+        error!("small_size must be at most {}", MAX_SMALL_SIZE);
+        std::process::exit(1)
+    }
+    for (bucket, small_size) in config.bucket_small_sizes.iter() {
+        if *small_size > MAX_SMALL_SIZE {
+            error!(
+                "The small_size of bucket {} must be at most {}",
+                bucket, MAX_SMALL_SIZE
+            );
+            std::process::exit(1)
+        }
+    }
+
+    match Listen::parse(&config.bind) {
+        None => {
+            error!(
+                "Bad bind address {:?}, expected ip:port, [ipv6]:port or unix:/path/to/socket",
+                config.bind
+            );
+            std::process::exit(1)
+        }
+        Some(Listen::Unix(_)) if config.tls_cert.is_some() => {
+            error!("tls is not supported on unix sockets, terminate it in the reverse proxy");
+            std::process::exit(1)
+        }
+        Some(_) => (),
+    }
+
+    let cors = config.cors_origins.iter().chain(config.cors_methods.iter());
+    if let Some(v) = cors
+        .chain(config.cors_headers.iter())
+        .find(|v| v.is_empty() || !v.bytes().all(|c| c.is_ascii_graphic()))
+    {
+        error!("Bad cors origin, method or header {:?}", v);
+        std::process::exit(1)
+    }
+
+    if config.shard_depth == 0 || config.shard_depth > MAX_SHARD_DEPTH {
+        error!("shard_depth must be between 1 and {}", MAX_SHARD_DEPTH);
+        std::process::exit(1)
+    }
+
+    if config.tls_cert.is_some() != config.tls_key.is_some() {
+        error!("Both tls_cert and tls_key must be given to enable tls");
+        std::process::exit(1)
+    }
+
+    if config.tls_client_ca.is_some() && config.tls_cert.is_none() {
+        error!("tls_client_ca requires tls_cert and tls_key");
+        std::process::exit(1)
+    }
+    if config.tls_client_required && config.tls_client_ca.is_none() {
+        error!("tls_client_required requires tls_client_ca");
+        std::process::exit(1)
+    }
+
+    let command = match matches.subcommand_matches("scrub") {
+        Some(m) => Command::Scrub {
+            bucket: m.value_of("bucket").map(str::to_string),
+        },
+        None if matches.subcommand_matches("reshard").is_some() => Command::Reshard,
+        None => Command::Serve,
+    };
+
+    (config, command)
 }
@@ -1,12 +1,44 @@
-use hyper::header::CONTENT_LENGTH;
+use blake2::digest::{Update, VariableOutput};
+use blake2::VarBlake2b;
+use futures_util::stream;
+use hyper::header::{
+    HeaderValue, ACCEPT, ACCEPT_RANGES, ACCESS_CONTROL_ALLOW_CREDENTIALS,
+    ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+    ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD,
+    CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, ORIGIN, RANGE, RETRY_AFTER,
+    VARY,
+};
 use hyper::{Body, Method, Request, Response, StatusCode};
-use rusqlite::params;
-use std::sync::Arc;
+use rusqlite::{params, OptionalExtension, NO_PARAMS};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::config::{AccessType, SMALL_SIZE};
+use crate::config::{AccessType, Storage};
 use crate::error::{Error, ResponseFuture};
-use crate::state::State;
+use crate::metrics::Metrics;
+use crate::state::{db_path, lock, State};
+use crate::store::TempChunk;
 use hyper::body::HttpBody;
+use subtle::ConstantTimeEq;
+
+/// Header holding the Blake2b-256 of the body of chunk uploads
+const UPLOAD_HASH_HEADER: &str = "X-Content-Blake2b";
+
+/// Header holding the comma separated tags of a root when it is stored
+const ROOT_TAGS_HEADER: &str = "X-Root-Tags";
+
+/// The zstd level chunks are compressed with when compress_chunks is set
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// The largest number of chunks that can be checked in one request
+const EXISTS_BATCH_SIZE: usize = 10_000;
+
+/// The largest number of chunks deleted by one statement, SQLite allows at most 999 parameters
+const DELETE_BATCH_SIZE: usize = 500;
 
 /// Print an error to the terminal and return a future describing the error
 fn handle_error<E: std::fmt::Debug>(
@@ -17,7 +49,7 @@ fn handle_error<E: std::fmt::Debug>(
     e: E,
 ) -> ResponseFuture {
     //if code != StatusCode::NOT_FOUND {
-    error!("{}:{}: {} {} error {:?}", file, line, message, code, e);
+    error!(file = file, line = line, status = code.as_u16(); "{} error {:?}", message, e);
     //}
     Ok(Response::builder()
         .status(code)
@@ -64,34 +96,192 @@ fn unauthorized_message() -> ResponseFuture {
         .unwrap())
 }
 
+/// Construct a forbidden http response, for credentials that are valid but do not grant the access
+fn forbidden_message() -> ResponseFuture {
+    Ok(Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::from(""))
+        .unwrap())
+}
+
+/// The address of the client a request came from, stored in the extensions of the request
+#[derive(Clone, Copy)]
+pub struct RemoteAddr(pub Option<IpAddr>);
+
+/// The fingerprint of the tls certificate the client presented, stored in the extensions of the request
+#[derive(Clone)]
+struct ClientCert(Option<String>);
+
+/// The fingerprint of a certificate as given in the config, the hex encoded Blake2b-256
+/// of its DER encoding
+pub fn cert_fingerprint(der: &[u8]) -> String {
+    let mut hasher = VarBlake2b::new(256 / 8).unwrap();
+    hasher.update(der);
+    let mut fingerprint = String::new();
+    hasher.finalize_variable(|res| fingerprint = hex::encode(res));
+    fingerprint
+}
+
+/// The user authenticated by check_auth, stored in the extensions of the request for the access log
+#[derive(Clone, Default)]
+struct AuthenticatedUser(Arc<Mutex<Option<String>>>);
+
+/// Construct a too many requests http response, telling the client when to try again
+fn too_many_requests(wait: Duration) -> ResponseFuture {
+    let secs = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+    Ok(Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(RETRY_AFTER, secs)
+        .body(Body::from(""))
+        .unwrap())
+}
+
 /// Check if the user has an access lever greater than or equal to level
 /// If he does None is returned
 /// Otherwise Some(unauthorized_message()) is returned
+///
+/// Requests are rate limited by the authenticated user, or by the address of the client
+/// if not authenticated, in which case Some(too_many_requests()) is returned
 fn check_auth(req: &Request<Body>, state: Arc<State>, level: AccessType) -> Option<ResponseFuture> {
+    let remote = match req.extensions().get::<RemoteAddr>() {
+        Some(RemoteAddr(Some(ip))) => format!("ip {}", ip),
+        _ => "ip unknown".to_string(),
+    };
+    let (client, res) = match authenticate(req, &state, level, &remote) {
+        Ok(user) => {
+            if let Some(AuthenticatedUser(slot)) = req.extensions().get::<AuthenticatedUser>() {
+                *lock(slot) = Some(user.to_string());
+            }
+            (format!("user {}", user), None)
+        }
+        Err(res) => (remote, Some(res)),
+    };
+    if let Err(wait) = state.rate_limiter.check(&client) {
+        warn!("Rate limit exceeded for {}", client);
+        return Some(too_many_requests(wait));
+    }
+    res
+}
+
+/// Find the user authenticated by the request, if it has an access level greater than or equal to level
+///
+/// Passwords not verified before are only verified if the client, identified by remote,
+/// is within its rate limit, since the verification is slow by design
+fn authenticate<'a>(
+    req: &Request<Body>,
+    state: &'a State,
+    level: AccessType,
+    remote: &str,
+) -> Result<&'a str, ResponseFuture> {
+    // A known certificate authenticates the client on its own, unknown certificates are
+    // ignored so that their clients can still authenticate with the Authorization header
+    if let Some(ClientCert(Some(fingerprint))) = req.extensions().get::<ClientCert>() {
+        if let Some(res) = check_cert(fingerprint, state, level) {
+            return res;
+        }
+    }
+
     let auth = match req.headers().get("Authorization") {
         Some(data) => data,
-        None => return Some(unauthorized_message()),
+        None => return Err(unauthorized_message()),
     };
 
     let auth = match auth.to_str() {
         Ok(data) => data,
-        Err(_) => return Some(unauthorized_message()),
+        Err(_) => return Err(unauthorized_message()),
     };
 
-    for user in state.config.users.iter() {
-        if format!(
-            "Basic {}",
-            base64::encode(&format!("{}:{}", user.name, user.password))
-        ) != auth
-        {
-            continue;
+    if auth.starts_with("Bearer ") {
+        return check_token(auth[7..].as_bytes(), state, level);
+    }
+    if !auth.starts_with("Basic ") {
+        return Err(unauthorized_message());
+    }
+    let credentials = match base64::decode(&auth[6..]) {
+        Ok(data) => data,
+        Err(_) => return Err(unauthorized_message()),
+    };
+
+    let split = match credentials.iter().position(|&c| c == b':') {
+        Some(split) => split,
+        None => return Err(unauthorized_message()),
+    };
+    let (name, password) = (&credentials[..split], &credentials[split + 1..]);
+
+    let user = match state
+        .config
+        .users
+        .iter()
+        .find(|user| user.name.as_bytes() == name)
+    {
+        Some(user) => user,
+        None => return Err(unauthorized_message()),
+    };
+    if !state.passwords.contains(&user.name, password) {
+        if let Err(wait) = state.rate_limiter.peek(remote) {
+            return Err(too_many_requests(wait));
         }
-        if user.access_level >= level {
-            return None;
+        if !user.verify_password(password) {
+            return Err(unauthorized_message());
+        }
+        state.passwords.insert(&user.name, password);
+    }
+    if user.access_level >= level {
+        Ok(&user.name)
+    } else {
+        Err(unauthorized_message())
+    }
+}
+
+/// Check if a bearer token grants an access level greater than or equal to level,
+/// returning the user the token belongs to
+///
+/// A known token without the access level gets 403 Forbidden, as there is no other
+/// credential to ask for
+fn check_token<'a>(
+    token: &[u8],
+    state: &'a State,
+    level: AccessType,
+) -> Result<&'a str, ResponseFuture> {
+    // Compare in constant time against every token, so that the time taken
+    // does not reveal how much of the token was right
+    let mut matched = None;
+    for user in state.config.users.iter() {
+        for t in user.tokens.iter() {
+            if bool::from(t.token.as_bytes().ct_eq(token)) {
+                matched = Some((user, t));
+            }
         }
     }
 
-    Some(unauthorized_message())
+    match matched {
+        Some((user, t)) if t.access_level >= level => Ok(&user.name),
+        Some(_) => Err(forbidden_message()),
+        None => Err(unauthorized_message()),
+    }
+}
+
+/// Check if a client certificate grants an access level greater than or equal to level,
+/// returning the user the certificate belongs to, or None if no user has the certificate
+///
+/// A known certificate without the access level gets 403 Forbidden, like a known token
+fn check_cert<'a>(
+    fingerprint: &str,
+    state: &'a State,
+    level: AccessType,
+) -> Option<Result<&'a str, ResponseFuture>> {
+    for user in state.config.users.iter() {
+        for c in user.certs.iter() {
+            if c.fingerprint.eq_ignore_ascii_case(fingerprint) {
+                return Some(if c.access_level >= level {
+                    Ok(&user.name)
+                } else {
+                    Err(forbidden_message())
+                });
+            }
+        }
+    }
+    None
 }
 
 /// Validate that a string is a valid hex encoding of a 256bit hash
@@ -111,14 +301,63 @@ fn check_hash(name: &str) -> std::result::Result<(), Error> {
     Ok(())
 }
 
-fn chunk_path(data_dir: &str, bucket: &str, chunk: &str) -> String {
-    format!(
-        "{}/data/{}/{}/{}",
-        data_dir,
-        &bucket,
-        &chunk[..2],
-        &chunk[2..]
-    )
+/// The maximal number of bytes stored in a bucket, None if unlimited
+fn bucket_quota(state: &State, bucket: &str) -> Option<u64> {
+    state
+        .config
+        .bucket_quotas
+        .get(bucket)
+        .copied()
+        .or(state.config.quota)
+}
+
+/// The size from which chunks of a bucket are stored in the chunk storage instead of the database
+fn bucket_small_size(state: &State, bucket: &str) -> usize {
+    state
+        .config
+        .bucket_small_sizes
+        .get(bucket)
+        .copied()
+        .unwrap_or(state.config.small_size)
+}
+
+/// Reserve len bytes in the quota of a bucket
+///
+/// Returns false if the bucket would then store more than quota bytes
+fn reserve_quota(
+    state: &State,
+    conn: &rusqlite::Connection,
+    bucket: &str,
+    quota: u64,
+    len: u64,
+) -> rusqlite::Result<bool> {
+    let mut bucket_bytes = lock(&state.bucket_bytes);
+    let used = match bucket_bytes.get(bucket) {
+        Some(used) => *used,
+        None => {
+            let used: i64 = conn
+                .prepare_cached("SELECT IFNULL(SUM(size), 0) FROM chunks WHERE bucket=?")?
+                .query_row(params![bucket], |row| row.get(0))?;
+            used as u64
+        }
+    };
+    if used + len > quota {
+        return Ok(false);
+    }
+    bucket_bytes.insert(bucket.to_string(), used + len);
+    Ok(true)
+}
+
+/// Give back bytes reserved by reserve_quota for a chunk that was not stored
+fn release_quota(state: &State, bucket: &str, len: u64) {
+    if let Some(used) = lock(&state.bucket_bytes).get_mut(bucket) {
+        *used = used.saturating_sub(len);
+    }
+}
+
+/// Forget the bytes stored in a bucket after chunks are deleted from it
+fn forget_quota(state: &State, bucket: &str) {
+    lock(&state.bucket_bytes).remove(bucket);
 }
 
 /// Put a chunk into the chunk archive
@@ -144,90 +383,236 @@ async fn handle_put_chunk(
         "Bad chunk"
     );
 
+    // The client sends the Blake2b-256 of the body, so that we never store
+    // content under a hash it was not meant for
+    let expected_hash = match req.headers().get(UPLOAD_HASH_HEADER) {
+        Some(v) => tryfut!(v.to_str(), StatusCode::BAD_REQUEST, "Bad content hash").to_string(),
+        None => return handle_error!(StatusCode::BAD_REQUEST, "Missing content hash", chunk),
+    };
+
     // Check if the chunk is already there.
     {
-        let conn = state.conn.lock().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT id FROM chunks WHERE bucket=? AND hash=?")
-            .unwrap();
-
-        let mut rows = stmt.query(params![bucket, chunk]).unwrap();
-        if rows.next().expect("Unable to read db row").is_some() {
+        let conn = tryfut!(
+            state.read_pool.get(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Database unavailable"
+        );
+        let found: Option<i64> = tryfut!(
+            conn.prepare_cached("SELECT id FROM chunks WHERE bucket=? AND hash=?")
+                .and_then(|mut stmt| stmt
+                    .query_row(params![bucket, chunk], |row| row.get(0))
+                    .optional()),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        );
+        if found.is_some() {
+            Metrics::add(&state.metrics.chunks_deduped, 1);
             return handle_error!(StatusCode::CONFLICT, "Already there", "");
         }
     }
 
+    // Buffer the content in memory until it is the small_size of the bucket,
+    // after that it is spilled to a temporary file as it arrives
+    let small_size = bucket_small_size(&state, &bucket);
     let mut v = Vec::new();
+    let mut temp: Option<TempChunk> = None;
+    let mut len = 0;
+    let mut hasher = VarBlake2b::new(256 / 8).unwrap();
     let mut body = req.into_body();
-    while let Some(chunk) = body.data().await {
-        v.extend_from_slice(&chunk?);
-        if v.len() > 1024 * 1024 * 1024 {
+    while let Some(data) = body.data().await {
+        let data = data?;
+        len += data.len();
+        hasher.update(&data);
+        if len > 1024 * 1024 * 1024 {
             return handle_error!(StatusCode::BAD_REQUEST, "Content too large", "");
         }
+        if let Some(temp) = &mut temp {
+            tryfut!(
+                temp.write(&data).await,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Write failed"
+            );
+            continue;
+        }
+        v.extend_from_slice(&data);
+        if v.len() >= small_size && state.store.spill_to_disk() {
+            let mut t = tryfut!(
+                TempChunk::create(&state.config.data_dir, &bucket, &chunk).await,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Write failed"
+            );
+            tryfut!(
+                t.write(&v).await,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Write failed"
+            );
+            v = Vec::new();
+            temp = Some(t);
+        }
+    }
+
+    let mut hash = String::new();
+    hasher.finalize_variable(|res| hash = hex::encode(res));
+    if hash != expected_hash {
+        return handle_error!(StatusCode::BAD_REQUEST, "Content hash mismatch", chunk);
+    }
+
+    if let Some(quota) = bucket_quota(&state, &bucket) {
+        let conn = tryfut!(
+            state.read_pool.get(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Database unavailable"
+        );
+        if !tryfut!(
+            reserve_quota(&state, &conn, &bucket, quota, len as u64),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        ) {
+            return handle_error!(StatusCode::INSUFFICIENT_STORAGE, "Quota exceeded", bucket);
+        }
+    }
+    let res = store_chunk(&bucket, &chunk, v, temp, len, &state).await;
+    if res
+        .as_ref()
+        .map_or(true, |res| res.status() != StatusCode::OK)
+    {
+        release_quota(&state, &bucket, len as u64);
+    }
+    res
+}
+
+/// Store the content of a chunk that has been received and checked
+///
+/// If compress_chunks is set the content is stored compressed when that makes it smaller
+async fn store_chunk(
+    bucket: &str,
+    chunk: &str,
+    mut v: Vec<u8>,
+    mut temp: Option<TempChunk>,
+    len: usize,
+    state: &State,
+) -> ResponseFuture {
+    let mut compressed = false;
+    if state.config.compress_chunks {
+        match temp.take() {
+            Some(mut t) => {
+                let (c, c_len) = tryfut!(
+                    t.compress(&state.config.data_dir, bucket, chunk, COMPRESSION_LEVEL)
+                        .await,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Compression failed"
+                );
+                compressed = c_len < len as u64;
+                temp = Some(if compressed { c } else { t });
+            }
+            None => {
+                let c = tryfut!(
+                    zstd::encode_all(&v[..], COMPRESSION_LEVEL),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Compression failed"
+                );
+                if c.len() < v.len() {
+                    v = c;
+                    compressed = true;
+                }
+            }
+        }
     }
 
-    let len = v.len();
     // Small content is stored directly in the DB
-    if len < SMALL_SIZE {
-        let conn = state.conn.lock().unwrap();
+    if temp.is_none() && state.store.inline_small() {
+        let conn = state.lock_conn();
         tryfut!(
-            conn.execute(
-                "INSERT INTO chunks (bucket, hash, size, time, content) VALUES (?, ?, ?, strftime('%s', 'now'), ?)",
-                params![&bucket, &chunk, v.len() as i64, &v],
-            ),
+            conn.prepare_cached(
+                "INSERT INTO chunks (bucket, hash, size, time, content, compressed) VALUES (?, ?, ?, strftime('%s', 'now'), ?, ?)",
+            )
+            .and_then(|mut stmt| stmt.execute(params![bucket, chunk, len as i64, &v, compressed])),
             StatusCode::INTERNAL_SERVER_ERROR,
             "Insert failed",
         );
     } else {
-        // Large content is stored on disk. We first store the data in a temp upload folder
-        // and then atomically rename into its right location
+        // Large content is stored in the chunk store
         tryfut!(
-            std::fs::create_dir_all(format!("{}/data/upload/{}", state.config.data_dir, &bucket)),
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Could not create upload folder"
-        );
-        let temp_path = format!(
-            "{}/data/upload/{}/{}_{}",
-            state.config.data_dir,
-            bucket,
-            chunk,
-            rand::random::<u64>()
-        );
-        tryfut!(
-            std::fs::write(&temp_path, v),
+            match temp {
+                Some(temp) => state.store.put_temp(bucket, chunk, temp).await,
+                None => state.store.put(bucket, chunk, v).await,
+            },
             StatusCode::INTERNAL_SERVER_ERROR,
             "Write failed"
         );
-        tryfut!(
-            std::fs::create_dir_all(format!(
-                "{}/data/{}/{}",
-                state.config.data_dir,
-                &bucket,
-                &chunk[..2]
-            )),
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Could not create bucket folder"
-        );
-        {
-            let conn = state.conn.lock().unwrap();
-            tryfut!(conn.execute("INSERT INTO chunks (bucket, hash, size, time) VALUES (?, ?, ?, strftime('%s', 'now'))",
-                params![&bucket, &chunk, len as i64]),
-                StatusCode::INTERNAL_SERVER_ERROR, "Insert failed");
+        // The content is stored before the row, so that the database never lists a chunk that
+        // is not there. A crash in between leaves content without a row, which is overwritten
+        // when the chunk is put again
+        let res = {
+            let conn = state.lock_conn();
+            conn.prepare_cached("INSERT INTO chunks (bucket, hash, size, time, compressed) VALUES (?, ?, ?, strftime('%s', 'now'), ?)")
+                .and_then(|mut stmt| stmt.execute(params![bucket, chunk, len as i64, compressed]))
+        };
+        if let Err(e) = res {
+            // Unless a concurrent put of the same chunk recorded it, the content is not needed
+            let recorded = state.lock_conn().query_row(
+                "SELECT count(*) FROM chunks WHERE bucket=? AND hash=?",
+                params![bucket, chunk],
+                |row| row.get::<_, i64>(0),
+            );
+            if let Ok(0) = recorded {
+                if let Err(e) = state.store.delete(bucket, chunk).await {
+                    warn!("Unable to remove chunk {}/{}: {:?}", bucket, chunk, e);
+                }
+            }
+            return handle_error!(StatusCode::INTERNAL_SERVER_ERROR, "Insert failed", e);
         }
-        tryfut!(
-            std::fs::rename(
-                &temp_path,
-                chunk_path(&state.config.data_dir, &bucket, &chunk)
-            ),
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Move failed"
-        );
     }
-    info!("{}:{}: put chunk {} success", file!(), line!(), chunk);
+    Metrics::add(&state.metrics.chunks_put, 1);
+    Metrics::add(&state.metrics.bytes_stored, len as u64);
+    info!(bucket = bucket, chunk = chunk, status = 200u16; "put chunk success");
 
     ok_message(None)
 }
 
+/// Parse the first range of a Range header for content of size bytes into its start and length
+///
+/// Returns None if the range is malformed or cannot be satisfied
+fn parse_range(value: &str, size: u64) -> Option<(u64, u64)> {
+    let value = value.trim();
+    if !value.starts_with("bytes=") {
+        return None;
+    }
+    let last = size.checked_sub(1)?;
+    let mut range = value["bytes=".len()..].split(',').next()?.splitn(2, '-');
+    let start = range.next()?.trim();
+    let end = range.next()?.trim();
+    let (start, end) = if start.is_empty() {
+        // A suffix range of the last end bytes
+        let len: u64 = end.parse().ok()?;
+        if len == 0 {
+            return None;
+        }
+        (size.saturating_sub(len), last)
+    } else if end.is_empty() {
+        (start.parse().ok()?, last)
+    } else {
+        (start.parse().ok()?, u64::min(end.parse().ok()?, last))
+    };
+    if start > end {
+        return None;
+    }
+    Some((start, end - start + 1))
+}
+
+/// Construct a body streaming the content of reader
+fn reader_body(reader: Pin<Box<dyn AsyncRead + Send>>) -> Body {
+    Body::wrap_stream(stream::try_unfold(reader, |mut reader| async move {
+        let mut buf = vec![0; 64 * 1024];
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.truncate(n);
+        Ok::<_, std::io::Error>(Some((buf, reader)))
+    }))
+}
+
 /// Get a chunk from the archive
 async fn handle_get_chunk(
     bucket: String,
@@ -260,120 +645,187 @@ async fn handle_get_chunk(
         "Bad chunk"
     );
 
-    let (content, size) = {
-        let conn = state.conn.lock().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT id, content, size FROM chunks WHERE bucket=? AND hash=?")
-            .unwrap();
-
-        let mut rows = stmt.query(params![bucket, chunk]).unwrap();
-        let (_id, content, size) = match rows.next().expect("Unable to read db row") {
-            Some(row) => {
-                let id: i64 = row.get(0).unwrap();
-                let content: Option<Vec<u8>> = row.get(1).unwrap();
-                let size: i64 = row.get(2).unwrap();
-                (id, content, size)
-            }
+    let (mut content, size, compressed) = {
+        let conn = tryfut!(
+            state.read_pool.get(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Database unavailable"
+        );
+        let row: Option<(Option<Vec<u8>>, i64, bool)> = tryfut!(
+            conn.prepare_cached(
+                "SELECT content, size, compressed FROM chunks WHERE bucket=? AND hash=?",
+            )
+            .and_then(|mut stmt| stmt
+                .query_row(params![bucket, chunk], |row| Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?
+                )))
+                .optional()),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        );
+        match row {
+            Some(row) => row,
             None => {
                 return handle_error!(StatusCode::NOT_FOUND, "Not found", chunk);
             }
-        };
-        (content, size)
+        }
     };
 
     if head {
-        info!("{}:{}: head chunk {} success", file!(), line!(), chunk);
+        info!(bucket = bucket.as_str(), chunk = chunk.as_str(), status = 200u16; "head chunk success");
         return Ok(Response::builder()
             .status(StatusCode::OK)
             .header(CONTENT_LENGTH, size)
             .body(Body::from(""))
             .unwrap());
     }
-    let content = match content {
-        Some(content) => content,
-        None => {
-            let path = chunk_path(&state.config.data_dir, &bucket, &chunk);
-            match std::fs::read(path) {
-                //TODO use tokio for async fileread
-                Ok(data) => data,
-                Err(e) => {
-                    return handle_error!(StatusCode::INTERNAL_SERVER_ERROR, "Chunk missing", e)
-                }
+    // Only return the first range requested, if any
+    let size = size as u64;
+    let range = match req.headers().get(RANGE) {
+        Some(value) => match value.to_str().ok().and_then(|v| parse_range(v, size)) {
+            Some(range) => Some(range),
+            None => {
+                warn!("Unsatisfiable range for get chunk {}/{}", bucket, chunk);
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(CONTENT_RANGE, format!("bytes */{}", size))
+                    .body(Body::from("Range not satisfiable"))
+                    .unwrap());
+            }
+        },
+        None => None,
+    };
+
+    // Compressed content is decompressed into memory, ranges are of the decompressed content
+    if compressed {
+        let data = match content {
+            Some(data) => data,
+            None => tryfut!(
+                state.store.get(&bucket, &chunk).await,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Read failed"
+            ),
+        };
+        content = Some(tryfut!(
+            zstd::decode_all(&data[..]),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Decompression failed"
+        ));
+    }
+
+    let body = match content {
+        Some(mut content) => {
+            if let Some((start, len)) = range {
+                content = content[start as usize..(start + len) as usize].to_vec();
             }
+            Body::from(content)
         }
+        None => match match range {
+            Some((start, len)) => state.store.open_range(&bucket, &chunk, start, len).await,
+            None => state.store.open(&bucket, &chunk).await,
+        } {
+            Ok(reader) => reader_body(reader),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return handle_error!(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Chunk content missing from storage",
+                    chunk
+                )
+            }
+            Err(e) => return handle_error!(StatusCode::INTERNAL_SERVER_ERROR, "Read failed", e),
+        },
     };
 
-    info!("{}:{}: get chunk {} success", file!(), line!(), chunk);
+    Metrics::add(&state.metrics.chunks_get, 1);
+    if let Some((start, len)) = range {
+        Metrics::add(&state.metrics.bytes_served, len);
+        info!(bucket = bucket.as_str(), chunk = chunk.as_str(), status = 206u16; "get chunk range success");
+        return Ok(Response::builder()
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(CONTENT_LENGTH, len)
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, start + len - 1, size),
+            )
+            .body(body)
+            .unwrap());
+    }
+    Metrics::add(&state.metrics.bytes_served, size);
+    info!(bucket = bucket.as_str(), chunk = chunk.as_str(), status = 200u16; "get chunk success");
     Ok(Response::builder()
         .status(StatusCode::OK)
         .header(CONTENT_LENGTH, size)
-        .body(Body::from(content))
+        .header(ACCEPT_RANGES, "bytes")
+        .body(body)
         .unwrap())
 }
 
-async fn do_delete_chunks(bucket: String, chunks: &[&str], state: Arc<State>) -> ResponseFuture {
+/// Delete at most DELETE_BATCH_SIZE chunks of a bucket, whether or not they are used by a root
+///
+/// Chunks are not reference counted: the server cannot decrypt roots to see which chunks
+/// they use. Only the client knows that, so it decides which chunks are unused, see handle_gc
+///
+/// The rows of the chunks are deleted before their content in the chunk store, so that the
+/// database never lists a chunk that is not there. Content left behind by a crash or a failed
+/// delete is overwritten if the chunk is put again.
+///
+/// Returns the number of the given chunks that were in the bucket, or the response describing
+/// the error
+async fn delete_chunk_batch(
+    bucket: &str,
+    chunks: &[&str],
+    state: &State,
+) -> Result<usize, ResponseFuture> {
     if chunks.is_empty() {
-        return ok_message(None);
+        return Ok(0);
     }
+    let query_failed = |e| handle_error!(StatusCode::INTERNAL_SERVER_ERROR, "Query failed", e);
 
-    let mut params: Vec<&str> = vec![&bucket];
-    for chunk in chunks {
-        params.push(chunk)
-    }
-    let count = {
-        let conn = state.conn.lock().unwrap();
-        let mut stmt = conn
+    let mut params: Vec<&str> = vec![bucket];
+    params.extend_from_slice(chunks);
+    let (external, count) = {
+        let mut conn = state.lock_conn();
+        let tx = conn.transaction().map_err(query_failed)?;
+        let external: Vec<String> = tx
             .prepare(&format!(
-                "SELECT hash, content IS NULL FROM chunks WHERE bucket=? AND hash IN (?{})",
+                "SELECT hash FROM chunks WHERE bucket=? AND hash IN (?{}) AND content IS NULL",
                 ", ?".repeat(chunks.len() - 1)
             ))
-            .unwrap();
-
-        for row in stmt
-            .query_map(&params, |row| Ok((row.get(0)?, row.get(1)?)))
-            .unwrap()
-        {
-            let (chunk, external): (String, bool) = row.expect("Unable to read db row");
-            if external {
-                let path = chunk_path(&state.config.data_dir, &bucket, &chunk);
-                tryfut!(
-                    match std::fs::remove_file(path) {
-                        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
-                        v => v,
-                    },
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "Delete failed",
-                );
-            }
-        }
-
-        let count = tryfut!(
-            conn.execute(
+            .and_then(|mut stmt| {
+                stmt.query_map(&params, |row| row.get(0))
+                    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+            })
+            .map_err(query_failed)?;
+        let count = tx
+            .execute(
                 &format!(
                     "DELETE FROM chunks WHERE bucket=? AND hash IN (?{})",
                     ", ?".repeat(chunks.len() - 1)
                 ),
                 &params,
-            ),
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Query failed",
-        );
-
-        tryfut!(
-            conn.execute(
-                "REPLACE INTO deletes VALUES (?, strftime('%s', 'now'))",
-                params![bucket],
-            ),
-            StatusCode::INTERNAL_SERVER_ERROR,
-            "Query failed",
-        );
-        count
+            )
+            .map_err(query_failed)?;
+        tx.execute(
+            "REPLACE INTO deletes VALUES (?, strftime('%s', 'now'))",
+            params![bucket],
+        )
+        .map_err(query_failed)?;
+        tx.commit().map_err(query_failed)?;
+        (external, count)
     };
+    forget_quota(state, bucket);
+    Metrics::add(&state.metrics.chunks_deleted, count as u64);
 
-    if count != chunks.len() {
-        return handle_error!(StatusCode::NOT_FOUND, "Missing chunk", "");
+    for chunk in external.iter() {
+        state
+            .store
+            .delete(bucket, chunk)
+            .await
+            .map_err(|e| handle_error!(StatusCode::INTERNAL_SERVER_ERROR, "Delete failed", e))?;
     }
-    ok_message(None)
+    Ok(count)
 }
 
 async fn handle_delete_chunk(
@@ -397,11 +849,18 @@ async fn handle_delete_chunk(
         StatusCode::BAD_REQUEST,
         "Bad chunk"
     );
-    let chu: &str = &chunk;
 
-    do_delete_chunks(bucket, std::slice::from_ref(&chu), state).await
+    match delete_chunk_batch(&bucket, &[chunk.as_str()], &state).await {
+        Ok(1) => ok_message(None),
+        Ok(_) => handle_error!(StatusCode::NOT_FOUND, "Missing chunk", ""),
+        Err(res) => res,
+    }
 }
 
+/// Delete the chunks in the NUL separated list in the body
+///
+/// The chunks are deleted in batches as the list arrives, so it is never held in memory.
+/// A bad chunk in the list fails the request, leaving the chunks before it deleted
 async fn handle_delete_chunks(
     bucket: String,
     req: Request<Body>,
@@ -418,42 +877,70 @@ async fn handle_delete_chunks(
         "Bad bucket"
     );
 
-    let mut v = Vec::new();
     let mut body = req.into_body();
+    // The part of the list after the last NUL received
+    let mut pending: Vec<u8> = Vec::new();
+    let mut batch: Vec<String> = Vec::new();
+    let mut total = 0;
+    let mut count = 0;
+    let mut done = false;
+    while !done {
+        match body.data().await {
+            Some(data) => pending.extend_from_slice(&data?),
+            None => done = true,
+        }
+        let mut start = 0;
+        loop {
+            // Every chunk but the last is followed by a NUL
+            let end = match pending[start..].iter().position(|c| *c == 0) {
+                Some(len) => start + len,
+                None if done => pending.len(),
+                None => break,
+            };
+            let chunk = tryfut!(
+                String::from_utf8(pending[start..end].to_vec()),
+                StatusCode::BAD_REQUEST,
+                "Bad chunk"
+            );
+            tryfut!(check_hash(&chunk), StatusCode::BAD_REQUEST, "Bad chunk");
+            batch.push(chunk);
+            start = end + 1;
 
-    while let Some(chunk) = body.data().await {
-        let chunk = chunk?;
-        v.extend_from_slice(&chunk);
-        if v.len() >= 1024 * 1024 * 256 {
-            return handle_error!(StatusCode::BAD_REQUEST, "Too much data", "");
+            if batch.len() == DELETE_BATCH_SIZE || (done && start > pending.len()) {
+                let chunks: Vec<&str> = batch.iter().map(String::as_str).collect();
+                count += match delete_chunk_batch(&bucket, &chunks, &state).await {
+                    Ok(count) => count,
+                    Err(res) => return res,
+                };
+                total += batch.len();
+                batch.clear();
+            }
+            if start > pending.len() {
+                break;
+            }
+        }
+        pending.drain(..usize::min(start, pending.len()));
+        if pending.len() > 64 {
+            return handle_error!(StatusCode::BAD_REQUEST, "Bad chunk", "");
         }
     }
 
-    let s = tryfut!(String::from_utf8(v), StatusCode::BAD_REQUEST, "Bad chunks");
-    let chunks: Vec<&str> = s.split('\0').collect();
-    for chunk in chunks.iter() {
-        tryfut!(check_hash(chunk), StatusCode::BAD_REQUEST, "Bad bucket");
+    if count != total {
+        return handle_error!(StatusCode::NOT_FOUND, "Missing chunk", "");
     }
-    do_delete_chunks(bucket, &chunks, state).await
+    ok_message(None)
 }
 
-async fn handle_list_chunks(
+/// Check which of the chunks in the NUL separated list in the body are stored
+///
+/// The response has a byte per chunk, '1' if the chunk is stored and '0' if it is not
+async fn handle_chunks_exist(
     bucket: String,
     req: Request<Body>,
     state: Arc<State>,
 ) -> ResponseFuture {
-    let full = req.uri().query().map_or(false, |q| q.contains("validate"));
-
-    if let Some(res) = check_auth(
-        &req,
-        state.clone(),
-        if full {
-            AccessType::Get
-        } else {
-            AccessType::Put
-        },
-    ) {
-        warn!("Unauthorized access for list chunks {}", bucket);
+    if let Some(res) = check_auth(&req, state.clone(), AccessType::Put) {
+        warn!("Unauthorized access for chunks exist {}", bucket);
         return res;
     }
 
@@ -463,77 +950,544 @@ async fn handle_list_chunks(
         "Bad bucket"
     );
 
+    let mut v = Vec::new();
+    let mut body = req.into_body();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        v.extend_from_slice(&chunk);
+        if v.len() > EXISTS_BATCH_SIZE * 65 {
+            return handle_error!(StatusCode::BAD_REQUEST, "Too much data", "");
+        }
+    }
+
+    let s = tryfut!(String::from_utf8(v), StatusCode::BAD_REQUEST, "Bad chunks");
+    let chunks: Vec<&str> = s.split('\0').collect();
+    if chunks.len() > EXISTS_BATCH_SIZE {
+        return handle_error!(StatusCode::BAD_REQUEST, "Too many chunks", "");
+    }
+    for chunk in chunks.iter() {
+        tryfut!(check_hash(chunk), StatusCode::BAD_REQUEST, "Bad chunk");
+    }
+
     let ans = {
-        let mut ans = "".to_string();
-        let conn = state.conn.lock().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT hash, size, length(content) FROM chunks WHERE bucket=?")
-            .unwrap();
-
-        for row in stmt
-            .query_map(params![bucket], |row| {
-                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
-            })
-            .unwrap()
-        {
-            let (chunk, size, content_size): (String, i64, Option<i64>) = row.unwrap();
-            if full {
-                let content_size = match content_size {
-                    Some(v) => v,
-                    None => {
-                        let path = chunk_path(&state.config.data_dir, &bucket, &chunk);
-                        match std::fs::metadata(path) {
-                            Ok(md) => md.len() as i64,
-                            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => -1,
-                            Err(e) => {
-                                return handle_error!(
-                                    StatusCode::INTERNAL_SERVER_ERROR,
-                                    "Unable to access metadata",
-                                    e
-                                )
-                            }
-                        }
-                    }
-                };
-                ans.push_str(&format!("{} {} {}\n", chunk, size, content_size));
-            } else {
-                ans.push_str(&format!("{} {}\n", chunk, size));
-            }
+        let conn = tryfut!(
+            state.read_pool.get(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Database unavailable"
+        );
+        let mut stmt = tryfut!(
+            conn.prepare_cached("SELECT count(*) FROM chunks WHERE bucket=? AND hash=?"),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        );
+        let mut ans = String::with_capacity(chunks.len());
+        for chunk in chunks.iter() {
+            let cnt: i64 = tryfut!(
+                stmt.query_row(params![bucket, chunk], |row| row.get(0)),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Query failed"
+            );
+            ans.push(if cnt != 0 { '1' } else { '0' });
         }
         ans
     };
     ok_message(Some(ans))
 }
 
-async fn handle_get_status(
-    bucket: String,
-    req: Request<Body>,
-    state: Arc<State>,
-) -> ResponseFuture {
-    if let Some(res) = check_auth(&req, state.clone(), AccessType::Put) {
-        warn!("Unauthorized access for get status {}", bucket);
+/// Check if the client asked for json rather than the text format, by ?format=json
+/// or by accepting application/json
+fn wants_json(req: &Request<Body>) -> bool {
+    if let Some(format) = query_param(req, "format") {
+        return format == "json";
+    }
+    match req.headers().get(ACCEPT).map(|v| v.to_str()) {
+        Some(Ok(v)) => v
+            .split(',')
+            .any(|t| t.split(';').next().map(str::trim) == Some("application/json")),
+        _ => false,
+    }
+}
+
+/// A json response
+fn json_message<T: Serialize>(value: &T) -> ResponseFuture {
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .header(VARY, "Accept")
+        .body(Body::from(serde_json::to_string(value).unwrap()))
+        .unwrap())
+}
+
+/// Return the value of a query parameter of the request
+fn query_param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.uri().query()?.split('&').find_map(|kv| {
+        let mut kv = kv.splitn(2, '=');
+        if kv.next()? == name {
+            Some(kv.next().unwrap_or(""))
+        } else {
+            None
+        }
+    })
+}
+
+/// A gc running for a bucket, the bucket is removed from the running gcs when this is dropped
+struct RunningGc {
+    state: Arc<State>,
+    bucket: String,
+}
+
+impl RunningGc {
+    /// Register a gc of bucket, returns None if one is already running for it
+    fn start(state: Arc<State>, bucket: &str) -> Option<RunningGc> {
+        if !lock(&state.gc_buckets).insert(bucket.to_string()) {
+            return None;
+        }
+        Some(RunningGc {
+            state,
+            bucket: bucket.to_string(),
+        })
+    }
+}
+
+impl Drop for RunningGc {
+    fn drop(&mut self) {
+        lock(&self.state.gc_buckets).remove(&self.bucket);
+    }
+}
+
+/// Remove all chunks in a bucket created before a given time, that are not in the
+/// NUL separated list of live chunks given in the body
+///
+/// The client marks the live chunks by walking the roots, since only it can decrypt them.
+/// Chunks created after the client started marking may belong to a backup in progress,
+/// so the client must pass the server time at which it started as start.
+/// While a gc of the bucket is running, another one is refused with 409 Conflict.
+///
+/// A backup in progress may also reuse an older chunk that no root used, without uploading
+/// it again. Such a chunk is not marked, so the gc is refused with 409 Conflict if a root
+/// was added to the bucket since start. A backup storing its root after the gc could still
+/// refer to a swept chunk, so clients must check again that their chunks exist once the
+/// time of the last delete given by /status is past what their cache of chunks is from.
+async fn handle_gc(bucket: String, req: Request<Body>, state: Arc<State>) -> ResponseFuture {
+    if let Some(res) = check_auth(&req, state.clone(), AccessType::Delete) {
+        warn!("Unauthorized access for gc {}", bucket);
         return res;
     }
+
     tryfut!(
         check_hash(bucket.as_ref()),
         StatusCode::BAD_REQUEST,
         "Bad bucket"
     );
 
-    let conn = state.conn.lock().unwrap();
-    let mut stmt = conn
-        .prepare("SELECT time FROM deletes WHERE bucket=?")
-        .unwrap();
+    let start: i64 = match query_param(&req, "start").map(str::parse) {
+        Some(Ok(v)) => v,
+        _ => return handle_error!(StatusCode::BAD_REQUEST, "Missing start", ""),
+    };
 
-    let mut rows = stmt.query(params![bucket]).unwrap();
-    let time: i64 = match rows.next().expect("Unable to read db row") {
-        Some(row) => row.get(0).expect("Unable to get number"),
-        None => 0,
+    // Only one gc of a bucket may run at a time, as they share the marks of the bucket
+    let _running = match RunningGc::start(state.clone(), &bucket) {
+        Some(running) => running,
+        None => return handle_error!(StatusCode::CONFLICT, "Gc already running", ""),
+    };
+
+    let mut v = Vec::new();
+    let mut body = req.into_body();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        v.extend_from_slice(&chunk);
+        if v.len() >= 1024 * 1024 * 256 {
+            return handle_error!(StatusCode::BAD_REQUEST, "Too much data", "");
+        }
+    }
+
+    let s = tryfut!(String::from_utf8(v), StatusCode::BAD_REQUEST, "Bad chunks");
+    let live: Vec<&str> = if s.is_empty() {
+        Vec::new()
+    } else {
+        s.split('\0').collect()
     };
+    for chunk in live.iter() {
+        tryfut!(check_hash(chunk), StatusCode::BAD_REQUEST, "Bad chunk");
+    }
+
+    // Mark the live chunks, in batches so we do not hold the connection for too long
+    {
+        let conn = state.lock_conn();
+        tryfut!(
+            conn.execute("DELETE FROM gc_marks WHERE bucket=?", params![bucket]),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed",
+        );
+    }
+    for batch in live.chunks(4096) {
+        let mut conn = state.lock_conn();
+        let tx = tryfut!(
+            conn.transaction(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        );
+        for chunk in batch {
+            tryfut!(
+                tx.prepare_cached("INSERT OR IGNORE INTO gc_marks (bucket, hash) VALUES (?, ?)")
+                    .and_then(|mut stmt| stmt.execute(params![bucket, chunk])),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Query failed",
+            );
+        }
+        tryfut!(
+            tx.commit(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        );
+    }
+
+    // Sweep the unmarked chunks in batches
+    let mut removed_count: u64 = 0;
+    let mut removed_bytes: u64 = 0;
+    loop {
+        let batch: Vec<(String, i64, bool)> = {
+            let conn = state.lock_conn();
+            let mut stmt = tryfut!(
+                conn.prepare(
+                    "SELECT hash, size, content IS NULL FROM chunks WHERE bucket=? AND time < ?
+                     AND hash NOT IN (SELECT hash FROM gc_marks WHERE bucket=?) LIMIT 1024",
+                ),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Query failed",
+            );
+            let batch = tryfut!(
+                stmt.query_map(params![bucket, start, bucket], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>()),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Query failed",
+            );
+            batch
+        };
+        if batch.is_empty() {
+            break;
+        }
+        // The rows go before the content, as in delete_chunk_batch
+        {
+            let mut conn = state.lock_conn();
+            let tx = tryfut!(
+                conn.transaction(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Query failed"
+            );
+            // Roots are added with the connection locked, so none can be added until the commit
+            let added: i64 = tryfut!(
+                tx.query_row(
+                    "SELECT count(*) FROM roots WHERE bucket=? AND time >= ?",
+                    params![bucket, start],
+                    |row| row.get(0),
+                ),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Query failed",
+            );
+            if added != 0 {
+                return handle_error!(StatusCode::CONFLICT, "Root added during gc", "");
+            }
+            for (chunk, size, _) in batch.iter() {
+                tryfut!(
+                    tx.prepare_cached("DELETE FROM chunks WHERE bucket=? AND hash=?")
+                        .and_then(|mut stmt| stmt.execute(params![bucket, chunk])),
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Query failed",
+                );
+                removed_count += 1;
+                removed_bytes += *size as u64;
+            }
+            tryfut!(
+                tx.execute(
+                    "REPLACE INTO deletes VALUES (?, strftime('%s', 'now'))",
+                    params![bucket],
+                ),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Query failed",
+            );
+            tryfut!(
+                tx.commit(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Query failed"
+            );
+        }
+        for (chunk, _, external) in batch.iter() {
+            if *external {
+                tryfut!(
+                    state.store.delete(&bucket, chunk).await,
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Delete failed",
+                );
+            }
+        }
+    }
+
+    {
+        let conn = state.lock_conn();
+        tryfut!(
+            conn.execute("DELETE FROM gc_marks WHERE bucket=?", params![bucket]),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed",
+        );
+    }
+
+    forget_quota(&state, &bucket);
+    Metrics::add(&state.metrics.chunks_deleted, removed_count);
+    info!(
+        bucket = bucket.as_str(),
+        removed_chunks = removed_count,
+        removed_bytes = removed_bytes;
+        "gc success"
+    );
+    ok_message(Some(format!("{} {}", removed_count, removed_bytes)))
+}
+
+async fn handle_list_chunks(
+    bucket: String,
+    req: Request<Body>,
+    state: Arc<State>,
+) -> ResponseFuture {
+    let full = req.uri().query().map_or(false, |q| q.contains("validate"));
+
+    if let Some(res) = check_auth(
+        &req,
+        state.clone(),
+        if full {
+            AccessType::Get
+        } else {
+            AccessType::Put
+        },
+    ) {
+        warn!("Unauthorized access for list chunks {}", bucket);
+        return res;
+    }
+
+    tryfut!(
+        check_hash(bucket.as_ref()),
+        StatusCode::BAD_REQUEST,
+        "Bad bucket"
+    );
+
+    // If a limit is given at most limit chunks with hashes after after are listed,
+    // the hash to list after for the next page is returned in the X-Next-After header
+    let after = query_param(&req, "after").unwrap_or("");
+    if !after.is_empty() {
+        tryfut!(check_hash(after), StatusCode::BAD_REQUEST, "Bad after");
+    }
+    let limit: Option<i64> = match query_param(&req, "limit") {
+        Some(limit) => Some(tryfut!(limit.parse(), StatusCode::BAD_REQUEST, "Bad limit")),
+        None => None,
+    };
+
+    let rows: Vec<(String, i64, Option<i64>, bool)> = {
+        let conn = tryfut!(
+            state.read_pool.get(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Database unavailable"
+        );
+        let rows = tryfut!(
+            conn.prepare_cached(
+                "SELECT hash, size, length(content), compressed FROM chunks WHERE bucket=? AND hash > ?
+                 ORDER BY hash LIMIT ?",
+            )
+            .and_then(|mut stmt| stmt
+                .query_map(params![bucket, after, limit.unwrap_or(-1)], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+                })
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        );
+        rows
+    };
+    let next_after = match limit {
+        Some(limit) if rows.len() as i64 == limit => rows.last().map(|row| row.0.clone()),
+        _ => None,
+    };
+
+    let mut ans = "".to_string();
+    for (chunk, size, content_size, compressed) in rows {
+        if full {
+            // The size of compressed content does not tell if it is all there,
+            // so report the size of the chunk if it is there at all
+            let content_size = match content_size {
+                Some(_) if compressed => size,
+                Some(v) => v,
+                None => match state.store.size(&bucket, &chunk).await {
+                    Ok(_) if compressed => size,
+                    Ok(size) => size as i64,
+                    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => -1,
+                    Err(e) => {
+                        return handle_error!(
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Unable to access metadata",
+                            e
+                        )
+                    }
+                },
+            };
+            ans.push_str(&format!("{} {} {}\n", chunk, size, content_size));
+        } else {
+            ans.push_str(&format!("{} {}\n", chunk, size));
+        }
+    }
+    let mut res = Response::builder().status(StatusCode::OK);
+    if let Some(next_after) = next_after {
+        res = res.header("X-Next-After", next_after);
+    }
+    Ok(res.body(Body::from(ans)).unwrap())
+}
+
+/// The status of a bucket as json
+#[derive(Serialize)]
+struct BucketStatus {
+    /// The time of the last delete, 0 if nothing was ever deleted
+    last_delete: i64,
+    bucket: String,
+}
+
+/// Return the time of the last delete in a bucket, as text or json
+async fn handle_get_status(
+    bucket: String,
+    req: Request<Body>,
+    state: Arc<State>,
+) -> ResponseFuture {
+    if let Some(res) = check_auth(&req, state.clone(), AccessType::Put) {
+        warn!("Unauthorized access for get status {}", bucket);
+        return res;
+    }
+    tryfut!(
+        check_hash(bucket.as_ref()),
+        StatusCode::BAD_REQUEST,
+        "Bad bucket"
+    );
+
+    let conn = tryfut!(
+        state.read_pool.get(),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Database unavailable"
+    );
+    let time: Option<i64> = tryfut!(
+        conn.prepare_cached("SELECT time FROM deletes WHERE bucket=?")
+            .and_then(|mut stmt| stmt.query_row(params![bucket], |row| row.get(0)).optional()),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Query failed"
+    );
+    let time = time.unwrap_or(0);
+    if wants_json(&req) {
+        return json_message(&BucketStatus {
+            last_delete: time,
+            bucket,
+        });
+    }
     ok_message(Some(format!("{}", time)))
 }
 
-async fn handle_get_roots(bucket: String, req: Request<Body>, state: Arc<State>) -> ResponseFuture {
+/// Check if an If-None-Match header matches etag
+fn etag_matches(req: &Request<Body>, etag: &str) -> bool {
+    match req.headers().get(IF_NONE_MATCH).map(|v| v.to_str()) {
+        Some(Ok(v)) => v
+            .split(',')
+            .map(str::trim)
+            .any(|v| v == "*" || v == etag || v.trim_start_matches("W/") == etag),
+        _ => false,
+    }
+}
+
+/// A root as listed by /roots in json
+#[derive(Serialize)]
+struct RootInfo {
+    id: i64,
+    host: String,
+    time: i64,
+    hash: String,
+    tags: Vec<String>,
+}
+
+/// A host backing up to a bucket as listed in json
+#[derive(Serialize)]
+struct HostInfo {
+    host: String,
+    /// The time of the newest root of the host
+    last_time: i64,
+    roots: i64,
+}
+
+/// List the hosts with roots in a bucket
+///
+/// Each host is listed on a line with the time of its newest root and its number of roots,
+/// separated by spaces, or as a json array if the client asks for json
+async fn handle_get_hosts(bucket: String, req: Request<Body>, state: Arc<State>) -> ResponseFuture {
+    if let Some(res) = check_auth(&req, state.clone(), AccessType::Get) {
+        warn!("Unauthorized access for get hosts {}", bucket);
+        return res;
+    }
+    tryfut!(
+        check_hash(bucket.as_ref()),
+        StatusCode::BAD_REQUEST,
+        "Bad bucket"
+    );
+
+    let rows: Vec<(String, i64, i64)> = {
+        let conn = tryfut!(
+            state.read_pool.get(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Database unavailable"
+        );
+        tryfut!(
+            conn.prepare_cached(
+                "SELECT host, max(time), count(*) FROM roots WHERE bucket=?
+                 GROUP BY host ORDER BY host"
+            )
+            .and_then(|mut stmt| stmt
+                .query_map(params![bucket], |row| Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?
+                )))
+                .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        )
+    };
+
+    if wants_json(&req) {
+        let hosts: Vec<HostInfo> = rows
+            .into_iter()
+            .map(|(host, last_time, roots)| HostInfo {
+                host,
+                last_time,
+                roots,
+            })
+            .collect();
+        return json_message(&hosts);
+    }
+    let mut ans = "".to_string();
+    for (host, last_time, roots) in rows {
+        ans.push_str(&format!("{} {} {}\n", host, last_time, roots));
+    }
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(VARY, "Accept")
+        .body(Body::from(ans))
+        .unwrap())
+}
+
+/// List the roots of a bucket
+///
+/// The roots are listed as id, host, time, hash and, for roots with tags, the comma separated
+/// tags separated by \0, with \0\0 between roots, or as a json array if the client asks for json.
+/// The response carries an etag that changes whenever roots are added or removed,
+/// if it matches If-None-Match only 304 is returned. For head requests only the etag is returned
+async fn handle_get_roots(
+    bucket: String,
+    req: Request<Body>,
+    state: Arc<State>,
+    head: bool,
+) -> ResponseFuture {
     if let Some(res) = check_auth(&req, state.clone(), AccessType::Get) {
         warn!("Unauthorized access for get roots {}", bucket);
         return res;
@@ -544,35 +1498,139 @@ async fn handle_get_roots(bucket: String, req: Request<Body>, state: Arc<State>)
         "Bad bucket"
     );
 
+    let json = wants_json(&req);
+    let etag = {
+        let conn = tryfut!(
+            state.read_pool.get(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Database unavailable"
+        );
+        let (count, max_id, max_time): (i64, Option<i64>, Option<i64>) = tryfut!(
+            conn.prepare_cached("SELECT count(*), max(id), max(time) FROM roots WHERE bucket=?")
+                .and_then(|mut stmt| stmt.query_row(params![bucket], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        );
+        // The two formats are different representations, so they have different etags
+        format!(
+            "\"{}-{}-{}{}\"",
+            count,
+            max_id.unwrap_or(0),
+            max_time.unwrap_or(0),
+            if json { "-json" } else { "" }
+        )
+    };
+
+    if etag_matches(&req, &etag) {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(ETAG, etag)
+            .header(VARY, "Accept")
+            .body(Body::from(""))
+            .unwrap());
+    }
+    if head {
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(ETAG, etag)
+            .header(VARY, "Accept")
+            .body(Body::from(""))
+            .unwrap());
+    }
+
     let ans = {
-        let conn = state.conn.lock().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT id, host, time, hash FROM roots WHERE bucket=?")
-            .unwrap();
+        let conn = tryfut!(
+            state.read_pool.get(),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Database unavailable"
+        );
+        let rows: Vec<(i64, String, i64, String, Option<String>)> = tryfut!(
+            conn.prepare_cached("SELECT id, host, time, hash, tags FROM roots WHERE bucket=?")
+                .and_then(|mut stmt| stmt
+                    .query_map(params![bucket], |row| {
+                        Ok((
+                            row.get(0)?,
+                            row.get(1)?,
+                            row.get(2)?,
+                            row.get(3)?,
+                            row.get(4)?,
+                        ))
+                    })
+                    .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())),
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Query failed"
+        );
+
+        if json {
+            let roots: Vec<RootInfo> = rows
+                .into_iter()
+                .map(|(id, host, time, hash, tags)| RootInfo {
+                    id,
+                    host,
+                    time,
+                    hash,
+                    tags: tags
+                        .as_ref()
+                        .map_or(Vec::new(), |t| t.split(',').map(str::to_string).collect()),
+                })
+                .collect();
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(ETAG, etag)
+                .header(CONTENT_TYPE, "application/json")
+                .header(VARY, "Accept")
+                .body(Body::from(serde_json::to_string(&roots).unwrap()))
+                .unwrap());
+        }
 
         let mut ans = "".to_string();
-        for t in stmt
-            .query_map(params![bucket], |row| {
-                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
-            })
-            .unwrap()
-        {
-            let t = t.unwrap();
-            let id: i64 = t.0;
-            let host: String = t.1;
-            let time: i64 = t.2;
-            let hash: String = t.3;
+        for (id, host, time, hash, tags) in rows {
             if !ans.is_empty() {
                 ans.push('\0');
                 ans.push('\0');
             }
             ans.push_str(&format!("{}\0{}\0{}\0{}", id, host, time, hash));
+            // Only roots with tags get the extra field, as an empty last field would run into
+            // the \0\0 separating the roots
+            if let Some(tags) = tags.filter(|t| !t.is_empty()) {
+                ans.push('\0');
+                ans.push_str(&tags);
+            }
         }
         ans
     };
-    ok_message(Some(ans))
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(ETAG, etag)
+        .header(VARY, "Accept")
+        .body(Body::from(ans))
+        .unwrap())
 }
 
+/// Check that the comma separated tags of a root are non empty and made of
+/// letters, digits, '-', '_' and '.'
+fn check_tags(tags: &str) -> std::result::Result<(), Error> {
+    if tags.len() > 1024 {
+        return Err(Error::Server("too many tags"));
+    }
+    for tag in tags.split(',') {
+        if tag.is_empty() {
+            return Err(Error::Server("empty tag"));
+        }
+        if !tag
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'-' || c == b'_' || c == b'.')
+        {
+            return Err(Error::Server("bad character in tag"));
+        }
+    }
+    Ok(())
+}
+
+/// Store a root of a bucket for a host, with the optional tags given
+/// in the tags query parameter or the X-Root-Tags header
 async fn handle_put_root(
     bucket: String,
     host: String,
@@ -594,6 +1652,18 @@ async fn handle_put_root(
         return handle_error!(StatusCode::BAD_REQUEST, "Bad host name", "");
     }
 
+    let tags = match query_param(&req, "tags") {
+        Some(tags) => Some(tags.to_string()),
+        None => match req.headers().get(ROOT_TAGS_HEADER).map(|v| v.to_str()) {
+            Some(Ok(tags)) => Some(tags.to_string()),
+            Some(Err(_)) => return handle_error!(StatusCode::BAD_REQUEST, "Bad tags", ""),
+            None => None,
+        },
+    };
+    if let Some(tags) = &tags {
+        tryfut!(check_tags(tags), StatusCode::BAD_REQUEST, "Bad tags");
+    }
+
     let mut body = req.into_body();
     let mut v = Vec::new();
     while let Some(chunk) = body.data().await {
@@ -612,11 +1682,11 @@ async fn handle_put_root(
     );
 
     {
-        let conn = state.conn.lock().unwrap();
+        let conn = state.lock_conn();
         tryfut!(
                 conn.execute(
-                    "INSERT INTO roots (bucket, host, time, hash) VALUES (?, ?, strftime('%s', 'now'), ?)",
-                    params![&bucket, &host, &s],
+                    "INSERT INTO roots (bucket, host, time, hash, tags) VALUES (?, ?, strftime('%s', 'now'), ?, ?)",
+                    params![&bucket, &host, &s, &tags],
                 ),
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Insert failed",
@@ -640,7 +1710,7 @@ async fn handle_delete_root(
         StatusCode::BAD_REQUEST,
         "Bad bucket"
     );
-    let res = state.conn.lock().unwrap().execute(
+    let res = state.lock_conn().execute(
         "DELETE FROM roots WHERE bucket=? AND id=?",
         params![bucket, root],
     );
@@ -651,17 +1721,360 @@ async fn handle_delete_root(
     }
 }
 
-pub async fn backup_serve(req: Request<Body>, state: Arc<State>) -> ResponseFuture {
+/// The usage of a bucket as reported by /stats
+#[derive(Serialize)]
+struct BucketStats {
+    chunks: i64,
+    bytes: i64,
+    /// Chunks stored in the database
+    inline_chunks: i64,
+    inline_bytes: i64,
+    /// Chunks stored in the chunk store
+    stored_chunks: i64,
+    stored_bytes: i64,
+    roots: i64,
+    hosts: i64,
+}
+
+/// Report the space used by a bucket as json
+async fn handle_get_stats(bucket: String, req: Request<Body>, state: Arc<State>) -> ResponseFuture {
+    if let Some(res) = check_auth(&req, state.clone(), AccessType::Get) {
+        warn!("Unauthorized access for get stats {}", bucket);
+        return res;
+    }
+    tryfut!(
+        check_hash(bucket.as_ref()),
+        StatusCode::BAD_REQUEST,
+        "Bad bucket"
+    );
+
+    let conn = tryfut!(
+        state.read_pool.get(),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Database unavailable"
+    );
+    let (inline_chunks, inline_bytes, stored_chunks, stored_bytes) = tryfut!(
+        conn.prepare_cached(
+            "SELECT
+                count(content),
+                coalesce(sum(CASE WHEN content IS NOT NULL THEN size END), 0),
+                count(*) - count(content),
+                coalesce(sum(CASE WHEN content IS NULL THEN size END), 0)
+            FROM chunks WHERE bucket=?"
+        )
+        .and_then(|mut stmt| stmt.query_row(params![bucket], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+        })),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Query failed"
+    );
+    let (roots, hosts) = tryfut!(
+        conn.prepare_cached("SELECT count(*), count(DISTINCT host) FROM roots WHERE bucket=?")
+            .and_then(
+                |mut stmt| stmt.query_row(params![bucket], |row| Ok((row.get(0)?, row.get(1)?)))
+            ),
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Query failed"
+    );
+    let stats = BucketStats {
+        chunks: inline_chunks + stored_chunks,
+        bytes: inline_bytes + stored_bytes,
+        inline_chunks,
+        inline_bytes,
+        stored_chunks,
+        stored_bytes,
+        roots,
+        hosts,
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&stats).unwrap()))
+        .unwrap())
+}
+
+/// The sizes of the database before and after maintenance as reported by /maintenance
+#[derive(Serialize)]
+struct MaintenanceInfo {
+    bytes_before: u64,
+    wal_bytes_before: u64,
+    bytes_after: u64,
+    wal_bytes_after: u64,
+}
+
+/// The size in bytes of the database file and its write-ahead log, 0 for in memory databases
+fn db_file_sizes(state: &State) -> (u64, u64) {
+    let path = db_path(&state.config);
+    let size = |path: &str| std::fs::metadata(path).map_or(0, |m| m.len());
+    (size(&path), size(&format!("{}-wal", path)))
+}
+
+/// VACUUM the database and truncate the write-ahead log, returns None if a gc is running
+///
+/// This blocks, and holds the write connection while it runs
+fn run_maintenance(state: &State) -> rusqlite::Result<Option<MaintenanceInfo>> {
+    let conn = state.lock_conn();
+    // A running gc would wait between its batches for as long as this holds the connection
+    if !lock(&state.gc_buckets).is_empty() {
+        return Ok(None);
+    }
+    let (bytes_before, wal_bytes_before) = db_file_sizes(state);
+    let start = Instant::now();
+    conn.execute_batch("VACUUM")?;
+    // The checkpoint cannot complete while readers use the log, the log is then left as it is
+    let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", NO_PARAMS, |row| {
+        row.get(0)
+    })?;
+    if busy != 0 {
+        warn!("The write-ahead log was in use and was not truncated");
+    }
+    let (bytes_after, wal_bytes_after) = db_file_sizes(state);
+    drop(conn);
+    info!(
+        "Maintenance took {:?}, the database went from {} to {} bytes",
+        start.elapsed(),
+        bytes_before + wal_bytes_before,
+        bytes_after + wal_bytes_after
+    );
+    Ok(Some(MaintenanceInfo {
+        bytes_before,
+        wal_bytes_before,
+        bytes_after,
+        wal_bytes_after,
+    }))
+}
+
+/// VACUUM the database to return the pages freed by deletes, and truncate the write-ahead log
+///
+/// All other writes wait for it, while reads of the database continue. It is refused with
+/// 409 Conflict while a gc is running, as the gc would be held up until it is done.
+async fn handle_maintenance(req: Request<Body>, state: Arc<State>) -> ResponseFuture {
+    if let Some(res) = check_auth(&req, state.clone(), AccessType::Delete) {
+        warn!("Unauthorized access for maintenance");
+        return res;
+    }
+    if !state.config.allow_maintenance {
+        return handle_error!(StatusCode::FORBIDDEN, "Maintenance not allowed", "");
+    }
+
+    let info = tryfut!(
+        tokio::task::spawn_blocking(move || run_maintenance(&state)).await,
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "Maintenance failed"
+    );
+    let info = match tryfut!(info, StatusCode::INTERNAL_SERVER_ERROR, "Vacuum failed") {
+        Some(info) => info,
+        None => return handle_error!(StatusCode::CONFLICT, "Gc running", ""),
+    };
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(serde_json::to_string(&info).unwrap()))
+        .unwrap())
+}
+
+/// Check that the server can query its database, for liveness probes
+///
+/// If ready is true also check that data_dir is writable, for readiness probes
+async fn handle_health(state: Arc<State>, ready: bool) -> ResponseFuture {
+    let version: rusqlite::Result<i64> = match state.read_pool.get() {
+        Ok(conn) => conn
+            .prepare_cached("SELECT version FROM schema_version")
+            .and_then(|mut stmt| stmt.query_row(NO_PARAMS, |row| row.get(0))),
+        Err(e) => return handle_error!(StatusCode::SERVICE_UNAVAILABLE, "Database unavailable", e),
+    };
+    tryfut!(
+        version,
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Database unavailable"
+    );
+    if ready && state.config.storage != Storage::Memory {
+        let path = format!("{}/.ready_{}", state.config.data_dir, rand::random::<u64>());
+        tryfut!(
+            tokio::fs::write(&path, b"").await,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Data dir not writable"
+        );
+        tryfut!(
+            tokio::fs::remove_file(&path).await,
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Data dir not writable"
+        );
+    }
+    ok_message(Some("ok".to_string()))
+}
+
+/// Report the server metrics in the Prometheus text format
+async fn handle_get_metrics(req: Request<Body>, state: Arc<State>) -> ResponseFuture {
+    if let Some(res) = check_auth(&req, state.clone(), AccessType::Get) {
+        warn!("Unauthorized access for get metrics");
+        return res;
+    }
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Body::from(state.metrics.render()))
+        .unwrap())
+}
+
+/// The allowed origin of a request from a browser: its Origin if that is one of the cors_origins,
+/// or "*" if any origin is allowed
+fn cors_origin(req: &Request<Body>, state: &State) -> Option<HeaderValue> {
+    let origin = req.headers().get(ORIGIN)?;
+    let origins = &state.config.cors_origins;
+    if origins.iter().any(|o| o.as_bytes() == origin.as_bytes()) {
+        Some(origin.clone())
+    } else if origins.iter().any(|o| o == "*") {
+        Some(HeaderValue::from_static("*"))
+    } else {
+        None
+    }
+}
+
+/// Let the browser of an allowed origin send requests and read the responses
+///
+/// Only origins listed by name may send credentials, browsers refuse credentials with "*"
+/// and the server must not let every site use the cookies or certificates of the browser
+fn add_cors_headers(res: &mut Response<Body>, origin: HeaderValue) {
+    let headers = res.headers_mut();
+    if origin != "*" {
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static("ETag, Content-Range, Retry-After"),
+    );
+    headers.append(VARY, HeaderValue::from_static("Origin"));
+}
+
+/// Answer the preflight a browser sends before a cross origin request
+///
+/// Preflights carry no credentials, so they are answered before authentication. Origins that
+/// are not allowed get no Access-Control headers, which makes the browser refuse the request
+fn handle_preflight(origin: Option<HeaderValue>, state: &State) -> ResponseFuture {
+    let mut res = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::from(""))
+        .unwrap();
+    if let Some(origin) = origin {
+        add_cors_headers(&mut res, origin);
+        let headers = res.headers_mut();
+        let methods = state.config.cors_methods.join(", ");
+        let allowed_headers = state.config.cors_headers.join(", ");
+        if let (Ok(methods), Ok(allowed_headers)) = (
+            HeaderValue::from_str(&methods),
+            HeaderValue::from_str(&allowed_headers),
+        ) {
+            headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("3600"));
+    }
+    Ok(res)
+}
+
+pub async fn backup_serve(
+    mut req: Request<Body>,
+    state: Arc<State>,
+    remote: Option<IpAddr>,
+    client_cert: Option<String>,
+) -> ResponseFuture {
+    let user = AuthenticatedUser::default();
+    req.extensions_mut().insert(RemoteAddr(remote));
+    req.extensions_mut().insert(ClientCert(client_cert));
+    req.extensions_mut().insert(user.clone());
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let start = Instant::now();
+    let origin = cors_origin(&req, &state);
+    let res =
+        if method == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD) {
+            handle_preflight(origin, &state)
+        } else {
+            let mut res = route(req, state.clone()).await;
+            if let (Ok(res), Some(origin)) = (&mut res, origin) {
+                add_cors_headers(res, origin);
+            }
+            res
+        };
+    let duration = start.elapsed();
+    state.metrics.observe_request(&method, duration);
+
+    // Log every request, errors not turned into a response make hyper close the connection
+    let (status, bytes) = match &res {
+        Ok(res) => (
+            res.status().as_u16(),
+            res.body().size_hint().exact().or_else(|| {
+                res.headers()
+                    .get(CONTENT_LENGTH)?
+                    .to_str()
+                    .ok()?
+                    .parse()
+                    .ok()
+            }),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR.as_u16(), None),
+    };
+    let user = lock(&user.0).clone();
+    let remote = remote.map(|ip| ip.to_string());
+    info!(
+        target: "access",
+        remote = remote.as_deref().unwrap_or("-"),
+        user = user.as_deref().unwrap_or("anon"),
+        method = method.as_str(),
+        path = path.as_str(),
+        status = status,
+        bytes = bytes.unwrap_or(0),
+        duration = duration.as_secs_f64();
+        "request"
+    );
+    res
+}
+
+async fn route(req: Request<Body>, state: Arc<State>) -> ResponseFuture {
     let path: Vec<String> = req
         .uri()
         .path()
         .split('/')
         .map(std::string::ToString::to_string)
         .collect();
-    if req.method() == Method::GET && path.len() == 3 && path[1] == "status" {
+    // Only PUT, DELETE and POST to gc and maintenance change anything
+    let writes = req.method() == Method::PUT
+        || req.method() == Method::DELETE
+        || (req.method() == Method::POST
+            && path.len() > 1
+            && (path[1] == "gc" || path[1] == "maintenance"));
+    if writes && state.config.read_only {
+        return handle_error!(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Read only server",
+            req.uri()
+        );
+    }
+    if req.method() == Method::GET && path.len() == 2 && path[1] == "health" {
+        handle_health(state, false).await
+    } else if req.method() == Method::GET && path.len() == 2 && path[1] == "ready" {
+        handle_health(state, true).await
+    } else if req.method() == Method::GET && path.len() == 2 && path[1] == "metrics" {
+        handle_get_metrics(req, state).await
+    } else if req.method() == Method::POST && path.len() == 2 && path[1] == "maintenance" {
+        handle_maintenance(req, state).await
+    } else if req.method() == Method::GET && path.len() == 3 && path[1] == "stats" {
+        handle_get_stats(path[2].clone(), req, state).await
+    } else if req.method() == Method::GET && path.len() == 3 && path[1] == "status" {
         handle_get_status(path[2].clone(), req, state).await
     } else if req.method() == Method::GET && path.len() == 4 && path[1] == "chunks" {
         handle_get_chunk(path[2].clone(), path[3].clone(), req, state, false).await
+    } else if req.method() == Method::POST
+        && path.len() == 4
+        && path[1] == "chunks"
+        && path[3] == "exists"
+    {
+        handle_chunks_exist(path[2].clone(), req, state).await
     } else if req.method() == Method::PUT && path.len() == 4 && path[1] == "chunks" {
         handle_put_chunk(path[2].clone(), path[3].clone(), req, state).await
     } else if req.method() == Method::DELETE && path.len() == 3 && path[1] == "chunks" {
@@ -672,8 +2085,14 @@ pub async fn backup_serve(req: Request<Body>, state: Arc<State>) -> ResponseFutu
         handle_get_chunk(path[2].clone(), path[3].clone(), req, state, true).await
     } else if req.method() == Method::GET && path.len() == 3 && path[1] == "chunks" {
         handle_list_chunks(path[2].clone(), req, state).await
+    } else if req.method() == Method::POST && path.len() == 3 && path[1] == "gc" {
+        handle_gc(path[2].clone(), req, state).await
     } else if req.method() == Method::GET && path.len() == 3 && path[1] == "roots" {
-        handle_get_roots(path[2].clone(), req, state).await
+        handle_get_roots(path[2].clone(), req, state, false).await
+    } else if req.method() == Method::HEAD && path.len() == 3 && path[1] == "roots" {
+        handle_get_roots(path[2].clone(), req, state, true).await
+    } else if req.method() == Method::GET && path.len() == 3 && path[1] == "hosts" {
+        handle_get_hosts(path[2].clone(), req, state).await
     } else if req.method() == Method::PUT && path.len() == 4 && path[1] == "roots" {
         handle_put_root(path[2].clone(), path[3].clone(), req, state).await
     } else if req.method() == Method::DELETE && path.len() == 4 && path[1] == "roots" {
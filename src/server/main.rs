@@ -1,33 +1,84 @@
 //! This is the implementation for the mbackup server.
 //! It presents a REST api served over a hyper https server.
 
+extern crate argon2;
+extern crate blake2;
 extern crate clap;
+extern crate hex;
 extern crate hyper;
 extern crate rand;
 extern crate rusqlite;
 extern crate serde;
+extern crate serde_json;
+extern crate subtle;
 extern crate tokio;
 extern crate toml;
 #[macro_use]
 extern crate log;
+extern crate async_trait;
 extern crate base64;
 extern crate chrono;
+extern crate futures_util;
+extern crate r2d2;
+extern crate r2d2_sqlite;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+extern crate tokio_rustls;
+extern crate zstd;
 
+use futures_util::stream::StreamExt;
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
 use hyper::service::make_service_fn;
 use hyper::service::service_fn;
 use hyper::Server;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
+    RootCertStore, ServerConfig, Session,
+};
+use tokio_rustls::TlsAcceptor;
 
 mod config;
 mod error;
-use config::parse_config;
+use config::{parse_config, Command, Listen, LogFormat, Storage};
 use error::Error;
 mod handler;
-use handler::backup_serve;
+use handler::{backup_serve, cert_fingerprint};
+mod metrics;
+use metrics::Metrics;
+mod ratelimit;
+use ratelimit::RateLimiter;
+mod scrub;
 mod state;
-use state::{setup_db, State};
+use state::{setup_db, setup_read_pool, PasswordCache, State};
+mod store;
+use store::{open_store, remove_stale_uploads, reshard};
 
-struct Logger {}
+/// Collect the key values of a log record as strings
+struct Fields(Vec<(String, String)>);
+impl<'kvs> log::kv::Visitor<'kvs> for Fields {
+    fn visit_pair(
+        &mut self,
+        key: log::kv::Key<'kvs>,
+        value: log::kv::Value<'kvs>,
+    ) -> Result<(), log::kv::Error> {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+struct Logger {
+    /// Log a json object per line instead of plain text
+    json: AtomicBool,
+}
 impl log::Log for Logger {
     fn enabled(&self, _metadata: &log::Metadata) -> bool {
         true
@@ -40,41 +91,327 @@ impl log::Log for Logger {
         } else {
             record.module_path().unwrap_or_default()
         };
-        eprintln!(
-            "{} {:<5} [{}] {}",
-            chrono::Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
-            level_string,
-            target,
-            record.args()
-        );
+        let mut fields = Fields(Vec::new());
+        let _ = record.key_values().visit(&mut fields);
+        if self.json.load(Ordering::Relaxed) {
+            let mut line = serde_json::Map::new();
+            line.insert(
+                "timestamp".to_string(),
+                chrono::Local::now().to_rfc3339().into(),
+            );
+            line.insert("level".to_string(), level_string.into());
+            line.insert("target".to_string(), target.into());
+            line.insert("message".to_string(), record.args().to_string().into());
+            for (key, value) in fields.0 {
+                line.insert(key, value.into());
+            }
+            eprintln!("{}", serde_json::Value::Object(line));
+        } else {
+            let mut message = record.args().to_string();
+            for (key, value) in fields.0 {
+                message.push_str(&format!(" {}={}", key, value));
+            }
+            eprintln!(
+                "{} {:<5} [{}] {}",
+                chrono::Local::now().format("%Y-%m-%d %H:%M:%S,%3f"),
+                level_string,
+                target,
+                message
+            );
+        }
     }
 
     fn flush(&self) {}
 }
-static LOGGER: Logger = Logger {};
+static LOGGER: Logger = Logger {
+    json: AtomicBool::new(false),
+};
+
+/// How long to wait for requests in flight when shutting down
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait until we are asked to terminate by SIGTERM or SIGINT
+async fn shutdown_signal() {
+    let mut term = signal(SignalKind::terminate()).expect("Unable to handle SIGTERM");
+    let mut int = signal(SignalKind::interrupt()).expect("Unable to handle SIGINT");
+    tokio::select! {
+        _ = term.recv() => (),
+        _ = int.recv() => (),
+    }
+}
+
+/// Run the server until we are asked to terminate, then stop accepting connections
+/// and wait for the requests in flight to finish
+async fn serve_until_shutdown<F>(server: F, stop: oneshot::Sender<()>) -> Result<(), hyper::Error>
+where
+    F: Future<Output = Result<(), hyper::Error>>,
+{
+    tokio::pin!(server);
+    tokio::select! {
+        res = &mut server => return res,
+        _ = shutdown_signal() => info!("Shutting down"),
+    }
+    let _ = stop.send(());
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, server).await {
+        Ok(res) => res,
+        Err(_) => {
+            warn!("Requests still in flight after {:?}", SHUTDOWN_TIMEOUT);
+            Ok(())
+        }
+    }
+}
+
+/// Remove the socket at path left behind by a server that did not stop cleanly
+///
+/// A socket a server still listens on, and anything else at path, is left alone and makes
+/// binding fail
+fn remove_stale_socket(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(md) if md.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    "Another server is listening on the socket",
+                ));
+            }
+            std::fs::remove_file(path)
+        }
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// Build the acceptor of tls connections from the certificate and key of the server
+///
+/// If client_ca is given clients may present a certificate signed by it, and must if required is set
+fn tls_acceptor(
+    cert: &str,
+    key: &str,
+    client_ca: Option<&str>,
+    required: bool,
+) -> Result<TlsAcceptor, Box<dyn std::error::Error + Send + Sync>> {
+    let certs = pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(cert)?))
+        .map_err(|_| Error::Server("Unable to parse tls certificate"))?;
+    let mut keys =
+        pemfile::pkcs8_private_keys(&mut std::io::BufReader::new(std::fs::File::open(key)?))
+            .map_err(|_| Error::Server("Unable to parse tls key"))?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut std::io::BufReader::new(std::fs::File::open(key)?))
+            .map_err(|_| Error::Server("Unable to parse tls key"))?;
+    }
+    if keys.is_empty() {
+        return Err(Box::new(Error::Server("No private key in tls key file")));
+    }
+    let client_auth = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            roots
+                .add_pem_file(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+                .map_err(|_| Error::Server("Unable to parse tls client ca"))?;
+            if required {
+                AllowAnyAuthenticatedClient::new(roots)
+            } else {
+                AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            }
+        }
+        None => NoClientAuth::new(),
+    };
+    let mut tls_config = ServerConfig::new(client_auth);
+    tls_config.set_single_cert(certs, keys.remove(0))?;
+    Ok(TlsAcceptor::from(Arc::new(tls_config)))
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     log::set_logger(&LOGGER).unwrap();
 
-    let config = parse_config();
+    let (config, command) = parse_config();
     log::set_max_level(config.verbosity);
+    LOGGER
+        .json
+        .store(config.log_format == LogFormat::Json, Ordering::Relaxed);
 
     debug!("Config {:?}", config);
+    if let Command::Scrub { bucket } = command {
+        if config.storage == Storage::Memory {
+            error!("There is nothing to scrub in memory storage");
+            std::process::exit(1)
+        }
+        let conn = setup_db(&config);
+        let store = open_store(&config);
+        let bad = scrub::scrub(&conn, &*store, bucket.as_deref()).await?;
+        std::process::exit(if bad == 0 { 0 } else { 1 })
+    }
+    if let Command::Reshard = command {
+        if config.storage != Storage::Local {
+            error!("Only local storage is sharded");
+            std::process::exit(1)
+        }
+        let moved = reshard(&config.data_dir, config.shard_depth)?;
+        info!(
+            "Moved {} chunks to shard depth {}",
+            moved, config.shard_depth
+        );
+        std::process::exit(0)
+    }
+    if let Err(e) = remove_stale_uploads(&config.data_dir, config.upload_max_age) {
+        warn!("Unable to remove stale uploads: {:?}", e);
+    }
     let conn = Mutex::new(setup_db(&config));
-    let state = Arc::new(State { config, conn });
-    let addr = state.config.bind.parse().expect("Bad bind address");
-    let bind = state.config.bind.clone();
-
-    let service = make_service_fn(move |_| {
-        let state = state.clone();
-        async { Ok::<_, Error>(service_fn(move |req| backup_serve(req, state.clone()))) }
+    let read_pool = setup_read_pool(&config);
+    let store = open_store(&config);
+    let rate_limiter = RateLimiter::new(config.rate_limit, config.rate_limit_burst);
+    let state = Arc::new(State {
+        config,
+        conn,
+        read_pool,
+        store,
+        metrics: Metrics::default(),
+        bucket_bytes: Mutex::new(HashMap::new()),
+        gc_buckets: Mutex::new(HashSet::new()),
+        rate_limiter,
+        passwords: PasswordCache::default(),
     });
+    // The address was checked by parse_config
+    let listen = Listen::parse(&state.config.bind).expect("Bad bind address");
+    let db_state = state.clone();
+
+    if state.config.upload_sweep_interval != 0 {
+        let data_dir = state.config.data_dir.clone();
+        let max_age = state.config.upload_max_age;
+        let mut interval =
+            tokio::time::interval(Duration::from_secs(state.config.upload_sweep_interval));
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                let data_dir = data_dir.clone();
+                match tokio::task::spawn_blocking(move || remove_stale_uploads(&data_dir, max_age))
+                    .await
+                {
+                    Ok(Ok(())) => (),
+                    Ok(Err(e)) => warn!("Unable to remove stale uploads: {:?}", e),
+                    Err(e) => warn!("Unable to remove stale uploads: {:?}", e),
+                }
+            }
+        });
+    }
+    let (stop, stopped) = oneshot::channel::<()>();
+    let stopped = async {
+        let _ = stopped.await;
+    };
 
-    let server = Server::bind(&addr).serve(service);
-    info!("Server listening on {}", &bind);
-    info!("Notify started HgWiE0XJQKoFzmEzLuR9Tv0bcyWK0AR7N");
-    server.await?;
+    match (listen, &state.config.tls_cert, &state.config.tls_key) {
+        (Listen::Unix(path), _, _) => {
+            remove_stale_socket(&path)?;
+            let mut listener = tokio::net::UnixListener::bind(&path)?;
+            let service = make_service_fn(move |_: &tokio::net::UnixStream| {
+                let state = state.clone();
+                async move {
+                    Ok::<_, Error>(service_fn(move |req| {
+                        backup_serve(req, state.clone(), None, None)
+                    }))
+                }
+            });
+            let server = Server::builder(accept::from_stream(listener.incoming()))
+                .serve(service)
+                .with_graceful_shutdown(stopped);
+            info!("Server listening on unix:{}", &path);
+            info!("Notify started HgWiE0XJQKoFzmEzLuR9Tv0bcyWK0AR7N");
+            let res = serve_until_shutdown(server, stop).await;
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Unable to remove socket {}: {:?}", path, e);
+            }
+            res?;
+        }
+        (Listen::Tcp(addr), Some(cert), Some(key)) => {
+            let acceptor = tls_acceptor(
+                cert,
+                key,
+                state.config.tls_client_ca.as_deref(),
+                state.config.tls_client_required,
+            )?;
+            let mut listener = tokio::net::TcpListener::bind(&addr).await?;
+            let local_addr = listener.local_addr()?;
+            // Each handshake runs in its own task, so that a slow client does not hold up
+            // the others. The connections that complete their handshake are served
+            let (tx, rx) = mpsc::channel(64);
+            tokio::spawn(async move {
+                loop {
+                    let stream = match listener.accept().await {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            warn!("Unable to accept connection: {:?}", e);
+                            // Out of file descriptors, give the open connections time to finish
+                            tokio::time::delay_for(Duration::from_millis(100)).await;
+                            continue;
+                        }
+                    };
+                    let acceptor = acceptor.clone();
+                    let mut tx = tx.clone();
+                    tokio::spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => {
+                                // Fails only when the server has stopped
+                                let _ = tx.send(stream).await;
+                            }
+                            Err(e) => warn!("Tls handshake failed: {:?}", e),
+                        }
+                    });
+                }
+            });
+            let incoming = rx.map(Ok::<_, std::io::Error>);
+            let service = make_service_fn(
+                move |conn: &tokio_rustls::server::TlsStream<tokio::net::TcpStream>| {
+                    let state = state.clone();
+                    let remote = conn.get_ref().0.peer_addr().ok().map(|a| a.ip());
+                    // The certificate chain starts with the certificate of the client
+                    let client_cert = conn
+                        .get_ref()
+                        .1
+                        .get_peer_certificates()
+                        .and_then(|certs| certs.first().map(|cert| cert_fingerprint(&cert.0)));
+                    async move {
+                        Ok::<_, Error>(service_fn(move |req| {
+                            backup_serve(req, state.clone(), remote, client_cert.clone())
+                        }))
+                    }
+                },
+            );
+            let server = Server::builder(accept::from_stream(incoming))
+                .serve(service)
+                .with_graceful_shutdown(stopped);
+            info!("Server listening on https://{}", local_addr);
+            info!("Notify started HgWiE0XJQKoFzmEzLuR9Tv0bcyWK0AR7N");
+            serve_until_shutdown(server, stop).await?;
+        }
+        (Listen::Tcp(addr), _, _) => {
+            let service = make_service_fn(move |conn: &AddrStream| {
+                let state = state.clone();
+                let remote = Some(conn.remote_addr().ip());
+                async move {
+                    Ok::<_, Error>(service_fn(move |req| {
+                        backup_serve(req, state.clone(), remote, None)
+                    }))
+                }
+            });
+            let server = Server::try_bind(&addr)?.serve(service);
+            info!("Server listening on {}", server.local_addr());
+            let server = server.with_graceful_shutdown(stopped);
+            info!("Notify started HgWiE0XJQKoFzmEzLuR9Tv0bcyWK0AR7N");
+            serve_until_shutdown(server, stop).await?;
+        }
+    }
+
+    // Move the content of the wal into the database, so that it is complete on its own
+    if let Err(e) = db_state
+        .lock_conn()
+        .execute_batch("PRAGMA wal_checkpoint(TRUNCATE)")
+    {
+        warn!("Unable to checkpoint the database: {:?}", e);
+    }
+    info!("Server stopped");
 
     Ok(())
 }
@@ -0,0 +1,142 @@
+use hyper::Method;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds in seconds of the request duration histogram buckets
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The methods we keep separate request duration histograms for
+const METHODS: [&str; 6] = ["GET", "HEAD", "PUT", "POST", "DELETE", "OTHER"];
+
+/// A histogram of durations that can be updated concurrently
+#[derive(Default)]
+struct Histogram {
+    buckets: [AtomicU64; 11],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(self.buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters describing the work done by the server since it was started
+#[derive(Default)]
+pub struct Metrics {
+    pub chunks_put: AtomicU64,
+    pub chunks_deduped: AtomicU64,
+    pub bytes_stored: AtomicU64,
+    pub chunks_get: AtomicU64,
+    pub bytes_served: AtomicU64,
+    pub chunks_deleted: AtomicU64,
+    durations: [Histogram; 6],
+}
+
+impl Metrics {
+    /// Increment a counter
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Record the time taken to handle a request
+    pub fn observe_request(&self, method: &Method, duration: Duration) {
+        let idx = match *method {
+            Method::GET => 0,
+            Method::HEAD => 1,
+            Method::PUT => 2,
+            Method::POST => 3,
+            Method::DELETE => 4,
+            _ => 5,
+        };
+        self.durations[idx].observe(duration);
+    }
+
+    /// Render the metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            (
+                "chunks_put_total",
+                "Number of chunks stored",
+                &self.chunks_put,
+            ),
+            (
+                "chunks_deduped_total",
+                "Number of chunk puts rejected since the chunk was already there",
+                &self.chunks_deduped,
+            ),
+            (
+                "bytes_stored_total",
+                "Number of bytes of chunks stored",
+                &self.bytes_stored,
+            ),
+            (
+                "chunks_get_total",
+                "Number of chunks served",
+                &self.chunks_get,
+            ),
+            (
+                "bytes_served_total",
+                "Number of bytes of chunks served",
+                &self.bytes_served,
+            ),
+            (
+                "chunks_deleted_total",
+                "Number of chunks deleted",
+                &self.chunks_deleted,
+            ),
+        ];
+        for (name, help, counter) in counters.iter() {
+            writeln!(out, "# HELP mbackup_{} {}", name, help).unwrap();
+            writeln!(out, "# TYPE mbackup_{} counter", name).unwrap();
+            writeln!(out, "mbackup_{} {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+
+        let name = "mbackup_request_duration_seconds";
+        writeln!(out, "# HELP {} Time taken to handle requests", name).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for (method, h) in METHODS.iter().zip(self.durations.iter()) {
+            for (bound, bucket) in DURATION_BUCKETS.iter().zip(h.buckets.iter()) {
+                writeln!(
+                    out,
+                    "{}_bucket{{method=\"{}\",le=\"{}\"}} {}",
+                    name,
+                    method,
+                    bound,
+                    bucket.load(Ordering::Relaxed)
+                )
+                .unwrap();
+            }
+            let count = h.count.load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "{}_bucket{{method=\"{}\",le=\"+Inf\"}} {}",
+                name, method, count
+            )
+            .unwrap();
+            writeln!(
+                out,
+                "{}_sum{{method=\"{}\"}} {}",
+                name,
+                method,
+                h.sum_micros.load(Ordering::Relaxed) as f64 / 1e6
+            )
+            .unwrap();
+            writeln!(out, "{}_count{{method=\"{}\"}} {}", name, method, count).unwrap();
+        }
+        out
+    }
+}
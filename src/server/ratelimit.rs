@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::state::lock;
+
+/// Forget the buckets of clients that have been idle long enough to be full again
+/// once we track this many clients
+const MAX_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket per client limiting the rate of requests
+///
+/// Each bucket holds at most burst requests and is refilled with rate requests per second
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter {
+            rate,
+            burst: f64::max(burst as f64, 1.0),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a request from the bucket of a client
+    ///
+    /// Returns how long to wait before the next request is allowed if the bucket is empty
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let mut buckets = lock(&self.buckets);
+        if buckets.len() >= MAX_CLIENTS && !buckets.contains_key(client) {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets
+                .retain(|_, b| b.tokens + now.duration_since(b.last).as_secs_f64() * rate < burst);
+        }
+        let bucket = buckets.entry(client.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            last: now,
+        });
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = f64::min(self.burst, bucket.tokens + elapsed * self.rate);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// How long to wait before the next request of a client is allowed, like check,
+    /// but without taking a request from its bucket
+    pub fn peek(&self, client: &str) -> Result<(), Duration> {
+        if self.rate <= 0.0 {
+            return Ok(());
+        }
+        let buckets = lock(&self.buckets);
+        let bucket = match buckets.get(client) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        let elapsed = Instant::now().duration_since(bucket.last).as_secs_f64();
+        let tokens = f64::min(self.burst, bucket.tokens + elapsed * self.rate);
+        if tokens < 1.0 {
+            return Err(Duration::from_secs_f64((1.0 - tokens) / self.rate));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,51 @@
+//! An offline check that the chunks not stored in the database are in the chunk store
+use rusqlite::{params, Connection};
+use std::io::ErrorKind;
+
+use crate::store::ChunkStore;
+
+/// Check that every chunk of bucket, or of every bucket if none is given, that is not stored in
+/// the database is in the chunk store with the size recorded in the database
+///
+/// Compressed chunks are read and decompressed to find their size.
+/// A line is printed for each bad chunk, and the number of bad chunks is returned
+pub async fn scrub(
+    conn: &Connection,
+    store: &dyn ChunkStore,
+    bucket: Option<&str>,
+) -> rusqlite::Result<u64> {
+    let rows: Vec<(String, String, i64, bool)> = conn
+        .prepare(
+            "SELECT bucket, hash, size, compressed FROM chunks
+             WHERE content IS NULL AND (?1 IS NULL OR bucket = ?1) ORDER BY bucket, hash",
+        )
+        .and_then(|mut stmt| {
+            stmt.query_map(params![bucket], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .and_then(|rows| rows.collect::<Result<Vec<_>, _>>())
+        })?;
+
+    let mut bad = 0;
+    for (bucket, chunk, size, compressed) in rows.iter() {
+        let stored = if *compressed {
+            store
+                .get(bucket, chunk)
+                .await
+                .and_then(|content| zstd::decode_all(&content[..]))
+                .map(|content| content.len() as u64)
+        } else {
+            store.size(bucket, chunk).await
+        };
+        let problem = match stored {
+            Ok(stored) if stored == *size as u64 => continue,
+            Ok(stored) => format!("size {} stored {}", size, stored),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => "missing".to_string(),
+            Err(e) => format!("unreadable {}", e),
+        };
+        println!("{} {} {}", bucket, chunk, problem);
+        bad += 1;
+    }
+    info!("Scrubbed {} chunks, {} bad", rows.len(), bad);
+    Ok(bad)
+}
@@ -1,22 +1,143 @@
-use rusqlite::{Connection, NO_PARAMS};
-use std::sync::Mutex;
+use blake2::digest::{Update, VariableOutput};
+use blake2::VarBlake2b;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, Connection, OptionalExtension, NO_PARAMS};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
 
-use crate::config::Config;
+use crate::config::{Config, Storage};
+use crate::metrics::Metrics;
+use crate::ratelimit::RateLimiter;
+use crate::store::ChunkStore;
+
+/// How long to wait for a lock held by another connection before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
 
 /// The state passed around the variaus methods
 pub struct State {
     pub config: Config,
+    /// The connection used for all writes
     pub conn: Mutex<Connection>,
+    /// Connections used for reads, these may run concurrently since the database is in wal mode
+    pub read_pool: r2d2::Pool<SqliteConnectionManager>,
+    pub store: Box<dyn ChunkStore>,
+    pub metrics: Metrics,
+    /// The number of bytes stored in buckets with a quota, computed when first needed.
+    /// A bucket is removed when chunks are deleted from it, so that it is computed again
+    pub bucket_bytes: Mutex<HashMap<String, u64>>,
+    /// The buckets a gc is running for. The marks of a gc are kept by bucket, so a second gc
+    /// of the same bucket would mix its marks with those of the first
+    pub gc_buckets: Mutex<HashSet<String>>,
+    pub rate_limiter: RateLimiter,
+    pub passwords: PasswordCache,
 }
 
-pub fn setup_db(conf: &Config) -> Connection {
-    trace!("opening database");
-    let conn = Connection::open(format!("{}/backup.db", conf.data_dir))
-        .expect("Unable to open hash cache");
+impl State {
+    /// Lock the connection used for writes, see lock
+    pub fn lock_conn(&self) -> MutexGuard<Connection> {
+        lock(&self.conn)
+    }
+}
 
-    conn.pragma_update(None, "journal_mode", &"WAL".to_string())
-        .expect("Cannot enable wal");
+/// The passwords of users that were verified, so that the slow argon2 verification
+/// of a password runs once and not on every request
+///
+/// Passwords are kept as their Blake2b keyed with a random key, never in plain text
+pub struct PasswordCache {
+    key: [u8; 32],
+    verified: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl Default for PasswordCache {
+    fn default() -> PasswordCache {
+        PasswordCache {
+            key: rand::random(),
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl PasswordCache {
+    fn digest(&self, password: &[u8]) -> Vec<u8> {
+        let mut hasher = VarBlake2b::new_keyed(&self.key, 32);
+        hasher.update(password);
+        let mut digest = Vec::new();
+        hasher.finalize_variable(|res| digest.extend_from_slice(res));
+        digest
+    }
+
+    /// Check if password was verified as the password of user
+    pub fn contains(&self, user: &str, password: &[u8]) -> bool {
+        match lock(&self.verified).get(user) {
+            Some(digest) => bool::from(digest.ct_eq(&self.digest(password))),
+            None => false,
+        }
+    }
+
+    /// Remember that password was verified as the password of user
+    pub fn insert(&self, user: &str, password: &[u8]) {
+        lock(&self.verified).insert(user.to_string(), self.digest(password));
+    }
+}
+
+/// Lock a mutex, also if a thread panicked while holding it
+///
+/// The server keeps no invariants across the statements run under a lock, and transactions
+/// are rolled back as a panic unwinds, so what is behind the lock is still usable
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<T> {
+    mutex.lock().unwrap_or_else(|e| {
+        warn!("Recovering a lock held by a thread that panicked");
+        e.into_inner()
+    })
+}
+
+/// The path of the database, for the memory storage it is a memory database shared by all connections
+pub fn db_path(conf: &Config) -> String {
+    match conf.storage {
+        Storage::Memory => "file:mbackup?mode=memory&cache=shared".to_string(),
+        _ => format!("{}/backup.db", conf.data_dir),
+    }
+}
+
+/// Settings shared by the write connection and the read connections
+fn setup_connection(conn: &Connection, memory: bool) -> rusqlite::Result<()> {
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    if memory {
+        // Connections to a shared memory database lock tables instead of using the wal,
+        // let the readers proceed while the writer holds its locks
+        conn.pragma_update(None, "read_uncommitted", &true)?;
+    }
+    // The handlers use prepare_cached for their static queries,
+    // so that they are not parsed again on every request
+    conn.set_prepared_statement_cache_capacity(64);
+    Ok(())
+}
+
+/// Construct the pool of read connections, must be called after setup_db
+pub fn setup_read_pool(conf: &Config) -> r2d2::Pool<SqliteConnectionManager> {
+    let memory = conf.storage == Storage::Memory;
+    let manager = SqliteConnectionManager::file(db_path(conf))
+        .with_init(move |conn| setup_connection(conn, memory));
+    r2d2::Pool::builder()
+        .max_size(conf.read_connections)
+        .build(manager)
+        .expect("Unable to open read connections")
+}
+
+/// A migration of the database schema from one version to the next
+type Migration = fn(&Connection) -> rusqlite::Result<()>;
+
+/// The migrations of the database schema, the ith migration brings it from version i to i + 1.
+/// Databases created before the schema was versioned are at version 0
+///
+/// Migrations must also work on databases from before the schema was versioned,
+/// which may already have some of their changes
+const MIGRATIONS: [Migration; 3] = [create_tables, add_compressed_column, add_tags_column];
 
+/// Version 1: The tables as they were when the schema was versioned
+fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
     trace!("Creating chunks table");
     // The chunks table contains metadata for all chunks
     // and the content of small chunks
@@ -30,15 +151,13 @@ pub fn setup_db(conf: &Config) -> Connection {
              content BLOB
              )",
         NO_PARAMS,
-    )
-    .expect("Unable to create cache table");
+    )?;
 
     conn.execute(
         "CREATE INDEX IF NOT EXISTS idx_bucket_hash
         ON chunks (bucket,hash)",
         NO_PARAMS,
-    )
-    .expect("Unable to create cache table index");
+    )?;
 
     trace!("Creating roots table");
     // The roots table records the root of the merkel tree of all backups
@@ -51,8 +170,18 @@ pub fn setup_db(conf: &Config) -> Connection {
              hash TEXT NOT NULL
              )",
         NO_PARAMS,
-    )
-    .expect("Unable to create cache table");
+    )?;
+
+    trace!("Creating gc_marks table");
+    // The gc_marks table records the chunks found to be live during garbage collection
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS gc_marks (
+             bucket TEXT NOT NULL,
+             hash TEXT NOT NULL,
+             PRIMARY KEY (bucket, hash)
+             )",
+        NO_PARAMS,
+    )?;
 
     trace!("Creating deletes table");
     conn.execute(
@@ -61,8 +190,76 @@ pub fn setup_db(conf: &Config) -> Connection {
              time INTEGER NOT NULL
              )",
         NO_PARAMS,
-    )
-    .expect("Unable to deletes cache table");
+    )?;
+    Ok(())
+}
+
+/// Version 2: Record if the content of chunks is compressed
+fn add_compressed_column(conn: &Connection) -> rusqlite::Result<()> {
+    // Chunks stored before compression was added are not compressed
+    if conn
+        .prepare("SELECT compressed FROM chunks LIMIT 0")
+        .is_err()
+    {
+        conn.execute(
+            "ALTER TABLE chunks ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0",
+            NO_PARAMS,
+        )?;
+    }
+    Ok(())
+}
+
+/// Version 3: Add the tags of roots
+fn add_tags_column(conn: &Connection) -> rusqlite::Result<()> {
+    // Roots stored before tags were added have no tags
+    if conn.prepare("SELECT tags FROM roots LIMIT 0").is_err() {
+        conn.execute("ALTER TABLE roots ADD COLUMN tags TEXT", NO_PARAMS)?;
+    }
+    Ok(())
+}
+
+/// Bring the schema of the database to the newest version, in a single transaction
+fn migrate(conn: &mut Connection) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        NO_PARAMS,
+    )?;
+    let version: i64 = tx
+        .query_row("SELECT version FROM schema_version", NO_PARAMS, |row| {
+            row.get(0)
+        })
+        .optional()?
+        .unwrap_or(0);
+    if version as usize > MIGRATIONS.len() {
+        panic!(
+            "Database schema version {} is newer than this server supports",
+            version
+        );
+    }
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Migrating database schema to version {}", idx + 1);
+        migration(&tx)?;
+    }
+    tx.execute("DELETE FROM schema_version", NO_PARAMS)?;
+    tx.execute(
+        "INSERT INTO schema_version (version) VALUES (?)",
+        params![MIGRATIONS.len() as i64],
+    )?;
+    tx.commit()
+}
+
+pub fn setup_db(conf: &Config) -> Connection {
+    trace!("opening database");
+    let mut conn = Connection::open(db_path(conf)).expect("Unable to open hash cache");
+
+    conn.pragma_update(None, "journal_mode", &"WAL".to_string())
+        .expect("Cannot enable wal");
+
+    setup_connection(&conn, conf.storage == Storage::Memory)
+        .expect("Unable to configure database connection");
+
+    migrate(&mut conn).expect("Unable to migrate database");
 
     conn
 }
@@ -0,0 +1,536 @@
+use async_trait::async_trait;
+use rusoto_core::{ByteStream, Region, RusotoError};
+use rusoto_s3::{
+    DeleteObjectRequest, GetObjectError, GetObjectRequest, HeadObjectRequest, PutObjectRequest,
+    S3Client, S3,
+};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::config::{Config, Storage};
+use crate::state::lock;
+
+/// Storage for the content of chunks that are not stored inline in the database
+#[async_trait]
+pub trait ChunkStore: Send + Sync {
+    /// Store the content of a chunk, replacing any existing content
+    async fn put(&self, bucket: &str, chunk: &str, content: Vec<u8>) -> Result<()>;
+
+    /// Store the content of a chunk received into a temporary file
+    async fn put_temp(&self, bucket: &str, chunk: &str, temp: TempChunk) -> Result<()>;
+
+    /// Get the content of a chunk, fails with NotFound if it is not there
+    async fn get(&self, bucket: &str, chunk: &str) -> Result<Vec<u8>>;
+
+    /// Open the content of a chunk for reading, fails with NotFound if it is not there
+    async fn open(&self, bucket: &str, chunk: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>>;
+
+    /// Open len bytes of the content of a chunk starting at start for reading
+    async fn open_range(
+        &self,
+        bucket: &str,
+        chunk: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let mut reader = self.open(bucket, chunk).await?;
+        tokio::io::copy(&mut (&mut reader).take(start), &mut tokio::io::sink()).await?;
+        Ok(Box::pin(reader.take(len)))
+    }
+
+    /// Remove a chunk, succeeds if the chunk is not there
+    async fn delete(&self, bucket: &str, chunk: &str) -> Result<()>;
+
+    /// Check if a chunk is stored
+    async fn exists(&self, bucket: &str, chunk: &str) -> Result<bool> {
+        match self.size(bucket, chunk).await {
+            Ok(_) => Ok(true),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Get the stored size of a chunk, fails with NotFound if it is not there
+    async fn size(&self, bucket: &str, chunk: &str) -> Result<u64>;
+
+    /// Should chunks smaller than small_size be stored in the database instead
+    fn inline_small(&self) -> bool;
+
+    /// Should uploads larger than small_size be received into a temporary file instead of memory
+    fn spill_to_disk(&self) -> bool {
+        true
+    }
+}
+
+/// A chunk being received into {data_dir}/data/upload/{bucket}/{chunk}_{rand}
+///
+/// The file is removed when this is dropped, unless it has been moved into place
+pub struct TempChunk {
+    path: String,
+    file: tokio::fs::File,
+}
+
+impl TempChunk {
+    pub async fn create(data_dir: &str, bucket: &str, chunk: &str) -> Result<TempChunk> {
+        tokio::fs::create_dir_all(format!("{}/data/upload/{}", data_dir, bucket)).await?;
+        let path = format!(
+            "{}/data/upload/{}/{}_{}",
+            data_dir,
+            bucket,
+            chunk,
+            rand::random::<u64>()
+        );
+        let file = tokio::fs::File::create(&path).await?;
+        Ok(TempChunk { path, file })
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file.write_all(data).await
+    }
+
+    /// Write the content compressed with zstd to a new temporary chunk, returning it and its size
+    pub async fn compress(
+        &mut self,
+        data_dir: &str,
+        bucket: &str,
+        chunk: &str,
+        level: i32,
+    ) -> Result<(TempChunk, u64)> {
+        let src = self.finish().await?.to_string();
+        let dst = TempChunk::create(data_dir, bucket, chunk).await?;
+        let dst_path = dst.path.clone();
+        let len = tokio::task::spawn_blocking(move || -> Result<u64> {
+            let mut out = std::fs::File::create(&dst_path)?;
+            zstd::stream::copy_encode(std::fs::File::open(&src)?, &mut out, level)?;
+            Ok(out.metadata()?.len())
+        })
+        .await
+        .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))??;
+        Ok((dst, len))
+    }
+
+    /// Flush the written content and return the path of the file
+    async fn finish(&mut self) -> Result<&str> {
+        self.file.flush().await?;
+        Ok(&self.path)
+    }
+}
+
+impl Drop for TempChunk {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Remove the temporary files in {data_dir}/data/upload not modified for max_age seconds
+///
+/// These are left behind by uploads interrupted by a crash. Files of uploads in progress
+/// are written to continuously, so they are never that old
+pub fn remove_stale_uploads(data_dir: &str, max_age: u64) -> Result<()> {
+    let upload_dir = format!("{}/data/upload", data_dir);
+    let buckets = match std::fs::read_dir(&upload_dir) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        v => v?,
+    };
+    let now = std::time::SystemTime::now();
+    let mut removed = 0;
+    for bucket in buckets {
+        for entry in std::fs::read_dir(bucket?.path())? {
+            let entry = entry?;
+            let modified = entry.metadata()?.modified()?;
+            match now.duration_since(modified) {
+                Ok(age) if age.as_secs() >= max_age => (),
+                _ => continue,
+            }
+            match std::fs::remove_file(entry.path()) {
+                Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+                v => v?,
+            }
+            removed += 1;
+        }
+    }
+    if removed != 0 {
+        info!("Removed {} stale uploads from {}", removed, upload_dir);
+    }
+    Ok(())
+}
+
+/// The folder of a chunk, {data_dir}/data/{bucket} followed by a folder for each of the
+/// first shard_depth pairs of hex digits of the chunk
+fn chunk_dir(data_dir: &str, bucket: &str, chunk: &str, shard_depth: usize) -> String {
+    let mut ans = format!("{}/data/{}", data_dir, bucket);
+    for i in 0..shard_depth {
+        ans.push('/');
+        ans.push_str(&chunk[2 * i..2 * i + 2]);
+    }
+    ans
+}
+
+/// The file of a chunk, the rest of the chunk in chunk_dir
+fn chunk_path(data_dir: &str, bucket: &str, chunk: &str, shard_depth: usize) -> String {
+    format!(
+        "{}/{}",
+        chunk_dir(data_dir, bucket, chunk, shard_depth),
+        &chunk[2 * shard_depth..]
+    )
+}
+
+/// Find the chunk files below dir, where prefix is the part of the chunk names given by the
+/// folders above dir
+fn find_chunk_files(
+    dir: &std::path::Path,
+    prefix: &str,
+    files: &mut Vec<(String, std::path::PathBuf)>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) if name.bytes().all(|c| c.is_ascii_hexdigit()) => name,
+            _ => {
+                warn!("Ignoring {:?}, it is not a chunk", entry.path());
+                continue;
+            }
+        };
+        if entry.file_type()?.is_dir() {
+            find_chunk_files(&entry.path(), &format!("{}{}", prefix, name), files)?;
+        } else {
+            files.push((format!("{}{}", prefix, name), entry.path()));
+        }
+    }
+    Ok(())
+}
+
+/// Remove the empty folders below dir
+fn remove_empty_dirs(dir: &std::path::Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty_dirs(&entry.path())?;
+            // Fails if the folder is not empty, which is fine
+            let _ = std::fs::remove_dir(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Move the chunk files in {data_dir}/data to the folders of shard_depth
+///
+/// Chunks are found at any depth, so an interrupted move is completed by running this again.
+/// This must not run while a server is using data_dir. Returns the number of chunks moved
+pub fn reshard(data_dir: &str, shard_depth: usize) -> Result<u64> {
+    let data = std::path::Path::new(data_dir).join("data");
+    let buckets = match std::fs::read_dir(&data) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        v => v?,
+    };
+    let mut moved = 0;
+    for bucket in buckets {
+        let bucket = bucket?;
+        let name = bucket.file_name().to_string_lossy().into_owned();
+        if name == "upload" || !bucket.file_type()?.is_dir() {
+            continue;
+        }
+        let mut files = Vec::new();
+        find_chunk_files(&bucket.path(), "", &mut files)?;
+        for (chunk, path) in files {
+            if chunk.len() <= 2 * shard_depth {
+                warn!("Ignoring {:?}, the chunk name is too short", path);
+                continue;
+            }
+            let target = chunk_path(data_dir, &name, &chunk, shard_depth);
+            if path == std::path::Path::new(&target) {
+                continue;
+            }
+            std::fs::create_dir_all(chunk_dir(data_dir, &name, &chunk, shard_depth))?;
+            std::fs::rename(&path, &target)?;
+            moved += 1;
+        }
+        remove_empty_dirs(&bucket.path())?;
+    }
+    Ok(moved)
+}
+
+/// Store chunks as files in the folders given by chunk_path
+pub struct LocalStore {
+    data_dir: String,
+    shard_depth: usize,
+}
+
+impl LocalStore {
+    fn chunk_path(&self, bucket: &str, chunk: &str) -> String {
+        chunk_path(&self.data_dir, bucket, chunk, self.shard_depth)
+    }
+}
+
+#[async_trait]
+impl ChunkStore for LocalStore {
+    async fn put(&self, bucket: &str, chunk: &str, content: Vec<u8>) -> Result<()> {
+        // We first store the data in a temp upload folder
+        // and then atomically rename into its right location
+        let mut temp = TempChunk::create(&self.data_dir, bucket, chunk).await?;
+        temp.write(&content).await?;
+        self.put_temp(bucket, chunk, temp).await
+    }
+
+    async fn put_temp(&self, bucket: &str, chunk: &str, mut temp: TempChunk) -> Result<()> {
+        let temp_path = temp.finish().await?;
+        tokio::fs::create_dir_all(chunk_dir(&self.data_dir, bucket, chunk, self.shard_depth))
+            .await?;
+        tokio::fs::rename(temp_path, self.chunk_path(bucket, chunk)).await
+    }
+
+    async fn get(&self, bucket: &str, chunk: &str) -> Result<Vec<u8>> {
+        tokio::fs::read(self.chunk_path(bucket, chunk)).await
+    }
+
+    async fn open(&self, bucket: &str, chunk: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let file = tokio::fs::File::open(self.chunk_path(bucket, chunk)).await?;
+        Ok(Box::pin(file))
+    }
+
+    async fn open_range(
+        &self,
+        bucket: &str,
+        chunk: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let mut file = tokio::fs::File::open(self.chunk_path(bucket, chunk)).await?;
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        Ok(Box::pin(file.take(len)))
+    }
+
+    async fn delete(&self, bucket: &str, chunk: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.chunk_path(bucket, chunk)).await {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            v => v,
+        }
+    }
+
+    async fn size(&self, bucket: &str, chunk: &str) -> Result<u64> {
+        Ok(tokio::fs::metadata(self.chunk_path(bucket, chunk))
+            .await?
+            .len())
+    }
+
+    fn inline_small(&self) -> bool {
+        true
+    }
+}
+
+/// Store chunks as objects {prefix}{bucket}/{chunk} in an S3 bucket
+pub struct S3Store {
+    client: S3Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl S3Store {
+    fn key(&self, bucket: &str, chunk: &str) -> String {
+        format!("{}{}/{}", self.prefix, bucket, chunk)
+    }
+
+    /// Read an object, or the part of it given by an http range
+    async fn get_object(
+        &self,
+        bucket: &str,
+        chunk: &str,
+        range: Option<String>,
+    ) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let res = match self
+            .client
+            .get_object(GetObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(bucket, chunk),
+                range,
+                ..Default::default()
+            })
+            .await
+        {
+            Ok(res) => res,
+            Err(RusotoError::Service(GetObjectError::NoSuchKey(_))) => {
+                return Err(Error::new(ErrorKind::NotFound, "Not found"))
+            }
+            Err(e) => return Err(s3_error(e)),
+        };
+        match res.body {
+            Some(body) => Ok(Box::pin(body.into_async_read())),
+            None => Ok(Box::pin(std::io::Cursor::new(Vec::new()))),
+        }
+    }
+}
+
+/// Convert an S3 error into an io error
+fn s3_error<E: std::error::Error + 'static>(e: RusotoError<E>) -> Error {
+    match e {
+        RusotoError::Unknown(ref res) if res.status.as_u16() == 404 => {
+            Error::new(ErrorKind::NotFound, "Not found")
+        }
+        e => Error::new(ErrorKind::Other, format!("{}", e)),
+    }
+}
+
+#[async_trait]
+impl ChunkStore for S3Store {
+    async fn put(&self, bucket: &str, chunk: &str, content: Vec<u8>) -> Result<()> {
+        self.client
+            .put_object(PutObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(bucket, chunk),
+                content_length: Some(content.len() as i64),
+                body: Some(ByteStream::from(content)),
+                ..Default::default()
+            })
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn put_temp(&self, bucket: &str, chunk: &str, mut temp: TempChunk) -> Result<()> {
+        let content = tokio::fs::read(temp.finish().await?).await?;
+        self.put(bucket, chunk, content).await
+    }
+
+    async fn get(&self, bucket: &str, chunk: &str) -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        self.open(bucket, chunk)
+            .await?
+            .read_to_end(&mut content)
+            .await?;
+        Ok(content)
+    }
+
+    async fn open(&self, bucket: &str, chunk: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        self.get_object(bucket, chunk, None).await
+    }
+
+    async fn open_range(
+        &self,
+        bucket: &str,
+        chunk: &str,
+        start: u64,
+        len: u64,
+    ) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let range = format!("bytes={}-{}", start, start + len - 1);
+        self.get_object(bucket, chunk, Some(range)).await
+    }
+
+    async fn delete(&self, bucket: &str, chunk: &str) -> Result<()> {
+        self.client
+            .delete_object(DeleteObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(bucket, chunk),
+                ..Default::default()
+            })
+            .await
+            .map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn size(&self, bucket: &str, chunk: &str) -> Result<u64> {
+        let res = self
+            .client
+            .head_object(HeadObjectRequest {
+                bucket: self.bucket.clone(),
+                key: self.key(bucket, chunk),
+                ..Default::default()
+            })
+            .await
+            .map_err(s3_error)?;
+        Ok(res.content_length.unwrap_or(0) as u64)
+    }
+
+    fn inline_small(&self) -> bool {
+        false
+    }
+}
+
+/// Store chunks in memory, for tests and throwaway servers
+#[derive(Default)]
+pub struct MemoryStore {
+    chunks: Mutex<HashMap<(String, String), Vec<u8>>>,
+}
+
+impl MemoryStore {
+    fn not_found() -> Error {
+        Error::new(ErrorKind::NotFound, "Not found")
+    }
+}
+
+#[async_trait]
+impl ChunkStore for MemoryStore {
+    async fn put(&self, bucket: &str, chunk: &str, content: Vec<u8>) -> Result<()> {
+        lock(&self.chunks).insert((bucket.to_string(), chunk.to_string()), content);
+        Ok(())
+    }
+
+    async fn put_temp(&self, bucket: &str, chunk: &str, mut temp: TempChunk) -> Result<()> {
+        let content = tokio::fs::read(temp.finish().await?).await?;
+        self.put(bucket, chunk, content).await
+    }
+
+    async fn get(&self, bucket: &str, chunk: &str) -> Result<Vec<u8>> {
+        lock(&self.chunks)
+            .get(&(bucket.to_string(), chunk.to_string()))
+            .cloned()
+            .ok_or_else(MemoryStore::not_found)
+    }
+
+    async fn open(&self, bucket: &str, chunk: &str) -> Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let content = self.get(bucket, chunk).await?;
+        Ok(Box::pin(std::io::Cursor::new(content)))
+    }
+
+    async fn delete(&self, bucket: &str, chunk: &str) -> Result<()> {
+        lock(&self.chunks).remove(&(bucket.to_string(), chunk.to_string()));
+        Ok(())
+    }
+
+    async fn size(&self, bucket: &str, chunk: &str) -> Result<u64> {
+        lock(&self.chunks)
+            .get(&(bucket.to_string(), chunk.to_string()))
+            .map(|content| content.len() as u64)
+            .ok_or_else(MemoryStore::not_found)
+    }
+
+    fn inline_small(&self) -> bool {
+        false
+    }
+
+    fn spill_to_disk(&self) -> bool {
+        false
+    }
+}
+
+/// Construct the chunk store selected in the config
+pub fn open_store(config: &Config) -> Box<dyn ChunkStore> {
+    match &config.storage {
+        Storage::Local => Box::new(LocalStore {
+            data_dir: config.data_dir.clone(),
+            shard_depth: config.shard_depth,
+        }),
+        Storage::S3 {
+            bucket,
+            region,
+            endpoint,
+            prefix,
+        } => {
+            let region = match endpoint {
+                Some(endpoint) => Region::Custom {
+                    name: region.clone(),
+                    endpoint: endpoint.clone(),
+                },
+                None => region.parse().expect("Bad S3 region"),
+            };
+            Box::new(S3Store {
+                client: S3Client::new(region),
+                bucket: bucket.clone(),
+                prefix: prefix.clone(),
+            })
+        }
+        Storage::Memory => Box::new(MemoryStore::default()),
+    }
+}
@@ -2,8 +2,2561 @@
 import subprocess
 import tempfile
 import shutil
+import signal
+import ssl
+import stat
 import os
 import time
+import base64
+import datetime
+import hashlib
+import json
+import sqlite3
+import threading
+import fcntl
+import socket
+import http.client
+import http.server
+import urllib.error
+import urllib.request
+
+
+# The bucket of the encryption key correcthorsebatterystaple, the name of the chunk holding "golden"
+# and the Blake2b-256 of that chunk encrypted with deterministic nonces. These pin the key derivation,
+# hashing and encryption, since changing any of them makes existing backups unreadable
+GOLDEN_BUCKET = "af77b26a33ade5566664a13cd93f326beeb3a64fb05765a06e92e8c36e7adf18"
+GOLDEN_CHUNK = "a3d92d9bfbc77dc5caa8d65d89ba818e408cb56951dabc4975585b318aab1416"
+GOLDEN_CONTENT_HASH = "0d406a0424981a06e88d972308bbc224b7a131bbf06d2b32cc8f1e7da1532ac2"
+
+
+def write_server_config(test_dir, name, port, settings="", password="hunter3", users="", memory=False):
+    """Write the config of a server for a test listening on port, with the given settings and an admin user
+    with Delete access followed by the given users. Returns the path of the config and the folder of its data"""
+    config = os.path.join(test_dir, "%s.toml" % name)
+    with open(config, "w") as f:
+        f.write('bind="127.0.0.1:%d"\n%s\n' % (port, settings))
+        f.write('\n[[users]]\nname="admin"\npassword="%s"\naccess_level="Delete"\n%s' % (password, users))
+        if memory:
+            f.write('\n[storage]\nbackend="Memory"\n')
+    server_data = os.path.join(test_dir, "%s_server" % name)
+    os.makedirs(server_data, exist_ok=True)
+    return config, server_data
+
+
+def start_server(test_dir, name, port, settings="", password="hunter3", users="", memory=False, **kwargs):
+    """Start a server with the config written by write_server_config, and give it time to start.
+    Returns the server and the folder of its data"""
+    config, server_data = write_server_config(test_dir, name, port, settings, password, users, memory)
+    server = subprocess.Popen(["target/release/mbackupd", "--config", config, "--data-dir", server_data], **kwargs)
+    time.sleep(0.5)
+    return server, server_data
+
+
+def stop_server(server):
+    server.terminate()
+    server.wait()
+
+
+def write_client_config(
+    test_dir, name, port, in_dir=None, settings="", password="hunter3", hostname=None, cache=None, scheme="http"
+):
+    """Write the config of a client backing up in_dir as the admin user of the server on port,
+    with the key of GOLDEN_BUCKET and the given settings. Returns the path of the config"""
+    path = os.path.join(test_dir, "%s_client.toml" % name)
+    with open(path, "w") as f:
+        f.write(
+            """
+user="admin"
+password="%s"
+encryption_key="correcthorsebatterystaple"
+server="%s://localhost:%d"
+hostname="%s"
+cache_db="%s"
+"""
+            % (password, scheme, port, hostname or name, os.path.join(test_dir, "%s_cache.db" % (cache or name)))
+        )
+        if in_dir is not None:
+            f.write('backup_dirs=["%s"]\n' % in_dir)
+        f.write(settings + "\n")
+    return path
+
+
+def request(port, method, path, data=None, user=b"admin:hunter3", headers={}):
+    """Make a request to the server on port as user, returning the status and the body of the response"""
+    req = urllib.request.Request("http://localhost:%d%s" % (port, path), data=data, method=method)
+    req.add_header("Authorization", "Basic " + base64.b64encode(user).decode())
+    for k, v in headers.items():
+        req.add_header(k, v)
+    try:
+        with urllib.request.urlopen(req) as res:
+            return res.status, res.read()
+    except urllib.error.HTTPError as e:
+        return e.code, b""
+
+
+def bucket_of(server_data):
+    """The bucket holding the roots on the server"""
+    db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+    bucket = db.execute("SELECT bucket FROM roots LIMIT 1").fetchone()[0]
+    db.close()
+    return bucket
+
+
+def test_memory_server(test_dir):
+    """Backup and restore against a server keeping everything in memory"""
+    server, server_data = start_server(test_dir, "memory", 31783, memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "memory_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "small"), "w") as fi:
+            fi.write("memory1")
+        with open(os.path.join(in_dir, "large"), "w") as fi:
+            fi.write("memory2" * 1024 * 1024)
+        for name in ["copy1", "copy2", "copy3"]:
+            with open(os.path.join(in_dir, name), "w") as fi:
+                fi.write("memory3" * 1024 * 1024)
+
+        client_config = write_client_config(test_dir, "memory", 31783, in_dir)
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+
+        # Each distinct large chunk is looked for once, copies are known to be queued for upload
+        req = urllib.request.Request("http://localhost:31783/metrics")
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"admin:hunter3").decode())
+        metrics = urllib.request.urlopen(req).read().decode()
+        if 'mbackup_request_duration_seconds_count{method="HEAD"} 2\n' not in metrics:
+            raise Exception("Chunks looked for more than once")
+
+        out = os.path.join(test_dir, "memory_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        with open(os.path.join(out, in_dir[1:], "small"), "r") as fi:
+            if fi.read() != "memory1":
+                raise Exception("Bad restore from memory server 1")
+        with open(os.path.join(out, in_dir[1:], "large"), "r") as fi:
+            if fi.read() != "memory2" * 1024 * 1024:
+                raise Exception("Bad restore from memory server 2")
+
+        # Prune everything, and check that the chunks are gone from memory as well
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "delete-root", "1"])
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "prune", "--age", "0"])
+
+        req = urllib.request.Request("http://localhost:31783/metrics")
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"admin:hunter3").decode())
+        metrics = urllib.request.urlopen(req).read().decode()
+        if "mbackup_chunks_deleted_total 0\n" in metrics:
+            raise Exception("Nothing deleted from memory server")
+
+        if os.listdir(server_data):
+            raise Exception("Memory server wrote to its data dir")
+    finally:
+        stop_server(server)
+
+
+def test_invalid_sizes(test_dir):
+    """Check that chunk sizes out of range are rejected at startup"""
+    server_config = os.path.join(test_dir, "small_size.toml")
+    with open(server_config, "w") as f:
+        f.write("small_size=%d\n" % (64 * 1024 * 1024 + 1))
+    if subprocess.call(["target/release/mbackupd", "--config", server_config]) == 0:
+        raise Exception("Too large small_size accepted")
+
+    client_config = os.path.join(test_dir, "chunk_size.toml")
+    for chunk_size in [0, 512 * 1024 * 1024 + 1]:
+        with open(client_config, "w") as f:
+            f.write(
+                """
+user="backup"
+password="hunter1"
+encryption_key="correcthorsebatterystaple"
+server="http://localhost:31782"
+hostname="test"
+chunk_size=%d
+backup_dirs=["%s"]
+cache_db="%s"
+"""
+                % (chunk_size, test_dir, os.path.join(test_dir, "chunk_size.db"))
+            )
+        if subprocess.call(["target/release/mbackup", "-c", client_config, "backup", "--dry-run"]) == 0:
+            raise Exception("Bad chunk_size %d accepted" % chunk_size)
+
+
+def test_deterministic_nonce(test_dir):
+    """Check that the same content encrypts to the same chunk with deterministic nonces,
+    also when backed up by independent clients"""
+    in_dir = os.path.join(test_dir, "deterministic_in")
+    os.mkdir(in_dir)
+    with open(os.path.join(in_dir, "file"), "w") as fi:
+        fi.write("deterministic" * 1024)
+    with open(os.path.join(in_dir, "golden"), "w") as fi:
+        fi.write("golden")
+
+    # Backup the same content to two fresh servers from two clients with their own caches
+    backups = []
+    for run in range(2):
+        name = "deterministic%d" % run
+        server, server_data = start_server(test_dir, name, 31784)
+        try:
+            settings = "deterministic_nonce=true\ncompression=true\nupload_threads=%d" % (run + 1)
+            client_config = write_client_config(test_dir, name, 31784, in_dir, settings)
+            subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+            bucket = bucket_of(server_data)
+            if bucket != GOLDEN_BUCKET:
+                raise Exception("Bucket changed to %s" % bucket)
+            chunks = {}
+            for line in request(31784, "GET", "/chunks/%s" % bucket)[1].decode().splitlines():
+                chunk = line.split(" ")[0]
+                chunks[chunk] = request(31784, "GET", "/chunks/%s/%s" % (bucket, chunk))[1]
+            backups.append(chunks)
+        finally:
+            stop_server(server)
+
+    # Check the name and encryption of a known chunk byte for byte
+    golden = backups[0].get(GOLDEN_CHUNK)
+    if golden is None:
+        raise Exception("Golden chunk not named %s" % GOLDEN_CHUNK)
+    if hashlib.blake2b(golden, digest_size=32).hexdigest() != GOLDEN_CONTENT_HASH:
+        raise Exception("Golden chunk encrypted differently")
+
+    # The roots differ by their times, but the chunks of the files must be identical
+    common = set(backups[0]) & set(backups[1])
+    if not common:
+        raise Exception("No chunks in common between deterministic backups")
+    for chunk in common:
+        if backups[0][chunk] != backups[1][chunk]:
+            raise Exception("Deterministic backups encrypted %s differently" % chunk)
+
+
+def test_blake3(test_dir):
+    """Check that backups naming chunks with Blake3 round trip, also after switching from Blake2b"""
+    server, server_data = start_server(test_dir, "blake3", 31785, memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "blake3_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "w") as fi:
+            fi.write("blake3" * 1024 * 1024)
+
+        for hash_algorithm in ["Blake2b", "Blake3"]:
+            settings = 'hash_algorithm="%s"' % hash_algorithm
+            client_config = write_client_config(test_dir, "blake3", 31785, in_dir, settings)
+            subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+
+        # The Blake3 backup is the second root, and the only one in its bucket
+        out = os.path.join(test_dir, "blake3_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "2", "--pattern", "/", "--dest", out]
+        )
+        with open(os.path.join(out, in_dir[1:], "file"), "r") as fi:
+            if fi.read() != "blake3" * 1024 * 1024:
+                raise Exception("Bad restore of Blake3 backup")
+    finally:
+        stop_server(server)
+
+
+def test_inode_change_detection(test_dir):
+    """Check that a renamed file is found in the cache by its inode"""
+    server, server_data = start_server(test_dir, "inode", 31786, memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "inode_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "w") as fi:
+            fi.write("inode" * 1024 * 1024)
+
+        client_config = write_client_config(test_dir, "inode", 31786, in_dir, "inode_change_detection=true")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+
+        os.rename(os.path.join(in_dir, "file"), os.path.join(in_dir, "moved"))
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "backup", "--dry-run", "--json"],
+            stdout=subprocess.PIPE,
+            check=True,
+        )
+        summary = json.loads(res.stdout)
+        if summary["files"] != 1 or summary["modified_files"] != 0:
+            raise Exception("Renamed file not found by its inode")
+
+        # A backup fails at once while another backup holds the lock on the cache
+        with open(os.path.join(test_dir, "inode_cache.db.lock"), "w") as lock:
+            fcntl.flock(lock, fcntl.LOCK_EX)
+            start = time.time()
+            if subprocess.call(["target/release/mbackup", "-c", client_config, "backup"]) == 0:
+                raise Exception("Backup ran while the cache was locked")
+            if time.time() - start > 5:
+                raise Exception("Backup waited for the lock")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+    finally:
+        stop_server(server)
+
+
+def test_quota(test_dir):
+    """Check that puts fail once a bucket is full, and succeed again after a delete"""
+    bucket = "5" * 64
+    settings = 'quota=1\n\n[bucket_quotas]\n"%s"=100' % bucket
+    server, server_data = start_server(test_dir, "quota", 31787, settings, memory=True)
+
+    def put(chunk, data, method, in_bucket=bucket):
+        headers = {} if data is None else {"X-Content-Blake2b": hashlib.blake2b(data, digest_size=32).hexdigest()}
+        return request(31787, method, "/chunks/%s/%s" % (in_bucket, chunk), data, headers=headers)[0]
+
+    try:
+        # The bucket is filled up exactly, one more byte is too much
+        if put("6" * 64, b"x" * 60, "PUT") != 200 or put("7" * 64, b"y" * 40, "PUT") != 200:
+            raise Exception("Put within quota failed")
+        if put("8" * 64, b"z", "PUT") != 507:
+            raise Exception("Put over quota accepted")
+        # Other buckets have the default quota
+        if put("8" * 64, b"zz", "PUT", "9" * 64) != 507:
+            raise Exception("Put over default quota accepted")
+        if put("7" * 64, None, "DELETE") != 200:
+            raise Exception("Delete failed")
+        if put("8" * 64, b"z", "PUT") != 200:
+            raise Exception("Put after delete failed")
+    finally:
+        stop_server(server)
+
+
+def test_rate_limit(test_dir):
+    """Check that a burst of requests is allowed, but a sustained rate above the limit is not"""
+    server, server_data = start_server(test_dir, "rate_limit", 31788, "rate_limit=1\nrate_limit_burst=5", memory=True)
+    try:
+        codes = []
+        for _ in range(8):
+            req = urllib.request.Request("http://localhost:31788/stats/%s" % ("0" * 64))
+            req.add_header("Authorization", "Basic " + base64.b64encode(b"admin:hunter3").decode())
+            try:
+                codes.append(urllib.request.urlopen(req).status)
+            except urllib.error.HTTPError as err:
+                codes.append(err.code)
+                if err.code == 429 and err.headers["Retry-After"] != "1":
+                    raise Exception("Bad Retry-After %s" % err.headers["Retry-After"])
+        if codes[:5] != [200] * 5 or 429 not in codes[5:]:
+            raise Exception("Bad rate limit %s" % codes)
+    finally:
+        stop_server(server)
+
+
+def test_retry(test_dir):
+    """Check that failed requests are retried as configured, honoring Retry-After"""
+    requests = []
+    failures = []
+
+    class Handler(http.server.BaseHTTPRequestHandler):
+        def do_GET(self):
+            requests.append(time.time())
+            if len(requests) <= len(failures):
+                self.send_response(503)
+                if failures[len(requests) - 1] is not None:
+                    self.send_header("Retry-After", failures[len(requests) - 1])
+            else:
+                self.send_response(200)
+            self.send_header("Content-Length", "0")
+            self.end_headers()
+
+        def log_message(self, *args):
+            pass
+
+    server = http.server.HTTPServer(("127.0.0.1", 31789), Handler)
+    thread = threading.Thread(target=server.serve_forever)
+    thread.start()
+    try:
+        settings = "[retry]\nattempts=2\ndelay=0.2\nmax_delay=0.3"
+        client_config = write_client_config(test_dir, "retry", 31789, settings=settings)
+
+        # Two failures are retried, first after at least half the delay, then as asked by the server
+        failures[:] = [None, "1"]
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "roots"])
+        if len(requests) != 3:
+            raise Exception("Bad number of attempts %d" % len(requests))
+        if requests[1] - requests[0] < 0.1 or requests[2] - requests[1] < 1:
+            raise Exception("Retried too soon")
+
+        # A third failure is not retried
+        del requests[:]
+        failures[:] = [None, None, None]
+        if subprocess.call(["target/release/mbackup", "-c", client_config, "roots"]) == 0:
+            raise Exception("Failure not reported")
+        if len(requests) != 3:
+            raise Exception("Bad number of attempts %d" % len(requests))
+    finally:
+        server.shutdown()
+        thread.join()
+
+
+def test_timeout(test_dir):
+    """Check that requests to a server that never responds time out"""
+    listener = socket.socket()
+    listener.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+    listener.bind(("127.0.0.1", 31790))
+    listener.listen(16)
+    try:
+        settings = "request_timeout=1\n\n[retry]\nattempts=1\ndelay=0.1"
+        client_config = write_client_config(test_dir, "timeout", 31790, settings=settings)
+        # Connections are accepted by the kernel but never answered
+        start = time.time()
+        res = subprocess.run(["target/release/mbackup", "-c", client_config, "roots"], timeout=30)
+        if res.returncode == 0:
+            raise Exception("Request to a stalled server succeeded")
+        if time.time() - start < 2:
+            raise Exception("Request was not retried after timing out")
+    finally:
+        listener.close()
+
+
+def test_access_log(test_dir):
+    """Check that handled requests are logged, also when they fail"""
+    log_path = os.path.join(test_dir, "access.log")
+    with open(log_path, "w") as log:
+        server, server_data = start_server(
+            test_dir, "access_log", 31791, memory=True, stderr=log, env=dict(os.environ, MBACKUP_LOG_FORMAT="json")
+        )
+    try:
+        request(31791, "GET", "/stats/%s" % ("0" * 64))
+        try:
+            urllib.request.urlopen("http://localhost:31791/nothing")
+            raise Exception("Missing path found")
+        except urllib.error.HTTPError as err:
+            if err.code != 404:
+                raise
+    finally:
+        stop_server(server)
+    with open(log_path, "r") as log:
+        lines = [json.loads(line) for line in log if line.startswith("{")]
+    access = [line for line in lines if line["target"] == "access"]
+    if [(line["user"], line["path"], line["status"]) for line in access] != [
+        ("admin", "/stats/" + "0" * 64, "200"),
+        ("anon", "/nothing", "404"),
+    ]:
+        raise Exception("Bad access log %s" % access)
+    if any("hunter3" in json.dumps(line) for line in lines):
+        raise Exception("Credentials logged")
+
+
+def test_server_compression(test_dir):
+    """Round trip compressible and incompressible chunks through a server compressing chunks"""
+    server, server_data = start_server(test_dir, "compress", 31792, "compress_chunks=true")
+    bucket = "a" * 64
+    # Chunks stored in the database and on disk, compressible or not
+    chunks = [
+        ("b" * 64, b"compress" * 1024, True),
+        ("c" * 64, b"compress" * 128 * 1024, True),
+        ("d" * 64, os.urandom(8 * 1024), False),
+        ("e" * 64, os.urandom(1024 * 1024), False),
+    ]
+    try:
+        for name, data, _ in chunks:
+            req = urllib.request.Request(
+                "http://localhost:31792/chunks/%s/%s" % (bucket, name), data=data, method="PUT"
+            )
+            req.add_header("Authorization", "Basic " + base64.b64encode(b"admin:hunter3").decode())
+            req.add_header("X-Content-Blake2b", hashlib.blake2b(data, digest_size=32).hexdigest())
+            urllib.request.urlopen(req)
+        for name, data, _ in chunks:
+            req = urllib.request.Request("http://localhost:31792/chunks/%s/%s" % (bucket, name))
+            req.add_header("Authorization", "Basic " + base64.b64encode(b"admin:hunter3").decode())
+            if urllib.request.urlopen(req).read() != data:
+                raise Exception("Bad round trip of compressed chunk")
+            req.add_header("Range", "bytes=10-19")
+            if urllib.request.urlopen(req).read() != data[10:20]:
+                raise Exception("Bad range of compressed chunk")
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        for name, data, compressible in chunks:
+            compressed, size = db.execute(
+                "SELECT compressed, size FROM chunks WHERE hash=?", (name,)
+            ).fetchone()
+            if compressed != compressible or size != len(data):
+                raise Exception("Bad compression of chunk")
+        db.close()
+        if os.path.getsize(os.path.join(server_data, "data", bucket, "cc", "c" * 62)) >= 1024 * 1024:
+            raise Exception("Chunk stored on disk not compressed")
+    finally:
+        stop_server(server)
+
+
+def test_schema_migration(test_dir):
+    """Check that databases from before the schema was versioned, and of versions 1 and 2, are migrated"""
+    for version in [0, 1, 2]:
+        name = "migration_%d" % version
+        _, server_data = write_server_config(test_dir, name, 31793)
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        db.execute(
+            "CREATE TABLE chunks (id INTEGER PRIMARY KEY, bucket TEXT NOT NULL, hash TEXT NOT NULL, "
+            "size INTEGER NOT NULL, time INTEGER NOT NULL, content BLOB)"
+        )
+        db.execute("INSERT INTO chunks (bucket, hash, size, time, content) VALUES ('b', 'h', 1, 0, 'x')")
+        if version >= 1:
+            db.execute("CREATE TABLE schema_version (version INTEGER NOT NULL)")
+            db.execute("INSERT INTO schema_version VALUES (?)", (version,))
+        if version == 2:
+            # The tables of version 2, with roots from before tags
+            db.execute("ALTER TABLE chunks ADD COLUMN compressed INTEGER NOT NULL DEFAULT 0")
+            db.execute("CREATE INDEX idx_bucket_hash ON chunks (bucket,hash)")
+            db.execute(
+                "CREATE TABLE roots (id INTEGER PRIMARY KEY, bucket TEXT NOT NULL, host TEXT NOT NULL, "
+                "time INTEGER NOT NULL, hash TEXT NOT NULL)"
+            )
+            db.execute("INSERT INTO roots (bucket, host, time, hash) VALUES ('b', 'host', 0, 'r')")
+            db.execute("CREATE TABLE gc_marks (bucket TEXT NOT NULL, hash TEXT NOT NULL, PRIMARY KEY (bucket, hash))")
+            db.execute("CREATE TABLE deletes (bucket TEXT NOT NULL UNIQUE, time INTEGER NOT NULL)")
+        db.commit()
+        db.close()
+        # Starting twice must leave the migrated database as it is
+        for _ in range(2):
+            server, _ = start_server(test_dir, name, 31793)
+            stop_server(server)
+            db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+            if db.execute("SELECT version FROM schema_version").fetchall() != [(3,)]:
+                raise Exception("Database of version %d not migrated" % version)
+            if db.execute("SELECT content, compressed FROM chunks").fetchall() != [("x", 0)]:
+                raise Exception("Chunks lost in migration of version %d" % version)
+            # Roots from before tags have none
+            roots = db.execute("SELECT host, hash, tags FROM roots").fetchall()
+            if roots != ([("host", "r", None)] if version == 2 else []):
+                raise Exception("Bad roots after migration of version %d: %s" % (version, roots))
+            db.close()
+
+
+def test_database_errors(test_dir):
+    """Check that database errors are answered with 500, and that the server keeps working"""
+    server, server_data = start_server(test_dir, "database_errors", 31794)
+
+    def status(path="/status/" + "0" * 64):
+        req = urllib.request.Request("http://localhost:31794" + path)
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"admin:hunter3").decode())
+        try:
+            return urllib.request.urlopen(req).status
+        except urllib.error.HTTPError as err:
+            return err.code
+
+    try:
+        if status() != 200:
+            raise Exception("Bad status")
+        # Take the table away from under the server
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        db.execute("ALTER TABLE deletes RENAME TO deletes_gone")
+        db.commit()
+        if status() != 500:
+            raise Exception("Database error not reported")
+        db.execute("ALTER TABLE deletes_gone RENAME TO deletes")
+        db.commit()
+        db.close()
+        if status() != 200 or server.poll() is not None:
+            raise Exception("Server did not recover from database error")
+
+        # Health checks fail while the database is broken, and readiness while data_dir is not writable
+        if status("/health") != 200 or status("/ready") != 200:
+            raise Exception("Server not healthy")
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        db.execute("ALTER TABLE schema_version RENAME TO schema_version_gone")
+        db.commit()
+        if status("/health") != 503 or status("/ready") != 503:
+            raise Exception("Broken database not reported by health checks")
+        db.execute("ALTER TABLE schema_version_gone RENAME TO schema_version")
+        db.commit()
+        db.close()
+        if status("/health") != 200:
+            raise Exception("Server not healthy again")
+        # The permissions do not apply to root
+        if os.geteuid() != 0:
+            os.chmod(server_data, 0o500)
+            try:
+                if status("/health") != 200 or status("/ready") != 503:
+                    raise Exception("Unwritable data dir not reported by readiness check")
+            finally:
+                os.chmod(server_data, 0o700)
+    finally:
+        stop_server(server)
+
+
+def test_webhook(test_dir):
+    """Check the summaries posted to the webhook after a backup succeeds and fails"""
+    reports = []
+
+    class Handler(http.server.BaseHTTPRequestHandler):
+        def do_POST(self):
+            body = self.rfile.read(int(self.headers["Content-Length"]))
+            reports.append(json.loads(body.decode()))
+            self.send_response(200)
+            self.send_header("Content-Length", "0")
+            self.end_headers()
+
+        def log_message(self, *args):
+            pass
+
+    receiver = http.server.HTTPServer(("127.0.0.1", 31795), Handler)
+    thread = threading.Thread(target=receiver.serve_forever)
+    thread.start()
+    server, server_data = start_server(test_dir, "webhook", 31796, memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "webhook_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "w") as fi:
+            fi.write("webhook")
+
+        settings = 'webhook="http://localhost:31795/hook"\n\n[retry]\nattempts=0\ndelay=0.1'
+        client_config = write_client_config(test_dir, "webhook", 31796, in_dir, settings)
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        if len(reports) != 1:
+            raise Exception("Bad number of webhook reports %d" % len(reports))
+        report = reports[0]
+        if (
+            not report["success"]
+            or report["error"] is not None
+            or report["host"] != "webhook"
+            or report["bucket"] != GOLDEN_BUCKET
+            or report["files"] != 1
+            or report["transferred_bytes"] == 0
+            or report["duration"] < 0
+            or len(report["root"]) != 64
+        ):
+            raise Exception("Bad webhook report %s" % report)
+
+        # Dry runs are not reported
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup", "--dry-run"])
+        if len(reports) != 1:
+            raise Exception("Dry run was reported")
+
+        # A backup failing to reach the server is reported before the error is returned
+        write_client_config(test_dir, "webhook", 31797, in_dir, settings)
+        if subprocess.call(["target/release/mbackup", "-c", client_config, "backup"]) == 0:
+            raise Exception("Backup without a server succeeded")
+        if len(reports) != 2:
+            raise Exception("Failure was not reported")
+        report = reports[1]
+        if report["success"] or not report["error"] or report["root"] is not None:
+            raise Exception("Bad webhook failure report %s" % report)
+    finally:
+        stop_server(server)
+        receiver.shutdown()
+        thread.join()
+
+
+def test_skipped_files(test_dir):
+    """Check that files that cannot be read are skipped, and the exit status tells"""
+    if os.geteuid() == 0:
+        # Permissions do not stop root from reading files
+        return
+    server, server_data = start_server(test_dir, "skipped", 31798, memory=True)
+    in_dir = os.path.join(test_dir, "skipped_in")
+    try:
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "readable"), "w") as fi:
+            fi.write("readable")
+        with open(os.path.join(in_dir, "secret"), "w") as fi:
+            fi.write("secret")
+        os.chmod(os.path.join(in_dir, "secret"), 0)
+        # A folder that cannot be listed does not stop its siblings from being backed up
+        os.mkdir(os.path.join(in_dir, "closed"))
+        os.mkdir(os.path.join(in_dir, "open"))
+        with open(os.path.join(in_dir, "open", "inner"), "w") as fi:
+            fi.write("inner")
+        os.chmod(os.path.join(in_dir, "closed"), 0)
+
+        client_config = write_client_config(test_dir, "skipped", 31798, in_dir, "continue_on_error=false")
+        res = subprocess.run(["target/release/mbackup", "-c", client_config, "backup"])
+        if res.returncode != 1:
+            raise Exception("Unreadable file did not fail the backup: %d" % res.returncode)
+
+        write_client_config(test_dir, "skipped", 31798, in_dir, "continue_on_error=true")
+        res = subprocess.run(["target/release/mbackup", "-c", client_config, "backup"], stderr=subprocess.PIPE)
+        if res.returncode != 2:
+            raise Exception("Bad exit status with skipped files %d" % res.returncode)
+        if b"secret" not in res.stderr:
+            raise Exception("Skipped file not listed")
+
+        out = os.path.join(test_dir, "skipped_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        with open(os.path.join(out, in_dir[1:], "readable"), "r") as fi:
+            if fi.read() != "readable":
+                raise Exception("Bad restore of readable file")
+        with open(os.path.join(out, in_dir[1:], "open", "inner"), "r") as fi:
+            if fi.read() != "inner":
+                raise Exception("Bad restore of file next to unreadable folder")
+        if os.path.exists(os.path.join(out, in_dir[1:], "secret")):
+            raise Exception("Unreadable file was restored")
+    finally:
+        stop_server(server)
+        if os.path.exists(os.path.join(in_dir, "secret")):
+            os.chmod(os.path.join(in_dir, "secret"), 0o600)
+        if os.path.exists(os.path.join(in_dir, "closed")):
+            os.chmod(os.path.join(in_dir, "closed"), 0o700)
+
+
+def test_interrupt(test_dir):
+    """Check that a backup stopped by SIGINT leaves a usable cache and is resumed"""
+    server, server_data = start_server(test_dir, "interrupt", 31799, memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "interrupt_in")
+        os.mkdir(in_dir)
+        for i in range(8):
+            os.mkdir(os.path.join(in_dir, "d%d" % i))
+            with open(os.path.join(in_dir, "d%d" % i, "file"), "wb") as fi:
+                fi.write(os.urandom(1024 * 1024))
+
+        cache_db = os.path.join(test_dir, "interrupt_cache.db")
+        settings = "chunk_size=65536\nmax_upload_bytes_per_sec=2097152"
+        client_config = write_client_config(test_dir, "interrupt", 31799, in_dir, settings)
+        client = subprocess.Popen(["target/release/mbackup", "-c", client_config, "backup"])
+        time.sleep(2)
+        client.send_signal(signal.SIGINT)
+        if client.wait(timeout=30) != 130:
+            raise Exception("Bad exit status after interrupt %d" % client.returncode)
+
+        db = sqlite3.connect(cache_db)
+        if db.execute("PRAGMA integrity_check").fetchone()[0] != "ok":
+            raise Exception("Cache corrupted by interrupt")
+        files = db.execute("SELECT count(*) FROM files").fetchone()[0]
+        resumed = db.execute("SELECT count(*) FROM resume").fetchone()[0]
+        finished = db.execute("SELECT path FROM resume LIMIT 1").fetchone()
+        db.close()
+        if not 0 < files < 8 or resumed == 0:
+            raise Exception("Bad cache after interrupt, %d files %d folders" % (files, resumed))
+
+        # Rewrite a file of a finished folder, keeping its size and modification time
+        changed = os.path.join(bytes(finished[0]).decode(), "file")
+        st = os.stat(changed)
+        with open(changed, "wb") as f:
+            f.write(os.urandom(1024 * 1024))
+        os.utime(changed, ns=(st.st_atime_ns, st.st_mtime_ns))
+
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        out = os.path.join(test_dir, "interrupt_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        for i in range(8):
+            path = os.path.join("d%d" % i, "file")
+            with open(os.path.join(in_dir, path), "rb") as fi, open(os.path.join(out, in_dir[1:], path), "rb") as fo:
+                if fi.read() != fo.read():
+                    raise Exception("Bad restore after interrupt")
+    finally:
+        stop_server(server)
+
+
+def test_follow_symlinks(test_dir):
+    """Check that linked folders are backed up with follow_symlinks, without following cycles"""
+    server, server_data = start_server(test_dir, "follow", 31800, memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "follow_in")
+        linked = os.path.join(test_dir, "follow_linked")
+        os.mkdir(in_dir)
+        os.mkdir(linked)
+        with open(os.path.join(linked, "file"), "w") as fi:
+            fi.write("followed")
+        os.symlink(linked, os.path.join(in_dir, "data"))
+        os.symlink(linked, os.path.join(linked, "loop"))
+        os.symlink(os.path.join(test_dir, "nowhere"), os.path.join(in_dir, "dangling"))
+
+        client_config = write_client_config(test_dir, "follow", 31800, in_dir, "follow_symlinks=true")
+        subprocess.run(["target/release/mbackup", "-c", client_config, "backup"], check=True, timeout=60)
+
+        out = os.path.join(test_dir, "follow_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        data = os.path.join(out, in_dir[1:], "data")
+        if os.path.islink(data) or not os.path.isdir(data):
+            raise Exception("Linked folder was not followed")
+        with open(os.path.join(data, "file"), "r") as fi:
+            if fi.read() != "followed":
+                raise Exception("Bad restore of followed folder")
+        if os.path.exists(os.path.join(data, "loop", "file")):
+            raise Exception("Cycle of links was followed")
+        if os.readlink(os.path.join(out, in_dir[1:], "dangling")) != os.path.join(test_dir, "nowhere"):
+            raise Exception("Dangling link was not kept")
+
+        # A followed link to another file system is not descended into with one_file_system
+        if os.stat("/proc").st_dev != os.stat(in_dir).st_dev:
+            os.symlink("/proc", os.path.join(in_dir, "proc"))
+            with open(client_config, "a") as f:
+                f.write("one_file_system=true\n")
+            res = subprocess.run(
+                ["target/release/mbackup", "-c", client_config, "backup", "--dry-run", "--json"],
+                stdout=subprocess.PIPE,
+                check=True,
+                timeout=60,
+            )
+            if json.loads(res.stdout)["files"] != 1:
+                raise Exception("Backup crossed into another file system")
+    finally:
+        stop_server(server)
+
+
+def test_size_filter(test_dir):
+    """Check that files outside the configured sizes are excluded, also from the scan estimate"""
+    server, server_data = start_server(test_dir, "size", 31801, memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "size_in")
+        os.mkdir(in_dir)
+        sizes = {"tiny": 2, "small": 10, "medium": 1000, "large": 100000}
+        for name, size in sizes.items():
+            with open(os.path.join(in_dir, name), "w") as fi:
+                fi.write("x" * size)
+
+        client_config = write_client_config(test_dir, "size", 31801, in_dir, "min_file_size=5\nmax_file_size=50000")
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "backup", "--dry-run", "--json"],
+            stdout=subprocess.PIPE,
+            check=True,
+        )
+        summary = json.loads(res.stdout)
+        if summary["files"] != 2 or summary["total_bytes"] != 1010:
+            raise Exception("Bad scan with size filter %s" % summary)
+
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        out = os.path.join(test_dir, "size_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        if sorted(os.listdir(os.path.join(out, in_dir[1:]))) != ["medium", "small"]:
+            raise Exception("Bad restore with size filter")
+    finally:
+        stop_server(server)
+
+
+def test_client_certs(test_dir):
+    """Check that tls client certificates authenticate as the users they are given to"""
+    cert_dir = os.path.join(test_dir, "certs")
+    os.mkdir(cert_dir)
+
+    def openssl(*args):
+        subprocess.check_call(["openssl"] + list(args), cwd=cert_dir, stderr=subprocess.DEVNULL)
+
+    openssl("req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=ca",
+            "-keyout", "ca.key", "-out", "ca.pem")
+    openssl("req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=localhost",
+            "-keyout", "server.key", "-out", "server.pem")
+    with open(os.path.join(cert_dir, "client.ext"), "w") as f:
+        f.write("basicConstraints=CA:FALSE\nextendedKeyUsage=clientAuth\n")
+    fingerprints = {}
+    for name in ["mapped", "unmapped"]:
+        openssl("req", "-newkey", "rsa:2048", "-nodes", "-subj", "/CN=" + name,
+                "-keyout", name + ".key", "-out", name + ".csr")
+        openssl("x509", "-req", "-days", "1", "-in", name + ".csr", "-CA", "ca.pem", "-CAkey", "ca.key",
+                "-CAcreateserial", "-extfile", "client.ext", "-out", name + ".pem")
+        with open(os.path.join(cert_dir, name + ".pem")) as f:
+            der = ssl.PEM_cert_to_DER_cert(f.read())
+        fingerprints[name] = hashlib.blake2b(der, digest_size=32).hexdigest()
+
+    server, server_data = start_server(
+        test_dir,
+        "certs",
+        31802,
+        'tls_cert="%s"\ntls_key="%s"\ntls_client_ca="%s"'
+        % (
+            os.path.join(cert_dir, "server.pem"),
+            os.path.join(cert_dir, "server.key"),
+            os.path.join(cert_dir, "ca.pem"),
+        ),
+        users='\n[[users.certs]]\nfingerprint="%s"\naccess_level="Get"\n' % fingerprints["mapped"],
+        memory=True,
+    )
+    try:
+        def status(path, cert=None, auth=None):
+            context = ssl.SSLContext(ssl.PROTOCOL_TLS_CLIENT)
+            context.check_hostname = False
+            context.verify_mode = ssl.CERT_NONE
+            if cert:
+                context.load_cert_chain(os.path.join(cert_dir, cert + ".pem"), os.path.join(cert_dir, cert + ".key"))
+            req = urllib.request.Request("https://localhost:31802" + path)
+            if auth:
+                req.add_header("Authorization", "Basic " + base64.b64encode(auth).decode())
+            try:
+                return urllib.request.urlopen(req, context=context).status
+            except urllib.error.HTTPError as err:
+                return err.code
+
+        codes = [
+            status("/roots/" + "0" * 64, cert="mapped"),
+            status("/status/" + "0" * 64, cert="mapped"),
+            status("/roots/" + "0" * 64, cert="unmapped"),
+            status("/roots/" + "0" * 64, cert="unmapped", auth=b"admin:hunter3"),
+            status("/roots/" + "0" * 64, auth=b"admin:hunter3"),
+            status("/roots/" + "0" * 64),
+        ]
+        if codes != [200, 403, 401, 200, 200, 401]:
+            raise Exception("Bad client certificate authentication %s" % codes)
+    finally:
+        stop_server(server)
+
+
+def test_generate_config(test_dir):
+    """Check that a generated config has a random key and can be backed up with"""
+    server, server_data = start_server(test_dir, "generate", 31803, password="hunter4", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "generate_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "w") as f:
+            f.write("generated")
+
+        client_config = os.path.join(test_dir, "generate_client.toml")
+        subprocess.check_call(
+            [
+                "target/release/mbackup",
+                "generate-config",
+                "--output",
+                client_config,
+                "--server",
+                "http://localhost:31803",
+                "--user",
+                "admin",
+                "--password",
+                "hunter4",
+                "--hostname",
+                "generate",
+                "--dir",
+                in_dir,
+                "--cache-db",
+                os.path.join(test_dir, "generate_cache.db"),
+            ]
+        )
+        if os.stat(client_config).st_mode & 0o077:
+            raise Exception("Generated config is readable by others")
+        if subprocess.call(["target/release/mbackup", "generate-config", "--output", client_config]) == 0:
+            raise Exception("Generated config was overwritten")
+
+        with open(client_config) as f:
+            keys = [line.split('"')[1] for line in f if line.startswith("encryption_key")]
+        other = subprocess.check_output(["target/release/mbackup", "generate-secrets"]).decode().strip()
+        for key in keys + [other]:
+            if len(key) != 64 or set(key) - set("0123456789abcdef"):
+                raise Exception("Bad generated key %s" % key)
+        if len(keys) != 1 or keys[0] == other:
+            raise Exception("Generated keys are not random")
+
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        out = os.path.join(test_dir, "generate_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        with open(os.path.join(out, in_dir[1:], "file")) as f:
+            if f.read() != "generated":
+                raise Exception("Bad restore with generated config")
+    finally:
+        stop_server(server)
+
+
+def test_key_sources(test_dir):
+    """Check that the encryption key can be read from a key file or an environment variable"""
+    server, server_data = start_server(test_dir, "keys", 31804, password="hunter5", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "keys_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "w") as f:
+            f.write("keys")
+
+        key_file = os.path.join(test_dir, "key")
+        with open(key_file, "w") as f:
+            f.write("correcthorsebatterystaple\n")
+        os.chmod(key_file, 0o600)
+
+        def config(name, source):
+            path = os.path.join(test_dir, "keys_%s.toml" % name)
+            with open(path, "w") as f:
+                f.write(
+                    """
+user="admin"
+password="hunter5"
+server="http://localhost:31804"
+hostname="keys"
+backup_dirs=["%s"]
+cache_db="%s"
+%s
+"""
+                    % (in_dir, os.path.join(test_dir, "keys_%s_cache.db" % name), source)
+                )
+            return path
+
+        file_config = config("file", 'encryption_key_file="%s"' % key_file)
+        env_config = config("env", 'encryption_key_env="MBACKUP_TEST_KEY"')
+        inline_config = config("inline", 'encryption_key="correcthorsebatterystaple"')
+
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", file_config, "backup"], stderr=subprocess.PIPE, check=True
+        )
+        if b"accessible by other users" in res.stderr:
+            raise Exception("Warned about a private key file")
+
+        # All three find the same key, so they see the same bucket
+        env = dict(os.environ, MBACKUP_TEST_KEY="correcthorsebatterystaple")
+        for path in [env_config, inline_config]:
+            roots = subprocess.check_output(["target/release/mbackup", "-c", path, "roots"], env=env)
+            # A header and the one root
+            if len(roots.strip().split(b"\n")) != 2:
+                raise Exception("Bad roots with key from %s" % path)
+        out = os.path.join(test_dir, "keys_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", env_config, "restore", "1", "--pattern", "/", "--dest", out], env=env
+        )
+        with open(os.path.join(out, in_dir[1:], "file")) as f:
+            if f.read() != "keys":
+                raise Exception("Bad restore with key from environment")
+
+        if subprocess.call(["target/release/mbackup", "-c", env_config, "roots"], stderr=subprocess.DEVNULL) == 0:
+            raise Exception("Missing key variable was accepted")
+
+        os.chmod(key_file, 0o644)
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", file_config, "roots"], stderr=subprocess.PIPE, check=True
+        )
+        if b"accessible by other users" not in res.stderr:
+            raise Exception("No warning about a readable key file")
+    finally:
+        stop_server(server)
+
+
+def test_daemon(test_dir):
+    """Check that the daemon backs up every interval and stops on SIGTERM"""
+    server, server_data = start_server(test_dir, "daemon", 31805, password="hunter6", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "daemon_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "w") as f:
+            f.write("daemon")
+
+        client_config = write_client_config(test_dir, "daemon", 31805, in_dir, "backup_interval=4", password="hunter6")
+        daemon = subprocess.Popen(["target/release/mbackup", "-c", client_config, "backup", "--daemon"])
+        try:
+            time.sleep(6)
+            daemon.send_signal(signal.SIGTERM)
+            if daemon.wait(timeout=10) != 0:
+                raise Exception("Daemon did not stop cleanly")
+        finally:
+            if daemon.poll() is None:
+                daemon.kill()
+                daemon.wait()
+
+        roots = subprocess.check_output(["target/release/mbackup", "-c", client_config, "roots"]).decode()
+        times = [
+            datetime.datetime.strptime(line.split(None, 2)[2].strip(), "%Y-%m-%d %H:%M:%S")
+            for line in roots.strip().split("\n")[1:]
+        ]
+        if len(times) != 2:
+            raise Exception("Expected two scheduled backups, got %d" % len(times))
+        spacing = (times[1] - times[0]).total_seconds()
+        if not 3 <= spacing <= 5:
+            raise Exception("Bad spacing of scheduled backups %s" % spacing)
+    finally:
+        stop_server(server)
+
+
+def test_rename_detection(test_dir):
+    """Check that a renamed file is not read or uploaded again without inode_change_detection"""
+    server, server_data = start_server(test_dir, "rename", 31806, password="hunter7", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "rename_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "wb") as f:
+            f.write(os.urandom(5 * 65536))
+
+        client_config = write_client_config(test_dir, "rename", 31806, in_dir, "chunk_size=65536", password="hunter7")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+
+        def chunk_sizes():
+            db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+            sizes = [size for (size,) in db.execute("SELECT size FROM chunks ORDER BY id")]
+            db.close()
+            return sizes
+
+        if len([size for size in chunk_sizes() if size >= 65536]) != 5:
+            raise Exception("Expected the file to be stored in five chunks")
+
+        os.mkdir(os.path.join(in_dir, "sub"))
+        os.rename(os.path.join(in_dir, "file"), os.path.join(in_dir, "sub", "moved"))
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "backup", "--dry-run", "--json"],
+            stdout=subprocess.PIPE,
+            check=True,
+        )
+        summary = json.loads(res.stdout)
+        if summary["files"] != 1 or summary["modified_files"] != 0 or summary["existing_chunks"] != 5:
+            raise Exception("Renamed file not found in the cache %s" % summary)
+
+        # Only the changed folder listings are uploaded, not the content of the file
+        before = len(chunk_sizes())
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        new = chunk_sizes()[before:]
+        if not new or any(size >= 65536 for size in new):
+            raise Exception("Renamed file was uploaded again %s" % new)
+
+        out = os.path.join(test_dir, "rename_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "2", "--pattern", "/", "--dest", out]
+        )
+        if not os.path.exists(os.path.join(out, in_dir[1:], "sub", "moved")):
+            raise Exception("Bad restore of renamed file")
+    finally:
+        stop_server(server)
+
+
+def test_mirrors(test_dir):
+    """Check that backups are stored on mirrors, and that a mirror catches up after being down"""
+
+    def chunks(data):
+        db = sqlite3.connect(os.path.join(data, "backup.db"))
+        ans = set(hash for (hash,) in db.execute("SELECT hash FROM chunks"))
+        db.close()
+        return ans
+
+    primary, primary_data = start_server(test_dir, "primary", 31807, password="hunter8")
+    mirror, mirror_data = start_server(test_dir, "mirror", 31808, password="hunter8")
+    try:
+        in_dir = os.path.join(test_dir, "mirror_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "wb") as f:
+            f.write(os.urandom(100000))
+
+        def client_config(quorum):
+            settings = 'quorum=%d\n\n[retry]\nattempts=1\ndelay=0.1\nmax_delay=0.1\n\n'
+            settings += '[[mirrors]]\nserver="http://localhost:31808"'
+            return write_client_config(
+                test_dir, "mirror_%d" % quorum, 31807, in_dir, settings % quorum, "hunter8", "mirror", "mirror"
+            )
+
+        subprocess.check_call(["target/release/mbackup", "-c", client_config(0), "backup"])
+        if not chunks(primary_data) or chunks(primary_data) != chunks(mirror_data):
+            raise Exception("Mirror has other chunks than the server")
+
+        # Without a quorum a backup fails when the mirror is down
+        stop_server(mirror)
+        mirror = None
+        with open(os.path.join(in_dir, "new"), "wb") as f:
+            f.write(os.urandom(100000))
+        if subprocess.call(["target/release/mbackup", "-c", client_config(0), "backup"]) != 1:
+            raise Exception("Backup did not fail without the mirror")
+
+        # With a quorum of one it succeeds, but tells that the mirror was missed
+        if subprocess.call(["target/release/mbackup", "-c", client_config(1), "backup"]) != 2:
+            raise Exception("Backup did not report the missing mirror")
+        if chunks(primary_data) == chunks(mirror_data):
+            raise Exception("Chunks reached the stopped mirror")
+
+        # Once the mirror is back, the next backup uploads what it missed
+        mirror, mirror_data = start_server(test_dir, "mirror", 31808, password="hunter8")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config(0), "backup"])
+        if chunks(primary_data) != chunks(mirror_data):
+            raise Exception("Mirror did not catch up")
+
+        out = os.path.join(test_dir, "mirror_out")
+        subprocess.check_call(
+            [
+                "target/release/mbackup",
+                "-c",
+                client_config(0),
+                "--server",
+                "http://localhost:31808",
+                "restore",
+                "2",
+                "--pattern",
+                "/",
+                "--dest",
+                out,
+            ]
+        )
+        with open(os.path.join(in_dir, "new"), "rb") as f, open(os.path.join(out, in_dir[1:], "new"), "rb") as g:
+            if f.read() != g.read():
+                raise Exception("Bad restore from mirror")
+
+        # The config is logged at debug verbosity, without the secrets in it
+        settings = '[[mirrors]]\nserver="http://localhost:31808"\npassword="hunter8"'
+        config = write_client_config(test_dir, "mirror_debug", 31807, in_dir, settings, "hunter8", "mirror", "mirror")
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", config, "-v", "debug", "roots"],
+            stderr=subprocess.PIPE,
+            stdout=subprocess.DEVNULL,
+            check=True,
+        )
+        if b"<redacted>" not in res.stderr or b"hunter8" in res.stderr or b"correcthorse" in res.stderr:
+            raise Exception("Secrets in the logged config")
+    finally:
+        stop_server(primary)
+        if mirror:
+            stop_server(mirror)
+
+
+def test_read_only(test_dir):
+    """Check that a read only server serves restores but refuses changes"""
+    def start_read_only(read_only):
+        return start_server(test_dir, "read_only", 31809, "read_only=%s" % str(read_only).lower(), "hunter9")
+
+    in_dir = os.path.join(test_dir, "read_only_in")
+    os.mkdir(in_dir)
+    with open(os.path.join(in_dir, "file"), "w") as f:
+        f.write("read only")
+    client_config = write_client_config(test_dir, "read_only", 31809, in_dir, password="hunter9")
+
+    server, server_data = start_read_only(False)
+    try:
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+    finally:
+        stop_server(server)
+
+    server, server_data = start_read_only(True)
+    try:
+        bucket = bucket_of(server_data)
+
+        def status(method, path, data=None):
+            return request(31809, method, path, data, b"admin:hunter9")[0]
+
+        if status("GET", "/roots/%s" % bucket) != 200:
+            raise Exception("Read only server did not list roots")
+        if status("GET", "/chunks/%s?validate=validate" % bucket) != 200:
+            raise Exception("Read only server did not list chunks with validate")
+        chunk = "a" * 64
+        if status("PUT", "/chunks/%s/%s" % (bucket, chunk), b"content") != 405:
+            raise Exception("Read only server accepted a chunk")
+        if status("DELETE", "/roots/%s/1" % bucket) != 405:
+            raise Exception("Read only server deleted a root")
+        if status("POST", "/gc/%s" % bucket, b"") != 405:
+            raise Exception("Read only server ran gc")
+
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "validate"])
+        out = os.path.join(test_dir, "read_only_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        with open(os.path.join(out, in_dir[1:], "file")) as f:
+            if f.read() != "read only":
+                raise Exception("Bad restore from read only server")
+
+        with open(os.path.join(in_dir, "new"), "w") as f:
+            f.write("new")
+        if subprocess.call(["target/release/mbackup", "-c", client_config, "backup"]) == 0:
+            raise Exception("Backup to read only server succeeded")
+    finally:
+        stop_server(server)
+
+
+def test_hosts(test_dir):
+    """Check the listing of the hosts backing up to a bucket"""
+    server, server_data = start_server(
+        test_dir,
+        "hosts",
+        31810,
+        password="hunter10",
+        users='\n[[users]]\nname="putter"\npassword="hunter11"\naccess_level="Put"\n',
+    )
+    try:
+        in_dir = os.path.join(test_dir, "hosts_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "w") as f:
+            f.write("hosts")
+        client_config = write_client_config(test_dir, "hosts", 31810, in_dir, password="hunter10")
+        for host in ["beta", "alpha", "beta"]:
+            subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup", "--hostname", host])
+
+        bucket = bucket_of(server_data)
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        rows = db.execute("SELECT host, max(time), count(*) FROM roots GROUP BY host ORDER BY host").fetchall()
+        db.close()
+        if [(host, count) for (host, _, count) in rows] != [("alpha", 1), ("beta", 2)]:
+            raise Exception("Bad roots in the database %s" % rows)
+
+        def get(path, user=b"admin:hunter10"):
+            req = urllib.request.Request("http://localhost:31810" + path)
+            req.add_header("Authorization", "Basic " + base64.b64encode(user).decode())
+            return urllib.request.urlopen(req).read().decode()
+
+        text = get("/hosts/%s" % bucket)
+        if text != "".join("%s %d %d\n" % row for row in rows):
+            raise Exception("Bad text hosts %r" % text)
+        hosts = json.loads(get("/hosts/%s?format=json" % bucket))
+        if [(h["host"], h["last_time"], h["roots"]) for h in hosts] != rows:
+            raise Exception("Bad json hosts %s" % hosts)
+        try:
+            get("/hosts/%s" % bucket, b"putter:hunter11")
+            raise Exception("Hosts listed without Get access")
+        except urllib.error.HTTPError as err:
+            if err.code != 401:
+                raise
+    finally:
+        stop_server(server)
+
+
+def test_hashed_cache_paths(test_dir):
+    """Check that files are found in the cache by the hashes of their paths with hash_cache_paths"""
+    server, server_data = start_server(test_dir, "hashed", 31811, password="hunter12", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "hashed_in")
+        os.mkdir(in_dir)
+        for name in ["one", "two"]:
+            with open(os.path.join(in_dir, name), "w") as f:
+                f.write(name * 10000)
+        cache_db = os.path.join(test_dir, "hashed_cache.db")
+
+        def client_config(hashed):
+            settings = "hash_cache_paths=%s" % str(hashed).lower()
+            return write_client_config(test_dir, "hashed", 31811, in_dir, settings, password="hunter12")
+
+        def cached_paths():
+            db = sqlite3.connect(cache_db)
+            ans = [path for (path,) in db.execute("SELECT path FROM files")]
+            db.close()
+            return ans
+
+        def modified_files(config):
+            res = subprocess.run(
+                ["target/release/mbackup", "-c", config, "backup", "--dry-run", "--json"],
+                stdout=subprocess.PIPE,
+                check=True,
+            )
+            return json.loads(res.stdout)["modified_files"]
+
+        subprocess.check_call(["target/release/mbackup", "-c", client_config(False), "backup"])
+        if sorted(cached_paths()) != [os.path.join(in_dir, "one"), os.path.join(in_dir, "two")]:
+            raise Exception("Bad plain cache paths %s" % cached_paths())
+
+        # Switching to hashed paths forgets the plain paths
+        if modified_files(client_config(True)) != 2:
+            raise Exception("Plain paths used with hash_cache_paths")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config(True), "backup"])
+        paths = cached_paths()
+        if len(paths) != 2 or any(not isinstance(p, bytes) or len(p) != 32 for p in paths):
+            raise Exception("Bad hashed cache paths %s" % paths)
+
+        # The hashed paths are found, and a changed file is not
+        if modified_files(client_config(True)) != 0:
+            raise Exception("Hashed cache paths not found")
+        with open(os.path.join(in_dir, "two"), "w") as f:
+            f.write("changed")
+        if modified_files(client_config(True)) != 1:
+            raise Exception("Changed file found by its hashed path")
+
+        # An interrupted backup leaves no plain paths of the folders it completed
+        for i in range(8):
+            os.mkdir(os.path.join(in_dir, "d%d" % i))
+            with open(os.path.join(in_dir, "d%d" % i, "file"), "wb") as fi:
+                fi.write(os.urandom(1024 * 1024))
+        settings = "hash_cache_paths=true\nchunk_size=65536\nmax_upload_bytes_per_sec=2097152"
+        throttled = write_client_config(test_dir, "hashed", 31811, in_dir, settings, password="hunter12")
+        client = subprocess.Popen(["target/release/mbackup", "-c", throttled, "backup"])
+        time.sleep(2)
+        client.send_signal(signal.SIGINT)
+        if client.wait(timeout=30) != 130:
+            raise Exception("Bad exit status after interrupt %d" % client.returncode)
+        db = sqlite3.connect(cache_db)
+        keys = [path for (path,) in db.execute("SELECT path FROM resume")]
+        db.close()
+        if not keys or any(len(key) != 32 for key in keys):
+            raise Exception("Bad hashed resume paths %s" % keys)
+        for name in [cache_db, cache_db + "-wal"]:
+            if os.path.exists(name):
+                with open(name, "rb") as f:
+                    if in_dir.encode() in f.read():
+                        raise Exception("Plain path left in the cache by an interrupted backup")
+        subprocess.check_call(["target/release/mbackup", "-c", throttled, "backup"])
+    finally:
+        stop_server(server)
+
+
+def test_stdin(test_dir):
+    """Check that a stream piped to backup --stdin is restored as a file"""
+    server, server_data = start_server(test_dir, "stdin", 31812, password="hunter13", memory=True)
+    try:
+        client_config = write_client_config(test_dir, "stdin", 31812, settings="chunk_size=65536", password="hunter13")
+        # Not a multiple of the chunk size, so the last chunk is short
+        blob = os.urandom(3 * 65536 + 1234)
+        subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "backup", "--stdin", "dump.bin"],
+            input=blob,
+            check=True,
+        )
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "cat", "1", "/dump.bin"],
+            stdout=subprocess.PIPE,
+            check=True,
+        )
+        if res.stdout != blob:
+            raise Exception("Bad content of stdin from cat")
+
+        out = os.path.join(test_dir, "stdin_out")
+        os.mkdir(out)
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        restored = os.path.join(out, "dump.bin")
+        with open(restored, "rb") as f:
+            if f.read() != blob:
+                raise Exception("Bad content of restored stdin")
+        if os.stat(restored).st_mode & 0o777 != 0o644:
+            raise Exception("Bad mode of restored stdin")
+
+        # An empty stream is an empty file
+        subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "backup", "--stdin", "empty"],
+            input=b"",
+            check=True,
+        )
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "cat", "2", "/empty"],
+            stdout=subprocess.PIPE,
+            check=True,
+        )
+        if res.stdout != b"":
+            raise Exception("Bad content of empty stdin")
+    finally:
+        stop_server(server)
+
+
+def test_delete_bucket(test_dir):
+    """Check that delete-bucket removes every root of the bucket only when confirmed"""
+    server, server_data = start_server(test_dir, "delete_bucket", 31813, password="hunter14", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "delete_bucket_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "file"), "w") as f:
+            f.write("content")
+
+        def client_config(host):
+            name = "delete_bucket_%s" % host
+            return write_client_config(test_dir, name, 31813, in_dir, password="hunter14", hostname=host)
+
+        def root_count():
+            res = subprocess.run(
+                ["target/release/mbackup", "-c", client_config("one"), "roots"],
+                stdout=subprocess.PIPE,
+                check=True,
+            )
+            # The first line is a header
+            return len(res.stdout.splitlines()) - 1
+
+        for host in ["one", "one", "two"]:
+            subprocess.check_call(["target/release/mbackup", "-c", client_config(host), "backup"])
+        if root_count() != 3:
+            raise Exception("Expected three roots")
+
+        res = subprocess.run(["target/release/mbackup", "-c", client_config("one"), "delete-root", "42"])
+        if res.returncode == 0:
+            raise Exception("delete-root of a missing root succeeded")
+
+        res = subprocess.run(["target/release/mbackup", "-c", client_config("one"), "delete-bucket"])
+        if res.returncode == 0 or root_count() != 3:
+            raise Exception("delete-bucket without --yes deleted roots")
+
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config("one"), "delete-bucket", "--yes"],
+            stdout=subprocess.PIPE,
+            check=True,
+        )
+        if res.stdout != b"Deleted 3 roots\n":
+            raise Exception("Bad delete-bucket output %s" % res.stdout)
+        if root_count() != 0:
+            raise Exception("Roots left after delete-bucket")
+    finally:
+        stop_server(server)
+
+
+def test_shard_depth(test_dir):
+    """Check that chunks are stored at the configured shard_depth and moved by reshard"""
+    settings = "small_size=1\nshard_depth=%d"
+    server_config, server_data = write_server_config(test_dir, "shard", 31814, settings % 1, "hunter15")
+
+    def chunk_files():
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        chunks = db.execute("SELECT bucket, hash FROM chunks WHERE content IS NULL").fetchall()
+        db.close()
+        files = set()
+        for dirpath, _, filenames in os.walk(os.path.join(server_data, "data")):
+            for name in filenames:
+                files.add(os.path.relpath(os.path.join(dirpath, name), server_data))
+        return chunks, files
+
+    def check_depth(depth):
+        chunks, files = chunk_files()
+        expected = set()
+        for bucket, chunk in chunks:
+            parts = [chunk[2 * i : 2 * i + 2] for i in range(depth)] + [chunk[2 * depth :]]
+            expected.add(os.path.join("data", bucket, *parts))
+        if not chunks or files != expected:
+            raise Exception("Bad chunk files at depth %d: %s" % (depth, sorted(files)))
+
+    server, server_data = start_server(test_dir, "shard", 31814, settings % 1, "hunter15")
+    try:
+        in_dir = os.path.join(test_dir, "shard_in")
+        os.mkdir(in_dir)
+        content = os.urandom(3 * 65536)
+        with open(os.path.join(in_dir, "file"), "wb") as f:
+            f.write(content)
+        client_config = write_client_config(test_dir, "shard", 31814, in_dir, "chunk_size=65536", password="hunter15")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        check_depth(1)
+    finally:
+        stop_server(server)
+
+    reshard = ["target/release/mbackupd", "--config", server_config, "--data-dir", server_data, "reshard"]
+    write_server_config(test_dir, "shard", 31814, settings % 2, "hunter15")
+    subprocess.check_call(reshard)
+    check_depth(2)
+    # Running it again moves nothing
+    subprocess.check_call(reshard)
+    check_depth(2)
+
+    server, server_data = start_server(test_dir, "shard", 31814, settings % 2, "hunter15")
+    try:
+        out = os.path.join(test_dir, "shard_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        with open(os.path.join(out, in_dir[1:], "file"), "rb") as f:
+            if f.read() != content:
+                raise Exception("Bad restore after reshard")
+    finally:
+        stop_server(server)
+
+    write_server_config(test_dir, "shard", 31814, settings % 1, "hunter15")
+    subprocess.check_call(reshard)
+    check_depth(1)
+
+
+def test_delete_chunks_list(test_dir):
+    """Check that a long list of chunks is deleted in one request, sent in pieces"""
+    server, server_data = start_server(test_dir, "delete_list", 31815, password="hunter16", memory=True)
+    bucket = "5" * 64
+    auth = {"Authorization": "Basic " + base64.b64encode(b"admin:hunter16").decode()}
+    chunks = [hashlib.blake2b(b"%d" % i, digest_size=32).hexdigest() for i in range(5000)]
+
+    def request(method, path, body=None, encode_chunked=False, headers={}):
+        headers = dict(auth, **headers)
+        conn.request(method, path, body=body, headers=headers, encode_chunked=encode_chunked)
+        res = conn.getresponse()
+        return res.status, res.read()
+
+    def delete_list(names):
+        # Pieces of an odd size, so that most names are split between two of them
+        body = "\0".join(names).encode()
+        pieces = (body[i : i + 1000] for i in range(0, len(body), 1000))
+        return request("DELETE", "/chunks/%s" % bucket, pieces, True)[0]
+
+    def existing(names):
+        return request("POST", "/chunks/%s/exists" % bucket, "\0".join(names).encode())[1]
+
+    try:
+        conn = http.client.HTTPConnection("localhost", 31815)
+        for chunk in chunks:
+            data = chunk.encode()
+            content_hash = {"X-Content-Blake2b": hashlib.blake2b(data, digest_size=32).hexdigest()}
+            if request("PUT", "/chunks/%s/%s" % (bucket, chunk), data, headers=content_hash)[0] != 200:
+                raise Exception("Put failed")
+        if existing(chunks) != b"1" * 5000:
+            raise Exception("Chunks missing before delete")
+
+        if delete_list(chunks[:4000]) != 200:
+            raise Exception("Delete of 4000 chunks failed")
+        if existing(chunks) != b"0" * 4000 + b"1" * 1000:
+            raise Exception("Bad chunks left after delete")
+
+        # The chunks that are there are deleted, and the missing ones are reported
+        if delete_list(chunks[3000:]) != 404:
+            raise Exception("Missing chunks not reported")
+        if existing(chunks) != b"0" * 5000:
+            raise Exception("Chunks left after delete")
+        metrics = request("GET", "/metrics")[1].decode()
+        if "mbackup_chunks_deleted_total 5000\n" not in metrics:
+            raise Exception("Bad count of deleted chunks")
+
+        if delete_list(chunks[:10] + ["bad"]) != 400:
+            raise Exception("Bad chunk accepted")
+    finally:
+        stop_server(server)
+
+
+def test_chunk_consistency(test_dir):
+    """Check that a failure at each step of putting and deleting a chunk leaves no row without content"""
+    server, server_data = start_server(test_dir, "consistency", 31816, "small_size=1", password="hunter17")
+    bucket = "6" * 64
+    data = b"consistency" * 100
+    chunk = hashlib.blake2b(data, digest_size=32).hexdigest()
+    chunk_file = os.path.join(server_data, "data", bucket, chunk[:2], chunk[2:])
+    upload_dir = os.path.join(server_data, "data", "upload", bucket)
+
+    def request(method, body=None):
+        req = urllib.request.Request(
+            "http://localhost:31816/chunks/%s/%s" % (bucket, chunk), data=body, method=method
+        )
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"admin:hunter17").decode())
+        if body is not None:
+            req.add_header("X-Content-Blake2b", chunk)
+        try:
+            return urllib.request.urlopen(req).status
+        except urllib.error.HTTPError as err:
+            return err.code
+
+    def rows():
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        (cnt,) = db.execute("SELECT count(*) FROM chunks WHERE hash=?", (chunk,)).fetchone()
+        db.close()
+        return cnt
+
+    def sql(statement):
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        db.execute(statement)
+        db.commit()
+        db.close()
+
+    def check(expect_row, expect_file, what):
+        if rows() != expect_row or os.path.isfile(chunk_file) != expect_file:
+            raise Exception("Inconsistent chunk after %s" % what)
+        if os.path.isdir(upload_dir) and os.listdir(upload_dir):
+            raise Exception("Temporary file left after %s" % what)
+
+    try:
+        # Storing the content fails, as something else is in its place
+        os.makedirs(os.path.join(chunk_file, "blocker"))
+        if request("PUT", data) != 500:
+            raise Exception("Failed store not reported")
+        check(0, False, "failed store")
+        shutil.rmtree(chunk_file)
+
+        # Inserting the row fails after the content is stored
+        sql("CREATE TRIGGER fail_insert BEFORE INSERT ON chunks BEGIN SELECT RAISE(FAIL, 'test'); END")
+        if request("PUT", data) != 500:
+            raise Exception("Failed insert not reported")
+        check(0, False, "failed insert")
+        sql("DROP TRIGGER fail_insert")
+
+        if request("PUT", data) != 200:
+            raise Exception("Put failed")
+        check(1, True, "put")
+
+        # Deleting the row fails, so the content stays
+        sql("CREATE TRIGGER fail_delete BEFORE DELETE ON chunks BEGIN SELECT RAISE(FAIL, 'test'); END")
+        if request("DELETE") != 500:
+            raise Exception("Failed row delete not reported")
+        check(1, True, "failed row delete")
+        sql("DROP TRIGGER fail_delete")
+
+        # Removing the content fails after the row is deleted, leaving only content the
+        # database does not list
+        os.rename(chunk_file, chunk_file + ".saved")
+        os.makedirs(os.path.join(chunk_file, "blocker"))
+        if request("DELETE") != 500:
+            raise Exception("Failed content delete not reported")
+        check(0, False, "failed content delete")
+        shutil.rmtree(chunk_file)
+
+        # The chunk can be put again
+        if request("PUT", data) != 200:
+            raise Exception("Put after failures failed")
+        check(1, True, "put again")
+        with open(chunk_file, "rb") as f:
+            if f.read() != data:
+                raise Exception("Bad content after failures")
+    finally:
+        stop_server(server)
+
+
+def test_bind(test_dir):
+    """Check that the server listens on an ephemeral port and on a unix socket, and rejects bad addresses"""
+    server_data = os.path.join(test_dir, "bind_server")
+    os.mkdir(server_data)
+
+    def start(bind):
+        return subprocess.Popen(
+            ["target/release/mbackupd", "--data-dir", server_data, "--bind", bind],
+            stderr=subprocess.PIPE,
+            universal_newlines=True,
+        )
+
+    res = subprocess.run(
+        ["target/release/mbackupd", "--data-dir", server_data, "--bind", "localhost"],
+        stderr=subprocess.PIPE,
+        universal_newlines=True,
+        timeout=10,
+    )
+    if res.returncode != 1 or "Bad bind address" not in res.stderr:
+        raise Exception("Bad bind address accepted")
+
+    server = start("127.0.0.1:0")
+    try:
+        port = None
+        for line in server.stderr:
+            if "Server listening on 127.0.0.1:" in line:
+                port = int(line.rsplit(":", 1)[1])
+                break
+        if not port:
+            raise Exception("Ephemeral port not logged")
+        if urllib.request.urlopen("http://127.0.0.1:%d/health" % port).status != 200:
+            raise Exception("Bad health on ephemeral port")
+    finally:
+        stop_server(server)
+
+    class UnixConnection(http.client.HTTPConnection):
+        def __init__(self, path):
+            super().__init__("localhost")
+            self.path = path
+
+        def connect(self):
+            self.sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+            self.sock.connect(self.path)
+
+    def health(path):
+        conn = UnixConnection(path)
+        conn.request("GET", "/health")
+        status = conn.getresponse().status
+        conn.close()
+        return status
+
+    sock = os.path.join(test_dir, "bind.sock")
+    for stop in [signal.SIGKILL, signal.SIGTERM]:
+        # The socket left behind by the killed server is replaced
+        server = start("unix:" + sock)
+        try:
+            for _ in range(50):
+                if os.path.exists(sock) and stat.S_ISSOCK(os.stat(sock).st_mode):
+                    break
+                time.sleep(0.1)
+            if health(sock) != 200:
+                raise Exception("Bad health on unix socket")
+        finally:
+            server.send_signal(stop)
+            server.wait()
+    if os.path.exists(sock):
+        raise Exception("Socket left behind by stopped server")
+
+
+def test_cors(test_dir):
+    """Check the preflight and the headers of cross origin requests, and that they still need credentials"""
+    server, server_data = start_server(
+        test_dir, "cors", 31817, 'cors_origins=["https://ui.example"]', password="hunter18", memory=True
+    )
+    url = "http://localhost:31817/roots/" + "7" * 64
+
+    def request(method, headers):
+        req = urllib.request.Request(url, method=method, headers=headers)
+        try:
+            res = urllib.request.urlopen(req)
+            return res.status, res.headers
+        except urllib.error.HTTPError as err:
+            return err.code, err.headers
+
+    try:
+        preflight = {"Access-Control-Request-Method": "GET", "Access-Control-Request-Headers": "authorization"}
+        status, headers = request("OPTIONS", dict(preflight, Origin="https://ui.example"))
+        if (
+            status != 204
+            or headers["Access-Control-Allow-Origin"] != "https://ui.example"
+            or headers["Access-Control-Allow-Credentials"] != "true"
+            or "GET" not in headers["Access-Control-Allow-Methods"]
+            or "authorization" not in headers["Access-Control-Allow-Headers"].lower()
+        ):
+            raise Exception("Bad preflight response %d %s" % (status, dict(headers)))
+
+        status, headers = request("OPTIONS", dict(preflight, Origin="https://evil.example"))
+        if status != 204 or "Access-Control-Allow-Origin" in headers:
+            raise Exception("Preflight of other origin allowed")
+
+        # Cross origin requests still need credentials
+        status, headers = request("GET", {"Origin": "https://ui.example"})
+        if status != 401 or headers["Access-Control-Allow-Origin"] != "https://ui.example":
+            raise Exception("Bad unauthenticated cross origin response %d" % status)
+        auth = "Basic " + base64.b64encode(b"admin:hunter18").decode()
+        status, headers = request("GET", {"Origin": "https://ui.example", "Authorization": auth})
+        if status != 200 or headers["Access-Control-Allow-Origin"] != "https://ui.example":
+            raise Exception("Bad cross origin response %d" % status)
+        if "Origin" not in headers["Vary"]:
+            raise Exception("Cross origin response does not vary by origin")
+        status, headers = request("GET", {"Origin": "https://evil.example", "Authorization": auth})
+        if status != 200 or "Access-Control-Allow-Origin" in headers:
+            raise Exception("Other origin allowed")
+    finally:
+        stop_server(server)
+
+    # Any origin is allowed by "*", but only origins listed by name may send credentials
+    server, server_data = start_server(
+        test_dir, "cors", 31817, 'cors_origins=["https://ui.example", "*"]', password="hunter18", memory=True
+    )
+    try:
+        status, headers = request("OPTIONS", dict(preflight, Origin="https://other.example"))
+        if (
+            status != 204
+            or headers["Access-Control-Allow-Origin"] != "*"
+            or "Access-Control-Allow-Credentials" in headers
+            or "authorization" not in headers["Access-Control-Allow-Headers"].lower()
+        ):
+            raise Exception("Bad preflight response for any origin %d %s" % (status, dict(headers)))
+        status, headers = request("GET", {"Origin": "https://other.example", "Authorization": auth})
+        if (
+            status != 200
+            or headers["Access-Control-Allow-Origin"] != "*"
+            or "Access-Control-Allow-Credentials" in headers
+        ):
+            raise Exception("Bad response for any origin %d %s" % (status, dict(headers)))
+        status, headers = request("GET", {"Origin": "https://ui.example", "Authorization": auth})
+        if (
+            headers["Access-Control-Allow-Origin"] != "https://ui.example"
+            or headers["Access-Control-Allow-Credentials"] != "true"
+        ):
+            raise Exception("Listed origin lost its credentials %s" % dict(headers))
+    finally:
+        stop_server(server)
+
+
+def test_packs(test_dir):
+    """Check that small files are bundled into packs, deduplicated against them and restored from them"""
+    server, server_data = start_server(test_dir, "packs", 31818, password="hunter19")
+    try:
+        in_dir = os.path.join(test_dir, "packs_in")
+        os.mkdir(in_dir)
+        files = {}
+        for i in range(200):
+            files["small%d" % i] = os.urandom(500 + i)
+        # Larger than pack_chunk_size, so stored on its own
+        files["large"] = os.urandom(100000)
+        for name, content in files.items():
+            with open(os.path.join(in_dir, name), "wb") as fi:
+                fi.write(content)
+
+        settings = "chunk_size=65536\npack_size=32768\npack_chunk_size=4096"
+        client_config = write_client_config(test_dir, "packs", 31818, in_dir, settings, password="hunter19")
+
+        def chunk_count():
+            db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+            count = db.execute("SELECT count(*) FROM chunks").fetchone()[0]
+            db.close()
+            return count
+
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        # About 150KB of small files in packs of 32KB, two chunks of the large file and the root
+        count = chunk_count()
+        if count > 12:
+            raise Exception("Small files were not packed, %d chunks" % count)
+
+        # A copy of a small file is found in its pack, a new one goes in a new pack
+        files["copy"] = files["small7"]
+        files["new"] = os.urandom(300)
+        for name in ["copy", "new"]:
+            with open(os.path.join(in_dir, name), "wb") as fi:
+                fi.write(files[name])
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        if chunk_count() != count + 2:
+            raise Exception("Expected a new pack and root, got %d chunks" % (chunk_count() - count))
+
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "validate"])
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "validate", "--full"])
+
+        out = os.path.join(test_dir, "packs_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "2", "--pattern", "/", "--dest", out]
+        )
+        for name, content in files.items():
+            with open(os.path.join(out, in_dir[1:], name), "rb") as fi:
+                if fi.read() != content:
+                    raise Exception("Bad content of restored %s" % name)
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "cat", "2", os.path.join(in_dir, "small42")],
+            stdout=subprocess.PIPE,
+            check=True,
+        )
+        if res.stdout != files["small42"]:
+            raise Exception("Bad content of packed file from cat")
+    finally:
+        stop_server(server)
+
+
+def test_mount(test_dir):
+    """Check reading files and listing folders of a root mounted with FUSE"""
+    if not os.path.exists("/dev/fuse") or shutil.which("fusermount") is None:
+        print("Skipping mount test, FUSE is not available")
+        return
+    subprocess.check_call(["cargo", "build", "--release", "--features", "fuser"])
+    server, server_data = start_server(test_dir, "mount", 31819, password="hunter20", memory=True)
+    mount = None
+    mountpoint = os.path.join(test_dir, "mount_point")
+    os.mkdir(mountpoint)
+    try:
+        in_dir = os.path.join(test_dir, "mount_in")
+        os.makedirs(os.path.join(in_dir, "sub"))
+        # Several chunks, so reads cross chunk boundaries
+        large = os.urandom(200000)
+        with open(os.path.join(in_dir, "sub", "large"), "wb") as fi:
+            fi.write(large)
+        with open(os.path.join(in_dir, "small"), "w") as fi:
+            fi.write("known content")
+        os.symlink("small", os.path.join(in_dir, "link"))
+
+        client_config = write_client_config(test_dir, "mount", 31819, in_dir, "chunk_size=65536", password="hunter20")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+
+        mount = subprocess.Popen(["target/release/mbackup", "-c", client_config, "mount", "1", mountpoint])
+        mounted = os.path.join(mountpoint, in_dir[1:])
+        for _ in range(50):
+            if os.path.exists(mounted):
+                break
+            time.sleep(0.1)
+        else:
+            raise Exception("Root was not mounted")
+
+        if sorted(os.listdir(mounted)) != ["link", "small", "sub"]:
+            raise Exception("Bad listing of mounted folder %s" % os.listdir(mounted))
+        with open(os.path.join(mounted, "small")) as fi:
+            if fi.read() != "known content":
+                raise Exception("Bad content of mounted file")
+        if os.readlink(os.path.join(mounted, "link")) != "small":
+            raise Exception("Bad target of mounted link")
+        path = os.path.join(mounted, "sub", "large")
+        if os.stat(path).st_size != len(large):
+            raise Exception("Bad size of mounted file")
+        with open(path, "rb") as fi:
+            # Start in the middle of the second chunk
+            fi.seek(100000)
+            if fi.read(50000) != large[100000:150000]:
+                raise Exception("Bad content read from the middle of mounted file")
+            fi.seek(0)
+            if fi.read() != large:
+                raise Exception("Bad content of mounted file")
+        try:
+            open(os.path.join(mounted, "small"), "w")
+            raise Exception("Mounted file opened for writing")
+        except OSError:
+            pass
+    finally:
+        if mount is not None:
+            subprocess.call(["fusermount", "-u", mountpoint])
+            mount.wait()
+        stop_server(server)
+
+
+def test_btime(test_dir):
+    """Check that the birth time of files is recorded where the file system reports it"""
+    server, server_data = start_server(test_dir, "btime", 31820, password="hunter21", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "btime_in")
+        os.mkdir(in_dir)
+        path = os.path.join(in_dir, "file")
+        with open(path, "w") as fi:
+            fi.write("born")
+        # stat prints 0 or - when the birth time is not known
+        res = subprocess.run(["stat", "-c", "%W", path], stdout=subprocess.PIPE, check=True)
+        expected = res.stdout.decode().strip()
+        expected = int(expected) if expected not in ("0", "-") else None
+
+        def listed_btime(run, btime):
+            settings = "btime=%s" % str(btime).lower()
+            client_config = write_client_config(test_dir, "btime", 31820, in_dir, settings, password="hunter21")
+            subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+            res = subprocess.run(
+                ["target/release/mbackup", "-c", client_config, "list", str(run), in_dir, "--json"],
+                stdout=subprocess.PIPE,
+                check=True,
+            )
+            return json.loads(res.stdout)[0].get("btime")
+
+        if listed_btime(1, False) is not None:
+            raise Exception("Birth time recorded without btime")
+        btime = listed_btime(2, True)
+        if btime != expected:
+            raise Exception("Recorded birth time %s but stat reports %s" % (btime, expected))
+        if expected is None:
+            print("Birth time is not reported here, checked that it is left out")
+    finally:
+        stop_server(server)
+
+
+def test_progress(test_dir):
+    """Check that --quiet shows no progress and --progress json writes progress events"""
+    server, server_data = start_server(test_dir, "progress", 31821, password="hunter22", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "progress_in")
+        os.mkdir(in_dir)
+        for i in range(3):
+            with open(os.path.join(in_dir, "file%d" % i), "wb") as fi:
+                fi.write(os.urandom(100000))
+        client_config = write_client_config(
+            test_dir, "progress", 31821, in_dir, "recheck=true\nchunk_size=65536", password="hunter22"
+        )
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "--quiet", "backup"],
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+            check=True,
+        )
+        if res.stdout or b"\r" in res.stderr:
+            raise Exception("Progress shown with --quiet %r" % res.stdout)
+
+        out = os.path.join(test_dir, "progress_out")
+        for args in [["backup"], ["restore", "1", "--pattern", "/", "--dest", out]]:
+            res = subprocess.run(
+                ["target/release/mbackup", "-c", client_config, "--progress", "json"] + args,
+                stdout=subprocess.PIPE,
+                check=True,
+            )
+            events = [json.loads(line) for line in res.stdout.decode().splitlines()]
+            if not events or not events[-1]["finished"]:
+                raise Exception("Missing final progress event of %s" % args[0])
+            last = events[-1]
+            if last["done"] != last["total"] or last["total"] < 300000:
+                raise Exception("Bad final progress event of %s %s" % (args[0], last))
+            # Events are written at most every second, and the transfer takes less
+            if len(events) > 3:
+                raise Exception("Too many progress events %d" % len(events))
+    finally:
+        stop_server(server)
+
+
+def test_bucket_override(test_dir):
+    """Check that --label and --bucket set the host and bucket of the stored root"""
+    server, server_data = start_server(test_dir, "override", 31822, password="hunter23")
+    try:
+        in_dir = os.path.join(test_dir, "override_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "data"), "w") as fi:
+            fi.write("nginx config")
+        client_config = write_client_config(test_dir, "override", 31822, in_dir, password="hunter23", hostname="web01")
+        bucket = "5" * 64
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "--bucket", bucket, "backup", "--label", "web01-nginx"]
+        )
+        # The same cache with the bucket of the key uploads the chunks again
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        roots = db.execute("SELECT bucket, host FROM roots ORDER BY id").fetchall()
+        db.close()
+        if roots != [(bucket, "web01-nginx"), (GOLDEN_BUCKET, "web01")]:
+            raise Exception("Bad buckets and hosts of roots %s" % roots)
+
+        out = os.path.join(test_dir, "override_out")
+        subprocess.check_call(
+            [
+                "target/release/mbackup",
+                "-c",
+                client_config,
+                "--bucket",
+                bucket,
+                "restore",
+                "1",
+                "--pattern",
+                "/",
+                "--dest",
+                out,
+            ]
+        )
+        with open(os.path.join(out, in_dir[1:], "data")) as fi:
+            if fi.read() != "nginx config":
+                raise Exception("Bad content restored from the other bucket")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "validate", "--full"])
+
+        for bad in ["5" * 63, "A" * 64, "g" * 64]:
+            res = subprocess.run(["target/release/mbackup", "-c", client_config, "--bucket", bad, "roots"])
+            if res.returncode == 0:
+                raise Exception("Bad bucket %s accepted" % bad)
+    finally:
+        stop_server(server)
+
+
+def test_root_tags(test_dir):
+    """Check that tags given with a root are stored and listed, and that roots without tags have none"""
+    server, server_data = start_server(test_dir, "tags", 31823, password="hunter24")
+    try:
+        in_dir = os.path.join(test_dir, "tags_in")
+        os.mkdir(in_dir)
+        with open(os.path.join(in_dir, "data"), "w") as fi:
+            fi.write("before the upgrade")
+        client_config = write_client_config(test_dir, "tags", 31823, in_dir, password="hunter24", hostname="tagged")
+        # Two roots without tags, so that the text listing has untagged roots next to each other
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "backup", "--tag", "monthly", "--tag", "pre-upgrade"]
+        )
+        if subprocess.run(["target/release/mbackup", "-c", client_config, "backup", "--tag", "a,b"]).returncode == 0:
+            raise Exception("Tag with a comma accepted")
+
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        rows = db.execute("SELECT id, tags FROM roots ORDER BY id").fetchall()
+        hash = db.execute("SELECT hash FROM roots WHERE id=1").fetchone()[0]
+        db.close()
+        if rows != [(1, None), (2, None), (3, "monthly,pre-upgrade")]:
+            raise Exception("Bad tags in the database %s" % rows)
+
+        def tags_request(method, path, headers={}):
+            data = hash.encode() if method == "PUT" else None
+            return request(31823, method, path, data, b"admin:hunter24", headers)
+
+        # Tags can be given as a query parameter or a header
+        if tags_request("PUT", "/roots/%s/tagged?tags=weekly" % GOLDEN_BUCKET)[0] != 200:
+            raise Exception("Root with tags in the query refused")
+        if tags_request("PUT", "/roots/%s/tagged" % GOLDEN_BUCKET, {"X-Root-Tags": "daily,v1.2"})[0] != 200:
+            raise Exception("Root with tags in the header refused")
+        for bad in ["", "a,,b", "a%20b", "a;b"]:
+            if tags_request("PUT", "/roots/%s/tagged?tags=%s" % (GOLDEN_BUCKET, bad))[0] != 400:
+                raise Exception("Bad tags %r accepted" % bad)
+
+        status, body = tags_request("GET", "/roots/%s?format=json" % GOLDEN_BUCKET)
+        tags = [(r["id"], r["tags"]) for r in json.loads(body)]
+        expected = [(1, []), (2, []), (3, ["monthly", "pre-upgrade"]), (4, ["weekly"]), (5, ["daily", "v1.2"])]
+        if status != 200 or tags != expected:
+            raise Exception("Bad json tags %s" % tags)
+        # Roots without tags have only the four fields of roots from before tags
+        status, body = tags_request("GET", "/roots/%s" % GOLDEN_BUCKET)
+        rows = [row.split(b"\0") for row in body.split(b"\0\0")]
+        if [int(row[0]) for row in rows] != [1, 2, 3, 4, 5]:
+            raise Exception("Bad text roots %r" % body)
+        tags = [row[4:] for row in rows]
+        if tags != [[], [], [b"monthly,pre-upgrade"], [b"weekly"], [b"daily,v1.2"]]:
+            raise Exception("Bad text tags %s" % tags)
+
+        out = subprocess.check_output(["target/release/mbackup", "-c", client_config, "roots"]).decode()
+        lines = out.splitlines()
+        if len(lines) != 6 or "monthly,pre-upgrade" not in lines[3] or "daily,v1.2" not in lines[5]:
+            raise Exception("Tags not listed by roots %r" % out)
+        # Roots after untagged ones are still found by the client
+        for root in ["2", "3"]:
+            out_dir = os.path.join(test_dir, "tags_out_%s" % root)
+            subprocess.check_call(
+                ["target/release/mbackup", "-c", client_config, "restore", root, "--pattern", "/", "--dest", out_dir]
+            )
+            with open(os.path.join(out_dir, in_dir[1:], "data")) as fi:
+                if fi.read() != "before the upgrade":
+                    raise Exception("Bad content restored from root %s" % root)
+    finally:
+        stop_server(server)
+
+
+def test_bucket_small_size(test_dir):
+    """Check that chunks of a bucket with its own small_size are stored by that size"""
+    small_bucket = "7" * 64
+    other_bucket = "8" * 64
+    server, server_data = start_server(
+        test_dir, "small_size", 31824, 'small_size=4096\n\n[bucket_small_sizes]\n"%s" = 1000' % small_bucket, "hunter25"
+    )
+
+    def put(bucket, data):
+        chunk = hashlib.blake2b(data, digest_size=32).hexdigest()
+        path = "/chunks/%s/%s" % (bucket, chunk)
+        if request(31824, "PUT", path, data, b"admin:hunter25", {"X-Content-Blake2b": chunk})[0] != 200:
+            raise Exception("Put failed")
+        return chunk
+
+    def inline(bucket, chunk):
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        (content,) = db.execute(
+            "SELECT content FROM chunks WHERE bucket=? AND hash=?", (bucket, chunk)
+        ).fetchone()
+        db.close()
+        on_disk = os.path.isfile(os.path.join(server_data, "data", bucket, chunk[:2], chunk[2:]))
+        if (content is not None) == on_disk:
+            raise Exception("Chunk %s both or neither inline and on disk" % chunk)
+        return content is not None
+
+    try:
+        # Chunks are stored on disk from the small_size of their bucket
+        if not inline(small_bucket, put(small_bucket, b"b" * 999)):
+            raise Exception("Chunk below the small_size of the bucket not inline")
+        if inline(small_bucket, put(small_bucket, b"a" * 1000)):
+            raise Exception("Chunk of the small_size of the bucket inline")
+        # Other buckets use small_size
+        if not inline(other_bucket, put(other_bucket, b"c" * 4095)):
+            raise Exception("Chunk below small_size not inline")
+        if inline(other_bucket, put(other_bucket, b"d" * 4096)):
+            raise Exception("Chunk of small_size inline")
+    finally:
+        stop_server(server)
+
+    # A small_size too large to store as a blob is refused
+    server_config, _ = write_server_config(
+        test_dir, "small_size", 31824, '[bucket_small_sizes]\n"%s" = 1073741824' % small_bucket, "hunter25"
+    )
+    res = subprocess.run(
+        ["target/release/mbackupd", "--config", server_config, "--data-dir", server_data], timeout=10
+    )
+    if res.returncode == 0:
+        raise Exception("Too large small_size of a bucket accepted")
+
+
+def test_maintenance(test_dir):
+    """Check that maintenance shrinks the database after chunks are deleted"""
+    bucket = "9" * 64
+
+    def start_maintenance(allow):
+        settings = "allow_maintenance=%s" % str(allow).lower()
+        users = '\n[[users]]\nname="backup"\npassword="hunter27"\naccess_level="Put"\n'
+        return start_server(test_dir, "maintenance", 31825, settings, "hunter26", users)
+
+    def call(method, path, data=None, user=b"admin:hunter26", headers={}):
+        return request(31825, method, path, data, user, headers)
+
+    def db_size():
+        path = os.path.join(server_data, "backup.db")
+        return sum(os.path.getsize(p) for p in [path, path + "-wal"] if os.path.exists(p))
+
+    server, server_data = start_maintenance(False)
+    try:
+        if call("POST", "/maintenance")[0] != 403:
+            raise Exception("Maintenance ran while not allowed")
+    finally:
+        stop_server(server)
+
+    server, server_data = start_maintenance(True)
+    try:
+        chunks = []
+        for _ in range(200):
+            data = os.urandom(50000)
+            chunk = hashlib.blake2b(data, digest_size=32).hexdigest()
+            if call("PUT", "/chunks/%s/%s" % (bucket, chunk), data, headers={"X-Content-Blake2b": chunk})[0] != 200:
+                raise Exception("Put failed")
+            chunks.append(chunk)
+        for chunk in chunks:
+            if call("DELETE", "/chunks/%s/%s" % (bucket, chunk))[0] != 200:
+                raise Exception("Delete failed")
+
+        if call("POST", "/maintenance", user=b"backup:hunter27")[0] == 200:
+            raise Exception("Maintenance ran without Delete access")
+
+        # Maintenance is refused while a gc is running, which it is while its body is sent
+        conn = http.client.HTTPConnection("localhost", 31825)
+        conn.putrequest("POST", "/gc/%s?start=0" % bucket)
+        conn.putheader("Authorization", "Basic " + base64.b64encode(b"admin:hunter26").decode())
+        conn.putheader("Content-Length", "64")
+        conn.endheaders()
+        conn.send(b"0" * 32)
+        time.sleep(0.5)
+        try:
+            if call("POST", "/maintenance")[0] != 409:
+                raise Exception("Maintenance ran during a gc")
+        finally:
+            conn.send(b"0" * 32)
+            if conn.getresponse().status != 200:
+                raise Exception("Gc during maintenance failed")
+            conn.close()
+
+        before = db_size()
+        status, body = call("POST", "/maintenance")
+        if status != 200:
+            raise Exception("Maintenance failed with %d" % status)
+        info = json.loads(body)
+        after = db_size()
+        if info["bytes_before"] + info["wal_bytes_before"] != before:
+            raise Exception("Bad size before maintenance %s, expected %d" % (info, before))
+        if info["bytes_after"] + info["wal_bytes_after"] != after or info["wal_bytes_after"] != 0:
+            raise Exception("Bad size after maintenance %s, expected %d" % (info, after))
+        if after > before - 200 * 50000 // 2:
+            raise Exception("Database did not shrink from %d to %d bytes" % (before, after))
+
+        # The server keeps working after maintenance
+        data = b"after maintenance"
+        chunk = hashlib.blake2b(data, digest_size=32).hexdigest()
+        if call("PUT", "/chunks/%s/%s" % (bucket, chunk), data, headers={"X-Content-Blake2b": chunk})[0] != 200:
+            raise Exception("Put after maintenance failed")
+        if call("GET", "/chunks/%s/%s" % (bucket, chunk)) != (200, data):
+            raise Exception("Get after maintenance failed")
+    finally:
+        stop_server(server)
+
+
+def test_concurrent_gc(test_dir):
+    """Check that a gc of a bucket is refused while another one runs, and that backups survive both"""
+    server, server_data = start_server(test_dir, "concurrent_gc", 31826, password="hunter28")
+    try:
+        in_dir = os.path.join(test_dir, "concurrent_gc_in")
+        os.mkdir(in_dir)
+        keep = os.urandom(200000)
+        with open(os.path.join(in_dir, "keep"), "wb") as f:
+            f.write(keep)
+        with open(os.path.join(in_dir, "drop"), "wb") as f:
+            f.write(os.urandom(200000))
+        client_config = write_client_config(
+            test_dir, "concurrent_gc", 31826, in_dir, "chunk_size=65536", password="hunter28"
+        )
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        os.unlink(os.path.join(in_dir, "drop"))
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "delete-root", "1"])
+        bucket = bucket_of(server_data)
+
+        def chunk_count():
+            db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+            (count,) = db.execute("SELECT count(*) FROM chunks").fetchone()
+            db.close()
+            return count
+
+        # A gc is running while its body is sent, one with start=0 removes nothing
+        live = ("0" * 64).encode()
+        conn = http.client.HTTPConnection("localhost", 31826)
+        conn.putrequest("POST", "/gc/%s?start=0" % bucket)
+        conn.putheader("Authorization", "Basic " + base64.b64encode(b"admin:hunter28").decode())
+        conn.putheader("Content-Length", str(len(live)))
+        conn.endheaders()
+        conn.send(live[:32])
+        time.sleep(0.5)
+        try:
+            if request(31826, "POST", "/gc/%s?start=0" % bucket, b"", b"admin:hunter28")[0] != 409:
+                raise Exception("Second gc of a bucket not refused")
+            if subprocess.call(["target/release/mbackup", "-c", client_config, "prune"]) == 0:
+                raise Exception("Prune ran during a gc")
+            # Other buckets are not held up
+            if request(31826, "POST", "/gc/%s?start=0" % ("1" * 64), b"", b"admin:hunter28")[0] != 200:
+                raise Exception("Gc of another bucket refused")
+        finally:
+            conn.send(live[32:])
+            res = conn.getresponse()
+            res.read()
+            conn.close()
+        if res.status != 200:
+            raise Exception("First gc failed with %d" % res.status)
+
+        # A root stored since start may use chunks that were not marked, so nothing is swept
+        before = chunk_count()
+        if request(31826, "POST", "/gc/%s?start=1" % bucket, b"", b"admin:hunter28")[0] != 409:
+            raise Exception("Gc not refused after a root was stored")
+        if chunk_count() != before:
+            raise Exception("Gc removed chunks after a root was stored")
+
+        # Once the first gc is done, the next one runs and leaves the remaining root intact
+        before = chunk_count()
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "prune"])
+        if chunk_count() >= before:
+            raise Exception("Prune removed nothing")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "validate"])
+        out = os.path.join(test_dir, "concurrent_gc_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "2", "--pattern", "/", "--dest", out]
+        )
+        with open(os.path.join(out, in_dir[1:], "keep"), "rb") as f:
+            if f.read() != keep:
+                raise Exception("Bad restore after concurrent gcs")
+    finally:
+        stop_server(server)
+
+
+def test_https(test_dir):
+    """Backup and restore over tls, while another client stalls in its handshake"""
+    cert_dir = os.path.join(test_dir, "https_certs")
+    os.mkdir(cert_dir)
+    cert = os.path.join(cert_dir, "server.pem")
+    key = os.path.join(cert_dir, "server.key")
+    subprocess.check_call(
+        ["openssl", "req", "-x509", "-newkey", "rsa:2048", "-nodes", "-days", "1", "-subj", "/CN=localhost",
+         "-addext", "subjectAltName=DNS:localhost", "-keyout", key, "-out", cert],
+        stderr=subprocess.DEVNULL,
+    )
+    settings = 'tls_cert="%s"\ntls_key="%s"' % (cert, key)
+    server, server_data = start_server(test_dir, "https", 31827, settings, password="hunter29", memory=True)
+    # The client trusts the certificate of the server through openssl
+    env = dict(os.environ, SSL_CERT_FILE=cert)
+    stalled = socket.create_connection(("localhost", 31827))
+    try:
+        in_dir = os.path.join(test_dir, "https_in")
+        os.mkdir(in_dir)
+        files = {"small": b"https", "large": os.urandom(3 * 65536 + 1234)}
+        for name, content in files.items():
+            with open(os.path.join(in_dir, name), "wb") as f:
+                f.write(content)
+        settings = "chunk_size=65536\n\n[retry]\nattempts=0"
+        client_config = write_client_config(
+            test_dir, "https", 31827, in_dir, settings, password="hunter29", scheme="https"
+        )
+        # A connection that never starts its handshake does not hold up the others
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"], env=env, timeout=60)
+        out = os.path.join(test_dir, "https_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out],
+            env=env,
+            timeout=60,
+        )
+        for name, content in files.items():
+            with open(os.path.join(out, in_dir[1:], name), "rb") as f:
+                if f.read() != content:
+                    raise Exception("Bad restore of %s over tls" % name)
+
+        # A client that does not trust the certificate refuses the server
+        if subprocess.call(["target/release/mbackup", "-c", client_config, "roots"], timeout=60) == 0:
+            raise Exception("Untrusted certificate accepted")
+    finally:
+        stalled.close()
+        stop_server(server)
+
+
+def test_passwords(test_dir):
+    """Check that hashed passwords authenticate when right and not when wrong,
+    and that passwords not verified before are only checked within the rate limit"""
+
+    def password_hash(password):
+        return subprocess.check_output(["target/release/mbackupd", "hash-password", password]).decode().strip()
+
+    users = ""
+    for name, password in [("hashed", "hunter30"), ("other", "hunter31")]:
+        users += '\n[[users]]\nname="%s"\npassword_hash="%s"\naccess_level="Get"\n' % (name, password_hash(password))
+    # Slow enough that no request is refilled while the test runs
+    settings = "rate_limit=0.2\nrate_limit_burst=3"
+    server, server_data = start_server(test_dir, "passwords", 31828, settings, users=users, memory=True)
+    path = "/stats/%s" % ("0" * 64)
+    try:
+        if request(31828, "GET", path, user=b"hashed:hunter30")[0] != 200:
+            raise Exception("Right password refused")
+        if request(31828, "GET", path, user=b"hashed:hunter30")[0] != 200:
+            raise Exception("Right password refused once verified")
+        # Wrong passwords count against the address of the client
+        for wrong in [b"hashed:hunter3", b"hashed:hunter32", b"other:hunter30"]:
+            if request(31828, "GET", path, user=wrong)[0] != 401:
+                raise Exception("Wrong password %s accepted" % wrong)
+        # Once it is used up, passwords are no longer checked, but those verified before are still known
+        if request(31828, "GET", path, user=b"other:hunter31")[0] != 429:
+            raise Exception("Password checked over the rate limit")
+        if request(31828, "GET", path, user=b"hashed:hunter30")[0] != 200:
+            raise Exception("Verified password refused over the rate limit of the address")
+        time.sleep(5)
+        if request(31828, "GET", path, user=b"other:hunter31")[0] != 200:
+            raise Exception("Right password refused after waiting")
+    finally:
+        stop_server(server)
+
+
+def test_plain_passwords(test_dir):
+    """Check that plain text passwords are only accepted when they match exactly"""
+    server, server_data = start_server(test_dir, "plain", 31830, password="hunter33", memory=True)
+    path = "/stats/%s" % ("0" * 64)
+    try:
+        if request(31830, "GET", path, user=b"admin:hunter33")[0] != 200:
+            raise Exception("Right password refused")
+        # Wrong passwords of the same length, and shorter or longer ones sharing a prefix with it
+        for wrong in [b"hunter34", b"Hunter33", b"hunter3", b"hunter333", b""]:
+            if request(31830, "GET", path, user=b"admin:" + wrong)[0] != 401:
+                raise Exception("Wrong password %s accepted" % wrong)
+    finally:
+        stop_server(server)
+
+
+def test_tokens(test_dir):
+    """Check that a bearer token grants exactly its own access level"""
+    put_token = "p" * 40
+    users = '\n[[users.tokens]]\ntoken="%s"\naccess_level="Put"\n' % put_token
+    server, server_data = start_server(test_dir, "tokens", 31831, users=users, memory=True)
+    data = b"token"
+    chunk = hashlib.blake2b(data, digest_size=32).hexdigest()
+    path = "/chunks/%s/%s" % ("6" * 64, chunk)
+
+    def bearer(token):
+        return {"Authorization": "Bearer " + token}
+
+    try:
+        headers = dict(bearer(put_token), **{"X-Content-Blake2b": chunk})
+        if request(31831, "PUT", path, data, headers=headers)[0] != 200:
+            raise Exception("Put token refused for a put")
+        # The token is known, but does not grant the access, while an unknown token is not authenticated
+        if request(31831, "DELETE", path, headers=bearer(put_token))[0] != 403:
+            raise Exception("Put token not forbidden to delete")
+        if request(31831, "DELETE", path, headers=bearer("q" * 40))[0] != 401:
+            raise Exception("Unknown token not unauthorized")
+        if request(31831, "GET", path)[1] != data:
+            raise Exception("Chunk deleted with a put token")
+    finally:
+        stop_server(server)
+
+
+def test_content_defined_chunking(test_dir):
+    """Check that content defined chunks have the configured average size,
+    and that inserting or removing bytes keeps most of the chunks of a file"""
+    server, server_data = start_server(test_dir, "cdc", 31832, password="hunter34", memory=True)
+    try:
+        in_dir = os.path.join(test_dir, "cdc_in")
+        os.mkdir(in_dir)
+        path = os.path.join(in_dir, "file")
+        content = os.urandom(16 * 1024 * 1024)
+        settings = "content_defined_chunking=true\nmin_chunk_size=65536\n"
+        settings += "avg_chunk_size=131072\nmax_chunk_size=524288"
+        client_config = write_client_config(test_dir, "cdc", 31832, in_dir, settings, password="hunter34")
+
+        def backup(content):
+            with open(path, "wb") as f:
+                f.write(content)
+            subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+
+        def chunk_count():
+            status, body = request(31832, "GET", "/chunks/%s" % GOLDEN_BUCKET, user=b"admin:hunter34")
+            return len(body.splitlines())
+
+        backup(content)
+        # 128 chunks of 128KB on average, apart from the chunks of the folders and the root
+        count = chunk_count()
+        if not 100 <= count <= 160:
+            raise Exception("Bad number of chunks %d" % count)
+
+        # An edit changes the chunk it is in, and maybe the next, but not the rest of the file.
+        # The folders and the root also get new chunks
+        for edited in [content[:5000000] + b"inserted" + content[5000000:], content[:9000000] + content[9001000:]]:
+            backup(edited)
+            added = chunk_count() - count
+            if added > 8:
+                raise Exception("Edit added %d chunks" % added)
+            count += added
+    finally:
+        stop_server(server)
+
+
+def test_compression(test_dir):
+    """Check that a backup with compression is restored byte for byte, and is stored compressed"""
+    server, server_data = start_server(test_dir, "compression", 31833, password="hunter35")
+    try:
+        in_dir = os.path.join(test_dir, "compression_in")
+        os.mkdir(in_dir)
+        files = {
+            "text": b"compressible content " * 500000,
+            "random": os.urandom(1024 * 1024),
+            "mixed": (b"a" * 65536 + os.urandom(65536)) * 8,
+            "small": b"s",
+            "empty": b"",
+        }
+        for name, content in files.items():
+            with open(os.path.join(in_dir, name), "wb") as f:
+                f.write(content)
+        settings = "compression=true\nchunk_size=262144"
+        client_config = write_client_config(test_dir, "compression", 31833, in_dir, settings, password="hunter35")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        (stored,) = db.execute("SELECT sum(size) FROM chunks").fetchone()
+        db.close()
+        if stored > len(files["random"]) + len(files["mixed"]) // 2 + len(files["text"]) // 10:
+            raise Exception("Backup of %d bytes not compressed" % stored)
+
+        out = os.path.join(test_dir, "compression_out")
+        subprocess.check_call(
+            ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest", out]
+        )
+        for name, content in files.items():
+            with open(os.path.join(out, in_dir[1:], name), "rb") as f:
+                if f.read() != content:
+                    raise Exception("Bad restore of compressed %s" % name)
+    finally:
+        stop_server(server)
+
+
+def test_tampered_chunk(test_dir):
+    """Check that a chunk changed on the server fails its authentication when restored"""
+    server, server_data = start_server(test_dir, "tampered", 31829, password="hunter32")
+    try:
+        in_dir = os.path.join(test_dir, "tampered_in")
+        os.mkdir(in_dir)
+        content = os.urandom(10000)
+        with open(os.path.join(in_dir, "file"), "wb") as f:
+            f.write(content)
+        client_config = write_client_config(test_dir, "tampered", 31829, in_dir, password="hunter32")
+        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        restore = ["target/release/mbackup", "-c", client_config, "restore", "1", "--pattern", "/", "--dest"]
+        subprocess.check_call(restore + [os.path.join(test_dir, "tampered_out")])
+
+        # The chunk of the file is the one as large as its encrypted content
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        rows = db.execute("SELECT id, content FROM chunks WHERE length(content) > 10000").fetchall()
+        if len(rows) != 1:
+            raise Exception("File chunk not found %d" % len(rows))
+        (chunk_id, stored) = rows[0]
+        if stored[0] not in (1, 2):
+            raise Exception("File chunk has no version %d" % stored[0])
+        # Flip a bit of the ciphertext, keeping the version so it is still taken as authenticated
+        tampered = bytearray(stored)
+        tampered[5000] ^= 1
+        db.execute("UPDATE chunks SET content=? WHERE id=?", (bytes(tampered), chunk_id))
+        db.commit()
+        db.close()
+
+        res = subprocess.run(restore + [os.path.join(test_dir, "tampered_out2")], stderr=subprocess.PIPE)
+        if res.returncode == 0 or b"Authentication" not in res.stderr:
+            raise Exception("Tampered chunk restored %d %s" % (res.returncode, res.stderr))
+    finally:
+        stop_server(server)
 
 
 def main():
@@ -39,9 +2592,19 @@ access_level="Delete"
 """
             )
 
-        # Start the server
+        test_invalid_sizes(test_dir)
+
+        # Start the server, with a stale and a fresh upload left behind
         server_data = os.path.join(test_dir, "server")
-        os.mkdir(server_data)
+        upload_dir = os.path.join(server_data, "data", "upload", "0" * 64)
+        os.makedirs(upload_dir)
+        stale_upload = os.path.join(upload_dir, "1" * 64 + "_1")
+        fresh_upload = os.path.join(upload_dir, "1" * 64 + "_2")
+        for path in [stale_upload, fresh_upload]:
+            with open(path, "w") as fi:
+                fi.write("upload")
+        old = time.time() - 2 * 24 * 60 * 60
+        os.utime(stale_upload, (old, old))
         server = subprocess.Popen(
             [
                 "target/release/mbackupd",
@@ -62,6 +2625,9 @@ password="hunter1"
 encryption_key="correcthorsebatterystaple"
 server="http://localhost:31782"
 hostname="test"
+xattrs=true
+exclude=["skip", "*.tmp", "!keep.tmp"]
+max_depth=4
 backup_dirs=["%s"]
 cache_db="%s"
 """%(in_dir, os.path.join(test_dir, "cache.db"))
@@ -86,12 +2652,77 @@ cache_db="%s"
             fi.write("test2" * 1024 * 1024)
         with open(e, "w") as fi:
             fi.write("test3")
+        # An mtime with nanoseconds
+        os.utime(e, ns=(1234567890123456789, 1234567890123456789))
         with open(f, "w") as fi:
             fi.write("x" * 1024 * 1024 * 50)
         os.symlink(i, h)
+        # A name that is not valid utf-8
+        u = os.path.join(d1.encode(), b"u\xff\xfe")
+        with open(u, "wb") as fi:
+            fi.write(b"test5")
+        # Names and link targets with separators of the old formats
+        n = os.path.join(d1, "n\n,l")
+        with open(n, "w") as fi:
+            fi.write("test6")
+        nl = os.path.join(d1, "nl")
+        os.symlink("t\n,x", nl)
+        # Special files, device nodes can only be created by root
+        fifo = os.path.join(d1, "fifo")
+        os.mkfifo(fifo)
+        dev = os.path.join(d1, "dev")
+        try:
+            os.mknod(dev, 0o600 | stat.S_IFCHR, os.makedev(1, 3))
+            has_dev = True
+        except PermissionError:
+            has_dev = False
+        # Folders nested too deep, and a link to a parent folder which must not be followed
+        deep = os.path.join(d1, "d2", "d3", "d4", "d5")
+        os.makedirs(deep)
+        with open(os.path.join(deep, "deep"), "w") as fi:
+            fi.write("test10")
+        os.symlink(d1, os.path.join(d1, "d2", "loop"))
+        # A second link to c
+        cl = os.path.join(d1, "cl")
+        os.link(c, cl)
+        # Excluded files and directories
+        skip = os.path.join(d1, "skip")
+        os.mkdir(skip)
+        with open(os.path.join(skip, "s"), "w") as fi:
+            fi.write("test7")
+        tmp = os.path.join(d1, "x.tmp")
+        with open(tmp, "w") as fi:
+            fi.write("test8")
+        keep = os.path.join(d1, "keep.tmp")
+        with open(keep, "w") as fi:
+            fi.write("test9")
+        # An extended attribute, if the file system supports it
+        try:
+            os.setxattr(a, "user.mbackup", b"xattr1")
+            has_xattrs = True
+        except OSError:
+            has_xattrs = False
 
-        # Backup the files and validate the files
+        # A dry run reports the files without storing anything, here logging json lines
         time.sleep(0.5)
+        res = subprocess.run(
+            ["target/release/mbackup", "-c", client_config, "backup", "--dry-run", "--json"],
+            env=dict(os.environ, MBACKUP_LOG_FORMAT="json"),
+            stdout=subprocess.PIPE,
+            stderr=subprocess.PIPE,
+            check=True,
+        )
+        summary = json.loads(res.stdout)
+        if summary["files"] == 0 or summary["new_bytes"] == 0:
+            raise Exception("Bad dry run summary")
+        log_lines = res.stderr.decode().splitlines()
+        if not log_lines:
+            raise Exception("No log lines")
+        for line in log_lines:
+            if "message" not in json.loads(line):
+                raise Exception("Bad json log line")
+
+        # Backup the files and validate the files
         subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
         subprocess.check_call(
             [
@@ -107,6 +2738,157 @@ cache_db="%s"
             ]
         )
 
+        # Check that only the stale upload was removed at startup
+        if os.path.exists(stale_upload) or not os.path.exists(fresh_upload):
+            raise Exception("Bad removal of stale uploads")
+        os.unlink(fresh_upload)
+
+        # Check that the backup shows up in the metrics
+        req = urllib.request.Request("http://localhost:31782/metrics")
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"restore:hunter2").decode())
+        metrics = urllib.request.urlopen(req).read().decode()
+        if "mbackup_chunks_put_total 0\n" in metrics or "mbackup_bytes_stored_total 0\n" in metrics:
+            raise Exception("Bad metrics")
+
+        # Check that listing the roots is only answered with 304 while they are unchanged
+        roots_url = "http://localhost:31782/roots/%s" % bucket_of(server_data)
+        req = urllib.request.Request(roots_url)
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"restore:hunter2").decode())
+        etag = urllib.request.urlopen(req).headers["ETag"]
+        req.add_header("If-None-Match", etag)
+        try:
+            urllib.request.urlopen(req)
+            raise Exception("Unchanged roots returned")
+        except urllib.error.HTTPError as err:
+            if err.code != 304:
+                raise
+
+        # Check the json listing of the roots and status, next to the text formats
+        req = urllib.request.Request(roots_url + "?format=json")
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"restore:hunter2").decode())
+        res = urllib.request.urlopen(req)
+        roots = json.loads(res.read())
+        if res.headers["ETag"] == etag or len(roots) != 1:
+            raise Exception("Bad json roots %s" % roots)
+        if (
+            set(roots[0]) != {"id", "host", "time", "hash", "tags"}
+            or roots[0]["id"] != 1
+            or len(roots[0]["hash"]) != 64
+            or roots[0]["tags"] != []
+        ):
+            raise Exception("Bad json root %s" % roots[0])
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        rows = db.execute("SELECT id, host, time, hash FROM roots WHERE bucket=?", (bucket_of(server_data),)).fetchall()
+        db.close()
+        if [(r["id"], r["host"], r["time"], r["hash"]) for r in roots] != rows:
+            raise Exception("Json roots do not match the database %s" % roots)
+        # The text format stays the default, as the client reads it
+        req = urllib.request.Request(roots_url)
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"restore:hunter2").decode())
+        text = urllib.request.urlopen(req).read().decode()
+        if text != "\0\0".join("%d\0%s\0%d\0%s" % row for row in rows):
+            raise Exception("Bad text roots %r" % text)
+        status_url = "http://localhost:31782/status/%s" % bucket_of(server_data)
+        req = urllib.request.Request(status_url)
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"backup:hunter1").decode())
+        int(urllib.request.urlopen(req).read())
+        req.add_header("Accept", "application/json")
+        status = json.loads(urllib.request.urlopen(req).read())
+        if set(status) != {"last_delete", "bucket"} or status["bucket"] != bucket_of(server_data):
+            raise Exception("Bad json status %s" % status)
+
+        # Scrub the chunk store, with a chunk file removed and another truncated
+        scrub_cmd = ["target/release/mbackupd", "--config", server_config, "--data-dir", server_data, "scrub"]
+        subprocess.check_call(scrub_cmd)
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        planted = db.execute("SELECT bucket, hash, size FROM chunks WHERE content IS NULL LIMIT 2").fetchall()
+        db.close()
+        paths = [os.path.join(server_data, "data", b, h[:2], h[2:]) for b, h, _ in planted]
+        saved = []
+        for path in paths:
+            with open(path, "rb") as fi:
+                saved.append(fi.read())
+        os.rename(paths[0], paths[0] + ".moved")
+        with open(paths[1], "wb") as fi:
+            fi.write(saved[1][:10])
+        res = subprocess.run(scrub_cmd, stdout=subprocess.PIPE)
+        expected = "%s %s missing\n%s %s size %d stored 10\n" % (
+            planted[0][0], planted[0][1], planted[1][0], planted[1][1], planted[1][2]
+        )
+        if res.returncode != 1 or sorted(res.stdout.decode().splitlines()) != sorted(expected.splitlines()):
+            raise Exception("Bad scrub report %r" % res.stdout)
+        os.rename(paths[0] + ".moved", paths[0])
+        with open(paths[1], "wb") as fi:
+            fi.write(saved[1])
+
+        # Check which chunks exist in one request
+        db = sqlite3.connect(os.path.join(server_data, "backup.db"))
+        bucket, present = db.execute("SELECT bucket, hash FROM chunks LIMIT 1").fetchone()
+        db.close()
+        req = urllib.request.Request(
+            "http://localhost:31782/chunks/%s/exists" % bucket,
+            data=("\0".join([present, "1" * 64, present])).encode(),
+            method="POST",
+        )
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"backup:hunter1").decode())
+        if urllib.request.urlopen(req).read() != b"101":
+            raise Exception("Bad chunks exist response")
+
+        # Check the usage reported for a bucket after putting a small and a large chunk
+        stats_bucket = "2" * 64
+        stats_chunks = [("3" * 64, 10), ("4" * 64, 200 * 1024)]
+        for name, size in stats_chunks:
+            data = bytes(i % 251 for i in range(size))
+            req = urllib.request.Request(
+                "http://localhost:31782/chunks/%s/%s" % (stats_bucket, name), data=data, method="PUT"
+            )
+            req.add_header("Authorization", "Basic " + base64.b64encode(b"backup:hunter1").decode())
+            req.add_header("X-Content-Blake2b", hashlib.blake2b(data, digest_size=32).hexdigest())
+            urllib.request.urlopen(req)
+        req = urllib.request.Request("http://localhost:31782/stats/%s" % stats_bucket)
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"restore:hunter2").decode())
+        stats = json.loads(urllib.request.urlopen(req).read())
+        if stats != {
+            "chunks": 2,
+            "bytes": 10 + 200 * 1024,
+            "inline_chunks": 1,
+            "inline_bytes": 10,
+            "stored_chunks": 1,
+            "stored_bytes": 200 * 1024,
+            "roots": 0,
+            "hosts": 0,
+        }:
+            raise Exception("Bad bucket stats %s" % stats)
+
+        # Get ranges of the chunk stored in the database and of the one stored on disk
+        for name, size in stats_chunks:
+            data = bytes(i % 251 for i in range(size))
+            for header, start, end in [("bytes=2-5", 2, 6), ("bytes=3-", 3, size), ("bytes=-4,0-1", size - 4, size)]:
+                req = urllib.request.Request("http://localhost:31782/chunks/%s/%s" % (stats_bucket, name))
+                req.add_header("Authorization", "Basic " + base64.b64encode(b"restore:hunter2").decode())
+                req.add_header("Range", header)
+                res = urllib.request.urlopen(req)
+                if res.status != 206 or res.read() != data[start:end]:
+                    raise Exception("Bad range %s of chunk of size %d" % (header, size))
+            req.add_header("Range", "bytes=%d-" % size)
+            try:
+                urllib.request.urlopen(req)
+                raise Exception("Unsatisfiable range returned")
+            except urllib.error.HTTPError as err:
+                if err.code != 416:
+                    raise
+
+        # Check that the server rejects content not matching its hash
+        req = urllib.request.Request("http://localhost:31782/chunks/%s/%s" % ("0" * 64, "1" * 64), data=b"hello", method="PUT")
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"backup:hunter1").decode())
+        req.add_header("X-Content-Blake2b", "2" * 64)
+        try:
+            urllib.request.urlopen(req)
+            raise Exception("Mismatched content accepted")
+        except urllib.error.HTTPError as err:
+            if err.code != 400:
+                raise
+
         # Recover from backup
         r1 = os.path.join(test_dir, "r1")
         subprocess.check_call(
@@ -143,6 +2925,8 @@ cache_db="%s"
         with open(os.path.join(r1, e[1:]), "r") as fi:
             if fi.read() != "test3":
                 raise Exception("Bad restore 4")
+        if os.stat(os.path.join(r1, e[1:])).st_mtime_ns != 1234567890123456789:
+            raise Exception("Bad restore of mtime")
 
         with open(os.path.join(r1, f[1:]), "r") as fi:
             if fi.read() != "x" * 1024 * 1024 * 50:
@@ -151,14 +2935,105 @@ cache_db="%s"
         if os.readlink(os.path.join(r1, h[1:])) != i:
             raise Exception("Bad restore link 1")
 
+        with open(os.path.join(r1.encode(), u[1:]), "rb") as fi:
+            if fi.read() != b"test5":
+                raise Exception("Bad restore non utf-8 name")
+
+        with open(os.path.join(r1, n[1:]), "r") as fi:
+            if fi.read() != "test6":
+                raise Exception("Bad restore of name with newline")
+
+        if os.readlink(os.path.join(r1, nl[1:])) != "t\n,x":
+            raise Exception("Bad restore of link target with newline")
+
+        if os.stat(os.path.join(r1, c[1:])).st_ino != os.stat(os.path.join(r1, cl[1:])).st_ino:
+            raise Exception("Bad restore of hard link")
+
+        if not stat.S_ISFIFO(os.lstat(os.path.join(r1, fifo[1:])).st_mode):
+            raise Exception("Bad restore of fifo")
+
+        if has_dev:
+            st = os.lstat(os.path.join(r1, dev[1:]))
+            if not stat.S_ISCHR(st.st_mode) or st.st_rdev != os.makedev(1, 3):
+                raise Exception("Bad restore of device node")
+
+        if not os.path.isdir(os.path.join(r1, deep[1:])) or os.listdir(os.path.join(r1, deep[1:])):
+            raise Exception("Bad restore of too deeply nested folder")
+        if os.readlink(os.path.join(r1, d1[1:], "d2", "loop")) != d1:
+            raise Exception("Bad restore of link to parent folder")
+
+        if os.path.lexists(os.path.join(r1, skip[1:])) or os.path.lexists(os.path.join(r1, tmp[1:])):
+            raise Exception("Excluded path restored")
+
+        # List a folder of the root, and all that is below it
+        list_cmd = ["target/release/mbackup", "-c", client_config, "--user", "restore", "--password", "hunter2", "list"]
+        listed = json.loads(subprocess.check_output(list_cmd + ["1", d1, "--json"]))
+        paths = [ent["path"] for ent in listed]
+        if a not in paths or os.path.join(d1, "d2") not in paths or os.path.join(d1, "d2", "d3") in paths:
+            raise Exception("Bad list of folder")
+        if [ent["size"] for ent in listed if ent["path"] == e] != [5]:
+            raise Exception("Bad size in list")
+        listed = json.loads(subprocess.check_output(list_cmd + ["1", d1, "--recursive", "--json"]))
+        if deep not in [ent["path"] for ent in listed]:
+            raise Exception("Bad recursive list")
+        lines = subprocess.check_output(list_cmd + ["1", d1]).decode("utf-8", "replace").splitlines()
+        if not any(line.startswith("-") and line.endswith(" " + a) for line in lines):
+            raise Exception("Bad ls style list")
+
+        with open(os.path.join(r1, keep[1:]), "r") as fi:
+            if fi.read() != "test9":
+                raise Exception("Bad restore of included path")
+
+        if has_xattrs and os.getxattr(os.path.join(r1, a[1:]), "user.mbackup") != b"xattr1":
+            raise Exception("Bad restore of extended attribute")
+
         # Modify state
         with open(g, "w") as fi:
             fi.write("test4")
         os.unlink(b)
         os.unlink(e)
+        t = os.path.join(d1, "t")
+        with open(t, "wb") as fi:
+            fi.write(os.urandom(3 * 1024 * 1024))
 
-        # Backup new state
-        subprocess.check_call(["target/release/mbackup", "-c", client_config, "backup"])
+        # Backup new state with the upload rate limited to 1MB/s,
+        # the 3MB of t take at least 2 seconds after the first second worth of bytes
+        throttled_config = os.path.join(test_dir, "throttled.toml")
+        with open(client_config, "r") as fi:
+            content = fi.read()
+        with open(throttled_config, "w") as fi:
+            fi.write(content + "max_upload_bytes_per_sec=1048576\n")
+        # Interrupt the first attempt while t is being uploaded, the next backup resumes it
+        interrupted = subprocess.Popen(["target/release/mbackup", "-c", throttled_config, "backup"])
+        time.sleep(1)
+        interrupted.kill()
+        interrupted.wait()
+        start = time.time()
+        subprocess.check_call(["target/release/mbackup", "-c", throttled_config, "backup"])
+        if time.time() - start < 2:
+            raise Exception("Upload was not throttled")
+        # The etag of the roots changes with the new backup
+        req = urllib.request.Request(roots_url, method="HEAD")
+        req.add_header("Authorization", "Basic " + base64.b64encode(b"restore:hunter2").decode())
+        req.add_header("If-None-Match", etag)
+        if urllib.request.urlopen(req).headers["ETag"] == etag:
+            raise Exception("Roots etag did not change")
+        db = sqlite3.connect(os.path.join(test_dir, "cache.db"))
+        if db.execute("SELECT count(*) FROM resume").fetchone()[0] != 0:
+            raise Exception("Resume state not cleared after backup")
+        db.close()
+
+        # Compare the roots before and after the modification
+        diff_cmd = ["target/release/mbackup", "-c", client_config, "--user", "restore", "--password", "hunter2", "diff"]
+        changes = {ent["path"]: ent["change"] for ent in json.loads(subprocess.check_output(diff_cmd + ["1", "2", "--json"]))}
+        if changes.get(t) != "added" or changes.get(g) != "added" or changes.get(b) != "removed":
+            raise Exception("Bad diff of added and removed files")
+        # The folder of the files changed mtime, the untouched a did not change
+        if changes.get(d1) != "modified" or a in changes:
+            raise Exception("Bad diff of modified files")
+        lines = subprocess.check_output(diff_cmd + ["1", "2"]).decode("utf-8", "replace").splitlines()
+        if "+ " + t not in lines or "- " + b not in lines or "~ " + d1 not in lines:
+            raise Exception("Bad text diff")
 
         # Remove the old root, prune all unused items and validate the content
         subprocess.check_call(
@@ -256,9 +3131,26 @@ cache_db="%s"
             if fi.read() != "test4":
                 raise Exception("Bad restore 11")
 
+        with open(os.path.join(r2, t[1:]), "rb") as fi, open(t, "rb") as fo:
+            if fi.read() != fo.read():
+                raise Exception("Bad restore of interrupted backup")
+
         if os.readlink(os.path.join(r1, h[1:])) != i:
             raise Exception("Bad restore link 2")
 
+        # Restore a single file of the second backup
+        single = os.path.join(test_dir, "single")
+        restore_cmd = ["target/release/mbackup", "-c", client_config, "--user", "restore", "--password", "hunter2"]
+        subprocess.check_call(restore_cmd + ["restore", "2", "--path", g, "--dest", single])
+        with open(os.path.join(single, g[1:]), "r") as fi:
+            if fi.read() != "test4":
+                raise Exception("Bad restore of single file")
+        for path in [a, c, f]:
+            if os.path.exists(os.path.join(single, path[1:])):
+                raise Exception("Restored more than a single file")
+        if subprocess.call(restore_cmd + ["restore", "2", "--path", b, "--dest", single]) == 0:
+            raise Exception("Restore of missing path succeeded")
+
         # Recreate e
         with open(e, "w") as fi:
             fi.write("test3")
@@ -309,11 +3201,12 @@ cache_db="%s"
             ]
         )
 
-        # And kill the server
+        # And stop the server, which should shut down cleanly and promptly
         if server.returncode != None:
             raise Exception("Server terminated early")
         server.terminate()
-        server.wait()
+        if server.wait(timeout=5) != 0:
+            raise Exception("Server did not shut down cleanly")
         server = 0
 
         # Check that the prune got rid of most of the data
@@ -323,6 +3216,56 @@ cache_db="%s"
                 usage += os.path.getsize(os.path.join(dirpath, f))
         if usage > 1024 * 1024:
             raise Exception("Prune did not remove enough data")
+
+        test_memory_server(test_dir)
+        test_deterministic_nonce(test_dir)
+        test_blake3(test_dir)
+        test_inode_change_detection(test_dir)
+        test_quota(test_dir)
+        test_rate_limit(test_dir)
+        test_retry(test_dir)
+        test_timeout(test_dir)
+        test_access_log(test_dir)
+        test_server_compression(test_dir)
+        test_schema_migration(test_dir)
+        test_database_errors(test_dir)
+        test_webhook(test_dir)
+        test_skipped_files(test_dir)
+        test_interrupt(test_dir)
+        test_follow_symlinks(test_dir)
+        test_size_filter(test_dir)
+        test_client_certs(test_dir)
+        test_generate_config(test_dir)
+        test_key_sources(test_dir)
+        test_daemon(test_dir)
+        test_rename_detection(test_dir)
+        test_mirrors(test_dir)
+        test_read_only(test_dir)
+        test_hosts(test_dir)
+        test_hashed_cache_paths(test_dir)
+        test_stdin(test_dir)
+        test_delete_bucket(test_dir)
+        test_shard_depth(test_dir)
+        test_delete_chunks_list(test_dir)
+        test_chunk_consistency(test_dir)
+        test_bind(test_dir)
+        test_cors(test_dir)
+        test_packs(test_dir)
+        test_btime(test_dir)
+        test_progress(test_dir)
+        test_bucket_override(test_dir)
+        test_root_tags(test_dir)
+        test_bucket_small_size(test_dir)
+        test_maintenance(test_dir)
+        test_concurrent_gc(test_dir)
+        test_https(test_dir)
+        test_passwords(test_dir)
+        test_plain_passwords(test_dir)
+        test_tokens(test_dir)
+        test_content_defined_chunking(test_dir)
+        test_compression(test_dir)
+        test_tampered_chunk(test_dir)
+        test_mount(test_dir)
     finally:
         # Kill the server
         if server: